@@ -0,0 +1,80 @@
+#![warn(clippy::unwrap_used)]
+use std::{io::IsTerminal, path::PathBuf};
+
+use clap::Parser;
+use clap_verbosity_flag::InfoLevel;
+use duco2mqtt::simulator::Simulator;
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "duco2mqtt-sim",
+    about = "Simulates the api of a duco connectivity board, for development without hardware"
+)]
+struct Opt {
+    #[command(flatten)]
+    verbose: clap_verbosity_flag::Verbosity<InfoLevel>,
+
+    // address to serve the api on
+    #[clap(long = "listen", env = "D2M_SIM_LISTEN", default_value = "127.0.0.1:8443")]
+    listen: String,
+
+    // directory with the responses to serve: info.json, info_nodes.json, node_actions.json and optionally
+    // box_actions.json, box_config.json and node_config.json
+    #[clap(long = "data-dir", env = "D2M_SIM_DATA_DIR", default_value = "test/data")]
+    data_dir: PathBuf,
+
+    // certificate the api is served with
+    #[clap(
+        long = "certificate",
+        env = "D2M_SIM_CERTIFICATE",
+        default_value = "test/data/tls/duco.pem"
+    )]
+    certificate: PathBuf,
+
+    // private key of the certificate
+    #[clap(long = "key", env = "D2M_SIM_KEY", default_value = "test/data/tls/duco.key")]
+    key: PathBuf,
+
+    // require this api key like newer firmware does
+    #[clap(long = "api-key", env = "D2M_SIM_API_KEY")]
+    api_key: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::parse();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env().add_directive(opt.verbose.tracing_level_filter().into()))
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .without_time()
+        .init();
+
+    let simulator = match Simulator::new(&opt.data_dir, &opt.certificate, &opt.key) {
+        Ok(simulator) => match opt.api_key {
+            Some(ref api_key) => simulator.with_api_key(api_key),
+            None => simulator,
+        },
+        Err(err) => {
+            tracing::error!("Failed to start the simulator: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let listener = match TcpListener::bind(&opt.listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("Failed to listen on {}: {}", opt.listen, err);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("Serving the duco api on https://{}", opt.listen);
+    tokio::select! {
+        _ = simulator.serve(listener) => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
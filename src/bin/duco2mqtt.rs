@@ -1,24 +1,114 @@
 #![warn(clippy::unwrap_used)]
 use core::time;
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand, error::ErrorKind};
 use clap_verbosity_flag::DebugLevel;
 use duco2mqtt::{
-    bridge::{self, DucoMqttBridgeConfig},
-    mqtt::MqttConfig,
+    bridge::{self, DucoMqttBridgeConfig, PollOnceError},
+    compat::TopicCompat,
+    doctor::CheckStatus,
+    mqtt::{MqttConfig, MqttProtocol},
+    naming::{DEFAULT_TOPIC_TEMPLATE, EntityNaming},
+    redact::LogRedaction,
+    secret::Secret,
+    statusfilter::{StatusFilter, StatusRule},
+    valuetransform::{TransformRule, ValueTransform},
 };
-use env_logger::Env;
+use serde_json::json;
+use strum::{Display, EnumString};
+use tracing_subscriber::EnvFilter;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const PACKAGE: &str = env!("CARGO_PKG_NAME");
+/// Exit codes of the poll subcommand, 1 is used for the other failures (e.g. an invalid configuration)
+const EXIT_DUCO_FAILED: i32 = 2;
+const EXIT_MQTT_FAILED: i32 = 3;
+const POLL_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Output of the subcommands, json is meant for scripts and installers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Format of the log lines, json (one json document per line) is meant for log aggregation systems
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render the home assistant discovery documents to files without publishing them
+    RenderDiscovery {
+        // directory the discovery documents are written to
+        #[clap(long = "out")]
+        out: PathBuf,
+    },
+    /// Only connect to the duco box and print the values that change on every poll
+    Watch,
+    /// Only connect to the duco box and print the device info and the nodes with their actions
+    Discover,
+    /// Check the connection to the duco box and the MQTT broker step by step
+    Doctor,
+    /// Download the certificate of the duco box to a PEM file for --certificate
+    FetchCert {
+        // file the certificate chain is written to
+        #[clap(long = "out")]
+        out: PathBuf,
+    },
+    /// Poll the duco box once, publish the values (and the discovery documents) and exit
+    Poll,
+    /// Obtain a new api key with --duco-api-key-command, check it against the duco box and write it to the file of
+    /// --duco-api-key-file
+    ApiKey {
+        // file the api key is written to
+        #[clap(long = "out")]
+        out: PathBuf,
+    },
+    /// Only connect to the duco box and perform an action of a node, e.g. set --node 2 SetVentilationState MAN2
+    Set {
+        // number of the node
+        #[clap(long = "node")]
+        node: u16,
+        // name of the action, e.g. SetVentilationState
+        action: String,
+        // value of the action, e.g. MAN2
+        value: String,
+    },
+    /// Validate the configuration without connecting to the duco box or the MQTT broker
+    CheckConfig,
+}
 
 #[derive(Parser, Debug)]
 #[clap(name = "duco2mqtt", about = "Interface between duco connectivity board and MQTT")]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity<DebugLevel>,
 
+    // output format of the subcommands: text or json (one json document per line)
+    #[clap(long = "output", env = "D2M_OUTPUT", default_value_t = OutputFormat::Text, global = true)]
+    output: OutputFormat,
+
+    // format of the log lines: text or json (one json document per line with the fields of the spans and events)
+    #[clap(long = "log-format", env = "D2M_LOG_FORMAT", default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+
     // set the duco connectivity board host name
     #[clap(long = "duco-host", env = "D2M_DUCO_HOST")]
     duco_host: String,
@@ -30,15 +120,125 @@ struct Opt {
     #[clap(long = "duco-poll-interval", env = "D2M_POLL_INTERVAL", default_value_t = 60)]
     duco_poll_interval: u64,
 
-    // set the mqtt addr
+    // number of consecutive polls a node has to be missing from the duco network before it is considered gone
+    #[clap(
+        long = "duco-node-missing-polls",
+        env = "D2M_NODE_MISSING_POLLS",
+        default_value_t = 3
+    )]
+    duco_node_missing_polls: u32,
+
+    // keep the nodes that left the duco network with unknown values instead of removing their entities
+    #[clap(long = "keep-missing-nodes", env = "D2M_KEEP_MISSING_NODES", default_value_t = false)]
+    keep_missing_nodes: bool,
+
+    // request the actions of the nodes again every number of seconds, to pick up changed actions (0 = disabled)
+    #[clap(
+        long = "duco-rediscovery-interval",
+        env = "D2M_REDISCOVERY_INTERVAL",
+        default_value_t = 3600
+    )]
+    duco_rediscovery_interval: u64,
+
+    // poll the duco board at this interval (seconds) for a while after a command, to report the new state quickly
+    #[clap(
+        long = "duco-command-poll-interval",
+        env = "D2M_COMMAND_POLL_INTERVAL",
+        default_value_t = 2
+    )]
+    duco_command_poll_interval: u64,
+
+    // how long (seconds) to keep polling at the command poll interval after a command (0 = disabled)
+    #[clap(
+        long = "duco-command-poll-window",
+        env = "D2M_COMMAND_POLL_WINDOW",
+        default_value_t = 30
+    )]
+    duco_command_poll_window: u64,
+
+    // number of seconds to wait for the connection to the duco board
+    #[clap(
+        long = "duco-connect-timeout",
+        env = "D2M_DUCO_CONNECT_TIMEOUT",
+        default_value_t = 15
+    )]
+    duco_connect_timeout: u64,
+
+    // number of seconds to wait for the response of a duco request
+    #[clap(long = "duco-read-timeout", env = "D2M_DUCO_READ_TIMEOUT", default_value_t = 30)]
+    duco_read_timeout: u64,
+
+    // close idle connections to the duco board after this number of seconds
+    #[clap(
+        long = "duco-pool-idle-timeout",
+        env = "D2M_DUCO_POOL_IDLE_TIMEOUT",
+        default_value_t = 30
+    )]
+    duco_pool_idle_timeout: u64,
+
+    // number of idle connections to keep open to the duco board (0 = a new connection for every request), the device
+    // info and the nodes are requested over two concurrent connections, 2 keeps both open
+    #[clap(long = "duco-pool-max-idle", env = "D2M_DUCO_POOL_MAX_IDLE", default_value_t = 1)]
+    duco_pool_max_idle: usize,
+
+    // number of attempts of a duco request that fails on a timeout or connection error (1 = no retries)
+    #[clap(long = "duco-retry-attempts", env = "D2M_DUCO_RETRY_ATTEMPTS", default_value_t = 3)]
+    duco_retry_attempts: u32,
+
+    // milliseconds to wait before the first retry of a duco request, doubled for every next retry
+    #[clap(long = "duco-retry-backoff", env = "D2M_DUCO_RETRY_BACKOFF", default_value_t = 500)]
+    duco_retry_backoff: u64,
+
+    // reach the duco board through this http(s) proxy (e.g. http://jumphost:3128), hosts in NO_PROXY are not proxied.
+    // Without this option the HTTPS_PROXY and NO_PROXY environment variables are honored.
+    #[clap(long = "duco-proxy", env = "D2M_DUCO_PROXY")]
+    duco_proxy: Option<String>,
+
+    // api key of the duco board, required by newer firmware
+    #[clap(long = "duco-api-key", env = "D2M_DUCO_API_KEY")]
+    duco_api_key: Option<Secret>,
+
+    // read the api key of the duco board from a file, the file is read again when the board rejects the key
+    #[clap(
+        long = "duco-api-key-file",
+        env = "D2M_DUCO_API_KEY_FILE",
+        conflicts_with = "duco_api_key"
+    )]
+    duco_api_key_file: Option<PathBuf>,
+
+    // shell command that prints the api key of the duco board, it runs again when the board rejects the key
+    #[clap(
+        long = "duco-api-key-command",
+        env = "D2M_DUCO_API_KEY_COMMAND",
+        conflicts_with_all = ["duco_api_key", "duco_api_key_file"]
+    )]
+    duco_api_key_command: Option<String>,
+
+    // save every raw api response of the duco board with a timestamp to this directory
+    #[clap(long = "record", env = "D2M_RECORD", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    // feed the api responses recorded with --record to the bridge instead of talking to the duco board
+    #[clap(long = "replay", env = "D2M_REPLAY")]
+    replay: Option<PathBuf>,
+
+    // set the mqtt addr, a comma separated list of host[:port] addresses of redundant brokers for failover
     #[clap(long = "mqtt-addr", env = "D2M_MQTT_ADDRESS")]
-    mqtt_addr: String,
+    mqtt_addr: Option<String>,
 
     #[clap(long = "mqtt-user", env = "D2M_MQTT_USER")]
     mqtt_user: Option<String>,
 
     #[clap(long = "mqtt-pass", env = "D2M_MQTT_PASS")]
-    mqtt_password: Option<String>,
+    mqtt_password: Option<Secret>,
+
+    // read the mqtt password from a file (e.g. a docker secret) instead of passing it on the command line
+    #[clap(
+        long = "mqtt-pass-file",
+        env = "D2M_MQTT_PASS_FILE",
+        conflicts_with = "mqtt_password"
+    )]
+    mqtt_password_file: Option<PathBuf>,
 
     #[clap(long = "mqtt-port", env = "D2M_MQTT_PORT", default_value_t = 1883)]
     mqtt_port: u16,
@@ -49,42 +249,571 @@ struct Opt {
     #[clap(long = "mqtt-base-topic", env = "D2M_MQTT_BASE_TOPIC", default_value_t = String::from("ventilation"))]
     mqtt_base_topic: String,
 
+    // the MQTT protocol version (5 or 3.1.1 for brokers that reject MQTT v5 connections)
+    #[clap(long = "mqtt-protocol", env = "D2M_MQTT_PROTOCOL", default_value_t = MqttProtocol::V5)]
+    mqtt_protocol: MqttProtocol,
+
+    // connect to the broker over TLS (usually on port 8883)
+    #[clap(long = "mqtt-tls", env = "D2M_MQTT_TLS", default_value_t = false)]
+    mqtt_tls: bool,
+
+    // PEM bundle with the CA certificates to validate the broker certificate (default: the system certificates)
+    #[clap(long = "mqtt-ca-cert", env = "D2M_MQTT_CA_CERT", requires = "mqtt_tls")]
+    mqtt_ca_cert: Option<PathBuf>,
+
+    // validate the broker certificate against this name instead of the mqtt addr (e.g. when connecting by ip address),
+    // the SNI is still derived from the mqtt addr
+    #[clap(
+        long = "mqtt-tls-server-name",
+        env = "D2M_MQTT_TLS_SERVER_NAME",
+        requires = "mqtt_tls"
+    )]
+    mqtt_tls_server_name: Option<String>,
+
+    // authenticate with a client certificate (PEM, the chain is allowed) instead of or next to the mqtt user
+    #[clap(
+        long = "mqtt-client-cert",
+        env = "D2M_MQTT_CLIENT_CERT",
+        requires_all = ["mqtt_tls", "mqtt_client_key"]
+    )]
+    mqtt_client_cert: Option<PathBuf>,
+
+    // the PEM encoded private key of the client certificate
+    #[clap(
+        long = "mqtt-client-key",
+        env = "D2M_MQTT_CLIENT_KEY",
+        requires_all = ["mqtt_tls", "mqtt_client_cert"]
+    )]
+    mqtt_client_key: Option<PathBuf>,
+
+    // handle the retained commands the broker replays after a restart (ignored by default)
+    #[clap(
+        long = "mqtt-retained-commands",
+        env = "D2M_MQTT_RETAINED_COMMANDS",
+        default_value_t = false
+    )]
+    mqtt_retained_commands: bool,
+
     #[clap(long = "hass-discovery", env = "D2M_HASS_DISCOVERY", default_value_t = false)]
     hass_discovery: bool,
 
+    // wait for the home assistant birth message (homeassistant/status) before publishing the discovery documents,
+    // but at most this number of seconds (0 = publish immediately)
+    #[clap(long = "hass-discovery-delay", env = "D2M_HASS_DISCOVERY_DELAY", default_value_t = 0)]
+    hass_discovery_delay: u64,
+
+    // wait at most this number of seconds for the broker to acknowledge the discovery documents before the states
+    // are published, so home assistant knows the entities first (0 = do not wait)
+    #[clap(
+        long = "hass-discovery-ack-timeout",
+        env = "D2M_HASS_DISCOVERY_ACK_TIMEOUT",
+        default_value_t = 10
+    )]
+    hass_discovery_ack_timeout: u64,
+
+    // compare the discovery documents retained on the broker with the generated ones every number of seconds and
+    // report the differences as a problem entity (0 = disabled)
+    #[clap(
+        long = "hass-drift-check-interval",
+        env = "D2M_HASS_DRIFT_CHECK_INTERVAL",
+        default_value_t = 0
+    )]
+    hass_drift_check_interval: u64,
+
+    // republish the discovery documents that differ from the generated ones
+    #[clap(long = "hass-drift-repair", env = "D2M_HASS_DRIFT_REPAIR", default_value_t = false)]
+    hass_drift_repair: bool,
+
+    // enable or disable home assistant entities on a fresh install, comma separated <unique id>=<true|false> pairs
+    // (diagnostic entities and timers are disabled by default, e.g. "duco_node_2_general_asso=true")
+    #[clap(
+        long = "hass-entity-defaults",
+        env = "D2M_HASS_ENTITY_DEFAULTS",
+        value_delimiter = ',',
+        value_parser = parse_entity_default
+    )]
+    hass_entity_defaults: Vec<(String, bool)>,
+
+    // publish one discovery document per device instead of one per entity (home assistant 2024.11 or newer)
+    #[clap(
+        long = "hass-device-discovery",
+        env = "D2M_HASS_DEVICE_DISCOVERY",
+        default_value_t = false
+    )]
+    hass_device_discovery: bool,
+
+    // publish the values of a node as a single json document (duco_node_<nr>/values) instead of a topic per value
+    #[clap(long = "json-state", env = "D2M_JSON_STATE", default_value_t = false)]
+    json_state: bool,
+
+    // publish the time of the last poll that reported a changed value of a node (duco_node_<nr>/last_seen), also as a
+    // diagnostic sensor
+    #[clap(long = "last-seen", env = "D2M_LAST_SEEN", default_value_t = false)]
+    last_seen: bool,
+
     #[clap(long = "certificate", env = "D2M_DUCO_CERTIFICATE")]
     certificate: Option<String>,
+
+    // trust the board when it presents the provided certificate, ignoring its validity period (clock skew)
+    #[clap(long = "duco-cert-pinning", env = "D2M_DUCO_CERT_PINNING", default_value_t = false)]
+    duco_cert_pinning: bool,
+
+    // redact identifying values (names, serials, addresses) in the logs: off, hash or redact
+    #[clap(long = "log-redaction", env = "D2M_LOG_REDACTION", default_value_t = LogRedaction::Off)]
+    log_redaction: LogRedaction,
+
+    // log the payload of every n-th publish per topic at info level (0 = only log publishes at debug level)
+    #[clap(long = "log-sample-interval", env = "D2M_LOG_SAMPLE_INTERVAL", default_value_t = 0)]
+    log_sample_interval: u32,
+
+    // check the github releases once a day and report when a newer version is available
+    #[clap(long = "update-check", env = "D2M_UPDATE_CHECK", default_value_t = false)]
+    update_check: bool,
+
+    // serve the health status of the bridge on http://<host>:<port>/healthz, for container health checks
+    #[clap(long = "health-port", env = "D2M_HEALTH_PORT")]
+    health_port: Option<u16>,
+
+    // the health check fails when the last successful poll of the box is older than this (seconds, 0 disables it)
+    #[clap(long = "health-max-poll-age", env = "D2M_HEALTH_MAX_POLL_AGE", default_value_t = 600)]
+    health_max_poll_age: u64,
+
+    // file used to persist the bridge state (e.g. the states to restore after away mode) across restarts
+    #[clap(long = "state-file", env = "D2M_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    // json file describing the virtual nodes that group physical nodes
+    #[clap(long = "virtual-nodes", env = "D2M_VIRTUAL_NODES")]
+    virtual_nodes: Option<PathBuf>,
+
+    // keep the topic layout of an older version after intentional topic changes (1 = current layout)
+    #[clap(long = "compat", env = "D2M_COMPAT", default_value_t = TopicCompat::V1)]
+    compat: TopicCompat,
+
+    // for battery or solar powered hosts: poll at most every 5 minutes, no fast polling after commands,
+    // no per-publish logging, unacknowledged (QoS 0) publishes, a 15 minute MQTT keep alive and
+    // no diagnostic topics, the values of a node are published as a single json document (--json-state)
+    #[clap(long = "low-power", env = "D2M_LOW_POWER", default_value_t = false)]
+    low_power: bool,
+
+    // keep the last n samples of every numeric node value and publish the min/max/avg of the last hour
+    // as json attributes (0 = disabled)
+    #[clap(long = "history-samples", env = "D2M_HISTORY_SAMPLES", default_value_t = 0)]
+    history_samples: usize,
+
+    // poll at the times of cron expressions (sec min hour day-of-month month day-of-week) instead of at a fixed
+    // interval, multiple expressions are separated by ';' (e.g. "*/15 * 7-22 * * *;0 */5 0-6,23 * * *")
+    #[clap(long = "poll-schedule", env = "D2M_POLL_SCHEDULE", value_delimiter = ';')]
+    poll_schedule: Vec<cron::Schedule>,
+
+    // naming of the home assistant entities: name (after the node name), number (after the status topic) or
+    // serial (after the node name, unique ids prefixed with the serial of the box)
+    #[clap(long = "entity-naming", env = "D2M_ENTITY_NAMING", default_value_t = EntityNaming::Name)]
+    entity_naming: EntityNaming,
+
+    // nodes (comma separated node numbers) that keep the air quality percentage sensor next to the CO2 ppm sensor
+    #[clap(long = "iaq-sensors", env = "D2M_IAQ_SENSORS", value_delimiter = ',')]
+    iaq_sensors: Vec<u16>,
+
+    // layout of the node topics: {base}/<node level>/{group}/{key}, the node level contains {nr} and/or {node_name}
+    // (e.g. "{base}/{node_name}/{group}/{key}")
+    #[clap(long = "topic-template", env = "D2M_TOPIC_TEMPLATE", default_value_t = String::from(DEFAULT_TOPIC_TEMPLATE))]
+    topic_template: String,
+
+    // lowercase the node names and status keys in the node topics (e.g. duco_node_1/ventilation/state)
+    #[clap(long = "topic-lowercase", env = "D2M_TOPIC_LOWERCASE", default_value_t = false)]
+    topic_lowercase: bool,
+
+    // select the published status keys of the nodes, comma separated rules where the last matching rule decides:
+    // -<group>/<key> skips a key, +<group>/<key> publishes it, <group>/* matches a group (e.g. "-General/*,+General/Name")
+    #[clap(
+        long = "status-filter",
+        env = "D2M_STATUS_FILTER",
+        value_delimiter = ',',
+        allow_hyphen_values = true
+    )]
+    status_filter: Vec<StatusRule>,
+
+    // publish values in another unit, comma separated rules: <group>/<key>=<factor> scales the value and
+    // <group>/<key>=timestamp decodes an epoch timestamp (e.g. "Sensor/TempOda=0.1,Ventilation/TimeStateEnd=timestamp")
+    #[clap(long = "value-transform", env = "D2M_VALUE_TRANSFORM", value_delimiter = ',')]
+    value_transform: Vec<TransformRule>,
 }
 
 #[tokio::main]
 async fn main() {
     let opt = Opt::parse();
 
-    env_logger::Builder::from_env(Env::default())
-        .format_timestamp(None)
-        .filter_level(opt.verbose.log_level_filter())
-        .init();
+    init_logging(&opt);
+
+    tracing::info!("{} version {}", PACKAGE, VERSION);
 
-    log::info!("{} version {}", PACKAGE, VERSION);
+    let mqtt_password = match opt.mqtt_password_file {
+        Some(ref path) => match Secret::from_file(path) {
+            Ok(password) => password,
+            Err(err) => {
+                tracing::error!("Failed to read the MQTT password from {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => opt.mqtt_password.unwrap_or_default(),
+    };
 
     let cfg = DucoMqttBridgeConfig {
         ducobox_host: opt.duco_host.clone(),
         ducobox_ip_address: opt.duco_ip.clone(),
         ducobox_certificate: opt.certificate.map(PathBuf::from),
+        ducobox_cert_pinning: opt.duco_cert_pinning,
+        ducobox_connect_timeout: time::Duration::from_secs(opt.duco_connect_timeout),
+        ducobox_read_timeout: time::Duration::from_secs(opt.duco_read_timeout),
+        ducobox_pool_idle_timeout: time::Duration::from_secs(opt.duco_pool_idle_timeout),
+        ducobox_pool_max_idle: opt.duco_pool_max_idle,
+        ducobox_retry_attempts: opt.duco_retry_attempts,
+        ducobox_retry_backoff: time::Duration::from_millis(opt.duco_retry_backoff),
+        ducobox_proxy: opt.duco_proxy.clone(),
+        ducobox_api_key: opt.duco_api_key.clone(),
+        ducobox_api_key_file: opt.duco_api_key_file.clone(),
+        ducobox_api_key_command: opt.duco_api_key_command.clone(),
+        duco_record_dir: opt.record.clone(),
+        duco_replay_dir: opt.replay.clone(),
         poll_interval: time::Duration::from_secs(opt.duco_poll_interval),
+        node_missing_polls: opt.duco_node_missing_polls,
+        keep_missing_nodes: opt.keep_missing_nodes,
+        rediscovery_interval: time::Duration::from_secs(opt.duco_rediscovery_interval),
+        command_poll_interval: time::Duration::from_secs(opt.duco_command_poll_interval),
+        command_poll_window: time::Duration::from_secs(opt.duco_command_poll_window),
         mqtt_config: MqttConfig {
-            server: opt.mqtt_addr,
+            // the subcommands that only talk to the duco box never connect to the broker
+            server: opt.mqtt_addr.clone().unwrap_or_else(|| "localhost".to_string()),
             port: opt.mqtt_port,
             client_id: opt.mqtt_client_id,
             user: opt.mqtt_user.unwrap_or(String::new()),
-            password: opt.mqtt_password.unwrap_or(String::new()),
+            password: mqtt_password,
             base_topic: opt.mqtt_base_topic,
+            low_power: opt.low_power,
+            protocol: opt.mqtt_protocol,
+            tls: opt.mqtt_tls,
+            ca_cert: opt.mqtt_ca_cert,
+            tls_server_name: opt.mqtt_tls_server_name,
+            client_cert: opt.mqtt_client_cert,
+            client_key: opt.mqtt_client_key,
+            retained_commands: opt.mqtt_retained_commands,
         },
         hass_discovery: opt.hass_discovery,
+        hass_device_discovery: opt.hass_device_discovery,
+        json_state: opt.json_state,
+        last_seen: opt.last_seen,
+        hass_discovery_delay: time::Duration::from_secs(opt.hass_discovery_delay),
+        hass_discovery_ack_timeout: time::Duration::from_secs(opt.hass_discovery_ack_timeout),
+        hass_drift_check_interval: time::Duration::from_secs(opt.hass_drift_check_interval),
+        hass_drift_repair: opt.hass_drift_repair,
+        hass_entity_defaults: opt.hass_entity_defaults.into_iter().collect(),
+        log_redaction: opt.log_redaction,
+        log_sample_interval: opt.log_sample_interval,
+        update_check: opt.update_check,
+        health_port: opt.health_port,
+        health_max_poll_age: time::Duration::from_secs(opt.health_max_poll_age),
+        state_file: opt.state_file,
+        virtual_nodes_file: opt.virtual_nodes,
+        compat: opt.compat,
+        history_samples: opt.history_samples,
+        poll_schedule: opt.poll_schedule,
+        entity_naming: opt.entity_naming,
+        iaq_sensor_nodes: opt.iaq_sensors,
+        topic_template: opt.topic_template,
+        topic_lowercase: opt.topic_lowercase,
+        status_filter: StatusFilter::new(opt.status_filter),
+        value_transform: ValueTransform::new(opt.value_transform),
+    };
+
+    match opt.command {
+        Some(Command::RenderDiscovery { out }) => {
+            if let Err(err) = render_discovery(cfg, &out, opt.output).await {
+                exit_with_error("Failed to render the discovery documents", &err, opt.output);
+            }
+        }
+        Some(Command::Watch) => {
+            if let Err(err) = watch(cfg, opt.output).await {
+                exit_with_error("Failed to watch the duco box", &err, opt.output);
+            }
+        }
+        Some(Command::Discover) => {
+            if let Err(err) = discover(cfg, opt.output).await {
+                exit_with_error("Failed to discover the duco box", &err, opt.output);
+            }
+        }
+        Some(Command::Doctor) => {
+            let checks = duco2mqtt::doctor::diagnose(cfg, opt.mqtt_addr.is_some()).await;
+            for check in &checks {
+                match opt.output {
+                    OutputFormat::Text => println!("{}", check),
+                    OutputFormat::Json => println!("{}", json!(check)),
+                }
+            }
+
+            if checks.iter().any(|check| check.status == CheckStatus::Fail) {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::FetchCert { out }) => {
+            if let Err(err) = fetch_cert(&opt.duco_host, opt.duco_ip.as_deref(), &out, opt.output).await {
+                exit_with_error("Failed to fetch the certificate", &err, opt.output);
+            }
+        }
+        Some(Command::ApiKey { out }) => {
+            if let Err(err) = api_key(cfg, &out, opt.output).await {
+                exit_with_error("Failed to renew the api key", &err, opt.output);
+            }
+        }
+        Some(Command::Set { node, action, value }) => {
+            if let Err(err) = set(cfg, node, &action, &value, opt.output).await {
+                exit_with_error("Failed to perform the action", &err, opt.output);
+            }
+        }
+        Some(Command::CheckConfig) => {
+            if let Err(err) = check_config(cfg, opt.mqtt_addr.is_some(), opt.output) {
+                exit_with_error("Invalid configuration", &err, opt.output);
+            }
+        }
+        Some(Command::Poll) => {
+            let bridge = create_bridge(cfg, opt.mqtt_addr.is_some());
+            if let Err(err) = bridge.poll_once(POLL_CONNECT_TIMEOUT).await {
+                match opt.output {
+                    OutputFormat::Text => tracing::error!("{}", err),
+                    OutputFormat::Json => println!("{}", json!({ "error": err.to_string() })),
+                }
+                std::process::exit(match err {
+                    PollOnceError::Duco(_) => EXIT_DUCO_FAILED,
+                    PollOnceError::Mqtt(_) => EXIT_MQTT_FAILED,
+                });
+            }
+        }
+        None => {
+            let bridge = create_bridge(cfg, opt.mqtt_addr.is_some());
+            if let Err(err) = bridge.run().await {
+                tracing::error!("Failed to run the bridge: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Logs to stderr at the verbosity level, RUST_LOG can still configure the levels of specific modules.
+/// The log records of the dependencies that use the log crate are forwarded.
+fn init_logging(opt: &Opt) {
+    let filter = EnvFilter::from_default_env().add_directive(opt.verbose.tracing_level_filter().into());
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match opt.log_format {
+        LogFormat::Text => subscriber
+            .with_ansi(std::io::stderr().is_terminal())
+            .without_time()
+            .init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+/// Creates the bridge to the broker, exits when the broker address is missing or the configuration is invalid
+fn create_bridge(cfg: DucoMqttBridgeConfig, has_mqtt_addr: bool) -> bridge::DucoMqttBridge {
+    if !has_mqtt_addr {
+        Opt::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--mqtt-addr is required to run the bridge",
+            )
+            .exit();
+    }
+
+    match bridge::DucoMqttBridge::new(cfg) {
+        Ok(bridge) => bridge,
+        Err(err) => {
+            tracing::error!("Failed to start the bridge: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn exit_with_error(context: &str, err: &anyhow::Error, output: OutputFormat) -> ! {
+    match output {
+        OutputFormat::Text => tracing::error!("{}: {:#}", context, err),
+        OutputFormat::Json => println!("{}", json!({ "error": format!("{}: {:#}", context, err) })),
+    }
+
+    std::process::exit(1);
+}
+
+async fn render_discovery(cfg: DucoMqttBridgeConfig, out: &Path, output: OutputFormat) -> anyhow::Result<()> {
+    let documents = bridge::DucoMqttBridge::new(cfg)?.discovery_documents().await?;
+
+    for document in &documents {
+        let path = out.join(format!("{}.json", document.topic));
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let json: serde_json::Value = serde_json::from_str(&document.payload)?;
+        std::fs::write(&path, serde_json::to_string_pretty(&json)? + "\n")?;
+    }
+
+    match output {
+        OutputFormat::Text => tracing::info!("{} discovery documents written to {}", documents.len(), out.display()),
+        OutputFormat::Json => println!(
+            "{}",
+            json!({
+                "out": out,
+                "topics": documents.iter().map(|doc| doc.topic.as_str()).collect::<Vec<_>>(),
+            })
+        ),
+    }
+
+    Ok(())
+}
+
+async fn discover(cfg: DucoMqttBridgeConfig, output: OutputFormat) -> anyhow::Result<()> {
+    let inventory = bridge::DucoMqttBridge::new(cfg)?.inventory().await?;
+
+    match output {
+        OutputFormat::Text => print!("{}", inventory),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&inventory)?),
+    }
+
+    Ok(())
+}
+
+async fn set(
+    cfg: DucoMqttBridgeConfig,
+    node: u16,
+    action: &str,
+    value: &str,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    bridge::DucoMqttBridge::new(cfg)?.set(node, action, value).await?;
+
+    match output {
+        OutputFormat::Text => tracing::info!("Node {}: {} set to {}", node, action, value),
+        OutputFormat::Json => println!("{}", json!({ "node": node, "action": action, "value": value })),
+    }
+
+    Ok(())
+}
+
+/// Creates the bridge like a regular run would, so the configuration errors are reported without starting it
+fn check_config(cfg: DucoMqttBridgeConfig, has_mqtt_addr: bool, output: OutputFormat) -> anyhow::Result<()> {
+    anyhow::ensure!(has_mqtt_addr, "--mqtt-addr is required to run the bridge");
+    bridge::DucoMqttBridge::new(cfg)?;
+
+    match output {
+        OutputFormat::Text => tracing::info!("The configuration is valid"),
+        OutputFormat::Json => println!("{}", json!({ "valid": true })),
+    }
+
+    Ok(())
+}
+
+/// Writes the api key that the duco box accepted, the file is replaced at once so a running bridge never reads a
+/// partial key
+async fn api_key(cfg: DucoMqttBridgeConfig, out: &Path, output: OutputFormat) -> anyhow::Result<()> {
+    let api_key = bridge::renew_api_key(cfg).await?;
+
+    let partial = out.with_extension("partial");
+    let mut file = std::fs::OpenOptions::new();
+    file.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+    file.open(&partial)
+        .and_then(|mut file| writeln!(file, "{}", api_key.expose()))
+        .and_then(|_| std::fs::rename(&partial, out))
+        .with_context(|| format!("Failed to write {}", out.display()))?;
+
+    match output {
+        OutputFormat::Text => tracing::info!("The duco box accepted the new api key, written to {}", out.display()),
+        OutputFormat::Json => println!("{}", json!({ "file": out })),
+    }
+
+    Ok(())
+}
+
+/// Writes the certificates the box presents, the details are printed to compare them with the web interface of the
+/// connectivity board before the certificate is trusted
+async fn fetch_cert(host: &str, ip: Option<&str>, out: &Path, output: OutputFormat) -> anyhow::Result<()> {
+    let chain = duco2mqtt::tlsprobe::fetch_certificate_chain(host, ip).await?;
+    let certificates = chain.info()?;
+    std::fs::write(out, chain.to_pem()).with_context(|| format!("Failed to write {}", out.display()))?;
+
+    match output {
+        OutputFormat::Text => {
+            for cert in &certificates {
+                println!("Subject:     {}", cert.subject);
+                println!("Issuer:      {}", cert.issuer);
+                println!("Valid:       {} until {}", cert.not_before, cert.not_after);
+                if !cert.names.is_empty() {
+                    println!("Names:       {}", cert.names.join(", "));
+                }
+                println!("Fingerprint: SHA256 {}\n", cert.sha256_fingerprint);
+            }
+            println!(
+                "Written to {}, compare the fingerprint with the certificate in the web interface of the \
+                 connectivity board and pass it with --certificate {}",
+                out.display(),
+                out.display()
+            );
+        }
+        OutputFormat::Json => println!("{}", json!({ "file": out, "certificates": certificates })),
+    }
+
+    Ok(())
+}
+
+async fn watch(cfg: DucoMqttBridgeConfig, output: OutputFormat) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(cfg.poll_interval);
+    let mut bridge = bridge::DucoMqttBridge::new(cfg)?;
+    let mut values: HashMap<String, String> = HashMap::new();
+    let color = std::io::stdout().is_terminal();
+
+    loop {
+        interval.tick().await;
+
+        for change in bridge.poll_changes().await? {
+            let previous = values.insert(change.topic.clone(), change.payload.clone());
+            match output {
+                OutputFormat::Text => println!(
+                    "{}",
+                    format_change(&change.topic, previous.as_deref(), &change.payload, color)
+                ),
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({ "topic": change.topic, "previous": previous, "value": change.payload })
+                ),
+            }
+        }
+    }
+}
+
+/// Parses a `<unique id>=<true|false>` pair of the entity defaults
+fn parse_entity_default(value: &str) -> Result<(String, bool), String> {
+    let (unique_id, enabled) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <unique id>=<true|false>, got '{}'", value))?;
+    let enabled = enabled
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected true or false for '{}', got '{}'", unique_id, enabled))?;
+
+    Ok((unique_id.trim().to_string(), enabled))
+}
+
+/// New values are printed in green, changed values show the previous value in red followed by the new value in green
+fn format_change(topic: &str, previous: Option<&str>, value: &str, color: bool) -> String {
+    let (red, green, reset) = if color {
+        ("\x1b[31m", "\x1b[32m", "\x1b[0m")
+    } else {
+        ("", "", "")
     };
 
-    bridge::DucoMqttBridge::new(cfg)
-        .run()
-        .await
-        .expect("Failed to run bridge");
+    match previous {
+        Some(previous) => format!("{topic:<50} {red}{previous}{reset} -> {green}{value}{reset}"),
+        None => format!("{topic:<50} {green}{value}{reset}"),
+    }
 }
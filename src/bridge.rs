@@ -1,93 +1,870 @@
-use crate::ducoapi::NodeInfo;
-use crate::ducoboxdevice::DucoBoxDevice;
-use crate::ducoboxnode::DucoBoxNode;
-use crate::hassdiscovery::{self};
+use crate::certpinning;
+use crate::commandqueue::{Command, CommandQueue};
+use crate::compat::TopicCompat;
+use crate::discoverydrift::DriftCheck;
+use crate::discoveryv1;
+use crate::ducoapi::{self, ApiKeySource, NodeAction, NodeActions, NodeBoolAction, NodeConfig, NodeInfo, StatusValue};
+use crate::ducobackend::{ConnectionConfig, DucoBackend, HttpBackend, ReplayBackend, RetryPolicy};
+use crate::ducoboxdevice::{
+    DucoBoxDevice, FILTER_REMAINING_DAYS, NETWORK_DUCO, REBOOT_ACTION, RESET_FILTER_ACTION, SERIAL_NUMBER,
+    SUPPLY_TEMPERATURE_TARGET,
+};
+use crate::ducoboxnode::{
+    CO2_SETPOINT, CONFIG, DucoBoxNode, EMPTY_HOUSE_STATE, GENERAL, LAST_SEEN, SET_IDENTIFY, SET_VENTILATION_STATE,
+    VENTILATION_STATE_KEY,
+};
+use crate::ducoendpoint::DucoEndpoint;
+use crate::duconodetypes::{Capability, NodeType};
+use crate::errorevent::{ErrorCategory, ErrorEvent};
+use crate::hassdiscovery::{self, DiscoveryContext};
+use crate::health::{self, HealthStatus};
+use crate::history::SampleHistory;
+use crate::inventory::Inventory;
+use crate::logsampler::LogSampler;
 use crate::mqtt::{MqttConfig, MqttConnection, MqttData};
-use crate::{Result, ducoapi};
-use anyhow::{anyhow, ensure};
-use std::net::SocketAddr;
+use crate::naming::{self, EntityNaming, NamingStrategy, TopicScheme};
+use crate::nodemodel::NodeModel;
+use crate::overridestack::OverrideStack;
+use crate::persistentstate::PersistentState;
+use crate::poller::{self, NodeRefresh, PollRequest, PollResponse, Poller};
+use crate::pollschedule::{BurstPoll, PollSchedule};
+use crate::recording::{Recorder, Recording};
+use crate::redact::LogRedaction;
+use crate::secret::Secret;
+use crate::statusfilter::StatusFilter;
+use crate::valuetransform::ValueTransform;
+use crate::virtualnode::{self, VirtualNodeConfig};
+use crate::{ApiResult, Error, Result, updatecheck};
+use anyhow::{anyhow, bail, ensure};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::time;
+use tracing::Instrument;
+
+const BRIDGE_COMMAND_PREFIX: &str = "bridge/cmnd/";
+const HEAT_RECOVERY_COMMAND_PREFIX: &str = "HeatRecovery/cmnd/";
+const BOX_COMMAND_PREFIX: &str = "duco_box/cmnd/";
+const AWAY_OVERRIDE: &str = "away";
+/// Virtual node actions to manage the temporary state overrides of a node
+const OVERRIDE_ACTION: &str = "Override";
+const CLEAR_OVERRIDE_ACTION: &str = "ClearOverride";
+const NODE_COMMAND_SUFFIX: &str = "/cmnd";
+const UPDATE_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(24 * 60 * 60);
+const LOW_POWER_MIN_POLL_INTERVAL: time::Duration = time::Duration::from_secs(5 * 60);
+/// The configuration of the nodes is requested again every this many polls, to pick up changes made on the box
+const NODE_CONFIG_REFRESH_POLLS: u64 = 10;
+/// Time given to the broker to acknowledge the last publishes when the bridge is stopped
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+const POLL_INTERVAL_RANGE: std::ops::RangeInclusive<time::Duration> =
+    time::Duration::from_secs(1)..=time::Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, PartialEq, Eq)]
+struct NodeCommandTopic {
+    node: u16,
+    action: String,
+    /// Sub-resource of grouped actions (e.g. the parameter of a configuration change)
+    resource: Option<String>,
+}
+
+/// Payload of the node command topic (`duco_node_<nr>/cmnd`), in the shape of the actions of the duco api
+#[derive(Deserialize)]
+struct NodeJsonCommand {
+    #[serde(rename = "Action")]
+    action: String,
+    #[serde(rename = "Val")]
+    val: serde_json::Value,
+}
+
+/// Payload of the override command, pushes a named override with the ventilation state on the stack of the node
+#[derive(Deserialize)]
+struct OverrideCommand {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "State")]
+    state: String,
+}
+
+/// Why the single poll of [`DucoMqttBridge::poll_once`] failed
+#[derive(Debug, thiserror::Error)]
+pub enum PollOnceError {
+    #[error("Failed to poll the duco box: {0}")]
+    Duco(Error),
+    #[error("Failed to publish on the MQTT broker: {0}")]
+    Mqtt(Error),
+}
 
 pub struct DucoMqttBridgeConfig {
     pub ducobox_host: String,
     pub ducobox_ip_address: Option<String>,
     pub ducobox_certificate: Option<PathBuf>,
+    pub ducobox_cert_pinning: bool,
+    pub ducobox_connect_timeout: time::Duration,
+    /// Maximum time to wait for the response of a request to the box
+    pub ducobox_read_timeout: time::Duration,
+    /// Idle connections to the box are closed after this period
+    pub ducobox_pool_idle_timeout: time::Duration,
+    /// Maximum number of idle connections kept open to the box, zero opens a new connection for every request
+    pub ducobox_pool_max_idle: usize,
+    /// Number of attempts of the requests to the box that fail on a transport error, 1 disables the retries
+    pub ducobox_retry_attempts: u32,
+    /// Delay before the first retry, doubled for every next retry
+    pub ducobox_retry_backoff: time::Duration,
+    /// Url of the http(s) proxy the box is reached through, the MQTT connection never uses a proxy
+    pub ducobox_proxy: Option<String>,
+    /// Api key of the public api of newer firmware
+    pub ducobox_api_key: Option<Secret>,
+    /// File with the api key, read again when the box rejects the key
+    pub ducobox_api_key_file: Option<PathBuf>,
+    /// Shell command that prints the api key, run again when the box rejects the key
+    pub ducobox_api_key_command: Option<String>,
+    /// Save the raw api responses of the box to this directory
+    pub duco_record_dir: Option<PathBuf>,
+    /// Feed the api responses recorded in this directory to the bridge instead of talking to the box
+    pub duco_replay_dir: Option<PathBuf>,
     pub mqtt_config: MqttConfig,
     pub hass_discovery: bool,
+    /// Publish a single discovery document per device instead of a document per entity
+    pub hass_device_discovery: bool,
+    /// Publish the values of a node as a single json document instead of a topic per value
+    pub json_state: bool,
+    /// Publish the time of the last poll that reported a changed value of a node (`duco_node_<nr>/last_seen`)
+    pub last_seen: bool,
+    /// Wait at most this long for the home assistant birth message before publishing the discovery documents
+    pub hass_discovery_delay: time::Duration,
+    /// Wait at most this long for the broker to acknowledge the discovery documents before publishing the states
+    /// (zero publishes the states right away)
+    pub hass_discovery_ack_timeout: time::Duration,
+    /// Compare the retained discovery documents with the generated ones at this interval (zero disables the check)
+    pub hass_drift_check_interval: time::Duration,
+    /// Republish the discovery documents that differ from the generated ones
+    pub hass_drift_repair: bool,
+    /// Overrides whether an entity is enabled by default, by unique id
+    pub hass_entity_defaults: BTreeMap<String, bool>,
     pub poll_interval: time::Duration,
+    /// A node that is missing from the duco network for this number of consecutive polls is considered gone
+    pub node_missing_polls: u32,
+    /// Keep the nodes that are gone with unknown values, instead of removing their entities and retained values
+    pub keep_missing_nodes: bool,
+    /// Request the actions of the nodes again at this interval, to pick up changed actions (zero disables it).
+    /// Nodes that join or leave the network are discovered on the next poll regardless.
+    pub rediscovery_interval: time::Duration,
+    /// Poll at this interval for the command poll window after a command, to report the new state quickly
+    pub command_poll_interval: time::Duration,
+    /// How long to keep polling at the command poll interval after a command (zero disables the fast polling)
+    pub command_poll_window: time::Duration,
+    pub log_redaction: LogRedaction,
+    pub log_sample_interval: u32,
+    pub update_check: bool,
+    /// Serve the health status of the bridge on `/healthz` on this port
+    pub health_port: Option<u16>,
+    /// The bridge is reported unhealthy when the last successful poll is older than this (zero disables the check)
+    pub health_max_poll_age: time::Duration,
+    pub state_file: Option<PathBuf>,
+    pub virtual_nodes_file: Option<PathBuf>,
+    pub compat: TopicCompat,
+    pub history_samples: usize,
+    pub poll_schedule: Vec<cron::Schedule>,
+    pub entity_naming: EntityNaming,
+    pub iaq_sensor_nodes: Vec<u16>,
+    /// Layout of the node topics (`{base}/<node level>/{group}/{key}`, see [`TopicScheme`])
+    pub topic_template: String,
+    /// Lowercase the node names and status keys in the node topics
+    pub topic_lowercase: bool,
+    /// The status keys of the nodes that are published
+    pub status_filter: StatusFilter,
+    /// Scales or decodes the raw values of the box before they are published
+    pub value_transform: ValueTransform,
 }
 
 pub struct DucoMqttBridge {
     mqtt: MqttConnection,
-    ducobox_host: String,
-    ducobox_ip_address: Option<SocketAddr>,
-    ducobox_certificate: Option<PathBuf>,
+    backend: Arc<dyn DucoBackend>,
+    commands: CommandQueue,
+    /// Performs the api requests of the polls in the background, so the MQTT connection stays responsive
+    poller: Poller,
+    /// A poll was requested while polling, it is started once the running poll completes
+    poll_queued: bool,
+    node_missing_polls: u32,
+    keep_missing_nodes: bool,
+    /// The number of consecutive polls the known nodes are missing from the duco network
+    missing_polls: HashMap<u16, u32>,
+    rediscovery_interval: time::Duration,
+    /// The next poll requests the actions of the nodes
+    rediscovery_due: bool,
+    /// The next poll requests the configuration parameters of the nodes
+    node_config_due: bool,
     poll_interval: time::Duration,
+    /// The box applies some commands only after a few seconds, it is polled more often for a while after a command
+    command_poll: BurstPoll,
+    /// The values of the commanded nodes requested after their commands, None when a command or the request failed
+    command_refresh: BTreeMap<u16, Option<NodeInfo>>,
+    /// The nodes with performed actions during the burst, the polls of the burst only request these nodes
+    commanded_nodes: BTreeSet<u16>,
+    /// A command of the box itself was performed during the burst, its outcome needs the poll of the whole network
+    commanded_box: bool,
+    node_refresh: NodeRefresh,
+    /// Values of command refreshes were merged since the last poll, the next poll merges the nodes also when
+    /// the box reports them unchanged
+    nodes_refreshed: bool,
     device_info: Option<DucoBoxDevice>,
     nodes: Vec<DucoBoxNode>,
     mqtt_base_topic: String,
     hass_discovery: bool,
+    hass_device_discovery: bool,
+    json_state: bool,
+    last_seen: bool,
+    hass_discovery_delay: time::Duration,
+    hass_discovery_ack_timeout: time::Duration,
+    /// The discovery documents are held back until home assistant is online or the discovery delay passed
+    discovery_held: bool,
+    discovery_pending: bool,
+    hass_drift_check_interval: time::Duration,
+    hass_drift_repair: bool,
+    hass_entity_defaults: BTreeMap<String, bool>,
+    drift_check: Option<DriftCheck>,
+    log_redaction: LogRedaction,
+    log_sampler: LogSampler,
+    update_check: bool,
+    health_port: Option<u16>,
+    health_max_poll_age: time::Duration,
+    /// The state reported by the health endpoint
+    health: tokio::sync::watch::Sender<HealthStatus>,
+    state: PersistentState,
+    virtual_nodes: Vec<VirtualNodeConfig>,
+    compat: TopicCompat,
+    low_power: bool,
+    history: SampleHistory,
+    published_attributes: HashMap<String, String>,
+    poll_schedule: Vec<cron::Schedule>,
+    /// Derives the topics of the nodes and the names of their home assistant entities
+    naming: Arc<dyn NamingStrategy>,
+    iaq_sensor_nodes: Vec<u16>,
+    status_filter: StatusFilter,
+    value_transform: ValueTransform,
+    /// The node numbers by lowercase node name (see [`naming::topic_name`]), to address the nodes by name in the
+    /// command topics. Names shared by multiple nodes map to None.
+    node_names: HashMap<String, Option<u16>>,
+    poll_cycle: u64,
+}
+
+/// Checks the configuration before the bridge is started
+pub fn validate_config(cfg: &DucoMqttBridgeConfig) -> ApiResult<()> {
+    cfg.mqtt_config.normalized_base_topic()?;
+    cfg.mqtt_config.brokers()?;
+    TopicScheme::new(&cfg.topic_template, cfg.topic_lowercase)?;
+
+    DucoEndpoint::new(&cfg.ducobox_host, cfg.ducobox_ip_address.as_deref())?;
+
+    if let Some(ref proxy) = cfg.ducobox_proxy {
+        reqwest::Proxy::all(proxy).map_err(|err| Error::Config(format!("Invalid duco proxy '{}': {}", proxy, err)))?;
+    }
+
+    if cfg.ducobox_connect_timeout.is_zero() || cfg.ducobox_read_timeout.is_zero() {
+        return Err(Error::Config("The duco timeouts must be at least 1 second".to_string()));
+    }
+
+    let api_key_sources = [
+        cfg.ducobox_api_key.is_some(),
+        cfg.ducobox_api_key_file.is_some(),
+        cfg.ducobox_api_key_command.is_some(),
+    ];
+    if api_key_sources.into_iter().filter(|&source| source).count() > 1 {
+        return Err(Error::Config(
+            "Pass one of the duco api key, the file with the key or the command that prints the key".to_string(),
+        ));
+    }
+
+    if let Some(ref api_key) = cfg.ducobox_api_key {
+        ducoapi::api_key_header(api_key).map_err(|err| Error::Config(err.to_string()))?;
+    }
+
+    if cfg.ducobox_retry_attempts == 0 {
+        return Err(Error::Config(
+            "The number of duco retry attempts must be at least 1".to_string(),
+        ));
+    }
+
+    if cfg.duco_record_dir.is_some() && cfg.duco_replay_dir.is_some() {
+        return Err(Error::Config(
+            "Recording and replaying the api responses can not be combined".to_string(),
+        ));
+    }
+
+    if !POLL_INTERVAL_RANGE.contains(&cfg.poll_interval) {
+        return Err(Error::Config(format!(
+            "Poll interval must be between {:?} and {:?}, got {:?}",
+            POLL_INTERVAL_RANGE.start(),
+            POLL_INTERVAL_RANGE.end(),
+            cfg.poll_interval
+        )));
+    }
+
+    validate_tls_config(cfg)
+}
+
+/// Where the api key of the box comes from, None when the box does not require a key
+pub fn api_key_source(cfg: &DucoMqttBridgeConfig) -> Option<ApiKeySource> {
+    match (
+        &cfg.ducobox_api_key,
+        &cfg.ducobox_api_key_file,
+        &cfg.ducobox_api_key_command,
+    ) {
+        (Some(api_key), _, _) => Some(ApiKeySource::Key(api_key.clone())),
+        (None, Some(path), _) => Some(ApiKeySource::File(path.clone())),
+        (None, None, Some(command)) => Some(ApiKeySource::Command(command.clone())),
+        (None, None, None) => None,
+    }
+}
+
+/// Obtains a new api key with the api key command and checks that the box accepts it, to renew the key file of a
+/// running bridge
+pub async fn renew_api_key(mut cfg: DucoMqttBridgeConfig) -> ApiResult<Secret> {
+    let command = cfg
+        .ducobox_api_key_command
+        .take()
+        .ok_or_else(|| Error::Config("Renewing the duco api key requires the api key command".to_string()))?;
+    let api_key = ApiKeySource::Command(command).read()?;
+    cfg.ducobox_api_key = Some(api_key.clone());
+    cfg.ducobox_api_key_file = None;
+
+    DucoMqttBridge::new(cfg)?.backend.get_device_info().await?;
+    Ok(api_key)
+}
+
+/// Checks the TLS related options, the only TLS stack is rustls
+fn validate_tls_config(cfg: &DucoMqttBridgeConfig) -> ApiResult<()> {
+    match cfg.ducobox_certificate {
+        Some(ref cert) => Ok(certpinning::validate_certificate(cert)?),
+        None if cfg.ducobox_cert_pinning => Err(Error::Config(
+            "--duco-cert-pinning requires a certificate (--certificate)".to_string(),
+        )),
+        None => Ok(()),
+    }
 }
 
 impl DucoMqttBridge {
-    pub fn new(cfg: DucoMqttBridgeConfig) -> DucoMqttBridge {
-        let mqtt_base_topic = format!("{}/", cfg.mqtt_config.base_topic);
+    /// Validates the configuration and creates the bridge
+    pub fn new(cfg: DucoMqttBridgeConfig) -> ApiResult<DucoMqttBridge> {
+        validate_config(&cfg)?;
+
+        if let Some(ref dir) = cfg.duco_replay_dir {
+            tracing::info!("Replaying the duco api responses recorded in {}", dir.display());
+            let backend = Box::new(ReplayBackend::new(Recording::load(dir)?));
+            return DucoMqttBridge::with_backend(cfg, backend);
+        }
+
         if cfg.ducobox_certificate.is_none() {
-            log::warn!("No certificate provided, disabling certificate validation");
+            tracing::warn!("No certificate provided, disabling certificate validation");
+        } else if cfg.ducobox_cert_pinning {
+            tracing::info!("Certificate pinning enabled, the certificate validity period is not verified");
         }
 
-        let ip_addr = cfg
-            .ducobox_ip_address
-            .map(|ip| format!("{}:443", ip).parse().expect("Invalid ip address"));
+        let endpoint = DucoEndpoint::new(&cfg.ducobox_host, cfg.ducobox_ip_address.as_deref())?;
+        let mut backend = HttpBackend::new(
+            &endpoint,
+            cfg.ducobox_certificate.clone(),
+            cfg.ducobox_cert_pinning,
+            ConnectionConfig {
+                connect_timeout: cfg.ducobox_connect_timeout,
+                read_timeout: cfg.ducobox_read_timeout,
+                idle_timeout: cfg.ducobox_pool_idle_timeout,
+                max_idle: cfg.ducobox_pool_max_idle,
+            },
+            RetryPolicy {
+                attempts: cfg.ducobox_retry_attempts,
+                backoff: cfg.ducobox_retry_backoff,
+            },
+            cfg.duco_record_dir.as_deref().map(Recorder::new).transpose()?,
+            cfg.ducobox_proxy.as_deref(),
+        )?;
+
+        if let Some(api_key) = api_key_source(&cfg) {
+            backend = backend.with_api_key(api_key)?;
+        }
+
+        DucoMqttBridge::with_backend(cfg, Box::new(backend))
+    }
+
+    fn with_backend(mut cfg: DucoMqttBridgeConfig, backend: Box<dyn DucoBackend>) -> ApiResult<DucoMqttBridge> {
+        cfg.mqtt_config.normalize()?;
+        let mqtt_base_topic = format!("{}/", cfg.mqtt_config.base_topic);
+        let low_power = cfg.mqtt_config.low_power;
+        let naming = cfg
+            .entity_naming
+            .strategy(TopicScheme::new(&cfg.topic_template, cfg.topic_lowercase)?);
+
+        let state = PersistentState::load(cfg.state_file).unwrap_or_else(|err| {
+            tracing::error!(
+                "Failed to load the bridge state, starting with a clean state: {:#}",
+                err
+            );
+            PersistentState::default()
+        });
+
+        let virtual_nodes = match cfg.virtual_nodes_file {
+            Some(path) => virtualnode::load_virtual_nodes(&path).unwrap_or_else(|err| {
+                tracing::error!("Failed to load the virtual nodes: {:#}", err);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
 
-        DucoMqttBridge {
-            mqtt: MqttConnection::new(cfg.mqtt_config),
-            ducobox_host: cfg.ducobox_host,
-            ducobox_ip_address: ip_addr,
-            ducobox_certificate: cfg.ducobox_certificate,
-            poll_interval: cfg.poll_interval,
+        let backend: Arc<dyn DucoBackend> = Arc::from(backend);
+
+        let mut mqtt = MqttConnection::new(cfg.mqtt_config)?;
+        if cfg.hass_discovery {
+            mqtt.subscribe_on_connect(hassdiscovery::HASS_STATUS_TOPIC);
+        }
+
+        Ok(DucoMqttBridge {
+            mqtt,
+            commands: CommandQueue::new(backend.clone()),
+            poller: Poller::new(backend.clone()),
+            node_refresh: NodeRefresh::new(backend.clone()),
+            poll_queued: false,
+            node_missing_polls: cfg.node_missing_polls.max(1),
+            keep_missing_nodes: cfg.keep_missing_nodes,
+            missing_polls: HashMap::new(),
+            rediscovery_interval: cfg.rediscovery_interval,
+            rediscovery_due: false,
+            node_config_due: false,
+            backend,
+            poll_interval: if low_power {
+                cfg.poll_interval.max(LOW_POWER_MIN_POLL_INTERVAL)
+            } else {
+                cfg.poll_interval
+            },
+            command_poll: if low_power {
+                BurstPoll::new(cfg.command_poll_interval, time::Duration::ZERO)
+            } else {
+                BurstPoll::new(cfg.command_poll_interval, cfg.command_poll_window)
+            },
+            command_refresh: BTreeMap::new(),
+            commanded_nodes: BTreeSet::new(),
+            commanded_box: false,
+            nodes_refreshed: false,
             device_info: None,
             nodes: Vec::new(),
             mqtt_base_topic,
             hass_discovery: cfg.hass_discovery,
-        }
+            hass_device_discovery: cfg.hass_device_discovery,
+            // the values of a node are batched in a single publish
+            json_state: cfg.json_state || low_power,
+            last_seen: cfg.last_seen,
+            hass_discovery_delay: cfg.hass_discovery_delay,
+            hass_discovery_ack_timeout: cfg.hass_discovery_ack_timeout,
+            discovery_held: cfg.hass_discovery && !cfg.hass_discovery_delay.is_zero(),
+            discovery_pending: false,
+            hass_drift_check_interval: cfg.hass_drift_check_interval,
+            hass_drift_repair: cfg.hass_drift_repair,
+            hass_entity_defaults: cfg.hass_entity_defaults,
+            drift_check: None,
+            log_redaction: cfg.log_redaction,
+            log_sampler: LogSampler::new(cfg.log_sample_interval),
+            update_check: cfg.update_check,
+            health_port: cfg.health_port,
+            health_max_poll_age: cfg.health_max_poll_age,
+            health: tokio::sync::watch::Sender::new(HealthStatus::default()),
+            state,
+            virtual_nodes,
+            compat: cfg.compat,
+            low_power,
+            history: SampleHistory::new(cfg.history_samples),
+            published_attributes: HashMap::new(),
+            poll_schedule: cfg.poll_schedule,
+            naming,
+            iaq_sensor_nodes: cfg.iaq_sensor_nodes,
+            status_filter: cfg.status_filter,
+            value_transform: cfg.value_transform,
+            node_names: HashMap::new(),
+            poll_cycle: 0,
+        })
     }
 
-    pub async fn run(mut self) -> Result<()> {
-        let mut interval = time::interval(self.poll_interval);
-        log::debug!("Poll interval: {interval:?}");
+    pub async fn run(mut self) -> ApiResult<()> {
+        if self.poll_schedule.is_empty() {
+            tracing::debug!("Poll interval: {:?}", self.poll_interval);
+        } else {
+            for schedule in &self.poll_schedule {
+                tracing::info!("Poll schedule: {}", schedule);
+            }
+        }
+        let mut poll_schedule = PollSchedule::new(self.poll_interval, std::mem::take(&mut self.poll_schedule));
+        tracing::info!("Topic layout version: {}", self.compat);
+        let mut update_check_interval = time::interval(UPDATE_CHECK_INTERVAL);
+        self.start_health_server().await?;
+
+        if let Err(err) = self.publish_away_mode().await {
+            tracing::error!("Failed to publish away mode: {:#}", err);
+        }
+
+        let discovery_delay = time::sleep(self.hass_discovery_delay);
+        tokio::pin!(discovery_delay);
+        let drift_check_enabled = self.hass_discovery && !self.hass_drift_check_interval.is_zero();
+        let mut drift_check_interval = time::interval_at(
+            time::Instant::now() + self.hass_drift_check_interval,
+            self.hass_drift_check_interval.max(time::Duration::from_secs(1)),
+        );
+        let rediscovery_enabled = !self.rediscovery_interval.is_zero();
+        let mut rediscovery_interval = time::interval_at(
+            time::Instant::now() + self.rediscovery_interval,
+            self.rediscovery_interval.max(time::Duration::from_secs(1)),
+        );
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let mut shutdown_signal_failed = false;
 
         loop {
+            let drift_check_deadline = self.drift_check.as_ref().map(DriftCheck::deadline);
+            let command_poll_deadline = self.command_poll.deadline();
+            let mqtt_connected = self.mqtt.is_connected();
+            self.health.send_if_modified(|health| {
+                std::mem::replace(&mut health.mqtt_connected, mqtt_connected) != mqtt_connected
+            });
+
             tokio::select! {
+                result = &mut shutdown, if !shutdown_signal_failed => {
+                    match result {
+                        Ok(()) => {
+                            tracing::info!("Shutting down");
+                            if let Err(err) = self.mqtt.disconnect(SHUTDOWN_TIMEOUT).await {
+                                tracing::warn!("Failed to disconnect from the MQTT broker: {:#}", err);
+                            }
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            tracing::error!("Failed to listen for the shutdown signals: {}", err);
+                            shutdown_signal_failed = true;
+                        }
+                    }
+                }
                 mqtt_msg = self.mqtt.poll() => {
-                    if let Ok(Some(msg)) = mqtt_msg {
-                        log::info!("MQTT cmnd: {} {}", msg.topic, msg.payload);
-                        if let Err(err) = self.handle_node_command(msg).await {
-                            log::error!("Failed to process command: {:#}", err);
+                    match mqtt_msg {
+                        Ok(Some(msg)) if self.drift_check.as_mut().is_some_and(|check| check.handle(&msg)) => {}
+                        Ok(Some(msg)) if msg.topic == hassdiscovery::HASS_STATUS_TOPIC => {
+                            if let Err(err) = self.handle_hass_status(&msg.payload).await {
+                                tracing::error!("Failed to publish the discovery documents: {:#}", err);
+                            }
                         }
+                        Ok(None) if self.mqtt.take_broker_switched() => {
+                            if let Err(err) = self.republish().await {
+                                tracing::error!("Failed to publish the topics on the MQTT broker: {:#}", err);
+                            }
+                        }
+                        Ok(Some(msg)) => {
+                            let span = tracing::info_span!("command", topic = %msg.topic);
+                            span.in_scope(|| tracing::info!(payload = %msg.payload, "MQTT cmnd"));
+                            let topic = msg.topic.clone();
+                            match self.handle_command(msg).instrument(span.clone()).await {
+                                Ok(()) => self.command_poll.start(),
+                                Err(err) => {
+                                    span.in_scope(|| tracing::error!("Failed to process command: {:#}", err));
+                                    self.report_error(ErrorEvent::new(
+                                        ErrorCategory::Command,
+                                        &err,
+                                        serde_json::json!({ "topic": topic }),
+                                    ))
+                                    .await;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ = &mut discovery_delay, if self.discovery_held => {
+                    tracing::info!("No home assistant birth message received, publishing the discovery documents");
+                    if let Err(err) = self.release_discovery().await {
+                        tracing::error!("Failed to publish the discovery documents: {:#}", err);
+                    }
+                }
+                _ = drift_check_interval.tick(), if drift_check_enabled => {
+                    if let Err(err) = self.start_drift_check().await {
+                        tracing::error!("Failed to check the discovery documents: {:#}", err);
+                    }
+                }
+                _ = time::sleep_until(drift_check_deadline.unwrap_or_else(time::Instant::now)), if drift_check_deadline.is_some() => {
+                    if let Err(err) = self.finish_drift_check().await {
+                        tracing::error!("Failed to check the discovery documents: {:#}", err);
+                    }
+                }
+                (command, result) = self.commands.completed() => {
+                    self.command_completed(command, result).await;
+                }
+                _ = time::sleep_until(command_poll_deadline.unwrap_or_else(time::Instant::now)), if command_poll_deadline.is_some() => {
+                    self.command_poll.polled();
+                    self.poll_commanded();
+                }
+                result = self.node_refresh.completed() => {
+                    if let Err(err) = self.apply_node_refresh(result).await {
+                        tracing::error!("Failed to publish the values of the commanded nodes: {:#}", err);
                     }
                 }
-                _ = interval.tick() => {
-                    log::debug!("Polling ducobox for updates");
-                    let client = self.http_client()?;
-                    log::debug!("Client obtained: {client:?}");
-                    if let Err(err) = self.poll_ducobox(&client).await {
-                        log::error!("Failed to update duco status: {:#}", err);
+                _ = poll_schedule.tick() => {
+                    tracing::debug!("Polling ducobox for updates");
+                    self.request_poll();
+                }
+                (cycle, response) = self.poller.completed() => {
+                    let result = match response {
+                        Ok(response) => self.update_ducobox(response).instrument(tracing::info_span!("poll", cycle)).await,
+                        Err(err) => Err(err),
+                    };
+
+                    self.record_poll(result.is_ok());
+                    if let Err(err) = result {
+                        tracing::error!(cycle, "Failed to update duco status: {:#}", err);
+                        self.report_error(ErrorEvent::poll(&err, cycle)).await;
+                        if certpinning::is_clock_skew_error(&err) {
+                            tracing::error!("{}", certpinning::CLOCK_SKEW_GUIDANCE);
+                        }
                         self.reset_status();
                         let _ = self.mqtt.publish_offline().await;
+                        let _ = self.publish_device_availability(false).await;
                     } else {
                         let _ = self.mqtt.publish_online().await;
+                        let _ = self.publish_device_availability(true).await;
+                    }
+
+                    if std::mem::take(&mut self.poll_queued) {
+                        self.request_poll();
+                    }
+                }
+                _ = rediscovery_interval.tick(), if rediscovery_enabled => {
+                    tracing::debug!("Rediscovering the nodes on the next poll");
+                    self.rediscovery_due = true;
+                }
+                _ = update_check_interval.tick(), if self.update_check => {
+                    if let Err(err) = self.check_for_update().await {
+                        tracing::warn!("Failed to check for updates: {:#}", err);
                     }
                 }
             }
         }
     }
 
-    async fn discover_nodes(ducobox_address: &str, client: &reqwest::Client) -> Result<Vec<DucoBoxNode>> {
-        let nodes = ducoapi::get_nodes(client, ducobox_address).await?;
-        let node_actions = ducoapi::get_node_actions(client, ducobox_address).await?;
+    /// Polls the box once, publishes the values (and the discovery documents) and disconnects, for cron driven
+    /// setups. The bridge stays online after a successful poll, the values remain retained until the next run.
+    pub async fn poll_once(mut self, connect_timeout: time::Duration) -> std::result::Result<(), PollOnceError> {
+        // there is no time to wait for the home assistant birth message
+        self.discovery_held = false;
+        self.connect_mqtt(connect_timeout).await.map_err(PollOnceError::Mqtt)?;
+
+        let polled = self.poll_ducobox().await;
+        if let Err(err) = &polled {
+            self.report_error(ErrorEvent::poll(err, self.poll_cycle)).await;
+        }
+
+        let online = polled.is_ok();
+        let published: Result<bool> = async {
+            if online {
+                self.mqtt.publish_online().await?;
+            } else {
+                self.mqtt.publish_offline().await?;
+            }
+            self.publish_device_availability(online).await?;
+            self.mqtt.close(SHUTDOWN_TIMEOUT).await
+        }
+        .await;
+
+        polled.map_err(|err| PollOnceError::Duco(err.into()))?;
+        match published {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(PollOnceError::Mqtt(Error::Runtime(
+                "The broker did not acknowledge the publishes".to_string(),
+            ))),
+            Err(err) => Err(PollOnceError::Mqtt(err.into())),
+        }
+    }
+
+    /// Connects to the broker and disconnects cleanly without publishing, to verify the address and the credentials
+    pub async fn check_mqtt_connection(&mut self, timeout: time::Duration) -> ApiResult<()> {
+        self.connect_mqtt(timeout).await?;
+        self.mqtt.close(SHUTDOWN_TIMEOUT).await?;
+        Ok(())
+    }
+
+    async fn connect_mqtt(&mut self, timeout: time::Duration) -> ApiResult<()> {
+        if self.mqtt.wait_for_connection(timeout).await? {
+            return Ok(());
+        }
+
+        Err(Error::Runtime(match self.mqtt.connect_error() {
+            Some(err) => format!("Not connected to the MQTT broker within {:?}: {}", timeout, err),
+            None => format!("Not connected to the MQTT broker within {:?}", timeout),
+        }))
+    }
+
+    /// Listens on the health port, failing to bind the port is a startup error so the misconfiguration is noticed
+    async fn start_health_server(&self) -> ApiResult<()> {
+        let Some(port) = self.health_port else {
+            return Ok(());
+        };
+
+        let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, port)).await?;
+        tracing::info!("Serving the health status on port {} (/healthz)", port);
+        tokio::spawn(health::serve(
+            listener,
+            self.health.subscribe(),
+            self.health_max_poll_age,
+        ));
+        Ok(())
+    }
+
+    fn record_poll(&self, ok: bool) {
+        let now = chrono::Utc::now();
+        self.health.send_modify(|health| {
+            health.last_poll = Some(now);
+            health.last_poll_ok = ok;
+            if ok {
+                health.last_successful_poll = Some(now);
+            }
+        });
+    }
+
+    /// Publishes the error on the error topic, failing to do so is only logged
+    async fn report_error(&mut self, event: ErrorEvent) {
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::warn!("Failed to serialize the error event: {}", err);
+                return;
+            }
+        };
+
+        let topic = format!("{}bridge/errors", self.mqtt_base_topic);
+        if let Err(err) = self.mqtt.publish_event(MqttData::new(topic, payload)).await {
+            tracing::warn!("Failed to publish the error event: {:#}", err);
+        }
+    }
+
+    /// Applies the outcome of a queued command, the values of the commanded nodes are verified once all queued
+    /// commands are performed
+    async fn command_completed(&mut self, command: Command, result: Result<Option<NodeInfo>>) {
+        let refreshed = match result {
+            Ok(refreshed) => {
+                if let Err(err) = self.command_performed(&command).await {
+                    tracing::error!("Failed to apply the performed command: {:#}", err);
+                }
+                refreshed
+            }
+            Err(err) => {
+                tracing::error!(node = command.node(), "Failed to perform command: {:#}", err);
+                self.report_error(ErrorEvent::new(
+                    ErrorCategory::Command,
+                    &err,
+                    serde_json::json!({ "node": command.node() }),
+                ))
+                .await;
+                None
+            }
+        };
+        match command {
+            Command::NodeAction(node_nr, _) => {
+                // a failed command of the node needs the poll, a later command that succeeded does not cancel it
+                if !matches!(self.command_refresh.get(&node_nr), Some(None)) {
+                    self.command_refresh.insert(node_nr, refreshed);
+                }
+                self.commanded_nodes.insert(node_nr);
+            }
+            Command::BoxAction(_) | Command::BoxConfig(_, _) => self.commanded_box = true,
+            Command::NodeConfig(_, _, _) => {}
+        }
+
+        if self.commands.is_idle() {
+            self.command_poll.start();
+            if !self.command_refresh.is_empty()
+                && let Err(err) = self.apply_command_refresh().await
+            {
+                tracing::error!("Failed to publish the values of the commanded nodes: {:#}", err);
+            }
+        }
+    }
+
+    /// Applies a performed command to the values of the bridge, the outcome of the box commands is obtained by polling
+    async fn command_performed(&mut self, command: &Command) -> Result<()> {
+        match command {
+            Command::NodeAction(_, _) => {}
+            Command::NodeConfig(node_nr, parameter, val) => {
+                tracing::info!(
+                    node = node_nr,
+                    "Configuration parameter '{}' changed to {}",
+                    parameter,
+                    val
+                );
+                self.node_with_number(*node_nr)?.set_config_value(parameter, *val);
+                self.publish_nodes().await?;
+            }
+            Command::BoxAction(action) if action.name() == REBOOT_ACTION => {
+                tracing::warn!("Rebooting the duco box, the polls fail until it is back online");
+            }
+            Command::BoxAction(action) => {
+                tracing::info!("Box action '{}' performed", action.name());
+                self.request_poll();
+            }
+            Command::BoxConfig(key, val) => {
+                tracing::info!("Box configuration '{}' changed to {}", key, val);
+                self.request_poll();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the values of the nodes that were requested after their commands were performed, the whole network is
+    /// polled when a command failed or the values of a node could not be requested
+    async fn apply_command_refresh(&mut self) -> Result<()> {
+        let refreshed: Option<Vec<NodeInfo>> = std::mem::take(&mut self.command_refresh).into_values().collect();
+        match refreshed {
+            Some(node_infos) => self.publish_refreshed_nodes(node_infos).await,
+            None => {
+                self.request_poll();
+                Ok(())
+            }
+        }
+    }
+
+    /// A poll of the burst after the commands, the commanded nodes are requested on their own. The whole network is
+    /// only polled for the outcome of the commands of the box itself.
+    fn poll_commanded(&mut self) {
+        if self.commanded_box {
+            tracing::debug!("Polling ducobox for the outcome of the command");
+            self.request_poll();
+        } else if !self.commanded_nodes.is_empty() {
+            tracing::debug!("Requesting the commanded nodes for the outcome of the command");
+            self.node_refresh.start(self.commanded_nodes.iter().copied().collect());
+        }
+
+        if self.command_poll.deadline().is_none() {
+            self.commanded_nodes.clear();
+            self.commanded_box = false;
+        }
+    }
+
+    /// Publishes the values of the commanded nodes requested by a poll of the burst, a failed request is covered by the
+    /// next poll
+    async fn apply_node_refresh(&mut self, result: Result<Vec<NodeInfo>>) -> Result<()> {
+        match result {
+            Ok(node_infos) => self.publish_refreshed_nodes(node_infos).await,
+            Err(err) => {
+                tracing::debug!("Failed to request the commanded nodes: {:#}", err);
+                Ok(())
+            }
+        }
+    }
+
+    async fn publish_refreshed_nodes(&mut self, mut node_infos: Vec<NodeInfo>) -> Result<()> {
+        node_infos.retain(|info| self.nodes.iter().any(|node| node.number() == info.node));
+        self.merge_nodes(node_infos)?;
+        self.nodes_refreshed = true;
+        self.update_virtual_nodes();
+        let topic_count = self.publish_nodes().await?;
+        tracing::debug!(topics = topic_count, "Commanded nodes refreshed");
+
+        Ok(())
+    }
 
+    fn discover_nodes(nodes: Vec<NodeInfo>, node_actions: Vec<NodeActions>) -> Result<Vec<DucoBoxNode>> {
         ensure!(
             nodes.len() == node_actions.len(),
             "Node and action count mismatch ({} <-> {})",
@@ -97,7 +874,7 @@ impl DucoMqttBridge {
 
         let nodes = nodes
             .into_iter()
-            .zip(node_actions.into_iter())
+            .zip(node_actions)
             .map(|(node_info, actions)| {
                 ensure!(
                     node_info.node == actions.node,
@@ -116,249 +893,3519 @@ impl DucoMqttBridge {
         Ok(nodes)
     }
 
-    async fn poll_ducobox(&mut self, client: &reqwest::Client) -> Result<()> {
-        log::debug!("Update ducobox values");
+    /// Polls the box and applies the responses right away, the run loop polls in the background (see [`Poller`])
+    async fn poll_ducobox(&mut self) -> Result<()> {
+        self.poll_cycle += 1;
+        let span = tracing::info_span!("poll", cycle = self.poll_cycle);
+        async {
+            tracing::debug!("Update ducobox values");
+            let request = self.poll_request();
+            let response = poller::fetch(self.backend.as_ref(), &request).await?;
+            self.update_ducobox(response).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Starts a poll in the background, the responses are applied by the run loop. The api requests of the poll cycle
+    /// are traced in a span with the poll cycle.
+    fn request_poll(&mut self) {
+        if self.poller.is_running() {
+            self.poll_queued = true;
+            return;
+        }
+
+        self.poll_cycle += 1;
+        tracing::debug!(cycle = self.poll_cycle, "Update ducobox values");
+        let request = self.poll_request();
+        self.poller.start(self.poll_cycle, request);
+    }
+
+    /// The requests of the next poll, the actions and the configuration are requested when the nodes are discovered.
+    /// The configuration is also refreshed every [`NODE_CONFIG_REFRESH_POLLS`] polls.
+    fn poll_request(&mut self) -> PollRequest {
+        // without known nodes the poll requests the node actions
+        let known_nodes = if std::mem::take(&mut self.rediscovery_due) {
+            None
+        } else {
+            self.known_nodes()
+        };
+        let discovery = known_nodes.is_none();
+        if self.poll_cycle.is_multiple_of(NODE_CONFIG_REFRESH_POLLS)
+            && self.nodes.iter().any(DucoBoxNode::has_config_parameters)
+        {
+            self.node_config_due = true;
+        }
+        let node_config_due = std::mem::take(&mut self.node_config_due);
+
+        PollRequest {
+            known_nodes,
+            // the configuration values are not part of topic layout version 1
+            node_config: self.compat != TopicCompat::V1 && (discovery || node_config_due),
+            box_actions: discovery,
+            // the ranges of the supply temperature targets, also for the commands of topic layout version 1
+            box_config: discovery,
+        }
+    }
+
+    /// The numbers of the nodes of the duco network, None when the nodes are not discovered yet
+    fn known_nodes(&self) -> Option<Vec<u16>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
 
-        let dev_info = ducoapi::get_device_info(client, &self.ducobox_host).await?;
+        Some(
+            self.nodes
+                .iter()
+                .filter(|node| !matches!(node.node_type(), NodeType::Virtual))
+                .map(|node| node.number())
+                .collect(),
+        )
+    }
+
+    async fn update_ducobox(&mut self, response: PollResponse) -> Result<()> {
+        let dev_info = response.device_info;
 
+        let mut discovered = false;
         match self.device_info {
             Some(ref mut device) => {
                 device.update_status(dev_info);
             }
             None => {
-                if self.hass_discovery {
-                    if let Ok(mqtt_data) = DucoMqttBridge::create_hass_descriptions_for_device(&self.mqtt_base_topic) {
-                        self.mqtt.publish_multiple(mqtt_data).await?;
-                    }
-                }
                 self.device_info = Some(DucoBoxDevice::try_from(dev_info)?);
+                discovered = true;
             }
         }
 
-        if self.nodes.is_empty() {
-            self.nodes = DucoMqttBridge::discover_nodes(&self.ducobox_host, client).await?;
+        if let (Some(device), Some(box_actions)) = (self.device_info.as_mut(), response.box_actions) {
+            match box_actions {
+                Ok(box_actions) => device.set_actions(box_actions),
+                Err(err) => tracing::debug!("Failed to obtain the box actions: {:#}", err),
+            }
+        }
+        if let (Some(device), Some(box_config)) = (self.device_info.as_mut(), response.box_config) {
+            match box_config {
+                Ok(box_config) => discovered |= device.set_config(box_config),
+                Err(err) => tracing::debug!("Failed to obtain the box configuration: {:#}", err),
+            }
+        }
 
-            if self.hass_discovery {
-                for node in &self.nodes {
-                    match DucoMqttBridge::create_hass_descriptions_for_node(node, &self.mqtt_base_topic) {
-                        Ok(mqtt_data) => {
-                            self.mqtt.publish_multiple(mqtt_data).await?;
-                        }
-                        Err(err) => {
-                            log::error!("Failed to create home assistant descriptions: {:#}", err);
-                        }
-                    }
-                }
+        let reported: Vec<u16> = response.nodes.iter().map(|info| info.node).collect();
+        let nodes_unchanged = response.nodes_unchanged && !std::mem::take(&mut self.nodes_refreshed);
+        let nodes_discovered = self
+            .update_nodes(response.nodes, response.node_actions, nodes_unchanged)
+            .await?;
+        // unchanged nodes only have new values when their configuration was requested
+        let publish_nodes = !nodes_unchanged || nodes_discovered || response.node_config.is_some();
+        match response.node_config {
+            Some(node_config) => discovered |= self.update_node_config(node_config),
+            // the configuration of the nodes that joined is requested by the next poll
+            None => {
+                self.node_config_due = nodes_discovered && self.nodes.iter().any(DucoBoxNode::has_config_parameters)
             }
-        } else {
-            self.merge_nodes(ducoapi::get_nodes(client, &self.ducobox_host).await?)?;
+        }
+        if nodes_discovered {
+            self.publish_action_options().await?;
+            discovered = true;
+        }
+
+        if discovered {
+            self.publish_discovery().await?;
         }
 
-        self.publish_device_info().await?;
-        self.publish_nodes().await?;
+        let mut topic_count = self.publish_device_info().await?;
+        if publish_nodes {
+            topic_count += self.publish_nodes().await?;
+        }
+        self.publish_last_seen(&reported).await?;
+        self.publish_history().await?;
+        self.publish_connection_reuse().await?;
+        tracing::info!(
+            topics = topic_count,
+            duration_ms = response.started.elapsed().as_millis() as u64,
+            "Poll completed"
+        );
 
         Ok(())
     }
 
-    fn node_with_number(&mut self, nr: u16) -> Result<&mut DucoBoxNode> {
-        if let Some(node) = self.nodes.iter_mut().find(|x| x.number() == nr) {
-            Ok(node)
-        } else {
-            Err(anyhow!("No node with id '{nr}'"))
+    /// Publishes the time of the last poll that reported a changed value of the nodes the box reported
+    async fn publish_last_seen(&mut self, reported: &[u16]) -> Result<()> {
+        if !self.last_seen {
+            return Ok(());
         }
+
+        let topics: Vec<MqttData> = self
+            .nodes
+            .iter()
+            .filter(|node| reported.contains(&node.number()))
+            .filter_map(|node| {
+                let last_seen = node.last_seen()?;
+                Some(MqttData::new(
+                    format!("{}{}", self.mqtt_base_topic, node.status_topic(LAST_SEEN)),
+                    last_seen.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                ))
+            })
+            .collect();
+        self.mqtt.publish_multiple(topics).await
     }
 
-    fn node_number_for_node_name(topic: &str) -> Result<u16> {
-        let parts: Vec<&str> = topic.split('_').collect();
-        if parts.len() == 3 && parts[0] == "duco" && parts[1] == "node" {
-            return parts[2]
-                .parse()
-                .map_err(|_| anyhow!("Invalid node number '{}'", parts[2]));
+    /// Reports how many of the requests to the box were sent over an existing connection
+    async fn publish_connection_reuse(&mut self) -> Result<()> {
+        let Some(reuse) = self.backend.connection_reuse() else {
+            return Ok(());
+        };
+
+        tracing::debug!(
+            "Duco connections: {} opened for {} requests (reuse rate {:.2})",
+            reuse.connections,
+            reuse.requests,
+            reuse.reuse_rate
+        );
+
+        if !self.low_power {
+            self.mqtt
+                .publish(MqttData {
+                    topic: format!("{}bridge/duco_connections", self.mqtt_base_topic),
+                    payload: serde_json::to_string(&reuse)?,
+                })
+                .await?;
         }
 
-        Err(anyhow!("Invalid node topic provided: {}", topic))
+        Ok(())
     }
 
-    fn node_and_action_from_topic(topic: &str) -> Result<(u16, String)> {
-        let topics: Vec<&str> = topic.split('/').collect();
-        if topics.len() == 3 && topics[1] == "cmnd" {
-            let node = DucoMqttBridge::node_number_for_node_name(topics[0])?;
-            let action = String::from(topics[2]);
-
-            return Ok((node, action));
+    /// Updates the nodes with the current state of the box, returns true when the nodes or their actions were
+    /// (re)discovered. The node actions are needed to discover the nodes, when the poll did not include them the nodes
+    /// are discovered by the next poll. Unchanged nodes are not merged again.
+    async fn update_nodes(
+        &mut self,
+        node_infos: Vec<NodeInfo>,
+        node_actions: Option<Vec<NodeActions>>,
+        unchanged: bool,
+    ) -> Result<bool> {
+        if !self.nodes.is_empty() && !self.nodes_joined(&node_infos) {
+            let missing = self.update_missing_nodes(&node_infos);
+            if !unchanged {
+                self.merge_nodes(node_infos)?;
+                self.update_virtual_nodes();
+            }
+            let mut discovered = match node_actions {
+                Some(node_actions) => self.update_node_actions(node_actions)?,
+                None => false,
+            };
+            if !missing.is_empty() {
+                discovered |= self.handle_missing_nodes(&missing).await?;
+            }
+            return Ok(discovered);
         }
 
-        Err(anyhow!("Invalid node topic provided: {} ({:?})", topic, topics))
-    }
+        let Some(node_actions) = node_actions else {
+            // the nodes changed while the poll was running
+            tracing::debug!("The nodes of the duco network changed, rediscovering the nodes on the next poll");
+            self.rediscovery_due = true;
+            return Ok(false);
+        };
+        let nodes = DucoMqttBridge::discover_nodes(node_infos, node_actions)?;
+        self.missing_polls.clear();
 
-    async fn handle_node_command(&mut self, msg: MqttData) -> Result<()> {
-        if let Some(path) = msg.topic.strip_prefix(self.mqtt_base_topic.as_str()) {
-            let (node_nr, action_name) = DucoMqttBridge::node_and_action_from_topic(path)?;
+        if self.nodes.is_empty() {
+            self.nodes = nodes;
+            self.finish_node_discovery();
+            return Ok(true);
+        }
 
-            let addr = self.ducobox_host.clone();
-            let client = self.http_client()?;
-            let node = self.node_with_number(node_nr)?;
+        // the nodes that are missing are removed right away, e.g. the installer re-paired the components
+        tracing::info!("The nodes of the duco network changed, rediscovering the nodes");
+        let previous = std::mem::replace(&mut self.nodes, nodes);
+        self.finish_node_discovery();
+        self.migrate_node_numbers(&previous).await?;
+        self.clear_removed_nodes(&previous).await?;
+        Ok(true)
+    }
 
-            node.process_command(action_name, msg.payload, &client, &addr).await?;
+    /// Counts the consecutive polls the known nodes are missing from the duco network, returns the nodes that are
+    /// missing for the configured number of polls
+    fn update_missing_nodes(&mut self, node_infos: &[NodeInfo]) -> Vec<u16> {
+        let mut missing = Vec::new();
+        for nr in self.known_nodes().unwrap_or_default() {
+            if node_infos.iter().any(|info| info.node == nr) {
+                if self
+                    .missing_polls
+                    .remove(&nr)
+                    .is_some_and(|polls| polls >= self.node_missing_polls)
+                {
+                    tracing::info!(node = nr, "Node is back in the duco network");
+                }
+                continue;
+            }
 
-            self.poll_ducobox(&client).await?;
-            return Ok(());
+            let polls = self.missing_polls.entry(nr).or_default();
+            *polls = polls.saturating_add(1);
+            if *polls == self.node_missing_polls {
+                missing.push(nr);
+            } else if *polls < self.node_missing_polls {
+                tracing::debug!(
+                    "Node {} is missing from the duco network ({}/{} polls)",
+                    nr,
+                    polls,
+                    self.node_missing_polls
+                );
+            }
         }
 
-        Err(anyhow!("Unexpected command path: {}", msg.topic))
+        missing
     }
 
-    fn merge_nodes(&mut self, new_nodes: Vec<NodeInfo>) -> Result<()> {
-        for new_node in new_nodes {
-            if let Ok(node) = self.node_with_number(new_node.node) {
-                node.update_status(new_node)?;
-            } else {
-                self.nodes.push(DucoBoxNode::try_from(new_node)?);
+    /// Removes the nodes that left the duco network, or keeps them with unknown values. Returns true when the nodes
+    /// were removed.
+    async fn handle_missing_nodes(&mut self, missing: &[u16]) -> Result<bool> {
+        if self.keep_missing_nodes {
+            for node in self.nodes.iter_mut().filter(|node| missing.contains(&node.number())) {
+                tracing::warn!(
+                    "Node {} is missing from the duco network, its values are unknown",
+                    node.number()
+                );
+                node.reset();
             }
+            return Ok(false);
         }
 
-        Ok(())
+        let (removed, nodes): (Vec<DucoBoxNode>, Vec<DucoBoxNode>) = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .partition(|node| missing.contains(&node.number()));
+        self.nodes = nodes;
+        for nr in missing {
+            tracing::warn!(node = nr, "Node left the duco network, removing its topics");
+            self.missing_polls.remove(nr);
+        }
+        self.update_node_names();
+        self.clear_removed_nodes(&removed).await?;
+        Ok(true)
     }
 
-    async fn publish_device_info(&mut self) -> Result<()> {
-        if let Some(device_info) = &mut self.device_info {
-            for mut mqtt_data in device_info.topics_that_need_updating() {
-                mqtt_data.topic = format!("{}{}", self.mqtt_base_topic, mqtt_data.topic);
-                log::info!("{}: {}", mqtt_data.topic, mqtt_data.payload);
-                self.mqtt.publish(mqtt_data).await?;
+    /// Applies the configuration parameters of the nodes that expose them (e.g. the CO2 setpoint), a box that does
+    /// not provide the node configuration only loses these entities. Returns true when the parameters of a node
+    /// changed, their entities have to be discovered.
+    fn update_node_config(&mut self, node_config: Result<Vec<NodeConfig>>) -> bool {
+        let node_config = match node_config {
+            Ok(node_config) => node_config,
+            Err(err) => {
+                tracing::warn!("Failed to obtain the node configuration: {:#}", err);
+                return false;
+            }
+        };
+
+        let mut changed = false;
+        for config in node_config {
+            if let Ok(node) = self.node_with_number(config.node) {
+                changed |= node.set_config(config);
             }
         }
 
-        Ok(())
+        changed
     }
 
-    async fn publish_nodes(&mut self) -> Result<()> {
-        for node in self.nodes.iter_mut() {
-            for mut mqtt_data in node.topics_that_need_updating() {
-                mqtt_data.topic = format!("{}{}", self.mqtt_base_topic, mqtt_data.topic);
-                log::info!("{}: {}", mqtt_data.topic, mqtt_data.payload);
-                self.mqtt.publish(mqtt_data).await?;
+    /// Replaces the actions of the nodes after a rediscovery, returns true when the actions of a node changed
+    fn update_node_actions(&mut self, node_actions: Vec<NodeActions>) -> Result<bool> {
+        let mut changed = false;
+        for actions in node_actions {
+            let nr = actions.node;
+            if self.node_with_number(nr)?.set_actions(actions)? {
+                tracing::info!(node = nr, "The actions of the node changed");
+                changed = true;
             }
         }
 
-        Ok(())
+        Ok(changed)
     }
 
-    fn reset_status(&mut self) {
-        if let Some(device_info) = &mut self.device_info {
-            device_info.reset();
-        }
-        for node in self.nodes.iter_mut() {
-            node.reset();
+    fn update_virtual_nodes(&mut self) {
+        for virtual_node in &self.virtual_nodes {
+            if let Err(err) = virtual_node.update_node(&mut self.nodes) {
+                tracing::debug!("Failed to update virtual node {}: {:#}", virtual_node.number, err);
+            }
         }
     }
 
-    fn create_hass_descriptions_for_device(base_topic: &str) -> Result<Vec<MqttData>> {
-        Ok(vec![hassdiscovery::filter_days_remaining_topic(base_topic)?])
+    /// Checks if nodes joined the duco network since the nodes were discovered
+    fn nodes_joined(&self, node_infos: &[NodeInfo]) -> bool {
+        poller::has_new_nodes(&self.known_nodes().unwrap_or_default(), node_infos)
     }
 
-    fn create_hass_descriptions_for_node(node: &DucoBoxNode, base_topic: &str) -> Result<Vec<MqttData>> {
-        let mut topics = Vec::new();
-
-        match node.node_type() {
-            crate::duconodetypes::NodeType::DucoBox | crate::duconodetypes::NodeType::CO2ControlValve => {
-                topics.push(hassdiscovery::ventilation_state_topic(
-                    node,
-                    base_topic,
-                    node.valid_action_values("SetVentilationState")?,
-                )?);
-                topics.push(hassdiscovery::flow_level_target_topic(node, base_topic)?);
-                topics.push(hassdiscovery::state_time_remaining_topic(node, base_topic)?);
-                topics.push(hassdiscovery::identify_topic(node, base_topic)?);
-            }
-            crate::duconodetypes::NodeType::CO2RoomSensor => {
-                topics.push(hassdiscovery::co2_sensor_topic(node, base_topic)?);
-                topics.push(hassdiscovery::identify_topic(node, base_topic)?);
-            }
-            crate::duconodetypes::NodeType::RemoteControlRFBAT => todo!(),
-            crate::duconodetypes::NodeType::RemoteControlRFWired => todo!(),
-            crate::duconodetypes::NodeType::HumidityRoomSensor => todo!(),
-            crate::duconodetypes::NodeType::SensorlessControlValve => todo!(),
-            crate::duconodetypes::NodeType::HumidityControlValve => todo!(),
-            crate::duconodetypes::NodeType::SwitchSensor => todo!(),
-            crate::duconodetypes::NodeType::ControlUnit => todo!(),
-            crate::duconodetypes::NodeType::CO2RHControlValve => todo!(),
-            crate::duconodetypes::NodeType::RemoteControlSunControlRFWired => todo!(),
-            crate::duconodetypes::NodeType::RemoteControlNightventRFWired => todo!(),
-            crate::duconodetypes::NodeType::ExternalMultiZoneValve => todo!(),
-            crate::duconodetypes::NodeType::HumidityBoxSensor => todo!(),
-            crate::duconodetypes::NodeType::CO2BoxSensors => todo!(),
-            crate::duconodetypes::NodeType::DucoWeatherStation => todo!(),
-            crate::duconodetypes::NodeType::Unknown => {}
-        }
-
-        // for register in DucoBoxNode::supported_holding_registers(node.node_type()) {
-        //     match register {
-        //         HoldingRegister::SupplyTemperatureTargetZone1 | HoldingRegister::SupplyTemperatureTargetZone2 => {
-        //             let mut sensor =
-        //                 create_number_for_register(node.number(), base_topic, register.to_string().as_str(), register);
-
-        //             sensor.device_class = Some(String::from("temperature"));
-        //             sensor.min = Some(0);
-        //             sensor.max = Some(400);
-        //             sensor.unit_of_measurement = Some(String::from("0.1°C"));
-
-        //             topics.push(MqttData {
-        //                 topic: format!("{}/number/{}/config", HASS_DISCOVERY_TOPIC, sensor.unique_id),
-        //                 payload: serde_json::to_string(&sensor)?,
-        //             });
-
-        //             log::debug!("{:?}", topics.last().unwrap());
-        //         }
-        //     }
-        // }
+    /// Maps the old node numbers on the new node numbers of the components that were renumbered, e.g. after the
+    /// installer re-paired the components. Components that can not be identified unambiguously are not mapped.
+    fn renumbered_nodes(previous: &[DucoBoxNode], current: &[DucoBoxNode]) -> BTreeMap<u16, u16> {
+        let identities = |nodes: &[DucoBoxNode]| {
+            let mut identities: HashMap<String, Option<u16>> = HashMap::new();
+            for node in nodes
+                .iter()
+                .filter(|node| !matches!(node.node_type(), NodeType::Virtual))
+            {
+                if let Some(identity) = node.identity() {
+                    identities
+                        .entry(identity)
+                        .and_modify(|nr| *nr = None)
+                        .or_insert(Some(node.number()));
+                }
+            }
+            identities
+        };
+
+        let current = identities(current);
+        identities(previous)
+            .into_iter()
+            .filter_map(|(identity, old_nr)| Some((old_nr?, current.get(&identity).copied()??)))
+            .filter(|(old_nr, new_nr)| old_nr != new_nr)
+            .collect()
+    }
+
+    /// Moves the bridge state (the active overrides) of renumbered components to their new node number
+    async fn migrate_node_numbers(&mut self, previous: &[DucoBoxNode]) -> Result<()> {
+        let renumbered = DucoMqttBridge::renumbered_nodes(previous, &self.nodes);
+        if renumbered.is_empty() {
+            return Ok(());
+        }
+
+        for (old_nr, new_nr) in &renumbered {
+            tracing::info!(node = new_nr, old_node = old_nr, "Node was renumbered");
+            if self.virtual_nodes.iter().any(|node| node.members.contains(old_nr)) {
+                tracing::warn!(
+                    "Node {} is a member of a virtual node, update the virtual nodes file",
+                    old_nr
+                );
+            }
+        }
+
+        let overrides = std::mem::take(&mut self.state.overrides);
+        self.state.overrides = overrides
+            .into_iter()
+            .map(|(nr, stack)| (renumbered.get(&nr).copied().unwrap_or(nr), stack))
+            .collect();
+        self.state.save()?;
+
+        for (old_nr, new_nr) in renumbered {
+            if self.state.overrides.contains_key(&new_nr) {
+                self.publish_overrides(old_nr).await?;
+                self.publish_overrides(new_nr).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clears the retained values of the node numbers that are no longer in use
+    async fn clear_removed_nodes(&mut self, previous: &[DucoBoxNode]) -> Result<()> {
+        let mut topics = Vec::new();
+        for node in previous {
+            if self.nodes.iter().all(|current| current.number() != node.number()) {
+                topics.extend(node.published_topics()?);
+                if self.last_seen {
+                    topics.push(node.status_topic(LAST_SEEN));
+                }
+            }
+        }
+
+        for topic in topics {
+            self.mqtt
+                .publish(MqttData::new(
+                    format!("{}{}", self.mqtt_base_topic, topic),
+                    String::new(),
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Polls the box and returns the values that changed since the previous poll without publishing them
+    pub async fn poll_changes(&mut self) -> ApiResult<Vec<MqttData>> {
+        let request = PollRequest {
+            known_nodes: self.known_nodes(),
+            ..Default::default()
+        };
+        let response = poller::fetch(self.backend.as_ref(), &request).await?;
+        match self.device_info {
+            Some(ref mut device) => device.update_status(response.device_info),
+            None => self.device_info = Some(DucoBoxDevice::try_from(response.device_info)?),
+        }
+
+        self.update_nodes(response.nodes, response.node_actions, response.nodes_unchanged)
+            .await?;
+
+        let mut changes = match &mut self.device_info {
+            Some(device_info) => device_info.topics_that_need_updating(&self.value_transform),
+            None => Vec::new(),
+        };
+        changes.extend(self.nodes.iter_mut().flat_map(|node| node.topics_that_need_updating()));
+        Ok(changes)
+    }
+
+    /// The typed values of the nodes as of the last poll
+    pub fn node_models(&self) -> Vec<NodeModel> {
+        self.nodes.iter().map(DucoBoxNode::model).collect()
+    }
+
+    fn node_with_number(&mut self, nr: u16) -> Result<&mut DucoBoxNode> {
+        if let Some(node) = self.nodes.iter_mut().find(|x| x.number() == nr) {
+            Ok(node)
+        } else {
+            Err(anyhow!("No node with id '{nr}'"))
+        }
+    }
+
+    fn node_number_for_node_name(topic: &str) -> Result<u16> {
+        let parts: Vec<&str> = topic.split('_').collect();
+        if parts.len() == 3 && parts[0] == "duco" && parts[1] == "node" {
+            return parts[2]
+                .parse()
+                .map_err(|_| anyhow!("Invalid node number '{}'", parts[2]));
+        }
+
+        Err(anyhow!("Invalid node topic provided: {}", topic))
+    }
+
+    /// The number of the node addressed by the node level of a command topic: the levels of the topic scheme, the
+    /// default `duco_node_<nr>` levels and the node names (case insensitive) are accepted
+    fn node_number(&self, node_topic: &str) -> Result<u16> {
+        if let Some(node) = self.nodes.iter().find(|node| node.topic() == node_topic) {
+            return Ok(node.number());
+        }
+
+        if let Ok(nr) = DucoMqttBridge::node_number_for_node_name(node_topic) {
+            return Ok(nr);
+        }
+
+        match self.node_names.get(&node_topic.to_lowercase()) {
+            Some(Some(nr)) => Ok(*nr),
+            Some(None) => bail!("Multiple nodes are named '{}', address the node by number", node_topic),
+            None => bail!("Invalid node topic provided: {}", node_topic),
+        }
+    }
+
+    /// The node level of the topics of a node, also for nodes that are no longer known
+    fn node_topic(&self, node_nr: u16) -> String {
+        match self.nodes.iter().find(|node| node.number() == node_nr) {
+            Some(node) => node.topic(),
+            None => self.naming.node_topic(node_nr, None),
+        }
+    }
+
+    /// Parses a node command topic: `<node>/cmnd/<action>` with an optional sub-resource of the action
+    /// (`<node>/cmnd/<action>/<resource>`, e.g. `duco_node_1/cmnd/Config/FlowMax`), the node level is resolved to the
+    /// node number with `node_number`
+    fn node_and_action_from_topic(
+        topic: &str,
+        node_number: impl FnOnce(&str) -> Result<u16>,
+    ) -> Result<NodeCommandTopic> {
+        let topics: Vec<&str> = topic.split('/').collect();
+        if (3..=4).contains(&topics.len()) && topics[1] == "cmnd" && topics[2..].iter().all(|level| !level.is_empty()) {
+            return Ok(NodeCommandTopic {
+                node: node_number(topics[0])?,
+                action: String::from(topics[2]),
+                resource: topics.get(3).map(|resource| resource.to_string()),
+            });
+        }
+
+        Err(anyhow!("Invalid node topic provided: {} ({:?})", topic, topics))
+    }
+
+    async fn handle_command(&mut self, msg: MqttData) -> Result<()> {
+        let path = msg
+            .topic
+            .strip_prefix(self.mqtt_base_topic.as_str())
+            .unwrap_or_default();
+
+        if let Some(command) = path.strip_prefix(BRIDGE_COMMAND_PREFIX) {
+            self.handle_bridge_command(command, &msg.payload).await
+        } else if let Some(command) = path.strip_prefix(HEAT_RECOVERY_COMMAND_PREFIX) {
+            self.handle_heat_recovery_command(command, &msg.payload)
+        } else if let Some(command) = path.strip_prefix(BOX_COMMAND_PREFIX) {
+            self.handle_box_command(command, &msg.payload).await
+        } else {
+            self.handle_node_command(msg).await
+        }
+    }
+
+    async fn handle_box_command(&mut self, command: &str, payload: &str) -> Result<()> {
+        match command {
+            "FilterReset" => {
+                let action = NodeAction::Bool(NodeBoolAction {
+                    action: RESET_FILTER_ACTION.to_string(),
+                    val: true,
+                });
+
+                self.commands.push(Command::BoxAction(action));
+                Ok(())
+            }
+            "Reboot" => {
+                // guards against accidental publishes, e.g. a retained message or a command for another box
+                let serial = self
+                    .device_info
+                    .as_ref()
+                    .and_then(|device| device.status_value(SERIAL_NUMBER))
+                    .map(|serial| serial.to_string())
+                    .ok_or_else(|| {
+                        anyhow!("The serial number of the box is not known yet, the reboot can not be confirmed")
+                    })?;
+                ensure!(
+                    payload.trim() == serial,
+                    "The reboot has to be confirmed with the serial number of the box as payload"
+                );
+
+                let action = NodeAction::Bool(NodeBoolAction {
+                    action: REBOOT_ACTION.to_string(),
+                    val: true,
+                });
+
+                self.commands.push(Command::BoxAction(action));
+                Ok(())
+            }
+            _ => {
+                let action = self.box_action_for_command(command, payload)?;
+                self.commands.push(Command::BoxAction(action));
+                Ok(())
+            }
+        }
+    }
+
+    /// Validates the command against the actions of the box, the actions are requested when the box is discovered
+    fn box_action_for_command(&self, command: &str, payload: &str) -> Result<NodeAction> {
+        if command.contains('/') {
+            bail!("Unknown box command: '{}'", command);
+        }
+
+        let device = self
+            .device_info
+            .as_ref()
+            .ok_or_else(|| anyhow!("The box is not discovered yet, box command '{}' is rejected", command))?;
+        device.action_for_command(command, payload.trim())
+    }
+
+    /// The supply temperature targets are validated against the range and the step of their configuration parameter
+    fn handle_heat_recovery_command(&mut self, command: &str, payload: &str) -> Result<()> {
+        let device = self.device_info.as_ref().ok_or_else(|| {
+            anyhow!(
+                "The box is not discovered yet, heat recovery command '{}' is rejected",
+                command
+            )
+        })?;
+        let key = match command {
+            "TempSupTgt" => SUPPLY_TEMPERATURE_TARGET,
+            _ => device
+                .supply_temperature_zones()
+                .into_iter()
+                .find(|key| key.rsplit('/').next() == Some(command))
+                .ok_or_else(|| anyhow!("Unknown heat recovery command: '{}'", command))?,
+        };
+        let parameter = device
+            .config_parameter(key)
+            .ok_or_else(|| anyhow!("The box did not report the range of '{}', command is rejected", key))?;
+
+        let temperature: f64 = payload
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid supply temperature target: '{}'", payload))?;
+        // the box expects the temperature in 0.1°C
+        let tenths = (temperature * 10.0).round() as i64;
+        parameter.validate(key, tenths)?;
+        self.commands.push(Command::BoxConfig(key.to_string(), tenths));
+        Ok(())
+    }
+
+    async fn handle_bridge_command(&mut self, command: &str, payload: &str) -> Result<()> {
+        match command {
+            "away" => match payload {
+                "ON" | "on" | "1" => self.enable_away_mode().await,
+                "OFF" | "off" | "0" => self.disable_away_mode().await,
+                _ => bail!("Invalid value for away mode: '{}'", payload),
+            },
+            "discovery" => match payload {
+                "PUBLISH" | "publish" => self.force_discovery(true).await,
+                "REMOVE" | "remove" => self.force_discovery(false).await,
+                _ => bail!("Invalid value for discovery: '{}'", payload),
+            },
+            _ => bail!("Unknown bridge command: '{}'", command),
+        }
+    }
+
+    /// Publishes all the discovery documents again or removes all the entities, until the next restart
+    async fn force_discovery(&mut self, publish: bool) -> Result<()> {
+        ensure!(
+            self.device_info.is_some(),
+            "The box was not polled yet, the discovery documents are not known"
+        );
+
+        if publish {
+            tracing::info!("Publishing the home assistant discovery documents");
+        } else {
+            tracing::info!("Removing the home assistant entities");
+        }
+
+        self.hass_discovery = publish;
+        self.discovery_held = false;
+        self.discovery_pending = false;
+        self.publish_discovery().await
+    }
+
+    async fn enable_away_mode(&mut self) -> Result<()> {
+        if self.away_mode_active() {
+            tracing::info!("Away mode already active");
+            return Ok(());
+        }
+
+        let away_nodes: Vec<u16> = self
+            .nodes
+            .iter()
+            .filter(|node| !matches!(node.node_type(), NodeType::Virtual))
+            .filter(|node| {
+                node.valid_action_values(SET_VENTILATION_STATE)
+                    .is_ok_and(|states| states.iter().any(|state| state == EMPTY_HOUSE_STATE))
+            })
+            .map(|node| node.number())
+            .collect();
+
+        for node_nr in &away_nodes {
+            if let Err(err) = self.push_override(*node_nr, AWAY_OVERRIDE, EMPTY_HOUSE_STATE).await {
+                tracing::error!(node = node_nr, "Failed to enable away mode: {:#}", err);
+            }
+        }
+
+        tracing::info!("Away mode enabled for {} nodes", away_nodes.len());
+        self.publish_away_mode().await
+    }
+
+    async fn disable_away_mode(&mut self) -> Result<()> {
+        if !self.away_mode_active() {
+            tracing::info!("Away mode not active");
+            return Ok(());
+        }
+
+        let away_nodes: Vec<u16> = self
+            .state
+            .overrides
+            .iter()
+            .filter(|(_, stack)| stack.contains(AWAY_OVERRIDE))
+            .map(|(node_nr, _)| *node_nr)
+            .collect();
+
+        for node_nr in away_nodes {
+            if let Err(err) = self.remove_override(node_nr, AWAY_OVERRIDE).await {
+                tracing::error!(node = node_nr, "Failed to disable away mode: {:#}", err);
+            }
+        }
+
+        tracing::info!("Away mode disabled");
+        self.publish_away_mode().await
+    }
+
+    fn away_mode_active(&self) -> bool {
+        self.state.overrides.values().any(|stack| stack.contains(AWAY_OVERRIDE))
+    }
+
+    async fn publish_away_mode(&mut self) -> Result<()> {
+        let payload = if self.away_mode_active() { "ON" } else { "OFF" };
+
+        self.mqtt
+            .publish(MqttData::new(
+                format!("{}bridge/away", self.mqtt_base_topic),
+                payload.to_string(),
+            ))
+            .await
+    }
+
+    /// Queues the command for the node, commands for virtual nodes are forwarded to all of their members.
+    /// The command is validated immediately, the outcome of performing it is reported by the command queue.
+    fn queue_node_command(&mut self, node_nr: u16, action_name: String, payload: String) -> Result<()> {
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| node.number() == node_nr)
+            .ok_or_else(|| anyhow!("No node with id '{node_nr}'"))?;
+
+        let mut actions = Vec::new();
+        match self
+            .virtual_nodes
+            .iter()
+            .find(|virtual_node| virtual_node.number == node_nr)
+        {
+            Some(virtual_node) => {
+                // validate against the common actions of the virtual node before forwarding to the members
+                node.verify_command(&action_name, &payload)?;
+                for member in &virtual_node.members {
+                    let Some(member_node) = self.nodes.iter().find(|node| node.number() == *member) else {
+                        tracing::warn!("Member {} of virtual node {} not found", member, node_nr);
+                        continue;
+                    };
+
+                    actions.push((
+                        *member,
+                        member_node.action_for_command(action_name.clone(), payload.clone())?,
+                    ));
+                }
+            }
+            None => actions.push((node_nr, node.action_for_command(action_name, payload)?)),
+        }
+
+        for (node_nr, action) in actions {
+            self.commands.push(Command::NodeAction(node_nr, action));
+        }
+
+        Ok(())
+    }
+
+    fn apply_ventilation_state(&mut self, node_nr: u16, state: &str) -> Result<()> {
+        self.queue_node_command(node_nr, SET_VENTILATION_STATE.to_string(), state.to_string())
+    }
+
+    /// Layers a temporary state override on the node, the current state of the node becomes the base state when
+    /// this is the first override
+    async fn push_override(&mut self, node_nr: u16, name: &str, state: &str) -> Result<()> {
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| node.number() == node_nr)
+            .ok_or_else(|| anyhow!("No node with id '{node_nr}'"))?;
+
+        node.action_for_command(SET_VENTILATION_STATE.to_string(), state.to_string())
+            .map_err(|err| anyhow!("Invalid override state for node {}: {:#}", node_nr, err))?;
+
+        let mut stack = match self.state.overrides.get(&node_nr) {
+            Some(stack) => stack.clone(),
+            None => match node.status_value(VENTILATION_STATE_KEY) {
+                Some(StatusValue::String(base)) => OverrideStack::new(base.clone()),
+                _ => bail!("Ventilation state of node {} is unknown", node_nr),
+            },
+        };
+
+        let previous = stack.effective().to_string();
+        stack.push(name, state);
+        if stack.effective() != previous {
+            self.apply_ventilation_state(node_nr, stack.effective())?;
+        }
+
+        self.state.overrides.insert(node_nr, stack);
+        self.state.save()?;
+        self.publish_overrides(node_nr).await
+    }
+
+    /// Removes a temporary state override from the node and applies the state of the next layer (or the base state)
+    async fn remove_override(&mut self, node_nr: u16, name: &str) -> Result<()> {
+        let Some(mut stack) = self.state.overrides.get(&node_nr).cloned() else {
+            bail!("No overrides active for node {}", node_nr);
+        };
+
+        let previous = stack.effective().to_string();
+        ensure!(stack.remove(name), "No override '{}' active for node {}", name, node_nr);
+        if stack.effective() != previous {
+            self.apply_ventilation_state(node_nr, stack.effective())?;
+        }
+
+        if stack.is_empty() {
+            self.state.overrides.remove(&node_nr);
+        } else {
+            self.state.overrides.insert(node_nr, stack);
+        }
+
+        self.state.save()?;
+        self.publish_overrides(node_nr).await
+    }
+
+    async fn publish_overrides(&mut self, node_nr: u16) -> Result<()> {
+        let (base, effective, layers) = match self.state.overrides.get(&node_nr) {
+            Some(stack) => (
+                stack.base().to_string(),
+                stack.effective().to_string(),
+                serde_json::to_string(stack.layers())?,
+            ),
+            None => (String::new(), String::new(), String::from("[]")),
+        };
+
+        let topic = format!("{}{}/Override", self.mqtt_base_topic, self.node_topic(node_nr));
+        self.mqtt
+            .publish_multiple(vec![
+                MqttData::new(format!("{}/Base", topic), base),
+                MqttData::new(format!("{}/Effective", topic), effective),
+                MqttData::new(format!("{}/Layers", topic), layers),
+            ])
+            .await
+    }
+
+    async fn handle_override_command(&mut self, node_nr: u16, action_name: &str, payload: &str) -> Result<()> {
+        if action_name == OVERRIDE_ACTION {
+            let cmd: OverrideCommand = serde_json::from_str(payload)?;
+            self.push_override(node_nr, &cmd.name, &cmd.state).await
+        } else {
+            self.remove_override(node_nr, payload).await
+        }
+    }
+
+    async fn handle_node_command(&mut self, msg: MqttData) -> Result<()> {
+        if let Some(path) = msg.topic.strip_prefix(self.mqtt_base_topic.as_str()) {
+            if let Some(node_name) = path.strip_suffix(NODE_COMMAND_SUFFIX)
+                && !node_name.contains('/')
+            {
+                let node = self.node_number(node_name)?;
+                let command: NodeJsonCommand = serde_json::from_str(&msg.payload)
+                    .map_err(|err| anyhow!("Invalid command for node {}: {} ({})", node, msg.payload, err))?;
+                let payload = match command.val {
+                    serde_json::Value::String(val) => val,
+                    val => val.to_string(),
+                };
+
+                return self.dispatch_node_command(node, command.action, payload).await;
+            }
+
+            let command = DucoMqttBridge::node_and_action_from_topic(path, |node| self.node_number(node))?;
+            if let Some(parameter) = command.resource.as_deref()
+                && command.action == CONFIG
+            {
+                return self.handle_node_config_command(command.node, parameter, &msg.payload);
+            }
+            if let Some(resource) = command.resource {
+                bail!(
+                    "Unsupported command for node {}: '{}/{}'",
+                    command.node,
+                    command.action,
+                    resource
+                );
+            }
+
+            return self
+                .dispatch_node_command(command.node, command.action, msg.payload)
+                .await;
+        }
+
+        Err(anyhow!("Unexpected command path: {}", msg.topic))
+    }
+
+    /// Changes a configuration parameter of a node (`<node>/cmnd/Config/<parameter>`) within the range the box reports
+    fn handle_node_config_command(&mut self, node_nr: u16, parameter: &str, payload: &str) -> Result<()> {
+        let val = self
+            .node_with_number(node_nr)?
+            .config_value_for_command(parameter, payload)?;
+        self.commands
+            .push(Command::NodeConfig(node_nr, parameter.to_string(), val));
+        Ok(())
+    }
+
+    async fn dispatch_node_command(&mut self, node: u16, action: String, payload: String) -> Result<()> {
+        if action == OVERRIDE_ACTION || action == CLEAR_OVERRIDE_ACTION {
+            return self.handle_override_command(node, &action, &payload).await;
+        }
+
+        self.queue_node_command(node, action, payload)
+    }
+
+    fn merge_nodes(&mut self, new_nodes: Vec<NodeInfo>) -> Result<()> {
+        for new_node in new_nodes {
+            if let Ok(node) = self.node_with_number(new_node.node) {
+                node.update_status(new_node)?;
+            } else {
+                let mut node = DucoBoxNode::try_from(new_node)?;
+                node.set_naming(self.naming.clone());
+                node.set_status_filter(self.status_filter.clone());
+                node.set_value_transform(self.value_transform.clone());
+                self.nodes.push(node);
+            }
+        }
+
+        self.update_node_names();
+        Ok(())
+    }
+
+    async fn publish_status_topics(&mut self, topics: Vec<MqttData>) -> Result<usize> {
+        let topic_count = topics.len();
+
+        for mut mqtt_data in topics {
+            mqtt_data.topic = format!("{}{}", self.mqtt_base_topic, mqtt_data.topic);
+            if !self.low_power {
+                let payload = self.log_redaction.payload(&mqtt_data.topic, &mqtt_data.payload);
+                if self.log_sampler.sample(&mqtt_data.topic) {
+                    tracing::info!(topic = %mqtt_data.topic, %payload, "Publish");
+                } else {
+                    tracing::debug!(topic = %mqtt_data.topic, %payload, "Publish");
+                }
+            }
+            self.mqtt.publish(mqtt_data).await?;
+        }
+
+        Ok(topic_count)
+    }
+
+    async fn publish_device_info(&mut self) -> Result<usize> {
+        let mut topics = match &mut self.device_info {
+            Some(device_info) => device_info.topics_that_need_updating(&self.value_transform),
+            None => Vec::new(),
+        };
+
+        if self.compat == TopicCompat::V1 {
+            // the array values and the duco network counters are not part of topic layout version 1
+            let device = self.device_info.as_ref();
+            topics.retain(|data| {
+                !data.topic.starts_with(NETWORK_DUCO) && !device.is_some_and(|dev| dev.is_json_value(&data.topic))
+            });
+        }
+
+        if self.low_power {
+            // the board and network information is diagnostic only
+            topics.retain(|data| !data.topic.starts_with(GENERAL) && !data.topic.starts_with(NETWORK_DUCO));
+        }
+
+        self.publish_status_topics(topics).await
+    }
+
+    /// Records the numeric node values and publishes the statistics of the last hour as json attributes
+    async fn publish_history(&mut self) -> Result<()> {
+        if !self.history.is_enabled() {
+            return Ok(());
+        }
+
+        let now = time::Instant::now();
+        for node in &self.nodes {
+            for key in node.status_keys().into_iter().filter(|key| !key.starts_with(GENERAL)) {
+                if let Some(StatusValue::Number(value)) = node.status_value(key) {
+                    self.history.record(&node.status_topic(key), *value, now);
+                }
+            }
+        }
+
+        let mut attributes = Vec::new();
+        for topic in self.history.topics() {
+            let Some(statistics) = self.history.statistics(topic, now) else {
+                continue;
+            };
+
+            let payload = serde_json::to_string(&statistics)?;
+            if self.published_attributes.get(topic) != Some(&payload) {
+                self.published_attributes.insert(topic.clone(), payload.clone());
+                attributes.push(MqttData {
+                    topic: format!("{}{}/attributes", self.mqtt_base_topic, topic),
+                    payload,
+                });
+            }
+        }
+
+        attributes.sort();
+        self.mqtt.publish_multiple(attributes).await
+    }
+
+    /// The heat recovery device is only reported online when the box provides heat recovery values
+    async fn publish_device_availability(&mut self, online: bool) -> Result<()> {
+        let heat_recovery = self.device_info.as_ref().is_some_and(DucoBoxDevice::has_heat_recovery);
+
+        self.mqtt
+            .publish_availability(
+                hassdiscovery::device_availability_topic(&self.mqtt_base_topic, hassdiscovery::VENTILATION_DEVICE),
+                online,
+            )
+            .await?;
+        self.mqtt
+            .publish_availability(
+                hassdiscovery::device_availability_topic(&self.mqtt_base_topic, hassdiscovery::HEAT_RECOVERY_DEVICE),
+                online && heat_recovery,
+            )
+            .await
+    }
+
+    async fn publish_action_options(&mut self) -> Result<()> {
+        if self.compat == TopicCompat::V1 {
+            return Ok(());
+        }
+
+        let mut topics = Vec::new();
+        for node in &self.nodes {
+            topics.extend(node.action_option_topics()?);
+        }
+
+        self.publish_status_topics(topics).await?;
+        Ok(())
+    }
+
+    async fn publish_nodes(&mut self) -> Result<usize> {
+        if self.json_state {
+            let mut topics = Vec::new();
+            for node in &mut self.nodes {
+                topics.extend(node.json_state_if_modified()?);
+            }
+
+            return self.publish_status_topics(topics).await;
+        }
+
+        let topics = self
+            .nodes
+            .iter_mut()
+            .flat_map(|node| node.topics_that_need_updating())
+            .collect();
+
+        self.publish_status_topics(topics).await
+    }
+
+    async fn check_for_update(&mut self) -> Result<()> {
+        let update_state = updatecheck::check_latest_release().await?;
+        if update_state.update_available() {
+            tracing::warn!(
+                "A newer duco2mqtt version is available: {} (running {}), see {}",
+                update_state.latest_version,
+                update_state.installed_version,
+                update_state.release_url
+            );
+        } else {
+            tracing::debug!("duco2mqtt is up to date ({})", update_state.installed_version);
+        }
+
+        if self.hass_discovery {
+            self.mqtt.publish(self.update_discovery_topic()?).await?;
+        }
+
+        self.mqtt
+            .publish(MqttData {
+                topic: format!("{}bridge/update", self.mqtt_base_topic),
+                payload: serde_json::to_string(&update_state)?,
+            })
+            .await
+    }
+
+    fn reset_status(&mut self) {
+        if let Some(device_info) = &mut self.device_info {
+            device_info.reset();
+        }
+        for node in self.nodes.iter_mut() {
+            node.reset();
+        }
+    }
+
+    fn update_discovery_topic(&self) -> Result<MqttData> {
+        match self.compat {
+            TopicCompat::V1 => discoveryv1::update_topic(&self.mqtt_base_topic),
+            TopicCompat::V2 => hassdiscovery::update_topic(&self.discovery_context(self.device_info.as_ref())),
+        }
+    }
+
+    /// The settings of the v2 discovery documents
+    fn discovery_context(&self, device: Option<&DucoBoxDevice>) -> DiscoveryContext {
+        let mut ctx = DiscoveryContext::new(&self.mqtt_base_topic, device);
+        ctx.history_attributes = self.history.is_enabled();
+        ctx.naming = self.naming.clone();
+        ctx.iaq_sensor_nodes = self.iaq_sensor_nodes.clone();
+        ctx.value_transform = self.value_transform.clone();
+        ctx.last_seen = self.last_seen;
+        ctx.entity_defaults = self.hass_entity_defaults.clone();
+        ctx
+    }
+
+    fn create_hass_descriptions_for_nodes(&self) -> Vec<MqttData> {
+        if self.compat == TopicCompat::V1 {
+            return self
+                .nodes
+                .iter()
+                .flat_map(|node| {
+                    discoveryv1::node_topics(node, &self.mqtt_base_topic).unwrap_or_else(|err| {
+                        tracing::error!("Failed to create home assistant descriptions: {:#}", err);
+                        Vec::new()
+                    })
+                })
+                .collect();
+        }
+
+        let ctx = self.discovery_context(self.device_info.as_ref());
+        let mut topics = Vec::new();
+        for node in &self.nodes {
+            match DucoMqttBridge::create_hass_descriptions_for_node(node, &ctx) {
+                Ok(mqtt_data) => topics.extend(mqtt_data),
+                Err(err) => {
+                    tracing::error!("Failed to create home assistant descriptions: {:#}", err);
+                }
+            }
+        }
+
+        topics
+    }
+
+    /// Home assistant announces that it is online after a (re)start, it has to receive the discovery documents again
+    async fn handle_hass_status(&mut self, payload: &str) -> Result<()> {
+        if payload != "online" {
+            tracing::info!("Home assistant is {}", payload);
+            return Ok(());
+        }
+
+        tracing::info!("Home assistant is online");
+        if self.discovery_held {
+            self.release_discovery().await
+        } else if self.hass_discovery && self.device_info.is_some() {
+            self.publish_discovery().await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// After a broker failover the retained topics are published again, the other broker does not have them
+    async fn republish(&mut self) -> Result<()> {
+        tracing::info!("Publishing all topics on the new MQTT broker");
+        self.publish_away_mode().await?;
+        let override_nodes: Vec<u16> = self.state.overrides.keys().copied().collect();
+        for node_nr in override_nodes {
+            self.publish_overrides(node_nr).await?;
+        }
+
+        if self.device_info.is_none() {
+            // nothing was published yet, the first poll publishes everything
+            return Ok(());
+        }
+
+        self.published_attributes.clear();
+        self.publish_action_options().await?;
+        self.publish_discovery().await?;
+        // the values are published when the poll restores them
+        self.reset_status();
+        self.request_poll();
+        Ok(())
+    }
+
+    async fn release_discovery(&mut self) -> Result<()> {
+        self.discovery_held = false;
+        if std::mem::take(&mut self.discovery_pending) {
+            self.publish_discovery().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the discovery documents of the box and its nodes, the entities that were published before but no
+    /// longer exist (e.g. a node that left the duco network or discovery that was disabled) are removed.
+    /// The removals go first: switching to device discovery moves the entities to a new topic under the same unique
+    /// id, home assistant ignores the new document while the old one still exists.
+    async fn publish_discovery(&mut self) -> Result<()> {
+        if self.discovery_held {
+            self.discovery_pending = true;
+            return Ok(());
+        }
+
+        let documents = self.current_discovery_documents()?;
+
+        let mut topics: BTreeSet<String> = documents.iter().map(|doc| doc.topic.clone()).collect();
+        if self.hass_discovery && self.update_check {
+            // published by the update check
+            topics.insert(self.update_discovery_topic()?.topic);
+        }
+
+        let stale: Vec<String> = self.state.discovery_topics.difference(&topics).cloned().collect();
+        for topic in stale {
+            tracing::info!(%topic, "Removing home assistant entity");
+            self.mqtt.publish(MqttData::new(topic, String::new())).await?;
+        }
+        self.mqtt.publish_multiple(documents).await?;
+
+        // home assistant warns about states of entities it does not know yet
+        if !self.hass_discovery_ack_timeout.is_zero()
+            && !self
+                .mqtt
+                .wait_for_acknowledgements(self.hass_discovery_ack_timeout)
+                .await?
+        {
+            tracing::warn!(
+                "The broker did not acknowledge the discovery documents within {:?}",
+                self.hass_discovery_ack_timeout
+            );
+        }
+
+        if self.state.discovery_topics != topics {
+            self.state.discovery_topics = topics;
+            self.state.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// The discovery documents of the current box and nodes in the configured format, empty without discovery
+    fn current_discovery_documents(&self) -> Result<Vec<MqttData>> {
+        let mut documents = Vec::new();
+        if self.hass_discovery {
+            if let Some(device) = &self.device_info {
+                documents.extend(self.device_discovery_documents(device)?);
+            }
+            if !self.hass_drift_check_interval.is_zero() {
+                let ctx = self.discovery_context(self.device_info.as_ref());
+                documents.push(hassdiscovery::discovery_drift_topic(&ctx)?);
+            }
+            documents.extend(self.create_hass_descriptions_for_nodes());
+            documents = self.in_discovery_format(self.without_diagnostics(documents))?;
+        }
+
+        Ok(documents)
+    }
+
+    /// Subscribes to the discovery topics to receive the documents that are retained on the broker,
+    /// they are compared with the generated documents when the check window closes
+    async fn start_drift_check(&mut self) -> Result<()> {
+        if self.discovery_held || self.drift_check.is_some() || self.device_info.is_none() {
+            return Ok(());
+        }
+
+        let check = DriftCheck::new(self.current_discovery_documents()?);
+        for topic in check.topics() {
+            self.mqtt.subscribe(topic).await?;
+        }
+        self.drift_check = Some(check);
+
+        Ok(())
+    }
+
+    async fn finish_drift_check(&mut self) -> Result<()> {
+        let Some(check) = self.drift_check.take() else {
+            return Ok(());
+        };
+
+        for topic in check.topics() {
+            self.mqtt.unsubscribe(topic).await?;
+        }
+
+        let drifted = check.drifted();
+        for doc in &drifted {
+            tracing::warn!(
+                "Home assistant discovery document differs from the generated one: {}",
+                doc.topic
+            );
+        }
+
+        self.mqtt
+            .publish(MqttData::new(
+                format!("{}bridge/discovery_drift", self.mqtt_base_topic),
+                (if drifted.is_empty() { "OFF" } else { "ON" }).to_string(),
+            ))
+            .await?;
+
+        if self.hass_drift_repair && !drifted.is_empty() {
+            tracing::info!("Republishing {} home assistant discovery documents", drifted.len());
+            self.mqtt.publish_multiple(drifted).await?;
+            // the next check reports whether the repair succeeded
+        }
+
+        Ok(())
+    }
+
+    /// Requests the device info, the nodes and their actions without creating the nodes, so the nodes the bridge does
+    /// not support are listed as well
+    pub async fn inventory(&self) -> ApiResult<Inventory> {
+        let (device_info, nodes, node_actions) = tokio::try_join!(
+            self.backend.get_device_info(),
+            self.backend.get_nodes(),
+            self.backend.get_node_actions()
+        )?;
+        Ok(Inventory::new(device_info, nodes, node_actions))
+    }
+
+    /// Performs an action of a node without the broker, the value is validated against the actions of the node
+    pub async fn set(&self, node: u16, action: &str, value: &str) -> ApiResult<()> {
+        let nodes =
+            DucoMqttBridge::discover_nodes(self.backend.get_nodes().await?, self.backend.get_node_actions().await?)?;
+        let Some(node) = nodes.iter().find(|candidate| candidate.number() == node) else {
+            return Err(Error::Runtime(format!("Node {} is not part of the duco network", node)));
+        };
+
+        let action = node.action_for_command(action.to_string(), value.to_string())?;
+        self.backend.perform_action(node.number(), action).await?;
+        Ok(())
+    }
+
+    /// Discovers the device and its nodes and returns the home assistant discovery documents without publishing them
+    pub async fn discovery_documents(&mut self) -> ApiResult<Vec<MqttData>> {
+        let mut device = DucoBoxDevice::try_from(self.backend.get_device_info().await?)?;
+        match self.backend.get_box_config().await {
+            Ok(box_config) => {
+                device.set_config(box_config);
+            }
+            Err(err) => tracing::debug!("Failed to obtain the box configuration: {:#}", err),
+        }
+        self.nodes =
+            DucoMqttBridge::discover_nodes(self.backend.get_nodes().await?, self.backend.get_node_actions().await?)?;
+        self.finish_node_discovery();
+        if self.compat != TopicCompat::V1 && self.nodes.iter().any(DucoBoxNode::has_config_parameters) {
+            let node_config = self.backend.get_node_config().await;
+            self.update_node_config(node_config);
+        }
+
+        let mut documents = self.device_discovery_documents(&device)?;
+        self.device_info = Some(device);
+        documents.extend(self.create_hass_descriptions_for_nodes());
+        Ok(self.in_discovery_format(self.without_diagnostics(documents))?)
+    }
+
+    fn in_discovery_format(&self, mut documents: Vec<MqttData>) -> Result<Vec<MqttData>> {
+        if self.json_state {
+            let node_topics: Vec<String> = self.nodes.iter().map(DucoBoxNode::topic).collect();
+            documents = hassdiscovery::json_state_documents(documents, &self.mqtt_base_topic, &node_topics)?;
+        }
+
+        if self.hass_device_discovery {
+            hassdiscovery::device_discovery_documents(documents)
+        } else {
+            Ok(documents)
+        }
+    }
+
+    /// Low power mode does not publish the diagnostic entities
+    fn without_diagnostics(&self, mut documents: Vec<MqttData>) -> Vec<MqttData> {
+        if self.low_power {
+            documents.retain(|doc| !hassdiscovery::is_diagnostic(doc));
+        }
+
+        documents
+    }
+
+    /// Adds the virtual nodes to the discovered nodes and applies the topic scheme
+    fn finish_node_discovery(&mut self) {
+        for virtual_node in &self.virtual_nodes {
+            match virtual_node.create_node(&self.nodes) {
+                Ok(node) => self.nodes.push(node),
+                Err(err) => tracing::error!("Failed to create virtual node {}: {:#}", virtual_node.number, err),
+            }
+        }
+
+        for node in &mut self.nodes {
+            node.set_naming(self.naming.clone());
+            node.set_status_filter(self.status_filter.clone());
+            node.set_value_transform(self.value_transform.clone());
+        }
+        self.update_node_names();
+    }
+
+    fn update_node_names(&mut self) {
+        let mut node_names: HashMap<String, Option<u16>> = HashMap::new();
+        for node in &self.nodes {
+            if let Some(name) = node.name() {
+                node_names
+                    .entry(naming::topic_name(&name).to_lowercase())
+                    .and_modify(|nr| *nr = None)
+                    .or_insert(Some(node.number()));
+            }
+        }
+
+        if node_names != self.node_names {
+            for (name, _) in node_names.iter().filter(|(_, nr)| nr.is_none()) {
+                tracing::warn!(
+                    "Multiple nodes are named '{}', they can not be addressed by name",
+                    self.log_redaction.payload("General/Name", name)
+                );
+            }
+            self.node_names = node_names;
+        }
+
+        // the topics of nodes with the same topic level would overwrite each other
+        let mut topic_levels: HashMap<String, usize> = HashMap::new();
+        for node in &self.nodes {
+            *topic_levels.entry(node.named_topic().to_lowercase()).or_default() += 1;
+        }
+        for node in &mut self.nodes {
+            let shared = topic_levels[&node.named_topic().to_lowercase()] > 1;
+            if shared && !node.has_shared_topic() {
+                tracing::warn!(
+                    node = node.number(),
+                    "Multiple nodes have the topic level '{}', the node keeps the topic level '{}'",
+                    self.log_redaction.payload("General/Name", &node.named_topic()),
+                    self.naming.node_topic(node.number(), None)
+                );
+            }
+            node.set_shared_topic(shared);
+        }
+    }
+
+    fn device_discovery_documents(&self, device: &DucoBoxDevice) -> Result<Vec<MqttData>> {
+        match self.compat {
+            TopicCompat::V1 => discoveryv1::device_topics(&self.mqtt_base_topic),
+            TopicCompat::V2 => {
+                DucoMqttBridge::create_hass_descriptions_for_device(device, &self.discovery_context(Some(device)))
+            }
+        }
+    }
+
+    fn create_hass_descriptions_for_device(device: &DucoBoxDevice, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+        let mut topics = hassdiscovery::box_device_topics(device, ctx)?;
+        if device.status_value(FILTER_REMAINING_DAYS).is_some() {
+            topics.push(hassdiscovery::filter_reset_topic(ctx)?);
+        }
+        topics.extend(hassdiscovery::supply_temperature_zone_topics(device, ctx)?);
+        if device.status_value(SERIAL_NUMBER).is_some() {
+            topics.push(hassdiscovery::reboot_topic(ctx)?);
+        }
+        topics.push(hassdiscovery::away_mode_topic(ctx)?);
+        Ok(topics)
+    }
+
+    fn create_hass_descriptions_for_valve(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+        let mut topics = Vec::new();
+        if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+            topics.push(hassdiscovery::ventilation_state_topic(node, ctx, valid_states)?);
+            topics.push(hassdiscovery::fan_topic(node, ctx, valid_states)?);
+        }
+        topics.push(hassdiscovery::flow_level_target_topic(node, ctx)?);
+
+        Ok(topics)
+    }
+
+    fn create_hass_descriptions_for_node(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+        let mut topics = Vec::new();
+
+        for capability in node.capabilities() {
+            match capability {
+                Capability::VentilationControl => {
+                    let valid_states = node.valid_action_values(SET_VENTILATION_STATE)?;
+                    topics.push(hassdiscovery::ventilation_state_topic(node, ctx, valid_states)?);
+                    topics.push(hassdiscovery::fan_topic(node, ctx, valid_states)?);
+                    topics.extend(hassdiscovery::permanent_mode_topics(node, ctx, valid_states)?);
+                    topics.push(hassdiscovery::flow_level_target_topic(node, ctx)?);
+                    topics.push(hassdiscovery::state_time_remaining_topic(node, ctx)?);
+                }
+                Capability::VentilationState => {
+                    if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+                        topics.push(hassdiscovery::ventilation_state_topic(node, ctx, valid_states)?);
+                    }
+                }
+                Capability::PermanentModes => {
+                    if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+                        topics.extend(hassdiscovery::permanent_mode_topics(node, ctx, valid_states)?);
+                    }
+                }
+                Capability::Valve => {
+                    topics.extend(DucoMqttBridge::create_hass_descriptions_for_valve(node, ctx)?);
+                }
+                Capability::StateTimeRemaining => {
+                    topics.push(hassdiscovery::state_time_remaining_topic(node, ctx)?);
+                }
+                Capability::Identify => {
+                    topics.push(hassdiscovery::identify_topic(node, ctx)?);
+                }
+                Capability::IdentifyIfSupported => {
+                    if node.has_action(SET_IDENTIFY) {
+                        topics.push(hassdiscovery::identify_topic(node, ctx)?);
+                    }
+                }
+                Capability::HeatRecovery => {
+                    if ctx.has_heat_recovery() {
+                        topics.extend(hassdiscovery::heat_recovery_topics(node, ctx)?);
+                    }
+                }
+                Capability::Co2Sensor => {
+                    topics.extend(hassdiscovery::co2_sensor_topics(node, ctx)?);
+                    if let Some(parameter) = node.config_parameter(CO2_SETPOINT) {
+                        topics.push(hassdiscovery::co2_setpoint_topic(node, ctx, parameter)?);
+                    }
+                }
+                Capability::HumiditySensor => {
+                    topics.push(hassdiscovery::humidity_sensor_topic(node, ctx)?);
+                    topics.extend(hassdiscovery::humidity_setpoint_topics(node, ctx)?);
+                }
+                Capability::Battery => {
+                    topics.extend(hassdiscovery::battery_diagnostic_topics(node, ctx)?);
+                }
+                Capability::BoxMountedSensors => {
+                    topics.extend(hassdiscovery::box_mounted_sensor_topics(node, ctx)?);
+                }
+                Capability::WeatherStation => {
+                    topics.extend(hassdiscovery::weather_station_topics(node, ctx)?);
+                }
+                Capability::StatusFallback => {
+                    if topics.is_empty() {
+                        topics.extend(hassdiscovery::status_diagnostic_topics(node, ctx)?);
+                    }
+                }
+                Capability::Generic => {
+                    // no dedicated mapping (yet), expose everything the node reports
+                    topics.extend(hassdiscovery::generic_topics(node, ctx)?);
+                }
+            }
+        }
+
+        if ctx.last_seen && !matches!(node.node_type(), NodeType::Virtual) {
+            topics.push(hassdiscovery::last_seen_topic(node, ctx)?);
+        }
+
+        // the generic and status mappings already expose these values as diagnostic sensors
+        for diagnostic in hassdiscovery::network_diagnostic_topics(node, ctx)? {
+            if !topics.iter().any(|topic| topic.topic == diagnostic.topic) {
+                topics.push(diagnostic);
+            }
+        }
 
         Ok(topics)
     }
+}
+
+/// Completes on SIGINT (ctrl-c) or SIGTERM (docker stop, systemctl stop)
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Completes on ctrl-c, there is no SIGTERM outside of unix
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+// Test for parsing node topics
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ducoapi::{ConfigParameter, NodeActionDescription, NodeConfig, StatusField},
+        ducobackend::mock::MockBackend,
+        ducoboxnode::{RH_DELTA, RH_SETPOINT},
+        infovalue::UNKNOWN,
+        mqtt::MqttProtocol,
+        naming::DEFAULT_TOPIC_TEMPLATE,
+    };
+    use std::{collections::HashMap, path::Path};
+
+    #[test]
+    fn test_node_number_for_node_name() {
+        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_1").unwrap(), 1);
+        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_2").unwrap(), 2);
+        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_3").unwrap(), 3);
+        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_67").unwrap(), 67);
+        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_68").unwrap(), 68);
+    }
+
+    #[tokio::test]
+    async fn test_discover_nodes() {
+        let backend = MockBackend::default();
+        let nodes = DucoMqttBridge::discover_nodes(
+            backend.get_nodes().await.unwrap(),
+            backend.get_node_actions().await.unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            nodes.iter().map(|node| node.number()).collect::<Vec<_>>(),
+            vec![1, 2, 3, 67, 68]
+        );
+        assert!(matches!(nodes[1].node_type(), NodeType::CO2RoomSensor));
+
+        let action = nodes[1]
+            .action_for_command("SetVentilationState".to_string(), "MAN2".to_string())
+            .unwrap();
+        backend.perform_action(2, action).await.unwrap();
+        assert!(
+            nodes[1]
+                .action_for_command("SetVentilationState".to_string(), "BOGUS".to_string())
+                .is_err()
+        );
+        assert_eq!(
+            *backend.performed_actions.lock().unwrap(),
+            vec![(2, r#"{"Action":"SetVentilationState","Val":"MAN2"}"#.to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set() {
+        let backend = MockBackend::default();
+        let performed_actions = backend.performed_actions.clone();
+        let bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+
+        bridge.set(2, "SetVentilationState", "MAN2").await.unwrap();
+        assert_eq!(
+            *performed_actions.lock().unwrap(),
+            vec![(2, r#"{"Action":"SetVentilationState","Val":"MAN2"}"#.to_string())]
+        );
+
+        assert!(bridge.set(2, "SetVentilationState", "BOGUS").await.is_err());
+        assert!(bridge.set(42, "SetVentilationState", "MAN2").await.is_err());
+    }
+
+    fn test_context() -> DiscoveryContext {
+        DiscoveryContext::new("ventilation/", None)
+    }
+
+    fn test_config() -> DucoMqttBridgeConfig {
+        DucoMqttBridgeConfig {
+            ducobox_host: "duco.local".to_string(),
+            ducobox_ip_address: None,
+            ducobox_certificate: None,
+            ducobox_cert_pinning: false,
+            ducobox_connect_timeout: time::Duration::from_secs(15),
+            ducobox_read_timeout: time::Duration::from_secs(30),
+            ducobox_pool_idle_timeout: time::Duration::from_secs(30),
+            ducobox_pool_max_idle: 1,
+            ducobox_retry_attempts: 1,
+            ducobox_retry_backoff: time::Duration::ZERO,
+            ducobox_proxy: None,
+            ducobox_api_key: None,
+            ducobox_api_key_file: None,
+            ducobox_api_key_command: None,
+            duco_record_dir: None,
+            duco_replay_dir: None,
+            mqtt_config: MqttConfig {
+                server: "localhost".to_string(),
+                port: 1883,
+                client_id: "duco2mqtt".to_string(),
+                user: String::new(),
+                password: Secret::default(),
+                base_topic: "ventilation".to_string(),
+                low_power: false,
+                protocol: MqttProtocol::V5,
+                tls: false,
+                ca_cert: None,
+                tls_server_name: None,
+                client_cert: None,
+                client_key: None,
+                retained_commands: false,
+            },
+            hass_discovery: true,
+            hass_device_discovery: false,
+            json_state: false,
+            last_seen: false,
+            hass_discovery_delay: time::Duration::ZERO,
+            hass_discovery_ack_timeout: time::Duration::from_secs(10),
+            hass_drift_check_interval: time::Duration::ZERO,
+            hass_drift_repair: false,
+            hass_entity_defaults: BTreeMap::new(),
+            poll_interval: time::Duration::from_secs(60),
+            node_missing_polls: 1,
+            keep_missing_nodes: false,
+            rediscovery_interval: time::Duration::ZERO,
+            command_poll_interval: time::Duration::from_secs(2),
+            command_poll_window: time::Duration::from_secs(30),
+            log_redaction: LogRedaction::Off,
+            log_sample_interval: 0,
+            update_check: false,
+            health_port: None,
+            health_max_poll_age: time::Duration::ZERO,
+            state_file: None,
+            virtual_nodes_file: None,
+            compat: TopicCompat::V2,
+            history_samples: 0,
+            poll_schedule: Vec::new(),
+            entity_naming: EntityNaming::Name,
+            iaq_sensor_nodes: Vec::new(),
+            topic_template: DEFAULT_TOPIC_TEMPLATE.to_string(),
+            topic_lowercase: false,
+            status_filter: StatusFilter::default(),
+            value_transform: ValueTransform::default(),
+        }
+    }
+
+    /// Waits for the queued commands and applies their outcome, like the run loop
+    async fn perform_commands(bridge: &mut DucoMqttBridge) {
+        while !bridge.commands.is_idle() {
+            let (command, result) = bridge.commands.completed().await;
+            bridge.command_completed(command, result).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_documents() {
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+        let documents = bridge.discovery_documents().await.unwrap();
+
+        assert!(documents.iter().all(|doc| doc.topic.starts_with("homeassistant/")));
+        assert!(
+            documents
+                .iter()
+                .any(|doc| doc.topic == "homeassistant/select/duco_node_1_ventilation_state/config")
+        );
+        assert!(
+            documents
+                .iter()
+                .any(|doc| doc.topic == "homeassistant/sensor/duco_node_2_sensor_iaq_co2/config")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_discovery_documents() {
+        let cfg = DucoMqttBridgeConfig {
+            hass_device_discovery: true,
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        let documents = bridge.discovery_documents().await.unwrap();
+
+        assert!(
+            documents
+                .iter()
+                .all(|doc| doc.topic.starts_with("homeassistant/device/"))
+        );
+        let node = documents
+            .iter()
+            .find(|doc| doc.topic == "homeassistant/device/duco_PS0000000000_node_2/config")
+            .unwrap();
+
+        let config: serde_json::Value = serde_json::from_str(&node.payload).unwrap();
+        assert_eq!(config["device"]["name"], "Boven");
+        assert_eq!(config["origin"]["name"], "duco2mqtt");
+        let sensor = &config["components"]["duco_node_2_sensor_iaq_co2"];
+        assert_eq!(sensor["platform"], "sensor");
+        assert_eq!(sensor["stat_t"], "ventilation/duco_node_2/Sensor/IaqCo2");
+        assert!(sensor.get("device").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_entities_enabled_by_default() {
+        let cfg = DucoMqttBridgeConfig {
+            hass_entity_defaults: BTreeMap::from([
+                ("duco_node_2_general_asso".to_string(), true),
+                ("duco_node_2_sensor_iaq_co2".to_string(), false),
+            ]),
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        let documents = bridge.discovery_documents().await.unwrap();
+        let enabled = |unique_id: &str| {
+            let doc = documents
+                .iter()
+                .find(|doc| doc.topic.ends_with(&format!("/{}/config", unique_id)))
+                .unwrap();
+            let config: serde_json::Value = serde_json::from_str(&doc.payload).unwrap();
+            config.get("enabled_by_default").is_none()
+        };
+
+        assert!(enabled("duco_node_2_identify"));
+        assert!(!enabled("duco_node_2_general_parent"));
+        assert!(!enabled("duco_node_1_ventilation_state_time_remaining"));
+        assert!(enabled("duco_node_2_general_asso"));
+        assert!(!enabled("duco_node_2_sensor_iaq_co2"));
+    }
+
+    #[tokio::test]
+    async fn test_json_state() {
+        let cfg = DucoMqttBridgeConfig {
+            json_state: true,
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(
+            published
+                .iter()
+                .all(|data| !data.topic.starts_with("ventilation/duco_node_2/Sensor/"))
+        );
+        let state = published
+            .iter()
+            .find(|data| data.topic == "ventilation/duco_node_2/values")
+            .unwrap();
+        let state: serde_json::Value = serde_json::from_str(&state.payload).unwrap();
+        assert_eq!(state["Sensor"]["IaqCo2"], 100);
+        assert_eq!(state["General"]["Name"], "Boven");
+
+        let config = |topic: &str| -> serde_json::Value {
+            let document = published.iter().find(|data| data.topic == topic).unwrap();
+            serde_json::from_str(&document.payload).unwrap()
+        };
+
+        let select = config("homeassistant/select/duco_node_1_ventilation_state/config");
+        assert_eq!(select["stat_t"], "ventilation/duco_node_1/values");
+        assert_eq!(select["value_template"], "{{ value_json.Ventilation.State }}");
+
+        // the templates of the entities are applied to the extracted value
+        let switch = config("homeassistant/switch/duco_node_1_permanent_mode_1/config");
+        assert_eq!(switch["stat_t"], "ventilation/duco_node_1/values");
+        assert!(
+            switch["value_template"]
+                .as_str()
+                .unwrap()
+                .starts_with("{% set value = value_json.Ventilation.State %}{{")
+        );
+
+        // the sensors of the box keep their own state topics
+        let filter = config("homeassistant/sensor/duco_PS0000000000_heat_recovery_remaining_filter_days/config");
+        assert_eq!(filter["stat_t"], "ventilation/HeatRecovery/General/TimeFilterRemain");
+        assert!(filter.get("value_template").is_none());
+
+        // unchanged nodes are not published again
+        bridge.poll_ducobox().await.unwrap();
+        assert!(bridge.mqtt.take_queued_publishes().is_empty());
+    }
+
+    fn golden_lines(publishes: &[MqttData]) -> String {
+        publishes
+            .iter()
+            .map(|data| format!("{} {}\n", data.topic, data.payload))
+            .collect::<String>()
+            .replace(env!("CARGO_PKG_VERSION"), "{version}")
+    }
+
+    /// Runs a poll cycle against the recorded responses and compares the published data with the golden file.
+    /// Run with D2M_UPDATE_GOLDEN=1 to regenerate the file after an intentional topic change, the files of the
+    /// previous layouts are frozen.
+    async fn verify_poll_cycle_golden(compat: TopicCompat, backend: MockBackend, golden_file: &str) {
+        let golden_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(golden_file);
+        let cfg = DucoMqttBridgeConfig {
+            compat,
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(backend)).unwrap();
+
+        bridge.poll_ducobox().await.unwrap();
+        let published = golden_lines(&bridge.mqtt.take_queued_publishes());
+
+        if compat == TopicCompat::V2 && std::env::var_os("D2M_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&golden_path, &published).unwrap();
+        }
+
+        assert_eq!(published, std::fs::read_to_string(&golden_path).unwrap());
+
+        // unchanged values are not published again
+        bridge.poll_ducobox().await.unwrap();
+        assert!(bridge.mqtt.take_queued_publishes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_poll_cycle_golden() {
+        verify_poll_cycle_golden(
+            TopicCompat::V2,
+            MockBackend::default(),
+            "test/data/golden/poll_cycle_v2.txt",
+        )
+        .await;
+    }
+
+    /// Version 1 is verified against the device info that was recorded when its layout was frozen
+    #[tokio::test]
+    async fn test_poll_cycle_golden_v1() {
+        let backend = MockBackend {
+            device_info: Some(include_bytes!("../test/data/golden/info_v1.json")),
+            ..MockBackend::default()
+        };
+        verify_poll_cycle_golden(TopicCompat::V1, backend, "test/data/golden/poll_cycle_v1.txt").await;
+    }
+
+    #[tokio::test]
+    async fn test_discovery_waits_for_hass() {
+        let mut cfg = test_config();
+        cfg.hass_discovery_delay = time::Duration::from_secs(30);
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        let is_discovery = |data: &MqttData| data.topic.starts_with("homeassistant/");
+
+        bridge.poll_ducobox().await.unwrap();
+        assert!(!bridge.mqtt.take_queued_publishes().iter().any(is_discovery));
+
+        bridge.handle_hass_status("offline").await.unwrap();
+        assert!(!bridge.mqtt.take_queued_publishes().iter().any(is_discovery));
+
+        // the birth message releases the documents, a restart of home assistant publishes them again
+        bridge.handle_hass_status("online").await.unwrap();
+        assert!(bridge.mqtt.take_queued_publishes().iter().any(is_discovery));
+        bridge.handle_hass_status("online").await.unwrap();
+        assert!(bridge.mqtt.take_queued_publishes().iter().any(is_discovery));
+    }
+
+    #[tokio::test]
+    async fn test_discovery_drift() {
+        let mut cfg = test_config();
+        cfg.hass_drift_check_interval = time::Duration::from_secs(3600);
+        cfg.hass_drift_repair = true;
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        let drift_state = |state: &str| MqttData::new("ventilation/bridge/discovery_drift", state);
+
+        bridge.poll_ducobox().await.unwrap();
+        let mut retained: Vec<MqttData> = bridge
+            .mqtt
+            .take_queued_publishes()
+            .into_iter()
+            .filter(|data| data.topic.starts_with("homeassistant/"))
+            .collect();
+        assert!(
+            retained
+                .iter()
+                .any(|doc| doc.topic == "homeassistant/binary_sensor/duco_bridge_discovery_drift/config")
+        );
+
+        // the broker returns the published documents
+        bridge.start_drift_check().await.unwrap();
+        for doc in &retained {
+            assert!(bridge.drift_check.as_mut().unwrap().handle(doc));
+        }
+        bridge.finish_drift_check().await.unwrap();
+        assert_eq!(bridge.mqtt.take_queued_publishes(), vec![drift_state("OFF")]);
+
+        // another tool overwrote a document: reported and repaired
+        let original = MqttData::new(&retained[0].topic, &retained[0].payload);
+        retained[0].payload = "{}".to_string();
+        bridge.start_drift_check().await.unwrap();
+        for doc in &retained {
+            bridge.drift_check.as_mut().unwrap().handle(doc);
+        }
+        bridge.finish_drift_check().await.unwrap();
+        assert_eq!(bridge.mqtt.take_queued_publishes(), vec![drift_state("ON"), original]);
+    }
+
+    #[tokio::test]
+    async fn test_stale_discovery_cleanup() {
+        let backend = MockBackend::default();
+        let removed_nodes = backend.removed_nodes.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        let mut node3: Vec<String> = published
+            .iter()
+            .filter(|data| data.topic.starts_with("homeassistant/") && data.topic.contains("/duco_node_3_"))
+            .map(|data| data.topic.clone())
+            .collect();
+        assert!(!node3.is_empty());
+        node3.sort();
+
+        // node 3 leaves the network: its entities are removed, the other entities are untouched
+        removed_nodes.lock().unwrap().push(3);
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        let removed: Vec<String> = published
+            .iter()
+            .filter(|data| data.topic.starts_with("homeassistant/") && data.payload.is_empty())
+            .map(|data| data.topic.clone())
+            .collect();
+        assert_eq!(removed, node3);
+        // the retained values of the node are cleared as well
+        assert!(published.contains(&MqttData::new("ventilation/duco_node_3/General/Type", "")));
+        assert!(bridge.nodes.iter().all(|node| node.number() != 3));
+
+        // switching to device discovery removes the entity documents before the devices reuse their unique ids
+        bridge.hass_device_discovery = true;
+        bridge.publish_discovery().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        let first_device = published
+            .iter()
+            .position(|data| data.topic.starts_with("homeassistant/device/"))
+            .unwrap();
+        assert!(first_device > 0);
+        assert!(published[..first_device].iter().all(|data| data.payload.is_empty()));
+        assert!(published[first_device..].iter().all(|data| !data.payload.is_empty()));
+
+        // disabling discovery removes all the entities that were published
+        bridge.hass_discovery = false;
+        bridge.publish_discovery().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(!published.is_empty());
+        assert!(published.iter().all(|data| data.payload.is_empty()));
+        assert!(bridge.state.discovery_topics.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_nodes() {
+        let backend = MockBackend::default();
+        let removed_nodes = backend.removed_nodes.clone();
+        let cfg = DucoMqttBridgeConfig {
+            node_missing_polls: 2,
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(backend)).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        bridge.mqtt.take_queued_publishes();
+
+        // a node that is missing for a single poll is kept
+        removed_nodes.lock().unwrap().push(3);
+        bridge.poll_ducobox().await.unwrap();
+        assert!(bridge.mqtt.take_queued_publishes().is_empty());
+        assert!(bridge.nodes.iter().any(|node| node.number() == 3));
+
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(published.contains(&MqttData::new("ventilation/duco_node_3/General/Type", "")));
+        assert!(bridge.nodes.iter().all(|node| node.number() != 3));
+
+        // the values of a node that is kept are unknown until it returns
+        let backend = MockBackend::default();
+        let removed_nodes = backend.removed_nodes.clone();
+        let cfg = DucoMqttBridgeConfig {
+            keep_missing_nodes: true,
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(backend)).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        bridge.mqtt.take_queued_publishes();
+
+        removed_nodes.lock().unwrap().push(2);
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(published.contains(&MqttData::new("ventilation/duco_node_2/Sensor/IaqCo2", UNKNOWN)));
+        assert!(published.iter().all(|data| !data.payload.is_empty()));
+
+        removed_nodes.lock().unwrap().clear();
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(published.contains(&MqttData::new("ventilation/duco_node_2/Sensor/IaqCo2", "100")));
+    }
+
+    #[tokio::test]
+    async fn test_discovery_command() {
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+        let discovery = |payload: &str| MqttData::new("ventilation/bridge/cmnd/discovery", payload);
+
+        // nothing to publish before the first poll
+        assert!(bridge.handle_command(discovery("publish")).await.is_err());
+
+        bridge.poll_ducobox().await.unwrap();
+        let documents: Vec<MqttData> = bridge
+            .mqtt
+            .take_queued_publishes()
+            .into_iter()
+            .filter(|data| data.topic.starts_with("homeassistant/"))
+            .collect();
+        assert!(!documents.is_empty());
+
+        bridge.handle_command(discovery("remove")).await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert_eq!(published.len(), documents.len());
+        assert!(published.iter().all(|data| data.payload.is_empty()));
+        assert!(bridge.state.discovery_topics.is_empty());
+
+        // the entities stay removed on the next poll
+        bridge.poll_ducobox().await.unwrap();
+        assert!(
+            bridge
+                .mqtt
+                .take_queued_publishes()
+                .iter()
+                .all(|data| !data.topic.starts_with("homeassistant/"))
+        );
+
+        bridge.handle_command(discovery("PUBLISH")).await.unwrap();
+        assert_eq!(bridge.mqtt.take_queued_publishes(), documents);
+
+        assert!(bridge.handle_command(discovery("refresh")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_last_seen() {
+        let backend = MockBackend::default();
+        let removed_nodes = backend.removed_nodes.clone();
+        let cfg = DucoMqttBridgeConfig {
+            last_seen: true,
+            keep_missing_nodes: true,
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(backend)).unwrap();
+        let last_seen = |published: &[MqttData]| -> Vec<String> {
+            published
+                .iter()
+                .filter(|data| data.topic.ends_with("/last_seen"))
+                .map(|data| {
+                    assert!(chrono::DateTime::parse_from_rfc3339(&data.payload).is_ok());
+                    data.topic.clone()
+                })
+                .collect()
+        };
+
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        let topics = last_seen(&published);
+        assert_eq!(topics.len(), bridge.nodes.len());
+        assert!(topics.contains(&"ventilation/duco_node_3/last_seen".to_string()));
+
+        let sensor = published
+            .iter()
+            .find(|data| data.topic.starts_with("homeassistant/") && data.payload.contains("/duco_node_3/last_seen\""))
+            .expect("last seen sensor");
+        let sensor: serde_json::Value = serde_json::from_str(&sensor.payload).unwrap();
+        assert_eq!(sensor["device_class"], "timestamp");
+        assert_eq!(sensor["entity_category"], "diagnostic");
+
+        // the time of a node that stops responding is no longer updated
+        let node_seen = |bridge: &DucoMqttBridge, nr: u16| {
+            bridge
+                .nodes
+                .iter()
+                .find(|node| node.number() == nr)
+                .unwrap()
+                .last_seen()
+        };
+        let seen = node_seen(&bridge, 2);
+        removed_nodes.lock().unwrap().push(3);
+        bridge.poll_ducobox().await.unwrap();
+        let topics = last_seen(&bridge.mqtt.take_queued_publishes());
+        assert_eq!(topics.len(), bridge.nodes.len() - 1);
+        assert!(!topics.contains(&"ventilation/duco_node_3/last_seen".to_string()));
+        // nor is the time of a node the box reports with the same values
+        assert_eq!(node_seen(&bridge, 2), seen);
+    }
+
+    #[tokio::test]
+    async fn test_poll_once_without_broker() {
+        let mut cfg = test_config();
+        cfg.mqtt_config.server = "127.0.0.1".to_string();
+        cfg.mqtt_config.port = 1;
+        let bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+
+        let result = bridge.poll_once(time::Duration::from_millis(500)).await;
+        assert!(matches!(result, Err(PollOnceError::Mqtt(_))));
+    }
+
+    /// The configuration of a bridge that talks to a simulated box
+    async fn simulator_config() -> DucoMqttBridgeConfig {
+        let simulator = crate::simulator::Simulator::new(
+            Path::new("test/data"),
+            Path::new("test/data/tls/duco.pem"),
+            Path::new("test/data/tls/duco.key"),
+        )
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut cfg = test_config();
+        cfg.ducobox_host = listener.local_addr().unwrap().to_string();
+        cfg.ducobox_certificate = Some(PathBuf::from("test/data/tls/duco.pem"));
+        cfg.ducobox_cert_pinning = true;
+        tokio::spawn(simulator.serve(listener));
+        cfg
+    }
+
+    #[tokio::test]
+    async fn test_simulator_end_to_end() {
+        let cfg = simulator_config().await;
+        let mut bridge = DucoMqttBridge::new(cfg).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        let state = |publishes: &[MqttData]| {
+            publishes
+                .iter()
+                .find(|data| data.topic == "ventilation/duco_node_2/Ventilation/State")
+                .map(|data| data.payload.clone())
+        };
+        assert_eq!(state(&bridge.mqtt.take_queued_publishes()).as_deref(), Some("-"));
+
+        let command = MqttData::new("ventilation/duco_node_2/cmnd/SetVentilationState", "MAN3");
+        bridge.handle_command(command).await.unwrap();
+        let (command, result) = bridge.commands.completed().await;
+        assert_eq!(command.node(), Some(2));
+
+        // only the commanded node is requested after the command, the network is not polled
+        let refreshed = result.unwrap();
+        bridge.command_refresh.insert(2, refreshed.clone());
+        bridge.apply_command_refresh().await.unwrap();
+        assert!(!bridge.poller.is_running());
+        assert_eq!(state(&bridge.mqtt.take_queued_publishes()).as_deref(), Some("MAN3"));
+
+        bridge.poll_ducobox().await.unwrap();
+        assert_eq!(state(&bridge.mqtt.take_queued_publishes()), None);
+
+        // the polls of the burst after the command only request the commanded node
+        bridge.commanded_nodes.insert(2);
+        bridge.command_poll.start();
+        bridge.poll_commanded();
+        assert!(!bridge.poller.is_running());
+        let result = bridge.node_refresh.completed().await;
+        assert_eq!(result.as_ref().unwrap().len(), 1);
+        bridge.apply_node_refresh(result).await.unwrap();
+        assert!(!bridge.poller.is_running());
+        // the refreshed values are merged, the next poll merges the nodes although the box reports them unchanged
+        assert!(bridge.nodes_refreshed);
+        bridge.poll_ducobox().await.unwrap();
+        assert!(!bridge.nodes_refreshed);
+
+        // without the values of a commanded node the network is polled, also when a later command of the node succeeded
+        bridge.command_refresh.insert(2, None);
+        bridge.command_completed(command, Ok(refreshed)).await;
+        assert!(bridge.poller.is_running());
+        assert!(bridge.command_refresh.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("duco2mqtt-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut cfg = simulator_config().await;
+        cfg.duco_record_dir = Some(dir.clone());
+        let host = cfg.ducobox_host.clone();
+        let mut bridge = DucoMqttBridge::new(cfg).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        let mut recorded = bridge.mqtt.take_queued_publishes();
+        // only the http backend reports the connection reuse
+        recorded.retain(|data| data.topic != "ventilation/bridge/duco_connections");
+
+        // the replayed responses result in the same publishes without a box
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_host: host,
+            duco_replay_dir: Some(dir.clone()),
+            ..test_config()
+        };
+        let mut bridge = DucoMqttBridge::new(cfg).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        assert_eq!(bridge.mqtt.take_queued_publishes(), recorded);
+        assert!(bridge.poll_ducobox().await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cfg = DucoMqttBridgeConfig {
+            duco_record_dir: Some(dir.clone()),
+            duco_replay_dir: Some(dir),
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_renumbered_nodes() {
+        let node = |nr: u16, node_type: &str, name: &str| {
+            DucoBoxNode::try_from(NodeInfo {
+                node: nr,
+                general: HashMap::from([
+                    ("Type".to_string(), StatusField::from(node_type)),
+                    ("Name".to_string(), StatusField::from(name)),
+                ]),
+                ventilation: HashMap::new(),
+                sensor: None,
+            })
+            .unwrap()
+        };
+
+        let previous = vec![
+            node(2, "UCCO2", "Bathroom"),
+            node(3, "UCCO2", "Bedroom"),
+            node(4, "VLVRH", "Kitchen"),
+            node(5, "UCCO2", ""),
+        ];
+        let current = vec![
+            node(2, "UCCO2", "Bedroom"),
+            node(3, "UCCO2", "Bathroom"),
+            node(4, "VLVRH", "Kitchen"),
+            node(6, "UCCO2", ""),
+        ];
+
+        // unnamed nodes can not be identified
+        assert_eq!(
+            DucoMqttBridge::renumbered_nodes(&previous, &current),
+            BTreeMap::from([(2, 3), (3, 2)])
+        );
+
+        // ambiguous identities are not mapped
+        let current = vec![node(7, "UCCO2", "Bathroom"), node(8, "UCCO2", "Bathroom")];
+        assert!(DucoMqttBridge::renumbered_nodes(&previous, &current).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_heat_recovery_command() {
+        let backend = MockBackend::default();
+        let config_changes = backend.config_changes.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
 
-    fn http_client(&self) -> Result<reqwest::Client> {
-        let mut builder = reqwest::Client::builder().connect_timeout(time::Duration::from_secs(15));
+        let documents = bridge.discovery_documents().await.unwrap();
+        let climate = documents
+            .iter()
+            .find(|doc| doc.topic == "homeassistant/climate/duco_node_1_heat_recovery/config")
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&climate.payload).unwrap();
+        assert_eq!(config["temp_cmd_t"], "ventilation/HeatRecovery/cmnd/TempSupTgt");
+        assert_eq!(config["temp_stat_t"], "ventilation/HeatRecovery/Bypass/TempSupTgt");
+        assert_eq!(config["device"]["identifiers"][0], "duco_PS0000000000_heat_recovery");
+        // the range and the step the box reports for the supply temperature target
+        assert_eq!(config["min_temp"], 10.0);
+        assert_eq!(config["max_temp"], 25.0);
+        assert_eq!(config["temp_step"], 0.5);
+
+        bridge
+            .handle_command(MqttData::new(
+                "ventilation/HeatRecovery/cmnd/TempSupTgt".to_string(),
+                "21.5".to_string(),
+            ))
+            .await
+            .unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(
+            *config_changes.lock().unwrap(),
+            vec![("HeatRecovery/Bypass/TempSupTgt".to_string(), 215)]
+        );
 
-        if let Some(addr) = self.ducobox_ip_address {
-            builder = builder.resolve(&self.ducobox_host, addr);
+        for invalid in ["warm", "40", "21.7"] {
+            let msg = MqttData::new(
+                "ventilation/HeatRecovery/cmnd/TempSupTgt".to_string(),
+                invalid.to_string(),
+            );
+            assert!(bridge.handle_command(msg).await.is_err());
         }
 
-        if let Some(ref cert) = self.ducobox_certificate {
-            builder = builder.use_rustls_tls();
-            for cert in reqwest::Certificate::from_pem_bundle(&std::fs::read(cert)?)? {
-                builder = builder.add_root_certificate(cert);
-            }
-        } else {
-            builder = builder.danger_accept_invalid_certs(true);
+        // without the range of the box the target is not changed
+        bridge
+            .device_info
+            .as_mut()
+            .unwrap()
+            .set_config(ducoapi::BoxConfig::default());
+        let msg = MqttData::new(
+            "ventilation/HeatRecovery/cmnd/TempSupTgt".to_string(),
+            "21.5".to_string(),
+        );
+        assert!(bridge.handle_command(msg).await.is_err());
+        assert_eq!(config_changes.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_supply_temperature_zone_command() {
+        let backend = MockBackend::default();
+        let config_changes = backend.config_changes.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        let zone_command = |zone: u8, payload: &str| {
+            MqttData::new(
+                format!("ventilation/HeatRecovery/cmnd/TempSupTgtZone{}", zone),
+                payload.to_string(),
+            )
+        };
+
+        // single zone boxes do not get the zone targets
+        bridge.poll_ducobox().await.unwrap();
+        let numbers = |device: &DucoBoxDevice| -> Vec<serde_json::Value> {
+            DucoMqttBridge::create_hass_descriptions_for_device(
+                device,
+                &DiscoveryContext::new("ventilation/", Some(device)),
+            )
+            .unwrap()
+            .into_iter()
+            .filter(|doc| doc.topic.starts_with("homeassistant/number/"))
+            .map(|doc| serde_json::from_str(&doc.payload).unwrap())
+            .collect()
+        };
+        assert!(numbers(bridge.device_info.as_ref().unwrap()).is_empty());
+
+        // the zones and their ranges are the configuration parameters of the box
+        let parameter = |val: i64| ConfigParameter {
+            val,
+            min: 100,
+            max: 250,
+            inc: Some(5),
+        };
+        let zones = ducoapi::BoxConfig {
+            parameters: BTreeMap::from([
+                ("HeatRecovery/Bypass/TempSupTgtZone1".to_string(), parameter(210)),
+                ("HeatRecovery/Bypass/TempSupTgtZone2".to_string(), parameter(190)),
+            ]),
+        };
+        let device = bridge.device_info.as_mut().unwrap();
+        assert!(device.set_config(zones));
+        let numbers = numbers(device);
+        assert_eq!(numbers.len(), 2);
+        assert_eq!(numbers[1]["name"], "Supply temperature target zone 2");
+        assert_eq!(numbers[1]["min"], 10.0);
+        assert_eq!(numbers[1]["max"], 25.0);
+        assert_eq!(numbers[1]["step"], 0.5);
+        assert_eq!(numbers[1]["stat_t"], "ventilation/HeatRecovery/Bypass/TempSupTgtZone2");
+        assert_eq!(numbers[1]["cmd_t"], "ventilation/HeatRecovery/cmnd/TempSupTgtZone2");
+        assert_eq!(numbers[1]["value_template"], "{{ value | float / 10 }}");
+        assert_eq!(numbers[1]["device_class"], "temperature");
+        assert_eq!(
+            numbers[1]["device"]["identifiers"][0],
+            "duco_PS0000000000_heat_recovery"
+        );
+
+        bridge.handle_command(zone_command(2, "19.5")).await.unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(
+            *config_changes.lock().unwrap(),
+            vec![("HeatRecovery/Bypass/TempSupTgtZone2".to_string(), 195)]
+        );
+
+        for (zone, payload) in [(1, "30"), (1, "19.7"), (1, "cold"), (3, "20")] {
+            assert!(
+                bridge.handle_command(zone_command(zone, payload)).await.is_err(),
+                "{}",
+                payload
+            );
         }
+        assert_eq!(config_changes.lock().unwrap().len(), 1);
+    }
 
-        Ok(builder.build()?)
+    #[test]
+    fn test_device_info_arrays() {
+        let dev_info = ducoapi::parse_device_info(
+            br#"{"General":{"Lan":{"ScanWifi":[{"Ssid":"home"},{"Ssid":"guest"}]},"Board":{"Fans":[1,2],"BoxName":{"Val":"ENERGY"}}}}"#,
+        )
+        .unwrap();
+        let mut device = DucoBoxDevice::try_from(dev_info).unwrap();
+
+        let topics = device.topics_that_need_updating(&ValueTransform::default());
+        assert!(topics.contains(&MqttData::new("General/Board/Fans", "[1,2]")));
+        assert!(topics.contains(&MqttData::new(
+            "General/Lan/ScanWifi",
+            r#"[{"Ssid":"home"},{"Ssid":"guest"}]"#
+        )));
+
+        // only the arrays with a known meaning get a sensor
+        let sensors: Vec<serde_json::Value> = DucoMqttBridge::create_hass_descriptions_for_device(
+            &device,
+            &DiscoveryContext::new("ventilation/", Some(&device)),
+        )
+        .unwrap()
+        .into_iter()
+        .filter(|doc| doc.topic.starts_with("homeassistant/sensor/"))
+        .map(|doc| serde_json::from_str(&doc.payload).unwrap())
+        .collect();
+        assert_eq!(sensors.len(), 2);
+        assert!(
+            !sensors
+                .iter()
+                .any(|sensor| sensor["stat_t"] == "ventilation/General/Board/Fans")
+        );
+        let wifi = sensors
+            .iter()
+            .find(|sensor| sensor["stat_t"] == "ventilation/General/Lan/ScanWifi")
+            .unwrap();
+        assert_eq!(wifi["name"], "Wifi networks");
+        assert_eq!(wifi["value_template"], "{{ value_json | count }}");
     }
-}
 
-// Test for parsing node topics
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_network_diagnostics() {
+        let bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+        let device = DucoBoxDevice::try_from(bridge.backend.get_device_info().await.unwrap()).unwrap();
+        let documents = DucoMqttBridge::create_hass_descriptions_for_device(
+            &device,
+            &DiscoveryContext::new("ventilation/", Some(&device)),
+        )
+        .unwrap();
+        let sensor = |unique_id: &str| -> serde_json::Value {
+            let doc = documents
+                .iter()
+                .find(|doc| doc.topic == format!("homeassistant/sensor/{}/config", unique_id))
+                .unwrap();
+            serde_json::from_str(&doc.payload).unwrap()
+        };
+
+        let signal = sensor("duco_PS0000000000_ventilation_general_lan_rssiwifi");
+        assert_eq!(signal["name"], "Wifi signal");
+        assert_eq!(signal["device_class"], "signal_strength");
+        assert_eq!(signal["unit_of_measurement"], "dBm");
+        assert_eq!(signal["entity_category"], "diagnostic");
+        assert_eq!(
+            signal["device"]["connections"],
+            serde_json::json!([["mac", "b1:b1:b1:b1:b1:b1"]])
+        );
+
+        let uptime = sensor("duco_PS0000000000_ventilation_general_board_uptime");
+        assert_eq!(uptime["device_class"], "duration");
+        assert_eq!(uptime["unit_of_measurement"], "s");
+
+        let errors = sensor("duco_PS0000000000_ventilation_networkduco_commerrorctr");
+        assert_eq!(errors["stat_t"], "ventilation/NetworkDuco/CommErrorCtr");
+        assert_eq!(errors["entity_category"], "diagnostic");
+
+        // only the ventilation device is connected to the network
+        let filter_days = sensor("duco_PS0000000000_heat_recovery_remaining_filter_days");
+        assert!(filter_days["device"].get("connections").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bypass_position() {
+        let bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+        let dev_info = bridge.backend.get_device_info().await.unwrap();
+        let device = DucoBoxDevice::try_from(dev_info).unwrap();
+        let documents = DucoMqttBridge::create_hass_descriptions_for_device(
+            &device,
+            &DiscoveryContext::new("ventilation/", Some(&device)),
+        )
+        .unwrap();
+        let position = documents
+            .iter()
+            .find(|doc| doc.payload.contains(r#""name":"Bypass position""#))
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&position.payload).unwrap();
+        assert_eq!(config["stat_t"], "ventilation/HeatRecovery/Bypass/Pos");
+        assert_eq!(config["unit_of_measurement"], "%");
+    }
+
+    #[tokio::test]
+    async fn test_filter_reset_command() {
+        let backend = MockBackend::default();
+        let box_actions = backend.box_actions.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+
+        let documents = bridge.discovery_documents().await.unwrap();
+        assert!(
+            documents
+                .iter()
+                .any(|doc| doc.topic == "homeassistant/button/duco_PS0000000000_heat_recovery_filter_reset/config")
+        );
+
+        bridge
+            .handle_command(MqttData::new(
+                "ventilation/duco_box/cmnd/FilterReset".to_string(),
+                "PRESS".to_string(),
+            ))
+            .await
+            .unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(
+            *box_actions.lock().unwrap(),
+            vec![r#"{"Action":"ResetFilterTimeRemain","Val":true}"#.to_string()]
+        );
+
+        let msg = MqttData::new("ventilation/duco_box/cmnd/Reboot".to_string(), "PRESS".to_string());
+        assert!(bridge.handle_command(msg).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reboot_command() {
+        let backend = MockBackend::default();
+        let box_actions = backend.box_actions.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        let reboot = |payload: &str| MqttData::new("ventilation/duco_box/cmnd/Reboot", payload);
+
+        // the serial number is only known after the first poll
+        assert!(bridge.handle_command(reboot("PS0000000000")).await.is_err());
+
+        let documents = bridge.discovery_documents().await.unwrap();
+        let button = documents
+            .iter()
+            .find(|doc| doc.topic == "homeassistant/button/duco_PS0000000000_ventilation_reboot/config")
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&button.payload).unwrap();
+        assert_eq!(config["payload_press"], "PS0000000000");
+        assert_eq!(config["entity_category"], "diagnostic");
+
+        for payload in ["PRESS", "", "PS0000000001"] {
+            assert!(bridge.handle_command(reboot(payload)).await.is_err(), "{}", payload);
+        }
+        assert!(box_actions.lock().unwrap().is_empty());
+
+        bridge.handle_command(reboot("PS0000000000")).await.unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(
+            *box_actions.lock().unwrap(),
+            vec![r#"{"Action":"Reboot","Val":true}"#.to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_co2_setpoint_command() {
+        let backend = MockBackend::default();
+        let node_config_changes = backend.node_config_changes.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        let setpoint = |node: u16, payload: &str| {
+            MqttData::new(
+                format!("ventilation/duco_node_{}/cmnd/Config/Co2SetPoint", node),
+                payload.to_string(),
+            )
+        };
+
+        let documents = bridge.discovery_documents().await.unwrap();
+        let number = documents
+            .iter()
+            .find(|doc| doc.topic == "homeassistant/number/duco_node_2_config_co2setpoint/config")
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&number.payload).unwrap();
+        assert_eq!(config["stat_t"], "ventilation/duco_node_2/Config/Co2SetPoint");
+        assert_eq!(config["cmd_t"], "ventilation/duco_node_2/cmnd/Config/Co2SetPoint");
+        assert_eq!(config["min"], 400.0);
+        assert_eq!(config["max"], 2000.0);
+        assert_eq!(config["step"], 10.0);
+        assert_eq!(config["unit_of_measurement"], "ppm");
+        // the valves do not report a setpoint
+        assert!(!documents.iter().any(|doc| doc.topic.contains("duco_node_67_config")));
+
+        bridge.handle_command(setpoint(2, "950.0")).await.unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(
+            *node_config_changes.lock().unwrap(),
+            vec![(2, CO2_SETPOINT.to_string(), 950)]
+        );
+        assert!(
+            bridge
+                .mqtt
+                .take_queued_publishes()
+                .contains(&MqttData::new("ventilation/duco_node_2/Config/Co2SetPoint", "950"))
+        );
+
+        for (node, payload) in [(2, "2500"), (2, "955"), (2, "low"), (67, "800")] {
+            assert!(
+                bridge.handle_command(setpoint(node, payload)).await.is_err(),
+                "{}",
+                payload
+            );
+        }
+        let msg = MqttData::new("ventilation/duco_node_2/cmnd/Config/FlowLvlAutoMin", "20");
+        assert!(bridge.handle_command(msg).await.is_err());
+        assert_eq!(node_config_changes.lock().unwrap().len(), 1);
+
+        // the configuration is refreshed periodically, to pick up the changes made on the box
+        bridge.poll_ducobox().await.unwrap();
+        bridge.poll_cycle = NODE_CONFIG_REFRESH_POLLS - 1;
+        assert!(!bridge.poll_request().node_config);
+        bridge.poll_cycle = NODE_CONFIG_REFRESH_POLLS;
+        assert!(bridge.poll_request().node_config);
+    }
+
+    #[tokio::test]
+    async fn test_box_action_command() {
+        let backend = MockBackend::default();
+        let box_actions = backend.box_actions.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        let box_command = |action: &str, payload: &str| {
+            MqttData::new(format!("ventilation/duco_box/cmnd/{}", action), payload.to_string())
+        };
+
+        // the box actions are only requested once the box is discovered
+        assert!(
+            bridge
+                .handle_command(box_command("SetCalibration", "START"))
+                .await
+                .is_err()
+        );
+        bridge.poll_ducobox().await.unwrap();
+
+        bridge
+            .handle_command(box_command("SetCalibration", "START"))
+            .await
+            .unwrap();
+        bridge
+            .handle_command(box_command("ResetFilterTimeRemain", "1"))
+            .await
+            .unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(
+            *box_actions.lock().unwrap(),
+            vec![
+                r#"{"Action":"SetCalibration","Val":"START"}"#.to_string(),
+                r#"{"Action":"ResetFilterTimeRemain","Val":true}"#.to_string(),
+            ]
+        );
+
+        for (action, payload) in [
+            ("SetCalibration", "PAUSE"),
+            ("ResetFilterTimeRemain", "ON"),
+            // actions with an unsupported value type are not accepted
+            ("SetTime", "1700000000"),
+            ("SetVentilationState", "MAN1"),
+            ("SetCalibration/Now", "START"),
+        ] {
+            assert!(
+                bridge.handle_command(box_command(action, payload)).await.is_err(),
+                "{} {}",
+                action,
+                payload
+            );
+        }
+        assert_eq!(box_actions.lock().unwrap().len(), 2);
+    }
 
     #[test]
-    fn test_node_number_for_node_name() {
-        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_1").unwrap(), 1);
-        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_2").unwrap(), 2);
-        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_3").unwrap(), 3);
-        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_67").unwrap(), 67);
-        assert_eq!(DucoMqttBridge::node_number_for_node_name("duco_node_68").unwrap(), 68);
+    fn test_public_errors_are_typed() {
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_cert_pinning: true,
+            ..test_config()
+        };
+        assert!(matches!(validate_tls_config(&cfg), Err(Error::Config(_))));
+
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_ip_address: Some("192.168.1.300".to_string()),
+            ..test_config()
+        };
+        assert!(matches!(DucoMqttBridge::new(cfg), Err(Error::Config(_))));
+
+        // typed errors survive the internal anyhow layer, other errors keep their context
+        let err = Error::from(anyhow::Error::from(Error::Config("invalid".to_string())));
+        assert!(matches!(err, Error::Config(_)));
+
+        let err = Error::from(anyhow!("no response").context("Failed to obtain nodes"));
+        assert_eq!(err.to_string(), "Failed to obtain nodes: no response");
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config(&test_config()).is_ok());
+
+        for base_topic in ["", "/", "home/+/duco", "#"] {
+            let mut cfg = test_config();
+            cfg.mqtt_config.base_topic = base_topic.to_string();
+            assert!(matches!(validate_config(&cfg), Err(Error::Config(_))), "{}", base_topic);
+        }
+
+        let cfg = DucoMqttBridgeConfig {
+            poll_interval: time::Duration::ZERO,
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_proxy: Some("http://jumphost:port".to_string()),
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_read_timeout: time::Duration::ZERO,
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_api_key: Some(Secret::from("key\n".to_string())),
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_api_key: Some(Secret::from("key".to_string())),
+            ducobox_api_key_command: Some("echo key".to_string()),
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+
+        let cfg = DucoMqttBridgeConfig {
+            ducobox_retry_attempts: 0,
+            ..test_config()
+        };
+        assert!(matches!(validate_config(&cfg), Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_base_topic_concatenation() {
+        let mut cfg = test_config();
+        cfg.mqtt_config.base_topic = "ventilation/".to_string();
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        assert_eq!(bridge.mqtt_base_topic, "ventilation/");
+
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(!published.is_empty());
+        for data in &published {
+            assert!(!data.topic.contains("//"), "{}", data.topic);
+            assert!(!data.payload.contains("ventilation//"), "{}", data.payload);
+        }
+        assert!(
+            published
+                .iter()
+                .any(|data| data.topic == "ventilation/duco_node_1/Ventilation/State")
+        );
+
+        let command = MqttData::new(
+            "ventilation/duco_node_1/cmnd/SetVentilationState".to_string(),
+            "MAN1".to_string(),
+        );
+        assert!(bridge.handle_command(command).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_override_command() {
+        let backend = MockBackend::default();
+        let performed_actions = backend.performed_actions.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        let push = |payload: &str| MqttData::new("ventilation/duco_node_2/cmnd/Override", payload);
+
+        // the state of node 2 ('-') is not a known ventilation state, it still becomes the base state
+        bridge
+            .handle_command(push(r#"{"Name":"shower","State":"MAN3x3"}"#))
+            .await
+            .unwrap();
+        perform_commands(&mut bridge).await;
+        assert_eq!(bridge.state.overrides[&2].effective(), "MAN3x3");
+        assert_eq!(
+            *performed_actions.lock().unwrap(),
+            vec![(2, r#"{"Action":"SetVentilationState","Val":"MAN3x3"}"#.to_string())]
+        );
+
+        // the states are validated against the actions of the node
+        assert!(
+            bridge
+                .handle_command(push(r#"{"Name":"shower","State":"BOGUS"}"#))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_node_json_command() {
+        let backend = MockBackend::default();
+        let performed_actions = backend.performed_actions.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+
+        let command = |payload: &str| MqttData::new("ventilation/duco_node_2/cmnd", payload);
+        bridge
+            .handle_command(command(r#"{"Action":"SetVentilationState","Val":"MAN2"}"#))
+            .await
+            .unwrap();
+        let (performed, result) = bridge.commands.completed().await;
+        assert_eq!(performed.node(), Some(2));
+        assert!(result.is_ok());
+        assert_eq!(
+            *performed_actions.lock().unwrap(),
+            vec![(2, r#"{"Action":"SetVentilationState","Val":"MAN2"}"#.to_string())]
+        );
+
+        // validated against the actions of the node like the per action topics
+        for payload in [
+            r#"{"Action":"SetVentilationState","Val":"BOGUS"}"#,
+            r#"{"Action":"Bogus","Val":"MAN2"}"#,
+            r#"{"Action":"SetVentilationState"}"#,
+            "MAN2",
+        ] {
+            assert!(bridge.handle_command(command(payload)).await.is_err(), "{}", payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timed_ventilation_state_command() {
+        let backend = MockBackend::default();
+        let performed_actions = backend.performed_actions.clone();
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(backend)).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+
+        bridge
+            .handle_command(MqttData::new(
+                "ventilation/duco_node_2/cmnd/SetVentilationState",
+                r#"{"state":"MAN3","duration":1800}"#,
+            ))
+            .await
+            .unwrap();
+        assert!(bridge.commands.completed().await.1.is_ok());
+
+        // the state and duration can also be given as the value of a json command
+        bridge
+            .handle_command(MqttData::new(
+                "ventilation/duco_node_2/cmnd",
+                r#"{"Action":"SetVentilationState","Val":{"state":"MAN1","duration":2700}}"#,
+            ))
+            .await
+            .unwrap();
+        assert!(bridge.commands.completed().await.1.is_ok());
+
+        assert_eq!(
+            *performed_actions.lock().unwrap(),
+            vec![
+                (2, r#"{"Action":"SetVentilationState","Val":"MAN3x2"}"#.to_string()),
+                (2, r#"{"Action":"SetVentilationState","Val":"MAN1x3"}"#.to_string()),
+            ]
+        );
+
+        assert!(
+            bridge
+                .handle_command(MqttData::new(
+                    "ventilation/duco_node_2/cmnd/SetVentilationState",
+                    r#"{"state":"AUTO","duration":900}"#,
+                ))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_topic_scheme() {
+        let mut cfg = test_config();
+        cfg.topic_template = "{base}/{node_name}/{group}/{key}".to_string();
+        cfg.topic_lowercase = true;
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+
+        let published = bridge.mqtt.take_queued_publishes();
+        let topics: Vec<&str> = published.iter().map(|data| data.topic.as_str()).collect();
+        assert!(topics.contains(&"ventilation/boven/sensor/iaqco2"));
+        assert!(topics.contains(&"ventilation/main/ventilation/state"));
+        // valves without a name keep the default node level
+        assert!(topics.contains(&"ventilation/duco_node_67/ventilation/state"));
+        assert!(topics.contains(&"ventilation/main/actions/SetVentilationState/options"));
+
+        let documents = bridge.discovery_documents().await.unwrap();
+        let select = documents
+            .iter()
+            .find(|doc| doc.topic == "homeassistant/select/duco_node_1_ventilation_state/config")
+            .unwrap();
+        let select: serde_json::Value = serde_json::from_str(&select.payload).unwrap();
+        assert_eq!(select["stat_t"], "ventilation/main/ventilation/state");
+        assert_eq!(select["cmd_t"], "ventilation/main/cmnd/SetVentilationState");
+
+        // the default node levels remain accepted
+        for topic in [
+            "ventilation/main/cmnd/SetVentilationState",
+            "ventilation/duco_node_1/cmnd/SetVentilationState",
+        ] {
+            bridge.handle_command(MqttData::new(topic, "MAN2")).await.unwrap();
+            let (command, result) = bridge.commands.completed().await;
+            assert_eq!(command.node(), Some(1));
+            assert!(result.is_ok());
+        }
+        assert!(
+            bridge
+                .handle_command(MqttData::new("ventilation/unknown/cmnd/SetVentilationState", "MAN2"))
+                .await
+                .is_err()
+        );
+
+        // nodes with the same topic level keep the default level
+        bridge
+            .merge_nodes(vec![NodeInfo {
+                node: 3,
+                general: HashMap::from([("Name".to_string(), StatusField::from("BOVEN"))]),
+                ventilation: HashMap::new(),
+                sensor: None,
+            }])
+            .unwrap();
+        assert_eq!(bridge.node_topic(2), "duco_node_2");
+        assert_eq!(bridge.node_topic(3), "duco_node_3");
+        assert_eq!(bridge.node_topic(1), "main");
+    }
+
+    #[tokio::test]
+    async fn test_node_name_commands() {
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+
+        for topic in [
+            "ventilation/main/cmnd/SetVentilationState",
+            "ventilation/Main/cmnd/SetVentilationState",
+            "ventilation/duco_node_1/cmnd/SetVentilationState",
+        ] {
+            bridge.handle_command(MqttData::new(topic, "MAN2")).await.unwrap();
+            let (command, result) = bridge.commands.completed().await;
+            assert_eq!(command.node(), Some(1));
+            assert!(result.is_ok());
+        }
+
+        // nodes that share a name have to be addressed by number
+        bridge
+            .merge_nodes(vec![NodeInfo {
+                node: 3,
+                general: HashMap::from([("Name".to_string(), StatusField::from("boven"))]),
+                ventilation: HashMap::new(),
+                sensor: None,
+            }])
+            .unwrap();
+        assert!(bridge.node_number("beneden").is_err());
+        assert!(bridge.node_number("Boven").is_err());
+        assert_eq!(bridge.node_number("duco_node_3").unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_background_poll() {
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+
+        // a poll requested while polling is queued
+        bridge.request_poll();
+        bridge.request_poll();
+        assert!(bridge.poll_queued);
+        assert_eq!(bridge.poll_cycle, 1);
+
+        let (cycle, response) = bridge.poller.completed().await;
+        assert_eq!(cycle, 1);
+        bridge.update_ducobox(response.unwrap()).await.unwrap();
+        assert_eq!(bridge.node_models().len(), 5);
+        assert!(
+            bridge
+                .mqtt
+                .take_queued_publishes()
+                .iter()
+                .any(|data| data.topic == "ventilation/duco_node_2/Sensor/IaqCo2")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rediscovery() {
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+        bridge.poll_ducobox().await.unwrap();
+        bridge.mqtt.take_queued_publishes();
+
+        // unchanged actions are not published again
+        bridge.rediscovery_due = true;
+        bridge.request_poll();
+        let (_, response) = bridge.poller.completed().await;
+        let response = response.unwrap();
+        assert!(response.node_actions.is_some());
+        bridge.update_ducobox(response).await.unwrap();
+        assert!(
+            bridge
+                .mqtt
+                .take_queued_publishes()
+                .iter()
+                .all(|data| { !data.topic.starts_with("homeassistant/") && !data.topic.ends_with("/options") })
+        );
+
+        bridge
+            .node_with_number(2)
+            .unwrap()
+            .set_actions(NodeActions {
+                node: 2,
+                actions: Vec::new(),
+            })
+            .unwrap();
+        bridge.rediscovery_due = true;
+        bridge.request_poll();
+        let (_, response) = bridge.poller.completed().await;
+        bridge.update_ducobox(response.unwrap()).await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(
+            published
+                .iter()
+                .any(|data| data.topic == "ventilation/duco_node_2/actions/SetVentilationState/options")
+        );
+        assert!(published.iter().any(|data| data.topic.starts_with("homeassistant/")));
+        assert!(!bridge.rediscovery_due);
+    }
+
+    #[tokio::test]
+    async fn test_poll_changes() {
+        let mut bridge = DucoMqttBridge::with_backend(test_config(), Box::new(MockBackend::default())).unwrap();
+
+        let changes = bridge.poll_changes().await.unwrap();
+        assert!(changes.iter().any(|data| data.topic == "duco_node_1/Ventilation/State"));
+        assert!(bridge.mqtt.take_queued_publishes().is_empty());
+        assert!(bridge.poll_changes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_low_power_poll() {
+        let mut cfg = test_config();
+        cfg.mqtt_config.low_power = true;
+        let mut bridge = DucoMqttBridge::with_backend(cfg, Box::new(MockBackend::default())).unwrap();
+        assert_eq!(bridge.poll_interval, LOW_POWER_MIN_POLL_INTERVAL);
+        bridge.command_poll.start();
+        assert!(bridge.command_poll.deadline().is_none());
+
+        bridge.poll_ducobox().await.unwrap();
+        let published = bridge.mqtt.take_queued_publishes();
+        assert!(
+            published
+                .iter()
+                .all(|data| !data.topic.starts_with("ventilation/General/"))
+        );
+        assert!(published.iter().all(|data| !hassdiscovery::is_diagnostic(data)));
+        assert!(
+            published
+                .iter()
+                .any(|data| data.topic == "ventilation/HeatRecovery/General/TimeFilterRemain")
+        );
+
+        // every node publishes its values as a single json document
+        assert!(
+            published
+                .iter()
+                .any(|data| data.topic == "ventilation/duco_node_1/values")
+        );
+        assert!(
+            published
+                .iter()
+                .all(|data| !data.topic.starts_with("ventilation/duco_node_1/Ventilation/"))
+        );
+    }
+
+    #[test]
+    fn test_battery_remote_discovery() {
+        let node_info = NodeInfo {
+            node: 4,
+            general: HashMap::from([
+                ("Type".to_string(), StatusField::from("UCBAT")),
+                ("Battery".to_string(), StatusField::from(80)),
+            ]),
+            ventilation: HashMap::new(),
+            sensor: None,
+        };
+
+        let node = DucoBoxNode::try_from(node_info).unwrap();
+        assert!(matches!(node.node_type(), NodeType::RemoteControlRFBAT));
+
+        let topics = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context()).unwrap();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].topic, "homeassistant/sensor/duco_node_4_battery/config");
+        assert!(topics[0].payload.contains(r#""device_class":"battery""#));
+        assert!(topics[0].payload.contains(r#""entity_category":"diagnostic""#));
+    }
+
+    #[test]
+    fn test_co2_rh_valve_discovery() {
+        let node_info = NodeInfo {
+            node: 5,
+            general: HashMap::from([("Type".to_string(), StatusField::from("VLVCO2RH"))]),
+            ventilation: HashMap::from([("FlowLvlTgt".to_string(), StatusField::from(20))]),
+            sensor: Some(HashMap::from([
+                ("IaqCo2".to_string(), StatusField::from(80)),
+                ("Rh".to_string(), StatusField::from(55)),
+            ])),
+        };
+
+        let node = DucoBoxNode::try_from(node_info).unwrap();
+        assert!(matches!(node.node_type(), NodeType::CO2RHControlValve));
+
+        let topics: Vec<String> = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context())
+            .unwrap()
+            .into_iter()
+            .map(|data| data.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/duco_node_5_ventilation_flow_level_target/config",
+                "homeassistant/sensor/duco_node_5_sensor_iaq_co2/config",
+                "homeassistant/sensor/duco_node_5_sensor_rh/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_humidity_setpoint_discovery() {
+        let node_info = NodeInfo {
+            node: 5,
+            general: HashMap::from([("Type".to_string(), StatusField::from("VLVCO2RH"))]),
+            ventilation: HashMap::new(),
+            sensor: Some(HashMap::from([("Rh".to_string(), StatusField::from(55))])),
+        };
+        let parameter = |val: i64, min: i64, max: i64| ConfigParameter {
+            val,
+            min,
+            max,
+            inc: Some(1),
+        };
+
+        let mut node = DucoBoxNode::try_from(node_info).unwrap();
+        node.set_config(NodeConfig {
+            node: 5,
+            parameters: HashMap::from([
+                (CO2_SETPOINT.to_string(), parameter(800, 400, 2000)),
+                (RH_SETPOINT.to_string(), parameter(70, 50, 90)),
+                (RH_DELTA.to_string(), parameter(10, 5, 30)),
+            ]),
+        });
+
+        let numbers: Vec<serde_json::Value> = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context())
+            .unwrap()
+            .into_iter()
+            .filter(|data| data.topic.starts_with("homeassistant/number/"))
+            .map(|data| serde_json::from_str(&data.payload).unwrap())
+            .collect();
+        let unique_ids: Vec<&str> = numbers
+            .iter()
+            .map(|number| number["unique_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            unique_ids,
+            vec![
+                "duco_node_5_config_co2setpoint",
+                "duco_node_5_config_rhsetpoint",
+                "duco_node_5_config_rhdelta",
+            ]
+        );
+        assert_eq!(numbers[1]["device_class"], "humidity");
+        assert_eq!(numbers[1]["min"], 50.0);
+        assert_eq!(numbers[2]["cmd_t"], "ventilation/duco_node_5/cmnd/Config/RhDelta");
+        assert_eq!(numbers[2]["max"], 30.0);
+
+        assert_eq!(node.config_value_for_command(RH_DELTA, "15").unwrap(), 15);
+        assert!(node.config_value_for_command(RH_SETPOINT, "95").is_err());
+    }
+
+    #[test]
+    fn test_box_sensor_discovery() {
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::HumidityBoxSensor, 6);
+        node.update_status(NodeInfo {
+            node: 6,
+            general: HashMap::new(),
+            ventilation: HashMap::new(),
+            sensor: Some(HashMap::from([("Rh".to_string(), StatusField::from(61))])),
+        })
+        .unwrap();
+
+        let topics = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context()).unwrap();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].topic, "homeassistant/sensor/duco_node_6_sensor_rh/config");
+        assert!(topics[0].payload.contains(r#""device_class":"humidity""#));
+        assert!(topics[0].payload.contains(r#""identifiers":["duco_box_ventilation"]"#));
+    }
+
+    #[test]
+    fn test_weather_station_discovery() {
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::DucoWeatherStation, 7);
+        node.update_status(NodeInfo {
+            node: 7,
+            general: HashMap::new(),
+            ventilation: HashMap::new(),
+            sensor: Some(HashMap::from([
+                ("Temp".to_string(), StatusField::from(12)),
+                ("WindSpeed".to_string(), StatusField::from(3)),
+            ])),
+        })
+        .unwrap();
+
+        let topics = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context()).unwrap();
+        assert_eq!(topics.len(), 2);
+        assert_eq!(topics[0].topic, "homeassistant/sensor/duco_node_7_sensor_temp/config");
+        assert!(topics[0].payload.contains(r#""device_class":"temperature""#));
+        assert!(topics[1].payload.contains(r#""device_class":"wind_speed""#));
+    }
+
+    #[test]
+    fn test_generic_discovery() {
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::Unknown, 8);
+        node.update_status(NodeInfo {
+            node: 8,
+            general: HashMap::from([("Type".to_string(), StatusField::from("UCNEW"))]),
+            ventilation: HashMap::from([("Mode".to_string(), StatusField::from("AUTO"))]),
+            sensor: None,
+        })
+        .unwrap();
+        node.set_actions(NodeActions {
+            node: 8,
+            actions: vec![
+                NodeActionDescription {
+                    action: "SetMode".to_string(),
+                    val_type: "Enum".to_string(),
+                    values: Some(vec!["AUTO".to_string(), "MAN".to_string()]),
+                },
+                NodeActionDescription {
+                    action: "SetLock".to_string(),
+                    val_type: "Boolean".to_string(),
+                    values: None,
+                },
+            ],
+        })
+        .unwrap();
+
+        let topics = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context()).unwrap();
+        assert_eq!(
+            topics.iter().map(|data| data.topic.as_str()).collect::<Vec<_>>(),
+            vec![
+                "homeassistant/sensor/duco_node_8_ventilation_mode/config",
+                "homeassistant/select/duco_node_8_action_setmode/config",
+                "homeassistant/switch/duco_node_8_action_setlock/config",
+            ]
+        );
+        assert!(
+            topics[1]
+                .payload
+                .contains(r#""stat_t":"ventilation/duco_node_8/Ventilation/Mode""#)
+        );
+        assert!(!topics[2].payload.contains("stat_t"));
+    }
+
+    #[test]
+    fn test_sub_type_capabilities() {
+        let valve = |sub_type: i64| {
+            let mut node = DucoBoxNode::try_from(NodeInfo {
+                node: 67,
+                general: HashMap::from([
+                    ("Type".to_string(), StatusField::from("VLV")),
+                    ("SubType".to_string(), StatusField::from(sub_type)),
+                ]),
+                ventilation: HashMap::new(),
+                sensor: None,
+            })
+            .unwrap();
+            node.set_actions(NodeActions {
+                node: 67,
+                actions: vec![NodeActionDescription {
+                    action: SET_VENTILATION_STATE.to_string(),
+                    val_type: "Enum".to_string(),
+                    values: Some(vec!["AUTO".to_string(), "MAN1".to_string()]),
+                }],
+            })
+            .unwrap();
+            DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context()).unwrap()
+        };
+        let has_co2 = |topics: &[MqttData]| topics.iter().any(|data| data.topic.contains("_sensor_iaq_co2/"));
+
+        // a valve without a built-in sensor follows the room sensors
+        assert!(!has_co2(&valve(0)));
+        assert!(has_co2(&valve(1)));
+        assert_eq!(NodeType::Unknown.capabilities(None), &[Capability::Generic]);
+    }
+
+    #[test]
+    fn test_multi_zone_valve_discovery() {
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::ExternalMultiZoneValve, 70);
+        node.set_actions(NodeActions {
+            node: 70,
+            actions: vec![NodeActionDescription {
+                action: SET_VENTILATION_STATE.to_string(),
+                val_type: "Enum".to_string(),
+                values: Some(vec!["AUTO".to_string(), "MAN1".to_string()]),
+            }],
+        })
+        .unwrap();
+
+        let topics: Vec<String> = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context())
+            .unwrap()
+            .into_iter()
+            .map(|data| data.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/select/duco_node_70_ventilation_state/config",
+                "homeassistant/fan/duco_node_70_ventilation_fan/config",
+                "homeassistant/sensor/duco_node_70_ventilation_flow_level_target/config",
+                "homeassistant/sensor/duco_node_70_ventilation_state_time_remaining/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fan_discovery() {
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::DucoBox, 1);
+        let states = ["AUTO", "MAN1", "MAN2", "MAN3", "EMPT"].map(String::from).to_vec();
+        node.set_actions(NodeActions {
+            node: 1,
+            actions: vec![NodeActionDescription {
+                action: SET_VENTILATION_STATE.to_string(),
+                val_type: "Enum".to_string(),
+                values: Some(states.clone()),
+            }],
+        })
+        .unwrap();
+
+        let fan = hassdiscovery::fan_topic(&node, &test_context(), &states).unwrap();
+        assert_eq!(fan.topic, "homeassistant/fan/duco_node_1_ventilation_fan/config");
+
+        let config: serde_json::Value = serde_json::from_str(&fan.payload).unwrap();
+        assert_eq!(config["cmd_t"], "ventilation/duco_node_1/cmnd/SetVentilationState");
+        assert_eq!(
+            config["pr_mode_cmd_t"],
+            "ventilation/duco_node_1/cmnd/SetVentilationState"
+        );
+        assert_eq!(config["pct_stat_t"], "ventilation/duco_node_1/Ventilation/FlowLvlTgt");
+        assert_eq!(config["pct_cmd_t"], "ventilation/duco_node_1/cmnd/FanSpeed");
+        assert_eq!(config["preset_modes"].as_array().unwrap().len(), 5);
+
+        // the percentage maps onto the manual states
+        assert!(node.verify_command("FanSpeed", "60").is_ok());
+
+        // without manual states there is no speed control
+        let fan = hassdiscovery::fan_topic(&node, &test_context(), &states[..2]).unwrap();
+        let config: serde_json::Value = serde_json::from_str(&fan.payload).unwrap();
+        assert!(config.get("pct_cmd_t").is_none());
+    }
+
+    #[test]
+    fn test_co2_sensor_discovery() {
+        let node = DucoBoxNode::try_from(NodeInfo {
+            node: 2,
+            general: HashMap::from([("Type".to_string(), StatusField::from("UCCO2"))]),
+            ventilation: HashMap::new(),
+            sensor: Some(HashMap::from([
+                ("Co2".to_string(), StatusField::from(650)),
+                ("IaqCo2".to_string(), StatusField::from(80)),
+            ])),
+        })
+        .unwrap();
+
+        let mut ctx = test_context();
+        let topics = hassdiscovery::co2_sensor_topics(&node, &ctx).unwrap();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].topic, "homeassistant/sensor/duco_node_2_sensor_co2/config");
+
+        let config: serde_json::Value = serde_json::from_str(&topics[0].payload).unwrap();
+        assert_eq!(config["device_class"], "carbon_dioxide");
+        assert_eq!(config["unit_of_measurement"], "ppm");
+        assert_eq!(config["stat_t"], "ventilation/duco_node_2/Sensor/Co2");
+
+        // the air quality percentage is selectable per node
+        ctx.iaq_sensor_nodes = vec![2];
+        let topics: Vec<String> = hassdiscovery::co2_sensor_topics(&node, &ctx)
+            .unwrap()
+            .into_iter()
+            .map(|data| data.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/duco_node_2_sensor_co2/config",
+                "homeassistant/sensor/duco_node_2_sensor_iaq_co2/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_network_diagnostic_discovery() {
+        let node = DucoBoxNode::try_from(NodeInfo {
+            node: 2,
+            general: HashMap::from([
+                ("Type".to_string(), StatusField::from("UCCO2")),
+                ("SubType".to_string(), StatusField::from(1)),
+                ("Parent".to_string(), StatusField::from(67)),
+                ("CommErrorCtr".to_string(), StatusField::from(3)),
+            ]),
+            ventilation: HashMap::new(),
+            sensor: Some(HashMap::from([("IaqCo2".to_string(), StatusField::from(80))])),
+        })
+        .unwrap();
+
+        let topics = DucoMqttBridge::create_hass_descriptions_for_node(&node, &test_context()).unwrap();
+        let diagnostics: Vec<&str> = topics
+            .iter()
+            .filter(|data| hassdiscovery::is_diagnostic(data))
+            .map(|data| data.topic.as_str())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "homeassistant/sensor/duco_node_2_general_commerrorctr/config",
+                "homeassistant/sensor/duco_node_2_general_parent/config",
+                "homeassistant/sensor/duco_node_2_general_subtype/config",
+            ]
+        );
+
+        let identify = topics
+            .iter()
+            .find(|data| data.topic.starts_with("homeassistant/light/"))
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&identify.payload).unwrap();
+        assert_eq!(config["entity_category"], "config");
+    }
+
+    #[test]
+    fn test_entity_names() {
+        let node = DucoBoxNode::try_from(NodeInfo {
+            node: 2,
+            general: HashMap::from([
+                ("Type".to_string(), StatusField::from("UCCO2")),
+                ("Name".to_string(), StatusField::from("Bathroom")),
+            ]),
+            ventilation: HashMap::new(),
+            sensor: Some(HashMap::from([("IaqCo2".to_string(), StatusField::from(80))])),
+        })
+        .unwrap();
+
+        let mut ctx = test_context();
+        let config: serde_json::Value =
+            serde_json::from_str(&hassdiscovery::co2_sensor_topics(&node, &ctx).unwrap()[0].payload).unwrap();
+        assert_eq!(config["name"], "CO2 air quality");
+        assert_eq!(config["obj_id"], "bathroom_co2_air_quality");
+        assert_eq!(config["unique_id"], "duco_node_2_sensor_iaq_co2");
+
+        ctx.naming = EntityNaming::Number.strategy(TopicScheme::default());
+        let config: serde_json::Value =
+            serde_json::from_str(&hassdiscovery::co2_sensor_topics(&node, &ctx).unwrap()[0].payload).unwrap();
+        assert_eq!(config["name"], "Sensor/IaqCo2");
+        assert_eq!(config["obj_id"], "duco_node_2_sensor_iaq_co2");
+    }
+
+    fn parse_command_topic(topic: &str) -> Result<NodeCommandTopic> {
+        DucoMqttBridge::node_and_action_from_topic(topic, DucoMqttBridge::node_number_for_node_name)
     }
 
     #[test]
     fn test_node_number_command_from_topic() {
         assert_eq!(
-            DucoMqttBridge::node_and_action_from_topic("duco_node_1/cmnd/SetVentilationState").unwrap(),
-            (1, "SetVentilationState".to_string())
+            parse_command_topic("duco_node_1/cmnd/SetVentilationState").unwrap(),
+            NodeCommandTopic {
+                node: 1,
+                action: "SetVentilationState".to_string(),
+                resource: None,
+            }
+        );
+        assert_eq!(
+            parse_command_topic("duco_node_2/cmnd/SetIdentify").unwrap(),
+            NodeCommandTopic {
+                node: 2,
+                action: "SetIdentify".to_string(),
+                resource: None,
+            }
         );
+    }
+
+    #[test]
+    fn test_node_command_with_resource_from_topic() {
         assert_eq!(
-            DucoMqttBridge::node_and_action_from_topic("duco_node_2/cmnd/SetIdentify").unwrap(),
-            (2, "SetIdentify".to_string())
+            parse_command_topic("duco_node_1/cmnd/Config/FlowMax").unwrap(),
+            NodeCommandTopic {
+                node: 1,
+                action: "Config".to_string(),
+                resource: Some("FlowMax".to_string()),
+            }
         );
+
+        for invalid in [
+            "duco_node_1/cmnd",
+            "duco_node_1/cmnd/",
+            "duco_node_1/cmnd/Config/",
+            "duco_node_1/cmnd/Config/Flow/Max",
+            "duco_node_1/state/Config/FlowMax",
+            "duco_box/cmnd/Config/FlowMax",
+        ] {
+            assert!(parse_command_topic(invalid).is_err(), "{}", invalid);
+        }
     }
 }
@@ -0,0 +1,176 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, ensure};
+use rustls::{
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+
+use crate::Result;
+
+/// Trusts the server when it presents one of the pinned certificates.
+/// The validity period is not checked, so boards with a clock that is out of sync (or hosts without an RTC)
+/// can still be validated.
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    pinned: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    pub fn from_pem_file(path: &Path) -> Result<PinnedCertVerifier> {
+        let pinned = CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("Failed to read certificate {}", path.display()))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(PinnedCertVerifier {
+            pinned,
+            provider: Arc::new(ring::default_provider()),
+        })
+    }
+
+    pub fn client_config(self) -> Result<rustls::ClientConfig> {
+        Ok(rustls::ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(self))
+            .with_no_client_auth())
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.pinned.iter().any(|pinned| pinned.as_ref() == cert.as_ref()))
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Verifies at startup that the certificate can be used by the rustls TLS stack
+pub fn validate_certificate(path: &Path) -> Result<()> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read certificate {}", path.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate {}", path.display()))?;
+
+    ensure!(
+        !certificates.is_empty(),
+        "No certificates found in {}, only PEM encoded certificates are supported (DER or PKCS#12 files require \
+         conversion, e.g. 'openssl x509 -inform der -in cert.der -out cert.pem')",
+        path.display()
+    );
+
+    Ok(())
+}
+
+fn tls_error<'a>(mut err: &'a (dyn std::error::Error + 'static)) -> Option<&'a rustls::Error> {
+    // io::Error forwards the source of the wrapped error instead of the wrapped error itself,
+    // the connector wraps the io::Error of the TLS stream in another one
+    while let Some(inner) = err.downcast_ref::<std::io::Error>().and_then(|io_err| io_err.get_ref()) {
+        err = inner;
+    }
+
+    err.downcast_ref::<rustls::Error>()
+}
+
+fn certificate_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a CertificateError> {
+    match tls_error(err) {
+        Some(rustls::Error::InvalidCertificate(cert_err)) => Some(cert_err),
+        _ => None,
+    }
+}
+
+/// Checks if the TLS handshake with the box failed (e.g. on a certificate that is not trusted or not valid at the
+/// current system time), as opposed to the connection itself
+pub fn is_tls_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| tls_error(err).is_some())
+}
+
+/// Checks if the error was caused by a certificate that is not valid at the current system time
+pub fn is_clock_skew_error(err: &anyhow::Error) -> bool {
+    err.chain().filter_map(certificate_error).any(|cert_err| {
+        matches!(
+            cert_err,
+            CertificateError::Expired
+                | CertificateError::ExpiredContext { .. }
+                | CertificateError::NotValidYet
+                | CertificateError::NotValidYetContext { .. }
+        )
+    })
+}
+
+pub const CLOCK_SKEW_GUIDANCE: &str = "The certificate of the connectivity board is not valid at the current system time. \
+    Verify that the system clock is correct (devices without a real time clock need a working NTP sync) \
+    or run with --duco-cert-pinning to trust the provided certificate regardless of its validity period";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate_failure(cert_err: CertificateError) -> anyhow::Error {
+        let tls_err = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(cert_err),
+        );
+        anyhow::Error::new(std::io::Error::other(tls_err)).context("Failed to obtain device info")
+    }
+
+    #[test]
+    fn test_validate_certificate() {
+        let path = std::env::temp_dir().join(format!("duco2mqtt-cert-{}.der", std::process::id()));
+        std::fs::write(&path, [0x30, 0x82, 0x01, 0x0a]).unwrap();
+        assert!(validate_certificate(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(validate_certificate(Path::new("/nonexistent/cert.pem")).is_err());
+    }
+
+    #[test]
+    fn test_clock_skew_detection() {
+        assert!(is_clock_skew_error(&certificate_failure(CertificateError::Expired)));
+        assert!(is_clock_skew_error(&certificate_failure(CertificateError::NotValidYet)));
+        assert!(!is_clock_skew_error(&certificate_failure(
+            CertificateError::UnknownIssuer
+        )));
+        assert!(!is_clock_skew_error(&anyhow::anyhow!("Connection refused")));
+
+        assert!(is_tls_error(&certificate_failure(CertificateError::UnknownIssuer)));
+        assert!(!is_tls_error(&anyhow::anyhow!("Connection refused")));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use crate::{
+    Result,
+    ducoapi::{NodeAction, NodeInfo},
+    ducobackend::DucoBackend,
+};
+
+/// A validated command that is performed on the box
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// An action of a node, the values of the node are requested after the last queued action of the node
+    NodeAction(u16, NodeAction),
+    /// Changes a configuration parameter of a node
+    NodeConfig(u16, String, i64),
+    /// An action of the box itself, e.g. the filter reset
+    BoxAction(NodeAction),
+    /// Changes a configuration value of the box
+    BoxConfig(String, i64),
+}
+
+impl Command {
+    /// The node the command is performed on, None for the commands of the box
+    pub fn node(&self) -> Option<u16> {
+        match self {
+            Command::NodeAction(node, _) | Command::NodeConfig(node, _, _) => Some(*node),
+            Command::BoxAction(_) | Command::BoxConfig(_, _) => None,
+        }
+    }
+
+    async fn perform(self, backend: &dyn DucoBackend) -> Result<()> {
+        match self {
+            Command::NodeAction(node, action) => backend.perform_action(node, action).await,
+            Command::NodeConfig(node, key, val) => backend.set_node_config(node, &key, val).await,
+            Command::BoxAction(action) => backend.perform_box_action(action).await,
+            Command::BoxConfig(key, val) => backend.set_config(&key, val).await,
+        }
+    }
+}
+
+/// The outcome of a command, with the values of the node requested after the last queued action of the node
+type Completed = (Command, Result<Option<NodeInfo>>);
+
+/// Performs the commands of a node one at a time in the order they were queued, so a command never races the
+/// previous command of the same node. Every node (and the box itself) has its own worker, the commands of different
+/// nodes run in parallel.
+pub struct CommandQueue {
+    backend: Arc<dyn DucoBackend>,
+    /// The workers by node number, the commands of the box itself have the None worker
+    workers: HashMap<Option<u16>, mpsc::UnboundedSender<Command>>,
+    completed_tx: mpsc::UnboundedSender<Completed>,
+    completed_rx: mpsc::UnboundedReceiver<Completed>,
+    pending: usize,
+}
+
+impl CommandQueue {
+    pub fn new(backend: Arc<dyn DucoBackend>) -> CommandQueue {
+        let (completed_tx, completed_rx) = mpsc::unbounded_channel();
+
+        CommandQueue {
+            backend,
+            workers: HashMap::new(),
+            completed_tx,
+            completed_rx,
+            pending: 0,
+        }
+    }
+
+    pub fn push(&mut self, command: Command) {
+        let node = command.node();
+        let worker = self
+            .workers
+            .entry(node)
+            .or_insert_with(|| CommandQueue::spawn_worker(node, self.backend.clone(), self.completed_tx.clone()));
+
+        // the workers live as long as the queue, sending can not fail
+        let _ = worker.send(command);
+        self.pending += 1;
+    }
+
+    fn spawn_worker(
+        node: Option<u16>,
+        backend: Arc<dyn DucoBackend>,
+        completed: mpsc::UnboundedSender<Completed>,
+    ) -> mpsc::UnboundedSender<Command> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+
+        tokio::spawn(async move {
+            let mut command_nr = 0;
+            while let Some(command) = rx.recv().await {
+                command_nr += 1;
+                let span = tracing::info_span!("command", node, command = command_nr);
+                let mut result = command
+                    .clone()
+                    .perform(backend.as_ref())
+                    .instrument(span.clone())
+                    .await
+                    .map(|()| None);
+                // only the outcome of the last queued action is of interest
+                if let Command::NodeAction(node, _) = command
+                    && result.is_ok()
+                    && rx.is_empty()
+                {
+                    match backend.get_node(node).instrument(span.clone()).await {
+                        Ok(info) => result = Ok(Some(info)),
+                        Err(err) => span.in_scope(|| tracing::debug!("Failed to refresh the node: {:#}", err)),
+                    }
+                }
+                if completed.send((command, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        tx
+    }
+
+    /// Completes when a queued command has been performed, never completes when nothing is queued. The values of the
+    /// node are included for node actions when no other commands of the node are queued and they could be requested.
+    pub async fn completed(&mut self) -> Completed {
+        if self.pending == 0 {
+            return std::future::pending().await;
+        }
+
+        // the queue holds a sender itself, so the channel is never closed
+        let completed = self.completed_rx.recv().await.expect("Command queue closed");
+        self.pending -= 1;
+        completed
+    }
+
+    /// No commands are waiting to be performed
+    pub fn is_idle(&self) -> bool {
+        self.pending == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ducoapi::{NodeAction, NodeEnumAction},
+        ducobackend::mock::MockBackend,
+    };
+
+    use super::*;
+
+    fn ventilation_state(state: &str) -> NodeAction {
+        NodeAction::Enum(NodeEnumAction {
+            action: "SetVentilationState".to_string(),
+            val: state.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_actions_per_node_are_ordered() {
+        let backend = Arc::new(MockBackend::default());
+        let mut queue = CommandQueue::new(backend.clone());
+        assert!(queue.is_idle());
+
+        queue.push(Command::NodeAction(1, ventilation_state("MAN1")));
+        queue.push(Command::NodeAction(2, ventilation_state("AUTO")));
+        queue.push(Command::NodeAction(1, ventilation_state("MAN3")));
+
+        let mut refreshed = Vec::new();
+        for _ in 0..3 {
+            let (command, result) = queue.completed().await;
+            refreshed.push((command.node(), result.unwrap().map(|info| info.node)));
+        }
+        assert!(queue.is_idle());
+
+        // the node is requested after the last queued action of the node
+        refreshed.sort();
+        assert_eq!(refreshed, vec![(Some(1), None), (Some(1), Some(1)), (Some(2), Some(2))]);
+
+        let performed = backend.performed_actions.lock().unwrap();
+        let node1: Vec<&String> = performed
+            .iter()
+            .filter(|(node, _)| *node == 1)
+            .map(|(_, action)| action)
+            .collect();
+        assert_eq!(
+            node1,
+            vec![
+                r#"{"Action":"SetVentilationState","Val":"MAN1"}"#,
+                r#"{"Action":"SetVentilationState","Val":"MAN3"}"#
+            ]
+        );
+        assert_eq!(performed.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_configuration_commands() {
+        let backend = Arc::new(MockBackend::default());
+        let mut queue = CommandQueue::new(backend.clone());
+
+        queue.push(Command::BoxConfig("HeatRecovery/Bypass/TempSupTgt".to_string(), 215));
+        queue.push(Command::NodeConfig(2, "Co2SetPoint".to_string(), 950));
+        for _ in 0..2 {
+            // only the node actions request the values of the node
+            let (_, result) = queue.completed().await;
+            assert!(result.unwrap().is_none());
+        }
+
+        assert_eq!(
+            *backend.config_changes.lock().unwrap(),
+            vec![("HeatRecovery/Bypass/TempSupTgt".to_string(), 215)]
+        );
+        assert_eq!(
+            *backend.node_config_changes.lock().unwrap(),
+            vec![(2, "Co2SetPoint".to_string(), 950)]
+        );
+    }
+}
@@ -0,0 +1,16 @@
+use strum::{Display, EnumString};
+
+/// Layout of the published topics and payloads.
+/// Intentional changes to the topics introduce a new version, older versions remain selectable so existing
+/// automations keep working until they are migrated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, EnumString, Display)]
+pub enum TopicCompat {
+    /// The frozen layout of the first release with compatibility guarantees, covered by the v1 golden file
+    #[default]
+    #[strum(serialize = "1")]
+    V1,
+    /// Home assistant devices per box and node, named entities and the additional entities and topics (action
+    /// options, configuration values, box arrays and network counters)
+    #[strum(serialize = "2")]
+    V2,
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use tokio::time::{Duration, Instant};
+
+use crate::mqtt::MqttData;
+
+/// How long the bridge listens for the retained discovery documents on the broker
+pub const DRIFT_CHECK_WINDOW: Duration = Duration::from_secs(10);
+
+/// Compares the generated discovery documents with the documents that are retained on the broker,
+/// e.g. to detect documents that were overwritten or removed by another tool
+pub struct DriftCheck {
+    expected: BTreeMap<String, String>,
+    /// Whether the retained document of a topic matches the generated one
+    received: BTreeMap<String, bool>,
+    deadline: Instant,
+}
+
+impl DriftCheck {
+    pub fn new(documents: Vec<MqttData>) -> DriftCheck {
+        DriftCheck {
+            expected: documents.into_iter().map(|doc| (doc.topic, doc.payload)).collect(),
+            received: BTreeMap::new(),
+            deadline: Instant::now() + DRIFT_CHECK_WINDOW,
+        }
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.expected.keys()
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Records a retained document of the broker, returns false when the topic is not part of the check
+    pub fn handle(&mut self, data: &MqttData) -> bool {
+        match self.expected.get(&data.topic) {
+            Some(expected) => {
+                self.received
+                    .insert(data.topic.clone(), same_document(expected, &data.payload));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The generated documents that are missing on the broker or differ from the retained ones
+    pub fn drifted(&self) -> Vec<MqttData> {
+        self.expected
+            .iter()
+            .filter(|(topic, _)| !self.received.get(*topic).copied().unwrap_or(false))
+            .map(|(topic, payload)| MqttData::new(topic, payload))
+            .collect()
+    }
+}
+
+/// Json documents are compared by value, so documents that only differ in the formatting do not count as drift
+fn same_document(expected: &str, retained: &str) -> bool {
+    match (
+        serde_json::from_str::<serde_json::Value>(expected),
+        serde_json::from_str::<serde_json::Value>(retained),
+    ) {
+        (Ok(expected), Ok(retained)) => expected == retained,
+        _ => expected == retained,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift_check() {
+        let mut check = DriftCheck::new(vec![
+            MqttData::new("homeassistant/sensor/a/config", r#"{"name":"A","unique_id":"a"}"#),
+            MqttData::new("homeassistant/sensor/b/config", r#"{"name":"B"}"#),
+            MqttData::new("homeassistant/sensor/c/config", r#"{"name":"C"}"#),
+        ]);
+
+        assert!(check.handle(&MqttData::new(
+            "homeassistant/sensor/a/config",
+            r#"{ "unique_id": "a", "name": "A" }"#
+        )));
+        assert!(check.handle(&MqttData::new("homeassistant/sensor/b/config", r#"{"name":"Other"}"#)));
+        assert!(!check.handle(&MqttData::new("homeassistant/sensor/other/config", "{}")));
+
+        // b was overwritten and c is missing on the broker
+        let drifted: Vec<String> = check.drifted().into_iter().map(|doc| doc.topic).collect();
+        assert_eq!(
+            drifted,
+            vec!["homeassistant/sensor/b/config", "homeassistant/sensor/c/config"]
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! The home assistant discovery documents of topic layout version 1.
+//! The documents are frozen: they are covered by the v1 golden file and must not change, new entities and changes to
+//! the existing ones belong in the current layout (`hassdiscovery`).
+
+use serde::Serialize;
+
+use crate::{
+    Result,
+    ducoboxnode::{
+        DucoBoxNode, GENERAL, PERMANENT_MODE_ACTION, PERMANENT_MODE_STATE, SENSOR, SET_IDENTIFY, SET_VENTILATION_STATE,
+        VENTILATION,
+    },
+    duconodetypes::NodeType,
+    hassdiscovery::{HASS_DISCOVERY_TOPIC, Origin},
+    mqtt::MqttData,
+};
+
+#[derive(Serialize)]
+struct Sensor {
+    origin: Origin,
+    name: String,
+    obj_id: String,
+    unique_id: String,
+    stat_t: String,
+    avty_t: String,
+    state_class: Option<String>,
+    unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Select {
+    origin: Origin,
+    name: String,
+    obj_id: String,
+    unique_id: String,
+    stat_t: String,
+    avty_t: String,
+    cmd_t: String,
+    options: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Light {
+    origin: Origin,
+    name: String,
+    obj_id: String,
+    unique_id: String,
+    stat_t: String,
+    avty_t: String,
+    cmd_t: String,
+    payload_on: String,
+    payload_off: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Switch {
+    origin: Origin,
+    name: String,
+    obj_id: String,
+    unique_id: String,
+    stat_t: String,
+    avty_t: String,
+    cmd_t: String,
+    payload_on: String,
+    payload_off: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Update {
+    origin: Origin,
+    name: String,
+    obj_id: String,
+    unique_id: String,
+    stat_t: String,
+    avty_t: String,
+    entity_category: String,
+}
+
+fn config_document(component: &str, unique_id: &str, document: &impl Serialize) -> Result<MqttData> {
+    Ok(MqttData {
+        topic: format!("{}/{}/{}/config", HASS_DISCOVERY_TOPIC, component, unique_id),
+        payload: serde_json::to_string(document)?,
+    })
+}
+
+fn create_sensor_for_status(node: &DucoBoxNode, base_topic: &str, topic_name: &str, status: &str) -> Sensor {
+    let unique_id = format!("duco_node_{}_{}", node.number(), status);
+
+    Sensor {
+        origin: Origin::duco2mqtt(),
+        name: topic_name.to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}{}", base_topic, node.status_topic(topic_name)),
+        avty_t: format!("{}state", base_topic),
+        state_class: None,
+        unit_of_measurement: None,
+        device_class: None,
+        entity_category: None,
+        icon: None,
+    }
+}
+
+fn ventilation_state_topic(node: &DucoBoxNode, base_topic: &str, valid_states: &[String]) -> Result<MqttData> {
+    let topic_name = format!("{}/State", VENTILATION);
+    let unique_id = format!("duco_node_{}_ventilation_state", node.number());
+
+    let select = Select {
+        origin: Origin::duco2mqtt(),
+        name: topic_name.clone(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}{}", base_topic, node.status_topic(&topic_name)),
+        avty_t: format!("{}state", base_topic),
+        cmd_t: format!("{}{}", base_topic, node.command_topic(SET_VENTILATION_STATE)),
+        options: Vec::from(valid_states),
+        icon: Some("mdi:fan".to_string()),
+    };
+
+    config_document("select", &select.unique_id, &select)
+}
+
+fn permanent_mode_topics(node: &DucoBoxNode, base_topic: &str, valid_states: &[String]) -> Result<Vec<MqttData>> {
+    valid_states
+        .iter()
+        .filter_map(|state| state.strip_prefix(PERMANENT_MODE_STATE))
+        .map(|level| {
+            let unique_id = format!("duco_node_{}_permanent_mode_{}", node.number(), level);
+            let switch = Switch {
+                origin: Origin::duco2mqtt(),
+                name: format!("Permanent mode {}", level),
+                obj_id: unique_id.clone(),
+                unique_id,
+                stat_t: format!("{}{}", base_topic, node.status_topic(&format!("{}/State", VENTILATION))),
+                avty_t: format!("{}state", base_topic),
+                cmd_t: format!(
+                    "{}{}",
+                    base_topic,
+                    node.command_topic(&format!("{}{}", PERMANENT_MODE_ACTION, level))
+                ),
+                payload_on: "ON".to_string(),
+                payload_off: "OFF".to_string(),
+                value_template: Some(format!(
+                    "{{{{ 'ON' if value == '{}{}' else 'OFF' }}}}",
+                    PERMANENT_MODE_STATE, level
+                )),
+                icon: Some("mdi:fan-lock".to_string()),
+            };
+
+            config_document("switch", &switch.unique_id, &switch)
+        })
+        .collect()
+}
+
+fn filter_days_remaining_topic(base_topic: &str) -> Result<MqttData> {
+    let unique_id = "duco_device_remaining_filter_days".to_string();
+
+    let sensor = Sensor {
+        origin: Origin::duco2mqtt(),
+        name: "Remaining filter days".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}HeatRecovery/{}/TimeFilterRemain", base_topic, GENERAL),
+        avty_t: format!("{}state", base_topic),
+        state_class: Some("measurement".to_string()),
+        unit_of_measurement: Some("days".to_string()),
+        device_class: None,
+        entity_category: None,
+        icon: Some("mdi:calendar-clock".to_string()),
+    };
+
+    config_document("sensor", &sensor.unique_id, &sensor)
+}
+
+fn flow_level_target_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+    let mut sensor = create_sensor_for_status(
+        node,
+        base_topic,
+        &format!("{}/FlowLvlTgt", VENTILATION),
+        "ventilation_flow_level_target",
+    );
+    sensor.state_class = Some("measurement".to_string());
+    sensor.unit_of_measurement = Some("%".to_string());
+    sensor.icon = Some("mdi:fan-clock".to_string());
+
+    config_document("sensor", &sensor.unique_id, &sensor)
+}
+
+fn co2_sensor_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+    let mut sensor = create_sensor_for_status(node, base_topic, &format!("{}/IaqCo2", SENSOR), "sensor_iaq_co2");
+    sensor.state_class = Some("measurement".to_string());
+    sensor.unit_of_measurement = Some("%".to_string());
+    sensor.icon = Some("mdi:molecule-co2".to_string());
+
+    config_document("sensor", &sensor.unique_id, &sensor)
+}
+
+fn humidity_sensor_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+    let mut sensor = create_sensor_for_status(node, base_topic, &format!("{}/Rh", SENSOR), "sensor_rh");
+    sensor.state_class = Some("measurement".to_string());
+    sensor.unit_of_measurement = Some("%".to_string());
+    sensor.device_class = Some("humidity".to_string());
+
+    config_document("sensor", &sensor.unique_id, &sensor)
+}
+
+fn state_time_remaining_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+    let mut sensor = create_sensor_for_status(
+        node,
+        base_topic,
+        &format!("{}/TimeStateRemain", VENTILATION),
+        "ventilation_state_time_remaining",
+    );
+    sensor.state_class = Some(String::from("measurement"));
+    sensor.unit_of_measurement = Some(String::from("seconds"));
+    sensor.icon = Some("mdi:timer".to_string());
+
+    config_document("sensor", &sensor.unique_id, &sensor)
+}
+
+fn battery_diagnostic_topics(node: &DucoBoxNode, base_topic: &str) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+
+    for key in node.status_keys() {
+        let Some(name) = key.rsplit('/').next() else {
+            continue;
+        };
+
+        let mut sensor = create_sensor_for_status(node, base_topic, key, &name.to_lowercase());
+        sensor.entity_category = Some("diagnostic".to_string());
+
+        if name.starts_with("Batt") {
+            sensor.state_class = Some("measurement".to_string());
+            sensor.unit_of_measurement = Some("%".to_string());
+            sensor.device_class = Some("battery".to_string());
+        } else if name.starts_with("Comm") {
+            sensor.icon = Some("mdi:access-point-network".to_string());
+        } else {
+            continue;
+        }
+
+        topics.push(config_document("sensor", &sensor.unique_id, &sensor)?);
+    }
+
+    Ok(topics)
+}
+
+fn status_diagnostic_topics(node: &DucoBoxNode, base_topic: &str) -> Result<Vec<MqttData>> {
+    node.status_keys()
+        .into_iter()
+        .filter(|key| key.as_str() != "General/Type")
+        .map(|key| {
+            let mut sensor = create_sensor_for_status(node, base_topic, key, &key.replace('/', "_").to_lowercase());
+            sensor.entity_category = Some("diagnostic".to_string());
+            config_document("sensor", &sensor.unique_id, &sensor)
+        })
+        .collect()
+}
+
+fn identify_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+    let topic_name = format!("{}/Identify", GENERAL);
+    let unique_id = format!("duco_node_{}_identify", node.number());
+
+    let light = Light {
+        origin: Origin::duco2mqtt(),
+        name: topic_name.clone(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}{}", base_topic, node.status_topic(&topic_name)),
+        avty_t: format!("{}state", base_topic),
+        cmd_t: format!("{}{}", base_topic, node.command_topic(SET_IDENTIFY)),
+        payload_on: "1".to_string(),
+        payload_off: "0".to_string(),
+        icon: Some("mdi:led-on".to_string()),
+    };
+
+    config_document("light", &light.unique_id, &light)
+}
+
+pub fn update_topic(base_topic: &str) -> Result<MqttData> {
+    let unique_id = "duco2mqtt_update".to_string();
+
+    let update = Update {
+        origin: Origin::duco2mqtt(),
+        name: "duco2mqtt".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}bridge/update", base_topic),
+        avty_t: format!("{}state", base_topic),
+        entity_category: "diagnostic".to_string(),
+    };
+
+    config_document("update", &update.unique_id, &update)
+}
+
+fn away_mode_topic(base_topic: &str) -> Result<MqttData> {
+    let unique_id = "duco_bridge_away_mode".to_string();
+
+    let switch = Switch {
+        origin: Origin::duco2mqtt(),
+        name: "Away mode".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}bridge/away", base_topic),
+        avty_t: format!("{}state", base_topic),
+        cmd_t: format!("{}bridge/cmnd/away", base_topic),
+        payload_on: "ON".to_string(),
+        payload_off: "OFF".to_string(),
+        value_template: None,
+        icon: Some("mdi:home-export-outline".to_string()),
+    };
+
+    config_document("switch", &switch.unique_id, &switch)
+}
+
+/// The documents of the box itself: the remaining filter days and the away mode of the bridge
+pub fn device_topics(base_topic: &str) -> Result<Vec<MqttData>> {
+    Ok(vec![
+        filter_days_remaining_topic(base_topic)?,
+        away_mode_topic(base_topic)?,
+    ])
+}
+
+fn valve_topics(node: &DucoBoxNode, base_topic: &str) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+    if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+        topics.push(ventilation_state_topic(node, base_topic, valid_states)?);
+    }
+    topics.push(flow_level_target_topic(node, base_topic)?);
+
+    Ok(topics)
+}
+
+/// The documents of a node, the node types without a mapping in version 1 do not get any entities
+pub fn node_topics(node: &DucoBoxNode, base_topic: &str) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+
+    match node.node_type() {
+        NodeType::DucoBox | NodeType::CO2ControlValve => {
+            let valid_states = node.valid_action_values(SET_VENTILATION_STATE)?;
+            topics.push(ventilation_state_topic(node, base_topic, valid_states)?);
+            topics.extend(permanent_mode_topics(node, base_topic, valid_states)?);
+            topics.push(flow_level_target_topic(node, base_topic)?);
+            topics.push(state_time_remaining_topic(node, base_topic)?);
+            topics.push(identify_topic(node, base_topic)?);
+        }
+        NodeType::CO2RoomSensor => {
+            topics.push(co2_sensor_topic(node, base_topic)?);
+            topics.push(identify_topic(node, base_topic)?);
+        }
+        NodeType::RemoteControlRFBAT => {
+            if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+                topics.push(ventilation_state_topic(node, base_topic, valid_states)?);
+            }
+            topics.extend(battery_diagnostic_topics(node, base_topic)?);
+        }
+        NodeType::SensorlessControlValve => {
+            topics.extend(valve_topics(node, base_topic)?);
+        }
+        NodeType::HumidityControlValve => {
+            topics.extend(valve_topics(node, base_topic)?);
+            topics.push(humidity_sensor_topic(node, base_topic)?);
+        }
+        NodeType::SwitchSensor | NodeType::ControlUnit => {
+            if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+                topics.push(ventilation_state_topic(node, base_topic, valid_states)?);
+            }
+            if node.has_action(SET_IDENTIFY) {
+                topics.push(identify_topic(node, base_topic)?);
+            }
+            if topics.is_empty() {
+                topics.extend(status_diagnostic_topics(node, base_topic)?);
+            }
+        }
+        NodeType::Virtual => {
+            if let Ok(valid_states) = node.valid_action_values(SET_VENTILATION_STATE) {
+                topics.push(ventilation_state_topic(node, base_topic, valid_states)?);
+                topics.extend(permanent_mode_topics(node, base_topic, valid_states)?);
+            }
+        }
+        NodeType::RemoteControlRFWired
+        | NodeType::HumidityRoomSensor
+        | NodeType::CO2RHControlValve
+        | NodeType::RemoteControlSunControlRFWired
+        | NodeType::RemoteControlNightventRFWired
+        | NodeType::ExternalMultiZoneValve
+        | NodeType::HumidityBoxSensor
+        | NodeType::CO2BoxSensors
+        | NodeType::DucoWeatherStation
+        | NodeType::Unknown => {}
+    }
+
+    Ok(topics)
+}
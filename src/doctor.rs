@@ -0,0 +1,319 @@
+use std::{fmt, net::SocketAddr, path::Path, sync::Arc};
+
+use rustls::pki_types::CertificateDer;
+use serde::Serialize;
+use strum::Display;
+use tokio::time;
+
+use crate::{
+    bridge::{DucoMqttBridge, DucoMqttBridgeConfig},
+    certpinning, ducobackend,
+    ducoendpoint::DucoEndpoint,
+    tlsprobe::{self, CONNECT_TIMEOUT, RecordingVerifier},
+};
+
+const RESOLUTION: &str = "Duco host resolution";
+const TLS_HANDSHAKE: &str = "Duco TLS handshake";
+const CERTIFICATE: &str = "Duco certificate";
+const API: &str = "Duco api";
+const CONFIGURATION: &str = "Configuration";
+const MQTT_BROKER: &str = "MQTT broker";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize)]
+#[strum(serialize_all = "UPPERCASE")]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// Not performed, because a previous step failed or it is not configured
+    Skip,
+}
+
+/// The outcome of a step of the connectivity diagnostics
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub details: String,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, details: impl Into<String>) -> Check {
+        Check {
+            name: name.to_string(),
+            status,
+            details: details.into(),
+        }
+    }
+
+    fn pass(name: &str, details: impl Into<String>) -> Check {
+        Check::new(name, CheckStatus::Pass, details)
+    }
+
+    fn fail(name: &str, details: impl Into<String>) -> Check {
+        Check::new(name, CheckStatus::Fail, details)
+    }
+
+    fn skip(name: &str, details: impl Into<String>) -> Check {
+        Check::new(name, CheckStatus::Skip, details)
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.details)
+    }
+}
+
+/// Checks the connection to the connectivity board (name resolution, TLS handshake, certificate and api) and to the
+/// MQTT broker step by step. The steps that depend on a failed step are skipped.
+pub async fn diagnose(mut cfg: DucoMqttBridgeConfig, check_mqtt: bool) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let endpoint = DucoEndpoint::new(&cfg.ducobox_host, cfg.ducobox_ip_address.as_deref());
+    let (check, addr) = match cfg.ducobox_proxy {
+        // the board is not reachable directly, the api check goes through the proxy
+        Some(ref proxy) => (
+            Check::skip(
+                RESOLUTION,
+                format!(
+                    "The duco host is reached through proxy {}",
+                    ducobackend::proxy_address(proxy)
+                ),
+            ),
+            None,
+        ),
+        None => match endpoint {
+            Ok(ref endpoint) => resolve_duco_host(endpoint).await,
+            Err(ref err) => (Check::fail(RESOLUTION, err.to_string()), None),
+        },
+    };
+    checks.push(check);
+    match (addr, endpoint) {
+        (Some(addr), Ok(endpoint)) => {
+            let certificate = cfg.ducobox_certificate.as_deref();
+            let (check, presented) = tls_handshake(&endpoint.name, addr, certificate, cfg.ducobox_cert_pinning).await;
+            checks.push(check);
+            checks.push(match presented {
+                Some(presented) => certificate_details(&presented, certificate.is_some() && !cfg.ducobox_cert_pinning),
+                None => Check::skip(CERTIFICATE, "No certificate was presented"),
+            });
+        }
+        _ => {
+            let reason = match cfg.ducobox_proxy {
+                Some(_) => "Only checked on a direct connection",
+                None => "The duco host could not be resolved",
+            };
+            checks.push(Check::skip(TLS_HANDSHAKE, reason));
+            checks.push(Check::skip(CERTIFICATE, reason));
+        }
+    }
+
+    let brokers = cfg
+        .mqtt_config
+        .brokers()
+        .map(|brokers| {
+            brokers
+                .iter()
+                .map(|(host, port)| format!("{}:{}", host, port))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    // a client id of its own, so the broker does not disconnect a running bridge
+    cfg.mqtt_config.client_id = format!("{}-doctor", cfg.mqtt_config.client_id);
+
+    let mut bridge = match DucoMqttBridge::new(cfg) {
+        Ok(bridge) => bridge,
+        Err(err) => {
+            checks.push(Check::fail(CONFIGURATION, err.to_string()));
+            return checks;
+        }
+    };
+
+    let started = time::Instant::now();
+    checks.push(match bridge.inventory().await {
+        Ok(inventory) => {
+            let value = |key: &str| inventory.device.get(key).map_or("?", String::as_str);
+            Check::pass(
+                API,
+                format!(
+                    "Box {} (api version {}) with {} nodes answered in {} ms",
+                    value("General/Board/BoxName"),
+                    value("General/Board/PublicApiVersion"),
+                    inventory.nodes.len(),
+                    started.elapsed().as_millis()
+                ),
+            )
+        }
+        Err(err) => Check::fail(API, err.to_string()),
+    });
+
+    checks.push(if !check_mqtt {
+        Check::skip(MQTT_BROKER, "No MQTT broker configured (--mqtt-addr)")
+    } else {
+        match bridge.check_mqtt_connection(CONNECT_TIMEOUT).await {
+            Ok(()) => Check::pass(MQTT_BROKER, format!("Connected to {}", brokers)),
+            Err(err) => Check::fail(MQTT_BROKER, format!("{} ({})", err, brokers)),
+        }
+    });
+
+    checks
+}
+
+async fn resolve_duco_host(endpoint: &DucoEndpoint) -> (Check, Option<SocketAddr>) {
+    let host = endpoint.name.as_str();
+    if let Some(addr) = endpoint.socket_addr() {
+        return (
+            Check::pass(RESOLUTION, format!("Using {} for {} (--duco-ip)", addr, host)),
+            Some(addr),
+        );
+    }
+
+    match time::timeout(CONNECT_TIMEOUT, tokio::net::lookup_host((host, endpoint.port))).await {
+        Ok(Ok(addrs)) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            let ips: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+            match addrs.first() {
+                Some(addr) => (
+                    Check::pass(RESOLUTION, format!("{} resolves to {}", host, ips.join(", "))),
+                    Some(*addr),
+                ),
+                None => (Check::fail(RESOLUTION, format!("{} has no addresses", host)), None),
+            }
+        }
+        Ok(Err(err)) => (
+            Check::fail(
+                RESOLUTION,
+                format!("Failed to resolve {}: {}, pass the address with --duco-ip", host, err),
+            ),
+            None,
+        ),
+        Err(_) => (Check::fail(RESOLUTION, format!("Resolving {} timed out", host)), None),
+    }
+}
+
+/// Performs the TLS handshake with the certificate verification of the bridge, the certificate the board presents is
+/// returned even when it is not trusted
+async fn tls_handshake(
+    host: &str,
+    addr: SocketAddr,
+    certificate: Option<&Path>,
+    cert_pinning: bool,
+) -> (Check, Option<CertificateDer<'static>>) {
+    let verifier = match RecordingVerifier::new(certificate, cert_pinning) {
+        Ok(verifier) => Arc::new(verifier),
+        Err(err) => return (Check::fail(TLS_HANDSHAKE, format!("{:#}", err)), None),
+    };
+
+    let result = tlsprobe::handshake(host, addr, verifier.clone()).await;
+    let presented = verifier.presented.lock().expect("Poisoned lock").first().cloned();
+    let verification = match certificate {
+        Some(cert) if cert_pinning => format!("pinned certificate {}", cert.display()),
+        Some(cert) => format!("validated against {}", cert.display()),
+        None => "the certificate is not validated without --certificate".to_string(),
+    };
+
+    let check = match result {
+        Ok(negotiated) => Check::pass(TLS_HANDSHAKE, format!("{} with {}, {}", addr, negotiated, verification)),
+        Err(err) if certpinning::is_clock_skew_error(&err) => Check::fail(
+            TLS_HANDSHAKE,
+            format!("{:#} ({}). {}", err, verification, certpinning::CLOCK_SKEW_GUIDANCE),
+        ),
+        Err(err) => Check::fail(TLS_HANDSHAKE, format!("{:#} ({})", err, verification)),
+    };
+
+    (check, presented)
+}
+
+/// The subject, issuer, names and validity period of the certificate. An invalid period only fails when the bridge
+/// validates it, pinned certificates and connections without a certificate ignore the validity period.
+fn certificate_details(certificate: &CertificateDer, validity_checked: bool) -> Check {
+    let info = match tlsprobe::certificate_info(certificate) {
+        Ok(info) => info,
+        Err(err) => return Check::fail(CERTIFICATE, format!("{:#}", err)),
+    };
+
+    let mut details = format!(
+        "Subject {}, issuer {}, valid from {} until {}",
+        info.subject, info.issuer, info.not_before, info.not_after
+    );
+    if !info.names.is_empty() {
+        details.push_str(&format!(", names {}", info.names.join(", ")));
+    }
+
+    match (info.valid_now, validity_checked) {
+        (true, _) => Check::pass(CERTIFICATE, details),
+        (false, true) => Check::fail(
+            CERTIFICATE,
+            format!("{}, not valid at the current system time", details),
+        ),
+        (false, false) => Check::pass(
+            CERTIFICATE,
+            format!("{}, not valid at the current system time (ignored)", details),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::pem::PemObject;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_duco_host() {
+        let endpoint = |host: &str, ip: Option<&str>| DucoEndpoint::new(host, ip).unwrap();
+
+        let (check, addr) = resolve_duco_host(&endpoint("duco.local", Some("192.168.1.5"))).await;
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert_eq!(addr, Some("192.168.1.5:443".parse().unwrap()));
+
+        let (check, addr) = resolve_duco_host(&endpoint("duco.local", Some("[fd00::1]:8443"))).await;
+        assert_eq!(
+            check.to_string(),
+            "[PASS] Duco host resolution: Using [fd00::1]:8443 for duco.local (--duco-ip)"
+        );
+        assert_eq!(addr, Some("[fd00::1]:8443".parse().unwrap()));
+
+        let (check, addr) = resolve_duco_host(&endpoint("127.0.0.1", None)).await;
+        assert_eq!(
+            check.to_string(),
+            "[PASS] Duco host resolution: 127.0.0.1 resolves to 127.0.0.1"
+        );
+        assert!(addr.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_failure() {
+        // nothing listens on the port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let (check, presented) = tls_handshake("duco.local", addr, None, false).await;
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.details.contains("Failed to connect"));
+        assert!(presented.is_none());
+
+        let (check, _) = tls_handshake("duco.local", addr, Some(Path::new("/nonexistent/cert.pem")), false).await;
+        assert!(check.details.contains("Failed to read certificate"));
+    }
+
+    #[test]
+    fn test_certificate_details() {
+        let cert = CertificateDer::from_pem_file("test/data/tls/client.pem").unwrap();
+        let check = certificate_details(&cert, true);
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert!(
+            check
+                .details
+                .starts_with("Subject CN=duco2mqtt, issuer CN=duco2mqtt, valid from "),
+            "{}",
+            check.details
+        );
+
+        let check = certificate_details(&CertificateDer::from(vec![0x30, 0x03, 0x02, 0x01, 0x01]), false);
+        assert_eq!(check.status, CheckStatus::Fail);
+    }
+}
@@ -1,12 +1,21 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    process::Stdio,
+    sync::Mutex,
+    time::Instant,
+};
 
-use anyhow::{Context, anyhow, bail};
+use anyhow::{Context, anyhow, bail, ensure};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     Result,
+    ducoboxdevice::NETWORK_DUCO,
     ducoboxnode::{GENERAL, HEAT_RECOVERY, SENSOR, VENTILATION},
+    recording::Recorder,
+    secret::Secret,
 };
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -62,9 +71,12 @@ pub struct NodeInfo {
 pub struct DeviceInfo {
     #[serde(rename = "General")]
     pub general: HashMap<String, StatusField>,
+    /// The keys of the array values, their value is the array serialized as json
+    #[serde(skip)]
+    pub json_keys: HashSet<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct NodeEnumAction {
     #[serde(rename = "Action")]
     pub action: String,
@@ -72,7 +84,7 @@ pub struct NodeEnumAction {
     pub val: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct NodeBoolAction {
     #[serde(rename = "Action")]
     pub action: String,
@@ -80,6 +92,23 @@ pub struct NodeBoolAction {
     pub val: bool,
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum NodeAction {
+    Enum(NodeEnumAction),
+    Bool(NodeBoolAction),
+}
+
+impl NodeAction {
+    /// The name of the action, e.g. `SetVentilationState`
+    pub fn name(&self) -> &str {
+        match self {
+            NodeAction::Enum(action) => &action.action,
+            NodeAction::Bool(action) => &action.action,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NodeActionDescription {
     #[serde(rename = "Action")]
@@ -98,45 +127,556 @@ pub struct NodeActions {
     pub actions: Vec<NodeActionDescription>,
 }
 
-pub async fn perform_action<T: serde::Serialize>(
-    client: &reqwest::Client,
-    addr: &str,
-    node: u16,
-    action: T,
-) -> Result<()> {
-    let url = format!("https://{}/action/nodes/{}", addr, node);
-    client
-        .post(url)
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(serde_json::to_string(&action)?)
-        .send()
-        .await
-        .context("Failed to perform node action")?;
-    Ok(())
+/// A configuration parameter of a node with the range the box accepts
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ConfigParameter {
+    #[serde(rename = "Val")]
+    pub val: i64,
+    #[serde(rename = "Min")]
+    pub min: i64,
+    #[serde(rename = "Max")]
+    pub max: i64,
+    #[serde(rename = "Inc")]
+    pub inc: Option<i64>,
 }
 
-pub async fn get_device_info(client: &reqwest::Client, addr: &str) -> Result<DeviceInfo> {
-    let url = format!("https://{}/info", addr);
-    let response = client.get(&url).send().await.context("Failed to obtain device info")?;
-    let json_data = response.bytes().await?;
-    parse_device_info(&json_data)
+impl ConfigParameter {
+    /// Validates a new value against the range and the step of the parameter
+    pub fn validate(&self, name: &str, val: i64) -> Result<()> {
+        ensure!(
+            (self.min..=self.max).contains(&val),
+            "Value of configuration parameter '{}' out of range ({}-{}): {}",
+            name,
+            self.min,
+            self.max,
+            val
+        );
+        if let Some(inc) = self.inc.filter(|inc| *inc > 1) {
+            ensure!(
+                (val - self.min) % inc == 0,
+                "Value of configuration parameter '{}' is not a multiple of {} from {}: {}",
+                name,
+                inc,
+                self.min,
+                val
+            );
+        }
+
+        Ok(())
+    }
 }
 
-pub async fn get_nodes(client: &reqwest::Client, addr: &str) -> Result<Vec<NodeInfo>> {
-    let url = format!("https://{}/info/nodes", addr);
-    let response = client.get(&url).send().await.context("Failed to obtain nodes")?;
-    let json_data = response.bytes().await?;
-    let mut nodes = parse_node_info(&json_data)?;
-    nodes.sort_by(|a, b| a.node.cmp(&b.node));
-    Ok(nodes)
+/// The configuration parameters of a node (`/config/nodes`), keyed by the name of the parameter
+#[derive(Debug)]
+pub struct NodeConfig {
+    pub node: u16,
+    pub parameters: HashMap<String, ConfigParameter>,
+}
+
+/// The configuration parameters of the box itself (`/config`), keyed by the path of the parameter
+/// (e.g. "HeatRecovery/Bypass/TempSupTgt")
+#[derive(Debug, Default)]
+pub struct BoxConfig {
+    pub parameters: BTreeMap<String, ConfigParameter>,
+}
+
+/// The actions of the box itself (`/action`)
+#[derive(Debug, Deserialize)]
+pub struct BoxActions {
+    #[serde(rename = "Actions")]
+    pub actions: Vec<NodeActionDescription>,
+}
+
+/// The status, entity tag and body of a response of the box
+struct ApiResponse {
+    status: reqwest::StatusCode,
+    etag: Option<reqwest::header::HeaderValue>,
+    body: bytes::Bytes,
+}
+
+/// The nodes of an /info/nodes response
+pub struct Nodes {
+    pub nodes: Vec<NodeInfo>,
+    /// The response is the same as the previous response of the cache, the values did not change
+    pub unchanged: bool,
+}
+
+/// The previous /info/nodes response, an unchanged response is not parsed again. The response is unchanged when the
+/// box answers the entity tag of the previous response with 304 (Not Modified) or when the body is identical.
+#[derive(Default)]
+pub struct NodesCache {
+    previous: Mutex<Option<CachedNodes>>,
+}
+
+struct CachedNodes {
+    etag: Option<reqwest::header::HeaderValue>,
+    digest: u64,
+    nodes: Vec<NodeInfo>,
+}
+
+impl NodesCache {
+    fn etag(&self) -> Option<reqwest::header::HeaderValue> {
+        self.previous
+            .lock()
+            .expect("Poisoned lock")
+            .as_ref()
+            .and_then(|previous| previous.etag.clone())
+    }
+
+    /// The nodes of the previous response when the response did not change
+    fn unchanged(&self, status: reqwest::StatusCode, digest: u64) -> Option<Vec<NodeInfo>> {
+        self.previous
+            .lock()
+            .expect("Poisoned lock")
+            .as_ref()
+            .filter(|previous| status == reqwest::StatusCode::NOT_MODIFIED || previous.digest == digest)
+            .map(|previous| previous.nodes.clone())
+    }
+
+    fn store(&self, etag: Option<reqwest::header::HeaderValue>, digest: u64, nodes: Vec<NodeInfo>) {
+        *self.previous.lock().expect("Poisoned lock") = Some(CachedNodes { etag, digest, nodes });
+    }
+}
+
+/// The first 8 bytes of the SHA-256 of the response
+fn body_digest(body: &[u8]) -> u64 {
+    let digest = ring::digest::digest(&ring::digest::SHA256, body);
+    u64::from_be_bytes(digest.as_ref()[..8].try_into().expect("SHA-256 digests are 32 bytes"))
+}
+
+/// The header with the api key that newer firmware of the connectivity board requires
+const API_KEY_HEADER: &str = "Api-Key";
+
+/// The box answered 401, the api key is missing or no longer valid
+#[derive(Debug, thiserror::Error)]
+#[error("The duco box rejected the api key (401 Unauthorized)")]
+pub struct Unauthorized;
+
+pub fn is_unauthorized(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| err.is::<Unauthorized>())
+}
+
+/// The api key as header value, it is marked sensitive so it does not show up in the debug output
+pub fn api_key_header(api_key: &Secret) -> Result<reqwest::header::HeaderValue> {
+    let mut value = reqwest::header::HeaderValue::from_str(api_key.expose())
+        .map_err(|_| anyhow!("The duco api key contains invalid characters"))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// The api key that newer firmware of the connectivity board requires
+#[derive(Clone)]
+pub enum ApiKeySource {
+    Key(Secret),
+    /// The file is read again when the box rejects the key, so the key can be renewed without restarting the bridge
+    File(PathBuf),
+    /// Shell command that prints a new key, it runs again when the box rejects the key. The board does not document
+    /// a request to obtain the key, the command obtains it the way the installation does (e.g. from the Duco portal).
+    Command(String),
+}
+
+impl ApiKeySource {
+    /// Obtains the key, this blocks while a key command runs
+    pub fn read(&self) -> Result<Secret> {
+        match self {
+            ApiKeySource::Key(key) => Ok(key.clone()),
+            ApiKeySource::File(path) => Secret::from_file(path)
+                .with_context(|| format!("Failed to read the duco api key from {}", path.display())),
+            ApiKeySource::Command(command) => run_api_key_command(command),
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            ApiKeySource::Key(_) => "the configured api key".to_string(),
+            ApiKeySource::File(path) => format!("the api key in {}", path.display()),
+            ApiKeySource::Command(_) => "the api key of the api key command".to_string(),
+        }
+    }
+}
+
+/// Runs the api key command, the key is the output without the trailing line break
+fn run_api_key_command(command: &str) -> Result<Secret> {
+    let mut process = if cfg!(windows) {
+        let mut process = std::process::Command::new("cmd");
+        process.arg("/C");
+        process
+    } else {
+        let mut process = std::process::Command::new("sh");
+        process.arg("-c");
+        process
+    };
+
+    let output = process
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run the duco api key command")?;
+    ensure!(
+        output.status.success(),
+        "The duco api key command failed: {}",
+        output.status
+    );
+
+    let mut key = String::from_utf8(output.stdout).context("The duco api key command printed an invalid key")?;
+    let len = key.trim_end_matches(['\r', '\n']).len();
+    key.truncate(len);
+    let key = Secret::from(key);
+    ensure!(!key.is_empty(), "The duco api key command did not print a key");
+    Ok(key)
+}
+
+struct ApiKey {
+    source: ApiKeySource,
+    current: Mutex<Secret>,
+}
+
+/// The REST API of the connectivity board, the requests share the http client, the api key and the recorder of the
+/// responses
+pub struct DucoApi {
+    client: reqwest::Client,
+    host: String,
+    recorder: Option<Recorder>,
+    api_key: Option<ApiKey>,
+}
+
+impl DucoApi {
+    pub fn new(client: reqwest::Client, host: String, recorder: Option<Recorder>) -> DucoApi {
+        DucoApi {
+            client,
+            host,
+            recorder,
+            api_key: None,
+        }
+    }
+
+    /// Sends the api key with every request
+    pub fn with_api_key(mut self, source: ApiKeySource) -> Result<DucoApi> {
+        let current = source.read()?;
+        api_key_header(&current)?;
+        self.api_key = Some(ApiKey {
+            source,
+            current: Mutex::new(current),
+        });
+        Ok(self)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Obtains the key again after the box rejected it, true when the source provides another key
+    pub async fn renew_api_key(&self) -> bool {
+        let Some(ApiKey { source, current }) = &self.api_key else {
+            return false;
+        };
+        if matches!(source, ApiKeySource::Key(_)) {
+            tracing::warn!("The duco box rejected {}", source.description());
+            return false;
+        }
+
+        let renewed = {
+            let source = source.clone();
+            tokio::task::spawn_blocking(move || source.read()).await
+        };
+
+        match renewed.map_err(anyhow::Error::from).and_then(|key| key) {
+            Ok(key) if key != *current.lock().expect("Poisoned lock") => {
+                if api_key_header(&key).is_err() {
+                    tracing::warn!("{} contains invalid characters", source.description());
+                    return false;
+                }
+                tracing::info!("The duco box rejected the api key, using {}", source.description());
+                *current.lock().expect("Poisoned lock") = key;
+                true
+            }
+            Ok(_) => {
+                tracing::warn!("The duco box rejected {}", source.description());
+                false
+            }
+            Err(err) => {
+                tracing::warn!("{:#}", err);
+                false
+            }
+        }
+    }
+
+    /// A request to the path of the box with the current api key
+    pub(crate) fn request(&self, method: reqwest::Method, path: &str) -> Result<reqwest::RequestBuilder> {
+        let request = self.client.request(method, format!("https://{}{}", self.host, path));
+        Ok(match &self.api_key {
+            Some(api_key) => request.header(
+                API_KEY_HEADER,
+                api_key_header(&api_key.current.lock().expect("Poisoned lock"))?,
+            ),
+            None => request,
+        })
+    }
+
+    fn json_request(&self, method: reqwest::Method, path: &str, body: String) -> Result<reqwest::RequestBuilder> {
+        Ok(self
+            .request(method, path)?
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body))
+    }
+
+    /// Sends the request and reads the response, the url, duration, status and byte counts are logged at debug level.
+    /// The callers run the requests in a span of the poll cycle or command they belong to.
+    /// The raw responses are saved when recording.
+    async fn execute(&self, request: reqwest::RequestBuilder) -> Result<ApiResponse> {
+        let request = request.build()?;
+        let (method, url) = (request.method().clone(), request.url().clone());
+        let sent = request.body().and_then(reqwest::Body::as_bytes).map_or(0, <[u8]>::len);
+        let start = Instant::now();
+
+        let result = match self.client.execute(request).await {
+            Ok(response) => {
+                let status = response.status();
+                let etag = response.headers().get(reqwest::header::ETAG).cloned();
+                response.bytes().await.map(|body| ApiResponse { status, etag, body })
+            }
+            Err(err) => Err(err),
+        };
+
+        if let (Some(recorder), Ok(response)) = (&self.recorder, &result) {
+            recorder.record(method.as_str(), url.path(), response.status.as_u16(), &response.body);
+        }
+
+        match &result {
+            Ok(response) => tracing::debug!(
+                %method,
+                %url,
+                status = response.status.as_u16(),
+                duration_ms = start.elapsed().as_millis() as u64,
+                sent,
+                received = response.body.len(),
+                "Api request completed"
+            ),
+            Err(err) => tracing::debug!(
+                %method,
+                %url,
+                duration_ms = start.elapsed().as_millis() as u64,
+                error = %err,
+                "Api request failed"
+            ),
+        }
+
+        let response = result?;
+        if response.status == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(Unauthorized.into());
+        }
+        Ok(response)
+    }
+
+    pub async fn perform_action(&self, node: u16, action: NodeAction) -> Result<()> {
+        let request = self.json_request(
+            reqwest::Method::POST,
+            &format!("/action/nodes/{}", node),
+            serde_json::to_string(&action)?,
+        )?;
+        let response = self.execute(request).await.context("Failed to perform node action")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to perform action '{}' of node {}: {}",
+            action.name(),
+            node,
+            response.status
+        );
+        Ok(())
+    }
+
+    /// Performs an action of the box itself (e.g. resetting the filter timer)
+    pub async fn perform_box_action(&self, action: NodeAction) -> Result<()> {
+        let request = self.json_request(reqwest::Method::POST, "/action", serde_json::to_string(&action)?)?;
+        let response = self.execute(request).await.context("Failed to perform box action")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to perform box action '{}': {}",
+            action.name(),
+            response.status
+        );
+        Ok(())
+    }
+
+    /// Changes a configuration parameter of the box, the key is the path of the parameter (e.g. "HeatRecovery/Bypass/TempSupTgt")
+    pub async fn set_config(&self, key: &str, val: i64) -> Result<()> {
+        let request = self.json_request(reqwest::Method::PATCH, "/config", config_body(key, val).to_string())?;
+        let response = self
+            .execute(request)
+            .await
+            .with_context(|| format!("Failed to change configuration parameter '{}'", key))?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to change configuration parameter '{}': {}",
+            key,
+            response.status
+        );
+        Ok(())
+    }
+
+    /// Changes a configuration parameter of a node (e.g. "Co2SetPoint")
+    pub async fn set_node_config(&self, node: u16, key: &str, val: i64) -> Result<()> {
+        let request = self.json_request(
+            reqwest::Method::PATCH,
+            &format!("/config/nodes/{}", node),
+            config_body(key, val).to_string(),
+        )?;
+        let response = self
+            .execute(request)
+            .await
+            .with_context(|| format!("Failed to change configuration parameter '{}' of node {}", key, node))?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to change configuration parameter '{}' of node {}: {}",
+            key,
+            node,
+            response.status
+        );
+        Ok(())
+    }
+
+    pub async fn get_device_info(&self) -> Result<DeviceInfo> {
+        let response = self
+            .execute(self.request(reqwest::Method::GET, "/info")?)
+            .await
+            .context("Failed to obtain device info")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to obtain device info: {}",
+            response.status
+        );
+        parse_device_info(&response.body)
+    }
+
+    /// With a cache an unchanged response is not parsed, the nodes of the previous response are returned instead
+    pub async fn get_nodes(&self, cache: Option<&NodesCache>) -> Result<Nodes> {
+        let mut request = self.request(reqwest::Method::GET, "/info/nodes")?;
+        // a recording needs the complete responses
+        if let Some(etag) = cache.filter(|_| !self.is_recording()).and_then(NodesCache::etag) {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        let response = self.execute(request).await.context("Failed to obtain nodes")?;
+        // not modified is the answer to the etag of the cache
+        ensure!(
+            response.status.is_success() || response.status == reqwest::StatusCode::NOT_MODIFIED,
+            "Failed to obtain nodes: {}",
+            response.status
+        );
+
+        let Some(cache) = cache else {
+            return Ok(Nodes {
+                nodes: parse_sorted_nodes(&response.body)?,
+                unchanged: false,
+            });
+        };
+
+        let digest = body_digest(&response.body);
+        if let Some(nodes) = cache.unchanged(response.status, digest) {
+            tracing::debug!("The nodes did not change since the previous poll");
+            return Ok(Nodes { nodes, unchanged: true });
+        }
+
+        let nodes = parse_sorted_nodes(&response.body)?;
+        cache.store(response.etag, digest, nodes.clone());
+        Ok(Nodes {
+            nodes,
+            unchanged: false,
+        })
+    }
+
+    /// The values of a single node, to verify the outcome of a command without requesting the whole network
+    pub async fn get_node(&self, node: u16) -> Result<NodeInfo> {
+        let response = self
+            .execute(self.request(reqwest::Method::GET, &format!("/info/nodes/{}", node))?)
+            .await
+            .with_context(|| format!("Failed to obtain node {}", node))?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to obtain node {}: {}",
+            node,
+            response.status
+        );
+        let info = parse_single_node(&response.body)?;
+        ensure!(
+            info.node == node,
+            "Requested node {}, received node {}",
+            node,
+            info.node
+        );
+        Ok(info)
+    }
+
+    pub async fn get_node_actions(&self) -> Result<Vec<NodeActions>> {
+        let response = self
+            .execute(self.request(reqwest::Method::GET, "/action/nodes")?)
+            .await
+            .context("Failed to obtain node actions")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to obtain node actions: {}",
+            response.status
+        );
+        let mut nodes = parse_node_actions(&response.body)?;
+        nodes.sort_by_key(|a| a.node);
+        Ok(nodes)
+    }
+
+    pub async fn get_node_config(&self) -> Result<Vec<NodeConfig>> {
+        let response = self
+            .execute(self.request(reqwest::Method::GET, "/config/nodes")?)
+            .await
+            .context("Failed to obtain node configuration")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to obtain node configuration: {}",
+            response.status
+        );
+        let mut nodes = parse_node_config(&response.body)?;
+        nodes.sort_by_key(|a| a.node);
+        Ok(nodes)
+    }
+
+    /// The configuration parameters of the box itself with the ranges the box accepts
+    pub async fn get_box_config(&self) -> Result<BoxConfig> {
+        let response = self
+            .execute(self.request(reqwest::Method::GET, "/config")?)
+            .await
+            .context("Failed to obtain box configuration")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to obtain box configuration: {}",
+            response.status
+        );
+        parse_box_config(&response.body)
+    }
+
+    /// The actions of the box itself, e.g. resetting the filter timer
+    pub async fn get_box_actions(&self) -> Result<BoxActions> {
+        let response = self
+            .execute(self.request(reqwest::Method::GET, "/action")?)
+            .await
+            .context("Failed to obtain box actions")?;
+        ensure!(
+            response.status.is_success(),
+            "Failed to obtain box actions: {}",
+            response.status
+        );
+        parse_box_actions(&response.body)
+    }
+}
+
+/// Nests the value in the groups of the parameter path: {"HeatRecovery":{"Bypass":{"TempSupTgt":{"Val":210}}}}
+fn config_body(key: &str, val: i64) -> serde_json::Value {
+    key.rsplit('/').fold(
+        serde_json::json!({ "Val": val }),
+        |value, group| serde_json::json!({ group: value }),
+    )
 }
 
-pub async fn get_node_actions(client: &reqwest::Client, addr: &str) -> Result<Vec<NodeActions>> {
-    let url = format!("https://{}/action/nodes", addr);
-    let response = client.get(&url).send().await.context("Failed to obtain node actions")?;
-    let json_data = response.bytes().await?;
-    let mut nodes = parse_node_actions(&json_data)?;
-    nodes.sort_by(|a, b| a.node.cmp(&b.node));
+fn parse_sorted_nodes(json_data: &[u8]) -> Result<Vec<NodeInfo>> {
+    let mut nodes = parse_node_info(json_data)?;
+    nodes.sort_by_key(|a| a.node);
     Ok(nodes)
 }
 
@@ -176,18 +716,22 @@ pub fn parse_node_info(json_data: &[u8]) -> Result<Vec<NodeInfo>> {
         bail!("Expected nodes to be an array: {:?}", json_nodes);
     };
 
-    node_values
-        .iter_mut()
-        .map(|node| -> Result<NodeInfo> {
-            let node = node.as_object_mut().ok_or_else(|| anyhow!("Invalid node object"))?;
-            Ok(NodeInfo {
-                node: parse_node_id(node.get("Node").ok_or_else(|| anyhow!("Missing node number"))?)?,
-                general: parse_status_map(GENERAL, node)?,
-                ventilation: parse_status_map(VENTILATION, node)?,
-                sensor: parse_optional_status_map(SENSOR, node)?,
-            })
-        })
-        .collect()
+    node_values.iter_mut().map(parse_node).collect()
+}
+
+/// A single node of /info/nodes/{node}, in the layout of the nodes of /info/nodes
+pub fn parse_single_node(json_data: &[u8]) -> Result<NodeInfo> {
+    parse_node(&mut serde_json::from_slice(json_data)?)
+}
+
+fn parse_node(node: &mut serde_json::Value) -> Result<NodeInfo> {
+    let node = node.as_object_mut().ok_or_else(|| anyhow!("Invalid node object"))?;
+    Ok(NodeInfo {
+        node: parse_node_id(node.get("Node").ok_or_else(|| anyhow!("Missing node number"))?)?,
+        general: parse_status_map(GENERAL, node)?,
+        ventilation: parse_status_map(VENTILATION, node)?,
+        sensor: parse_optional_status_map(SENSOR, node)?,
+    })
 }
 
 pub fn parse_device_info(json_data: &[u8]) -> Result<DeviceInfo> {
@@ -195,20 +739,20 @@ pub fn parse_device_info(json_data: &[u8]) -> Result<DeviceInfo> {
 
     let mut device_info = DeviceInfo {
         general: HashMap::new(),
+        json_keys: HashSet::new(),
     };
 
     for (&k, values) in data.iter_mut() {
-        if k == GENERAL || k == HEAT_RECOVERY {
-            for (group, val) in values.as_object().ok_or_else(|| anyhow!("Invalid general object"))? {
-                for (key, value) in val.as_object().ok_or_else(|| anyhow!("Invalid general object"))?.iter() {
-                    if value.is_array() {
-                        continue;
-                    }
-
-                    device_info.general.insert(
-                        format!("{}/{}/{}", k, group, key),
-                        serde_json::from_value(value.clone())?,
-                    );
+        if k == GENERAL || k == HEAT_RECOVERY || k == NETWORK_DUCO {
+            for (group, val) in values.as_object().ok_or_else(|| anyhow!("Invalid {} object", k))? {
+                // the sections contain groups of values, values directly in the section are kept as well
+                if val.is_array() || val.get("Val").is_some() {
+                    insert_device_value(&mut device_info, format!("{}/{}", k, group), val)?;
+                    continue;
+                }
+
+                for (key, value) in val.as_object().ok_or_else(|| anyhow!("Invalid {} object", k))?.iter() {
+                    insert_device_value(&mut device_info, format!("{}/{}/{}", k, group, key), value)?;
                 }
             }
         }
@@ -217,12 +761,91 @@ pub fn parse_device_info(json_data: &[u8]) -> Result<DeviceInfo> {
     Ok(device_info)
 }
 
+fn insert_device_value(device_info: &mut DeviceInfo, key: String, value: &serde_json::Value) -> Result<()> {
+    // arrays (e.g. the scanned wifi networks) are passed on as json, with or without a value object
+    let array = match value {
+        serde_json::Value::Array(_) => Some(value),
+        serde_json::Value::Object(obj) => obj.get("Val").filter(|val| val.is_array()),
+        _ => None,
+    };
+
+    if let Some(array) = array {
+        device_info
+            .general
+            .insert(key.clone(), StatusField::from(array.to_string().as_str()));
+        device_info.json_keys.insert(key);
+        return Ok(());
+    }
+
+    device_info.general.insert(key, serde_json::from_value(value.clone())?);
+    Ok(())
+}
+
 pub fn parse_node_actions(json_data: &[u8]) -> Result<Vec<NodeActions>> {
     let mut data: HashMap<&str, serde_json::Value> = serde_json::from_slice(json_data)?;
     let json_nodes = data.remove("Nodes").ok_or_else(|| anyhow!("Missing nodes list"))?;
     Ok(serde_json::from_value(json_nodes)?)
 }
 
+/// The entries of a node that are not a parameter with a range (e.g. read-only values) are left out
+pub fn parse_node_config(json_data: &[u8]) -> Result<Vec<NodeConfig>> {
+    let mut data: HashMap<&str, serde_json::Value> = serde_json::from_slice(json_data)?;
+    let json_nodes = data.remove("Nodes").ok_or_else(|| anyhow!("Missing nodes list"))?;
+    let serde_json::Value::Array(json_nodes) = json_nodes else {
+        bail!("Expected nodes to be an array: {:?}", json_nodes);
+    };
+
+    json_nodes
+        .into_iter()
+        .map(|json_node| {
+            let serde_json::Value::Object(mut json_node) = json_node else {
+                bail!("Expected a node object: {:?}", json_node);
+            };
+            let node = parse_node_id(&json_node.remove("Node").ok_or_else(|| anyhow!("Missing node id"))?)?;
+            let parameters = json_node
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, serde_json::from_value(value).ok()?)))
+                .collect();
+
+            Ok(NodeConfig { node, parameters })
+        })
+        .collect()
+}
+
+/// The parameters are nested in their groups, entries that are not a parameter with a range are left out
+pub fn parse_box_config(json_data: &[u8]) -> Result<BoxConfig> {
+    fn collect(path: &str, value: serde_json::Value, parameters: &mut BTreeMap<String, ConfigParameter>) {
+        let serde_json::Value::Object(entries) = value else {
+            return;
+        };
+        if entries.contains_key("Val") {
+            if let Ok(parameter) = serde_json::from_value(serde_json::Value::Object(entries)) {
+                parameters.insert(path.to_string(), parameter);
+            }
+            return;
+        }
+
+        for (name, value) in entries {
+            let path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+            collect(&path, value, parameters);
+        }
+    }
+
+    let data: serde_json::Value = serde_json::from_slice(json_data)?;
+    ensure!(data.is_object(), "Expected a configuration object: {:?}", data);
+    let mut parameters = BTreeMap::new();
+    collect("", data, &mut parameters);
+    Ok(BoxConfig { parameters })
+}
+
+pub fn parse_box_actions(json_data: &[u8]) -> Result<BoxActions> {
+    Ok(serde_json::from_slice(json_data)?)
+}
+
 impl<'de> serde::Deserialize<'de> for StatusValue {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
@@ -266,6 +889,36 @@ impl<'de> serde::Deserialize<'de> for StatusValue {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_single_node() {
+        let mut nodes: serde_json::Value =
+            serde_json::from_slice(include_bytes!("../test/data/info_nodes.json")).unwrap();
+        let node = parse_single_node(nodes["Nodes"][1].to_string().as_bytes()).unwrap();
+        assert_eq!(node.node, 2);
+        assert!(node.ventilation.contains_key("State"));
+
+        nodes["Nodes"][1].as_object_mut().unwrap().remove("General");
+        assert!(parse_single_node(nodes["Nodes"][1].to_string().as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_nodes_cache() {
+        let cache = NodesCache::default();
+        let body = include_bytes!("../test/data/info_nodes.json");
+        let digest = body_digest(body);
+        assert!(cache.unchanged(reqwest::StatusCode::OK, digest).is_none());
+
+        cache.store(None, digest, parse_sorted_nodes(body).unwrap());
+        assert_eq!(cache.unchanged(reqwest::StatusCode::OK, digest).unwrap().len(), 5);
+        assert!(cache.unchanged(reqwest::StatusCode::OK, body_digest(b"{}")).is_none());
+        assert!(
+            cache
+                .unchanged(reqwest::StatusCode::NOT_MODIFIED, body_digest(b""))
+                .is_some()
+        );
+        assert!(cache.etag().is_none());
+    }
+
     #[test]
     fn test_parse_node_info() {
         let json_repsonse = include_bytes!("../test/data/info_nodes.json");
@@ -291,6 +944,35 @@ mod tests {
             device.general["HeatRecovery/General/TimeFilterRemain"].val,
             StatusValue::Number(59)
         );
+        assert_eq!(
+            device.general["General/Lan/ScanWifi"].val,
+            StatusValue::String("[]".to_string())
+        );
+        assert_eq!(device.json_keys, HashSet::from(["General/Lan/ScanWifi".to_string()]));
+        assert_eq!(device.general["General/Lan/RssiWifi"].val, StatusValue::Number(-62));
+        assert_eq!(device.general["NetworkDuco/CommErrorCtr"].val, StatusValue::Number(0));
+
+        let device = parse_device_info(
+            br#"{"General":{"Lan":{"ScanWifi":[{"Ssid":"home","Rssi":-60}],"Zones":{"Val":[1,2]},"Ip":{"Val":"10.0.0.2"}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            device.general["General/Lan/ScanWifi"].val,
+            StatusValue::String(r#"[{"Rssi":-60,"Ssid":"home"}]"#.to_string())
+        );
+        assert_eq!(
+            device.general["General/Lan/Zones"].val,
+            StatusValue::String("[1,2]".to_string())
+        );
+        assert_eq!(device.json_keys.len(), 2);
+    }
+
+    #[test]
+    fn test_config_body() {
+        assert_eq!(
+            config_body("HeatRecovery/Bypass/TempSupTgt", 210).to_string(),
+            r#"{"HeatRecovery":{"Bypass":{"TempSupTgt":{"Val":210}}}}"#
+        );
     }
 
     #[test]
@@ -301,6 +983,55 @@ mod tests {
         assert_eq!(node_actions.len(), 5);
     }
 
+    #[test]
+    fn test_parse_node_config() {
+        let node_config = parse_node_config(include_bytes!("../test/data/node_config.json")).unwrap();
+        assert_eq!(node_config.len(), 5);
+        assert_eq!(
+            node_config[1].parameters,
+            HashMap::from([(
+                "Co2SetPoint".to_string(),
+                ConfigParameter {
+                    val: 800,
+                    min: 400,
+                    max: 2000,
+                    inc: Some(10),
+                }
+            )])
+        );
+        assert!(node_config[3].parameters.is_empty());
+    }
+
+    #[test]
+    fn test_parse_box_config() {
+        let box_config = parse_box_config(include_bytes!("../test/data/box_config.json")).unwrap();
+        assert_eq!(
+            box_config.parameters.keys().collect::<Vec<_>>(),
+            vec!["General/Time/TimeZone", "HeatRecovery/Bypass/TempSupTgt"]
+        );
+        assert_eq!(
+            box_config.parameters["HeatRecovery/Bypass/TempSupTgt"],
+            ConfigParameter {
+                val: 210,
+                min: 100,
+                max: 250,
+                inc: Some(5),
+            }
+        );
+
+        // values without a range are not parameters
+        let box_config = parse_box_config(br#"{"General":{"Board":{"BoxName":{"Val":"ENERGY"}}}}"#).unwrap();
+        assert!(box_config.parameters.is_empty());
+    }
+
+    #[test]
+    fn test_parse_box_actions() {
+        let box_actions = parse_box_actions(include_bytes!("../test/data/box_actions.json")).unwrap();
+        assert_eq!(box_actions.actions.len(), 4);
+        assert_eq!(box_actions.actions[0].action, "ResetFilterTimeRemain");
+        assert_eq!(box_actions.actions[2].values.as_ref().unwrap().len(), 2);
+    }
+
     #[test]
     fn test_node_value_compare() {
         let n1 = StatusValue::Number(1);
@@ -1,20 +1,62 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::anyhow;
 
 use crate::{
-    ducoapi::{self, DeviceInfo, StatusField, StatusValue},
+    Result,
+    ducoapi::{self, BoxActions, BoxConfig, ConfigParameter, DeviceInfo, NodeAction, StatusField, StatusValue},
+    ducoboxnode::{self, DucoNodeAction},
     infovalue::{InfoValue, UNKNOWN},
     mqtt::MqttData,
-    Result,
+    valuetransform::ValueTransform,
 };
 
+/// Supply temperature target of the heat recovery (0.1°C), writable through the configuration api
+pub const SUPPLY_TEMPERATURE_TARGET: &str = "HeatRecovery/Bypass/TempSupTgt";
+/// Two-zone boxes have a supply temperature target per zone (0.1°C), the configuration parameters of the heat recovery
+/// with a name that starts with this (e.g. `TempSupTgtZone1`). The commands use the name of the parameter.
+pub const SUPPLY_TEMPERATURE_TARGET_ZONE: &str = "TempSupTgtZone";
+/// Position of the heat recovery bypass (%), an open bypass brings in the outside air without recovering heat
+pub const BYPASS_POSITION: &str = "HeatRecovery/Bypass/Pos";
+
+/// Remaining days before the filters need to be replaced
+pub const FILTER_REMAINING_DAYS: &str = "HeatRecovery/General/TimeFilterRemain";
+/// Box action that restarts the filter timer after replacing the filters
+pub const RESET_FILTER_ACTION: &str = "ResetFilterTimeRemain";
+
+/// Serial number of the box, confirms the reboot command
+pub const SERIAL_NUMBER: &str = "General/Board/SerialBoardBox";
+/// Box action that restarts the connectivity board, e.g. when its web server stops responding
+pub const REBOOT_ACTION: &str = "Reboot";
+
+/// The wifi networks found by the connectivity board, a json array
+pub const WIFI_NETWORKS: &str = "General/Lan/ScanWifi";
+/// Signal strength of the wifi connection of the connectivity board (dBm)
+pub const WIFI_SIGNAL: &str = "General/Lan/RssiWifi";
+/// MAC address of the connectivity board, identifies the ventilation device on the network
+pub const MAC_ADDRESS: &str = "General/Lan/Mac";
+/// Time since the connectivity board started (s)
+pub const UPTIME: &str = "General/Board/UpTime";
+/// Diagnostics of the duco rf network (e.g. the communication error counter)
+pub const NETWORK_DUCO: &str = "NetworkDuco";
+
 pub struct DucoBoxDevice {
     status: HashMap<String, InfoValue>,
+    /// The keys of the values that are published as a json array
+    json_keys: HashSet<String>,
+    /// The actions of the box itself, `None` until they are requested
+    actions: Option<Vec<DucoNodeAction>>,
+    /// The configuration parameters of the box, empty until they are requested
+    config: BTreeMap<String, ConfigParameter>,
 }
 
 impl DucoBoxDevice {
     pub fn new() -> Self {
         Self {
             status: HashMap::default(),
+            json_keys: HashSet::default(),
+            actions: None,
+            config: BTreeMap::new(),
         }
     }
 
@@ -24,17 +66,98 @@ impl DucoBoxDevice {
         }
     }
 
-    pub fn topics_that_need_updating(&mut self) -> Vec<MqttData> {
-        self.status
+    pub fn topics_that_need_updating(&mut self, value_transform: &ValueTransform) -> Vec<MqttData> {
+        let mut topics: Vec<MqttData> = self
+            .status
             .iter_mut()
             .filter(|(_key, value)| value.is_modified())
             .map(|(key, value)| MqttData {
                 topic: key.clone(),
-                payload: value.get_and_reset().to_string(),
+                payload: value_transform.payload(key, &value.get_and_reset()),
+            })
+            .collect();
+
+        topics.sort();
+        topics
+    }
+
+    pub fn status_keys(&self) -> Vec<&String> {
+        let mut keys: Vec<&String> = self.status.keys().collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn status_value(&self, key: &str) -> Option<&StatusValue> {
+        self.status.get(key).map(InfoValue::value)
+    }
+
+    /// The value is an array of the device info (e.g. the scanned wifi networks), it is published as json
+    pub fn is_json_value(&self, key: &str) -> bool {
+        self.json_keys.contains(key)
+    }
+
+    /// The supply temperature targets of the zones the box reports, only two-zone boxes have them
+    pub fn supply_temperature_zones(&self) -> Vec<&str> {
+        self.config
+            .keys()
+            .filter(|key| key.starts_with("HeatRecovery/"))
+            .filter(|key| {
+                key.rsplit('/')
+                    .next()
+                    .is_some_and(|name| name.starts_with(SUPPLY_TEMPERATURE_TARGET_ZONE))
             })
+            .map(String::as_str)
             .collect()
     }
 
+    /// Stores the configuration parameters of the box, returns true when the parameters or their ranges changed
+    pub fn set_config(&mut self, config: BoxConfig) -> bool {
+        let range = |parameter: &ConfigParameter| (parameter.min, parameter.max, parameter.inc);
+        let changed = !config.parameters.keys().eq(self.config.keys())
+            || config
+                .parameters
+                .iter()
+                .any(|(key, parameter)| self.config.get(key).map(range) != Some(range(parameter)));
+        self.config = config.parameters;
+        changed
+    }
+
+    /// A configuration parameter of the box by its path (e.g. "HeatRecovery/Bypass/TempSupTgt")
+    pub fn config_parameter(&self, key: &str) -> Option<&ConfigParameter> {
+        self.config.get(key)
+    }
+
+    pub fn has_heat_recovery(&self) -> bool {
+        self.status.keys().any(|key| key.starts_with("HeatRecovery/"))
+    }
+
+    /// The box actions with a value type the bridge does not support (e.g. setting the time) are left out
+    pub fn set_actions(&mut self, actions: BoxActions) {
+        self.actions = Some(
+            actions
+                .actions
+                .into_iter()
+                .filter_map(|action| {
+                    let name = action.action.clone();
+                    DucoNodeAction::try_from(action)
+                        .inspect_err(|err| tracing::debug!("Box action '{}' is not supported: {:#}", name, err))
+                        .ok()
+                })
+                .collect(),
+        );
+    }
+
+    /// Validates the command against the actions of the box and creates the action to send to the duco box
+    pub fn action_for_command(&self, action_name: &str, data: &str) -> Result<NodeAction> {
+        let actions = self
+            .actions
+            .as_ref()
+            .ok_or_else(|| anyhow!("The actions of the box are not known yet"))?;
+
+        ducoboxnode::validated_action(actions, action_name, data)
+            .unwrap_or_else(|| Err(anyhow!("Invalid box action: '{}'", action_name)))
+    }
+
     fn merge_status_values(&mut self, values: HashMap<String, StatusField>) {
         for (name, value) in values {
             match self.status.get_mut(&name) {
@@ -47,6 +170,7 @@ impl DucoBoxDevice {
     }
 
     pub fn update_status(&mut self, dev: DeviceInfo) {
+        self.json_keys.extend(dev.json_keys);
         self.merge_status_values(dev.general);
     }
 }
@@ -1,32 +1,119 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
 
 use crate::{
-    Error, Result,
+    Result,
     ducoapi::{
-        self, NodeActionDescription, NodeActions, NodeBoolAction, NodeEnumAction, NodeInfo, StatusField, StatusValue,
+        self, ConfigParameter, NodeAction, NodeActionDescription, NodeActions, NodeBoolAction, NodeConfig,
+        NodeEnumAction, NodeInfo, StatusField, StatusValue,
     },
-    duconodetypes::NodeType,
+    duconodetypes::{Capability, NodeType},
     infovalue::{InfoValue, UNKNOWN},
     mqtt::MqttData,
+    naming::{self, NamingStrategy},
+    nodemodel::{NodeModel, VentilationState},
+    statusfilter::StatusFilter,
+    valuetransform::ValueTransform,
 };
 
 use anyhow::{anyhow, bail};
+use serde::Deserialize;
 
 pub const GENERAL: &str = "General";
 pub const VENTILATION: &str = "Ventilation";
 pub const SENSOR: &str = "Sensor";
 pub const HEAT_RECOVERY: &str = "HeatRecovery";
+/// Status group of the configuration parameters (`duco_node_<nr>/Config/<parameter>`), also the command action that
+/// changes them (`duco_node_<nr>/cmnd/Config/<parameter>`)
+pub const CONFIG: &str = "Config";
+/// CO2 concentration (ppm) the ventilation of a CO2 valve or sensor aims for
+pub const CO2_SETPOINT: &str = "Co2SetPoint";
+/// Relative humidity (%) the ventilation of a humidity valve aims for
+pub const RH_SETPOINT: &str = "RhSetPoint";
+/// Rise of the relative humidity (%) that triggers extra ventilation, e.g. when taking a shower
+pub const RH_DELTA: &str = "RhDelta";
+/// Topic of the node (`duco_node_<nr>/values`) with all its status values as a single json document
+pub const JSON_STATE: &str = "values";
+/// Topic of the node (`duco_node_<nr>/last_seen`) with the time of the last poll that reported a changed value of the
+/// node
+pub const LAST_SEEN: &str = "last_seen";
+
+pub const SET_VENTILATION_STATE: &str = "SetVentilationState";
+/// Status key of the current ventilation state, the box can report states that are not a [`VentilationState`]
+pub const VENTILATION_STATE_KEY: &str = "Ventilation/State";
+pub const SET_IDENTIFY: &str = "SetIdentify";
+/// Virtual actions (PermanentMode1..3) that toggle between AUTO and the matching permanent state (CNT1..3)
+pub const PERMANENT_MODE_ACTION: &str = "PermanentMode";
+pub const PERMANENT_MODE_STATE: &str = "CNT";
+/// Virtual action that sets the ventilation speed as a percentage, translated onto the manual states (MAN1..3)
+pub const FAN_SPEED_ACTION: &str = "FanSpeed";
+pub const MANUAL_STATE: &str = "MAN";
+/// Automatic ventilation with a temporary boost (AUT1..3), the boost only lasts the default duration of the box
+pub const AUTO_BOOST_STATE: &str = "AUT";
+pub const AUTO_STATE: &str = "AUTO";
+pub const EMPTY_HOUSE_STATE: &str = "EMPT";
+/// Default duration in seconds of the timed manual states, the x2 and x3 variants last two or three times as long
+pub const TIMED_STATE_DURATION: u32 = 900;
+
+/// Command payload of the SetVentilationState action that optionally specifies a duration in seconds
+#[derive(Deserialize)]
+struct TimedStateCommand {
+    state: String,
+    duration: Option<u32>,
+}
 
+#[derive(PartialEq)]
 pub enum DucoNodeAction {
     SetBoolean(String),
     SetEnum(String, Vec<String>),
 }
 
+impl DucoNodeAction {
+    pub fn name(&self) -> &str {
+        match self {
+            DucoNodeAction::SetBoolean(name) | DucoNodeAction::SetEnum(name, _) => name,
+        }
+    }
+}
+
+/// Validates the value against the action with the name and creates the action to send to the duco box, None when
+/// the actions do not have an action with the name
+pub fn validated_action(actions: &[DucoNodeAction], action_name: &str, data: &str) -> Option<Result<NodeAction>> {
+    let action = actions.iter().find(|action| action.name() == action_name)?;
+
+    Some(match action {
+        DucoNodeAction::SetEnum(_, values) if values.iter().any(|value| value == data) => {
+            Ok(NodeAction::Enum(NodeEnumAction {
+                action: action_name.to_string(),
+                val: data.to_string(),
+            }))
+        }
+        DucoNodeAction::SetBoolean(_) if data == "1" || data == "0" => Ok(NodeAction::Bool(NodeBoolAction {
+            action: action_name.to_string(),
+            val: data == "1",
+        })),
+        _ => Err(anyhow!("Invalid value for action '{}': '{}'", action_name, data)),
+    })
+}
+
 pub struct DucoBoxNode {
     number: u16,
     node_type: NodeType,
     status: HashMap<String, InfoValue>,
     actions: Vec<DucoNodeAction>,
+    /// The configuration parameters that can be changed, with the range the box accepts
+    config: BTreeMap<String, ConfigParameter>,
+    naming: Arc<dyn NamingStrategy>,
+    /// Another node has the same topic level, the node keeps the default `duco_node_<nr>` level
+    shared_topic: bool,
+    status_filter: StatusFilter,
+    value_transform: ValueTransform,
+    /// The box keeps reporting the last values of a node that stopped responding, only a changed value shows that the
+    /// node is still in contact with the box
+    last_seen: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl DucoBoxNode {
@@ -36,9 +123,60 @@ impl DucoBoxNode {
             node_type,
             status: HashMap::default(),
             actions: Vec::default(),
+            config: BTreeMap::default(),
+            naming: naming::default_strategy(),
+            shared_topic: false,
+            status_filter: StatusFilter::default(),
+            value_transform: ValueTransform::default(),
+            last_seen: None,
+        }
+    }
+
+    pub fn set_naming(&mut self, naming: Arc<dyn NamingStrategy>) {
+        self.naming = naming;
+    }
+
+    pub fn set_shared_topic(&mut self, shared_topic: bool) {
+        self.shared_topic = shared_topic;
+    }
+
+    pub fn has_shared_topic(&self) -> bool {
+        self.shared_topic
+    }
+
+    /// The node level the topics of the node get from the naming, without the default level for shared levels
+    pub fn named_topic(&self) -> String {
+        self.naming.node_topic(self.number, self.name().as_deref())
+    }
+
+    /// Selects the status values that are published, all values are kept (e.g. the name determines the topics)
+    pub fn set_status_filter(&mut self, status_filter: StatusFilter) {
+        self.status_filter = status_filter;
+    }
+
+    pub fn set_value_transform(&mut self, value_transform: ValueTransform) {
+        self.value_transform = value_transform;
+    }
+
+    /// The node level of the topics of the node (e.g. `duco_node_<nr>`)
+    pub fn topic(&self) -> String {
+        if self.shared_topic {
+            self.naming.node_topic(self.number, None)
+        } else {
+            self.named_topic()
         }
     }
 
+    /// Topic of a status key of the node, relative to the base topic (e.g. `duco_node_<nr>/Ventilation/State`)
+    pub fn status_topic(&self, key: &str) -> String {
+        format!("{}/{}", self.topic(), self.naming.status_key(key))
+    }
+
+    /// Topic of a command of the node, relative to the base topic (`<node>/cmnd/<action>`)
+    pub fn command_topic(&self, action: &str) -> String {
+        format!("{}/cmnd/{}", self.topic(), action)
+    }
+
     pub fn node_type(&self) -> NodeType {
         self.node_type
     }
@@ -47,6 +185,14 @@ impl DucoBoxNode {
         self.number
     }
 
+    pub fn actions(&self) -> &[DucoNodeAction] {
+        &self.actions
+    }
+
+    pub fn has_action(&self, action_name: &str) -> bool {
+        self.actions.iter().any(|action| action.name() == action_name)
+    }
+
     pub fn reset(&mut self) {
         for (_key, value) in self.status.iter_mut() {
             value.set(StatusValue::String(UNKNOWN.to_string()))
@@ -55,155 +201,433 @@ impl DucoBoxNode {
 
     pub fn topics_that_need_updating(&mut self) -> Vec<MqttData> {
         let mut topics = Vec::new();
+        let node_topic = self.topic();
 
         for (key, value) in self.status.iter_mut() {
+            if !self.status_filter.is_allowed(key) {
+                continue;
+            }
+
             if value.is_modified() {
                 let val = value.get_and_reset();
                 topics.push(MqttData {
-                    topic: DucoBoxNode::status_topic(self.number, key),
-                    payload: val.to_string(),
+                    topic: format!("{}/{}", node_topic, self.naming.status_key(key)),
+                    payload: self.value_transform.payload(key, &val),
                 });
             }
         }
 
+        topics.sort();
         topics
     }
 
+    /// The valid values of every enum action as a json array (`duco_node_<nr>/actions/<action>/options`),
+    /// so consumers without home assistant know which values a command accepts
+    pub fn action_option_topics(&self) -> Result<Vec<MqttData>> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action {
+                DucoNodeAction::SetEnum(name, values) => Some((name, values)),
+                DucoNodeAction::SetBoolean(_) => None,
+            })
+            .map(|(name, values)| {
+                Ok(MqttData {
+                    topic: format!("{}/actions/{}/options", self.topic(), name),
+                    payload: serde_json::to_string(values)?,
+                })
+            })
+            .collect()
+    }
+
+    /// All status values as a single json document, grouped like the status topics ({"Ventilation":{"State":"AUTO"}}).
+    /// Only returned when a value changed since the previous call.
+    pub fn json_state_if_modified(&mut self) -> Result<Option<MqttData>> {
+        if self.topics_that_need_updating().is_empty() {
+            return Ok(None);
+        }
+
+        let mut state = serde_json::Map::new();
+        for key in self.status_keys() {
+            let Some(value) = self.status_value(key) else {
+                continue;
+            };
+            let value = self.value_transform.json_value(key, value);
+            let key = self.naming.status_key(key);
+            let Some((group, name)) = key.split_once('/') else {
+                continue;
+            };
+
+            state
+                .entry(group)
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))[name] = value;
+        }
+
+        Ok(Some(MqttData {
+            topic: self.status_topic(JSON_STATE),
+            payload: serde_json::to_string(&state)?,
+        }))
+    }
+
+    /// The SubType the node reports, refines the capabilities of the node type
+    pub fn sub_type(&self) -> Option<i64> {
+        match self.status_value("General/SubType") {
+            Some(StatusValue::Number(sub_type)) => Some(*sub_type),
+            _ => None,
+        }
+    }
+
+    /// The name the node was given in the duco installation, if any
+    pub fn name(&self) -> Option<String> {
+        match self.status_value("General/Name") {
+            Some(StatusValue::String(name)) if !name.is_empty() && name != UNKNOWN => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    /// None for nodes without a ventilation state (e.g. sensors report '-') or a state this version does not know
+    pub fn ventilation_state(&self) -> Option<VentilationState> {
+        match self.status_value(VENTILATION_STATE_KEY) {
+            Some(StatusValue::String(state)) => VentilationState::from_str(state).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn time_state_remaining(&self) -> Option<u32> {
+        self.number_value("Ventilation/TimeStateRemain")
+    }
+
+    pub fn flow_level_target(&self) -> Option<u8> {
+        self.number_value("Ventilation/FlowLvlTgt")
+    }
+
+    pub fn co2_ppm(&self) -> Option<u32> {
+        self.number_value("Sensor/Co2")
+    }
+
+    pub fn iaq_co2(&self) -> Option<u8> {
+        self.number_value("Sensor/IaqCo2")
+    }
+
+    /// The typed values of the node
+    pub fn model(&self) -> NodeModel {
+        NodeModel {
+            number: self.number,
+            node_type: self.node_type.to_string(),
+            name: self.name(),
+            ventilation_state: self.ventilation_state(),
+            time_state_remaining: self.time_state_remaining(),
+            flow_level_target: self.flow_level_target(),
+            co2_ppm: self.co2_ppm(),
+            iaq_co2: self.iaq_co2(),
+        }
+    }
+
+    /// A numeric status value, None when it is missing or out of range for the type
+    fn number_value<T: TryFrom<i64>>(&self, key: &str) -> Option<T> {
+        match self.status_value(key) {
+            Some(StatusValue::Number(val)) => T::try_from(*val).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn capabilities(&self) -> &'static [Capability] {
+        self.node_type.capabilities(self.sub_type())
+    }
+
+    /// Identifies the component independent of its node number, which changes when the installer re-pairs the
+    /// components: the serial number when the node reports one, otherwise the type and the name it was given
+    pub fn identity(&self) -> Option<String> {
+        let value = |key: &str| {
+            self.status_value(key)
+                .map(|val| val.to_string())
+                .filter(|val| !val.is_empty() && val != UNKNOWN)
+        };
+
+        let serial = self
+            .status
+            .keys()
+            .find(|key| key.starts_with("General/Serial"))
+            .and_then(|key| value(key));
+
+        match serial {
+            Some(serial) => Some(format!("serial:{}", serial)),
+            None => value("General/Name").map(|name| format!("{}:{}", self.node_type, name)),
+        }
+    }
+
+    /// All the retained topics the node publishes its values on
+    pub fn published_topics(&self) -> Result<Vec<String>> {
+        let mut topics: Vec<String> = self
+            .status_keys()
+            .into_iter()
+            .map(|key| self.status_topic(key))
+            .collect();
+        topics.extend(self.action_option_topics()?.into_iter().map(|data| data.topic));
+        Ok(topics)
+    }
+
+    /// The published status keys reported by the node in sorted order, the status filter applies
+    pub fn status_keys(&self) -> Vec<&String> {
+        let mut keys: Vec<&String> = self
+            .status
+            .keys()
+            .filter(|key| self.status_filter.is_allowed(key))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Current value of a status key (e.g. "Ventilation/State"), also when the status filter does not publish it
+    pub fn status_value(&self, key: &str) -> Option<&StatusValue> {
+        self.status.get(key).map(InfoValue::value)
+    }
+
+    /// Current value of a status key that is published
+    pub fn published_value(&self, key: &str) -> Option<&StatusValue> {
+        self.status_value(key).filter(|_| self.status_filter.is_allowed(key))
+    }
+
     pub fn valid_action_values(&self, action_name: &str) -> Result<&[String]> {
         for action in &self.actions {
-            if let DucoNodeAction::SetEnum(name, enum_values) = action {
-                if name == action_name {
-                    return Ok(enum_values);
-                }
+            if let DucoNodeAction::SetEnum(name, enum_values) = action
+                && name == action_name
+            {
+                return Ok(enum_values);
             }
         }
 
         Err(anyhow!("No valid values found for action '{}'", action_name))
     }
 
-    fn status_topic(node_number: u16, topic: &str) -> String {
-        format!("duco_node_{}/{}", node_number, topic)
-    }
-
-    fn merge_status_values(&mut self, sub_topic: &str, values: HashMap<String, StatusField>) {
+    /// Returns true when one of the values differs from the previous value
+    fn merge_status_values(&mut self, sub_topic: &str, values: HashMap<String, StatusField>) -> bool {
+        let mut changed = false;
         for (name, value) in values {
             let key = format!("{sub_topic}/{name}");
             if let Some(info_value) = self.status.get_mut(&key) {
+                changed |= *info_value.value() != value.val;
                 info_value.set(value.val);
             } else {
                 self.status.insert(key, InfoValue::new(value.val));
+                changed = true;
             }
         }
+
+        changed
     }
 
     pub fn update_status(&mut self, node: NodeInfo) -> Result<()> {
         assert_eq!(self.number, node.node, "Node number mismatch");
 
-        self.merge_status_values(GENERAL, node.general);
-        self.merge_status_values(VENTILATION, node.ventilation);
+        let mut changed = self.merge_status_values(GENERAL, node.general);
+        changed |= self.merge_status_values(VENTILATION, node.ventilation);
         if let Some(sensor) = node.sensor {
-            self.merge_status_values(SENSOR, sensor);
+            changed |= self.merge_status_values(SENSOR, sensor);
+        }
+
+        if changed {
+            self.last_seen = Some(chrono::Utc::now());
         }
 
         Ok(())
     }
 
-    pub fn set_actions(&mut self, actions: NodeActions) -> Result<()> {
-        self.actions = actions
+    /// Time of the last update that changed a value of the node
+    pub fn last_seen(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.last_seen
+    }
+
+    /// Returns true when the actions differ from the previous actions of the node
+    pub fn set_actions(&mut self, actions: NodeActions) -> Result<bool> {
+        let actions = actions
             .actions
             .into_iter()
             .map(DucoNodeAction::try_from)
             .collect::<Result<Vec<_>>>()?;
 
-        Ok(())
+        let changed = actions != self.actions;
+        self.actions = actions;
+        Ok(changed)
+    }
+
+    /// The configuration parameters the bridge exposes for the capabilities of the node
+    fn supported_config_parameters(&self) -> Vec<&'static str> {
+        self.capabilities()
+            .iter()
+            .flat_map(|capability| match capability {
+                Capability::Co2Sensor => &[CO2_SETPOINT][..],
+                Capability::HumiditySensor => &[RH_SETPOINT, RH_DELTA][..],
+                _ => &[][..],
+            })
+            .copied()
+            .collect()
     }
 
-    fn verify_enum_action_is_valid(&self, action: &NodeEnumAction) -> Result<()> {
-        for node_action in &self.actions {
-            if let DucoNodeAction::SetEnum(action_name, values) = node_action {
-                if action_name == &action.action {
-                    if !values.contains(&action.val) {
-                        bail!("Invalid value for action '{}': '{}'", action.action, action.val);
-                    }
+    pub fn has_config_parameters(&self) -> bool {
+        !self.supported_config_parameters().is_empty()
+    }
 
-                    return Ok(());
-                }
-            }
+    /// Keeps the supported configuration parameters and publishes their values in the config group, returns true when
+    /// the parameters or their ranges changed (the discovery documents of the parameters are affected)
+    pub fn set_config(&mut self, config: NodeConfig) -> bool {
+        let supported = self.supported_config_parameters();
+        let config: BTreeMap<String, ConfigParameter> = config
+            .parameters
+            .into_iter()
+            .filter(|(name, _)| supported.contains(&name.as_str()))
+            .collect();
+        let range = |parameter: &ConfigParameter| (parameter.min, parameter.max, parameter.inc);
+        let changed = !config.keys().eq(self.config.keys())
+            || config
+                .iter()
+                .any(|(name, parameter)| self.config.get(name).map(range) != Some(range(parameter)));
+        self.config = config;
+
+        for (name, parameter) in self.config.clone() {
+            self.set_config_value(&name, parameter.val);
         }
+        changed
+    }
 
-        Err(anyhow!("Invalid action for node {}: '{}'", self.number, action.action))
+    pub fn config_parameter(&self, name: &str) -> Option<&ConfigParameter> {
+        self.config.get(name)
     }
 
-    fn verify_bool_action_is_valid(&self, action: &NodeBoolAction) -> Result<()> {
-        for node_action in &self.actions {
-            if let DucoNodeAction::SetBoolean(action_name) = node_action {
-                if *action_name == action.action {
-                    return Ok(());
-                }
-            }
+    /// Updates the value of a configuration parameter after it was changed
+    pub fn set_config_value(&mut self, name: &str, val: i64) {
+        if let Some(parameter) = self.config.get_mut(name) {
+            parameter.val = val;
         }
 
-        Err(anyhow!("Invalid action for node {}: '{}'", self.number, action.action))
-    }
-
-    pub async fn process_command(
-        &self,
-        action_name: String,
-        data: String,
-        client: &reqwest::Client,
-        addr: &str,
-    ) -> Result<()> {
-        if let Some(action) = self.actions.iter().find(|action| match action {
-            DucoNodeAction::SetBoolean(name) => *name == action_name,
-            DucoNodeAction::SetEnum(name, _) => *name == action_name,
-        }) {
-            match action {
-                DucoNodeAction::SetEnum(_, _) => {
-                    let action = NodeEnumAction {
-                        action: action_name,
-                        val: data,
-                    };
-
-                    self.process_enum_command(action, client, addr).await?;
-                }
-                DucoNodeAction::SetBoolean(_) => {
-                    if data != "1" && data != "0" {
-                        bail!("Invalid value for action '{}': '{}'", action_name, data);
-                    }
-
-                    let action = NodeBoolAction {
-                        action: action_name,
-                        val: data == "1",
-                    };
-
-                    self.process_bool_command(action, client, addr).await?;
-                }
+        let key = format!("{}/{}", CONFIG, name);
+        match self.status.get_mut(&key) {
+            Some(info_value) => info_value.set(StatusValue::Number(val)),
+            None => {
+                self.status.insert(key, InfoValue::new(StatusValue::Number(val)));
             }
-        } else {
-            bail!("Invalid action for node {}: '{}'", self.number, action_name);
         }
+    }
 
-        Ok(())
+    /// Validates the value of a configuration parameter command against the range the box reports
+    pub fn config_value_for_command(&self, name: &str, data: &str) -> Result<i64> {
+        let parameter = self
+            .config_parameter(name)
+            .ok_or_else(|| anyhow!("Invalid configuration parameter for node {}: '{}'", self.number, name))?;
+        // home assistant publishes the value of a number entity as a float
+        let val = data
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Invalid value for configuration parameter '{}': '{}'", name, data))?
+            .round() as i64;
+        parameter.validate(name, val)?;
+        Ok(val)
+    }
+
+    /// Translates the virtual permanent mode actions onto the SetVentilationState action
+    fn permanent_mode_action(action_name: &str, data: &str) -> Option<Result<NodeEnumAction>> {
+        let level = action_name.strip_prefix(PERMANENT_MODE_ACTION)?;
+
+        Some(match data {
+            "ON" | "1" => Ok(NodeEnumAction {
+                action: SET_VENTILATION_STATE.to_string(),
+                val: format!("{}{}", PERMANENT_MODE_STATE, level),
+            }),
+            "OFF" | "0" => Ok(NodeEnumAction {
+                action: SET_VENTILATION_STATE.to_string(),
+                val: AUTO_STATE.to_string(),
+            }),
+            _ => Err(anyhow!("Invalid value for action '{}': '{}'", action_name, data)),
+        })
+    }
+
+    /// Translates the virtual fan speed action onto the SetVentilationState action, 0% is the empty house state
+    fn fan_speed_action(action_name: &str, data: &str) -> Option<Result<NodeEnumAction>> {
+        if action_name != FAN_SPEED_ACTION {
+            return None;
+        }
+
+        let state = match data.parse::<u8>() {
+            Ok(0) => EMPTY_HOUSE_STATE.to_string(),
+            Ok(1..=33) => format!("{}1", MANUAL_STATE),
+            Ok(34..=66) => format!("{}2", MANUAL_STATE),
+            Ok(67..=100) => format!("{}3", MANUAL_STATE),
+            _ => return Some(Err(anyhow!("Invalid value for action '{}': '{}'", action_name, data))),
+        };
+
+        Some(Ok(NodeEnumAction {
+            action: SET_VENTILATION_STATE.to_string(),
+            val: state,
+        }))
+    }
+
+    /// Translates a ventilation state with an optional duration in seconds (`{"state":"MAN3","duration":1800}`)
+    /// onto the manual or boost state with the nearest duration
+    fn timed_state_action(action_name: &str, data: &str) -> Option<Result<NodeEnumAction>> {
+        if action_name != SET_VENTILATION_STATE || !data.trim_start().starts_with('{') {
+            return None;
+        }
+
+        Some(DucoBoxNode::timed_state(data).map(|state| NodeEnumAction {
+            action: SET_VENTILATION_STATE.to_string(),
+            val: state,
+        }))
+    }
+
+    fn timed_state(data: &str) -> Result<String> {
+        let command: TimedStateCommand =
+            serde_json::from_str(data).map_err(|err| anyhow!("Invalid timed ventilation state '{}': {}", data, err))?;
+
+        let Some(duration) = command.duration else {
+            return Ok(command.state);
+        };
+
+        let (prefix, level) = [MANUAL_STATE, AUTO_BOOST_STATE]
+            .into_iter()
+            .find_map(|prefix| {
+                let level = command.state.strip_prefix(prefix)?;
+                matches!(level, "1" | "2" | "3").then_some((prefix, level))
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "Only the manual and boost states can be given a duration: '{}'",
+                    command.state
+                )
+            })?;
+        if duration == 0 {
+            bail!("Invalid duration for state '{}': 0s", command.state);
+        }
+
+        // the nearest of the durations the box supports, longer durations are capped at three times the default
+        let multiple = match prefix {
+            AUTO_BOOST_STATE => 1,
+            _ => (duration.saturating_add(TIMED_STATE_DURATION / 2) / TIMED_STATE_DURATION).clamp(1, 3),
+        };
+        Ok(match multiple {
+            1 => format!("{}{}", prefix, level),
+            _ => format!("{}{}x{}", prefix, level, multiple),
+        })
     }
 
-    pub async fn process_enum_command(
-        &self,
-        action: NodeEnumAction,
-        client: &reqwest::Client,
-        addr: &str,
-    ) -> Result<()> {
-        self.verify_enum_action_is_valid(&action)?;
-        ducoapi::perform_action(client, addr, self.number(), action).await
+    /// Validates the command against the actions of the node and creates the action to send to the duco box
+    pub fn action_for_command(&self, action_name: String, data: String) -> Result<NodeAction> {
+        let (action_name, data) = match DucoBoxNode::permanent_mode_action(&action_name, &data)
+            .or_else(|| DucoBoxNode::fan_speed_action(&action_name, &data))
+            .or_else(|| DucoBoxNode::timed_state_action(&action_name, &data))
+        {
+            Some(action) => {
+                let action = action?;
+                (action.action, action.val)
+            }
+            None => (action_name, data),
+        };
+
+        validated_action(&self.actions, &action_name, &data)
+            .unwrap_or_else(|| Err(anyhow!("Invalid action for node {}: '{}'", self.number, action_name)))
     }
 
-    pub async fn process_bool_command(
-        &self,
-        action: NodeBoolAction,
-        client: &reqwest::Client,
-        addr: &str,
-    ) -> Result<()> {
-        self.verify_bool_action_is_valid(&action)?;
-        ducoapi::perform_action(client, addr, self.number(), action).await
+    pub fn verify_command(&self, action_name: &str, data: &str) -> Result<()> {
+        self.action_for_command(action_name.to_string(), data.to_string())
+            .map(|_| ())
     }
 }
 
@@ -214,14 +638,13 @@ impl TryFrom<ducoapi::NodeInfo> for DucoBoxNode {
         let StatusValue::String(ref type_str) = node_info
             .general
             .get("Type")
-            .ok_or_else(|| Error::Runtime("Type missing".to_string()))?
+            .ok_or_else(|| anyhow!("Type missing"))?
             .val
         else {
             bail!("Node type is not a string value");
         };
 
-        let node_type =
-            NodeType::from_str(type_str).map_err(|_| Error::Runtime(format!("Unknown node type: {}", type_str)))?;
+        let node_type = NodeType::from_str(type_str).map_err(|_| anyhow!("Unknown node type: {}", type_str))?;
 
         let mut node = DucoBoxNode::create_for_node_type(node_type, node_info.node);
         node.update_status(node_info)?;
@@ -236,9 +659,7 @@ impl TryFrom<NodeActionDescription> for DucoNodeAction {
         match action.val_type.as_str() {
             "Enum" => Ok(DucoNodeAction::SetEnum(
                 action.action,
-                action
-                    .values
-                    .ok_or_else(|| Error::Runtime("Enum values missing for action".to_string()))?,
+                action.values.ok_or_else(|| anyhow!("Enum values missing for action"))?,
             )),
             "Boolean" => Ok(DucoNodeAction::SetBoolean(action.action)),
             _ => Err(anyhow!("Unsupported action type '{}'", action.val_type)),
@@ -275,6 +696,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_permanent_mode_action() {
+        let action = DucoBoxNode::permanent_mode_action("PermanentMode2", "ON")
+            .unwrap()
+            .unwrap();
+        assert_eq!(action.action, SET_VENTILATION_STATE);
+        assert_eq!(action.val, "CNT2");
+
+        let action = DucoBoxNode::permanent_mode_action("PermanentMode2", "OFF")
+            .unwrap()
+            .unwrap();
+        assert_eq!(action.val, "AUTO");
+
+        assert!(
+            DucoBoxNode::permanent_mode_action("PermanentMode2", "MAN1")
+                .unwrap()
+                .is_err()
+        );
+        assert!(DucoBoxNode::permanent_mode_action(SET_VENTILATION_STATE, "CNT1").is_none());
+    }
+
+    #[test]
+    fn test_fan_speed_action() {
+        let state = |data: &str| {
+            DucoBoxNode::fan_speed_action(FAN_SPEED_ACTION, data)
+                .unwrap()
+                .map(|a| a.val)
+        };
+
+        assert_eq!(state("0").unwrap(), "EMPT");
+        assert_eq!(state("33").unwrap(), "MAN1");
+        assert_eq!(state("50").unwrap(), "MAN2");
+        assert_eq!(state("100").unwrap(), "MAN3");
+        assert!(state("101").is_err());
+        assert!(state("AUTO").is_err());
+        assert!(DucoBoxNode::fan_speed_action(SET_VENTILATION_STATE, "50").is_none());
+    }
+
+    #[test]
+    fn test_timed_state_action() {
+        let state = |data: &str| {
+            DucoBoxNode::timed_state_action(SET_VENTILATION_STATE, data)
+                .unwrap()
+                .map(|a| a.val)
+        };
+
+        assert_eq!(state(r#"{"state":"MAN3","duration":900}"#).unwrap(), "MAN3");
+        assert_eq!(state(r#"{"state":"MAN1","duration":1800}"#).unwrap(), "MAN1x2");
+        assert_eq!(state(r#"{"state":"MAN2","duration":2700}"#).unwrap(), "MAN2x3");
+        assert_eq!(state(r#"{"state":"AUTO"}"#).unwrap(), "AUTO");
+        // rounded to the nearest supported duration
+        assert_eq!(state(r#"{"state":"MAN2","duration":600}"#).unwrap(), "MAN2");
+        assert_eq!(state(r#"{"state":"MAN2","duration":1500}"#).unwrap(), "MAN2x2");
+        assert_eq!(state(r#"{"state":"MAN2","duration":7200}"#).unwrap(), "MAN2x3");
+        assert!(state(r#"{"state":"MAN2","duration":0}"#).is_err());
+
+        // the boost states only have the default duration
+        assert_eq!(state(r#"{"state":"AUT2","duration":900}"#).unwrap(), "AUT2");
+        assert_eq!(state(r#"{"state":"AUT3","duration":2700}"#).unwrap(), "AUT3");
+
+        assert!(state(r#"{"state":"AUTO","duration":900}"#).is_err());
+        assert!(state(r#"{"state":"MAN2x2","duration":900}"#).is_err());
+        assert!(state(r#"{"duration":900}"#).is_err());
+        assert!(DucoBoxNode::timed_state_action(SET_VENTILATION_STATE, "MAN2").is_none());
+        assert!(DucoBoxNode::timed_state_action(FAN_SPEED_ACTION, r#"{"state":"MAN2"}"#).is_none());
+    }
+
     #[test]
     fn test_ducobox_node() {
         let node_info = NodeInfo {
@@ -289,6 +777,7 @@ mod tests {
 
         let mut node = DucoBoxNode::try_from(node_info).unwrap();
         assert_eq!(node.number(), 1);
+        let first_seen = node.last_seen().unwrap();
 
         let mut topics = node.topics_that_need_updating();
         topics.sort();
@@ -315,8 +804,182 @@ mod tests {
             node.topics_that_need_updating(),
             vec![MqttData::new("duco_node_1/General/SubType", "2"),]
         );
+        let last_seen = node.last_seen().unwrap();
+        assert!(last_seen > first_seen);
 
+        // the box reports the same values for a node that stopped responding
         node.update_status(node_info_update.clone()).unwrap();
         assert!(node.topics_that_need_updating().is_empty(),);
+        assert_eq!(node.last_seen(), Some(last_seen));
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let nodes: Vec<DucoBoxNode> = ducoapi::parse_node_info(include_bytes!("../test/data/info_nodes.json"))
+            .unwrap()
+            .into_iter()
+            .map(|node| DucoBoxNode::try_from(node).unwrap())
+            .collect();
+
+        let duco_box = &nodes[0];
+        assert_eq!(duco_box.name().as_deref(), Some("Main"));
+        assert_eq!(duco_box.ventilation_state(), Some(VentilationState::Auto));
+        assert_eq!(duco_box.flow_level_target(), Some(35));
+        assert_eq!(duco_box.time_state_remaining(), Some(0));
+        assert_eq!(duco_box.co2_ppm(), None);
+
+        // the sensors report '-' as ventilation state
+        let sensor = nodes[1].model();
+        assert_eq!(sensor.node_type, "UCCO2");
+        assert_eq!(sensor.name.as_deref(), Some("Boven"));
+        assert_eq!(sensor.ventilation_state, None);
+        assert_eq!(sensor.flow_level_target, None);
+        assert_eq!(sensor.iaq_co2, Some(100));
+
+        let valve = &nodes[3];
+        assert_eq!(valve.number(), 67);
+        assert_eq!(valve.name(), None);
+        assert_eq!(valve.ventilation_state(), Some(VentilationState::Auto));
+    }
+
+    #[test]
+    fn test_ventilation_state() {
+        assert_eq!(VentilationState::from_str("MAN2x3").unwrap(), VentilationState::Man2x3);
+        assert_eq!(VentilationState::EmptyHouse.to_string(), EMPTY_HOUSE_STATE);
+        assert!(VentilationState::from_str("-").is_err());
+
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::DucoBox, 1);
+        node.update_status(NodeInfo {
+            node: 1,
+            general: HashMap::new(),
+            ventilation: HashMap::from([("State".to_string(), StatusField::from("CNT2"))]),
+            sensor: Some(HashMap::from([("Co2".to_string(), StatusField::from(-1))])),
+        })
+        .unwrap();
+        assert_eq!(node.ventilation_state(), Some(VentilationState::Cnt2));
+        // out of range values are not reported
+        assert_eq!(node.co2_ppm(), None);
+    }
+
+    #[test]
+    fn test_status_filter() {
+        let node_info = || NodeInfo {
+            node: 2,
+            general: HashMap::from([
+                ("Type".to_string(), StatusField::from("UCCO2")),
+                ("SubType".to_string(), StatusField::from(1)),
+                ("Asso".to_string(), StatusField::from(67)),
+                ("Parent".to_string(), StatusField::from(67)),
+            ]),
+            ventilation: HashMap::from([("State".to_string(), StatusField::from("AUTO"))]),
+            sensor: None,
+        };
+
+        let mut node = DucoBoxNode::try_from(node_info()).unwrap();
+        node.set_status_filter(StatusFilter::new(vec![
+            "-General/*".parse().unwrap(),
+            "+General/Parent".parse().unwrap(),
+        ]));
+        node.update_status(node_info()).unwrap();
+
+        assert_eq!(node.status_keys(), vec!["General/Parent", "Ventilation/State"]);
+        assert_eq!(
+            node.topics_that_need_updating(),
+            vec![
+                MqttData::new("duco_node_2/General/Parent", "67"),
+                MqttData::new("duco_node_2/Ventilation/State", "AUTO")
+            ]
+        );
+
+        // the filtered values are kept, e.g. the type determines the capabilities of the node
+        assert_eq!(
+            node.status_value("General/Type").map(|val| val.to_string()).as_deref(),
+            Some("UCCO2")
+        );
+        assert!(node.published_value("General/Type").is_none());
+    }
+
+    #[test]
+    fn test_action_option_topics() {
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::DucoBox, 1);
+        node.set_actions(NodeActions {
+            node: 1,
+            actions: vec![
+                NodeActionDescription {
+                    action: SET_VENTILATION_STATE.to_string(),
+                    val_type: "Enum".to_string(),
+                    values: Some(vec!["AUTO".to_string(), "MAN1".to_string()]),
+                },
+                NodeActionDescription {
+                    action: SET_IDENTIFY.to_string(),
+                    val_type: "Boolean".to_string(),
+                    values: None,
+                },
+            ],
+        })
+        .unwrap();
+
+        assert_eq!(
+            node.action_option_topics().unwrap(),
+            vec![MqttData::new(
+                "duco_node_1/actions/SetVentilationState/options",
+                r#"["AUTO","MAN1"]"#
+            )]
+        );
+    }
+
+    #[test]
+    fn test_config_parameters() {
+        let parameter = |val: i64| ConfigParameter {
+            val,
+            min: 400,
+            max: 2000,
+            inc: Some(10),
+        };
+        let config = |node: u16| NodeConfig {
+            node,
+            parameters: HashMap::from([
+                (CO2_SETPOINT.to_string(), parameter(800)),
+                ("FlowLvlAutoMin".to_string(), parameter(10)),
+            ]),
+        };
+
+        // only the parameters of the capabilities of the node are kept
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::CO2RoomSensor, 2);
+        assert!(node.has_config_parameters());
+        assert!(node.set_config(config(2)));
+        assert_eq!(node.config_parameter(CO2_SETPOINT), Some(&parameter(800)));
+        assert!(node.config_parameter("FlowLvlAutoMin").is_none());
+        assert_eq!(
+            node.topics_that_need_updating(),
+            vec![MqttData::new("duco_node_2/Config/Co2SetPoint", "800")]
+        );
+
+        assert_eq!(node.config_value_for_command(CO2_SETPOINT, "950").unwrap(), 950);
+        assert_eq!(node.config_value_for_command(CO2_SETPOINT, "950.0").unwrap(), 950);
+        for invalid in ["399", "2001", "955", "high", ""] {
+            assert!(
+                node.config_value_for_command(CO2_SETPOINT, invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert!(node.config_value_for_command("FlowLvlAutoMin", "20").is_err());
+
+        node.set_config_value(CO2_SETPOINT, 950);
+        assert_eq!(node.config_parameter(CO2_SETPOINT).map(|p| p.val), Some(950));
+        assert_eq!(
+            node.topics_that_need_updating(),
+            vec![MqttData::new("duco_node_2/Config/Co2SetPoint", "950")]
+        );
+
+        // a refresh with the same ranges only updates the values
+        assert!(!node.set_config(config(2)));
+        assert_eq!(node.config_parameter(CO2_SETPOINT).map(|p| p.val), Some(800));
+
+        let mut valve = DucoBoxNode::create_for_node_type(NodeType::SensorlessControlValve, 67);
+        assert!(!valve.has_config_parameters());
+        assert!(!valve.set_config(config(67)));
+        assert!(valve.config_parameter(CO2_SETPOINT).is_none());
     }
 }
@@ -0,0 +1,146 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use crate::{ApiResult, Error};
+
+pub(crate) const DUCO_PORT: u16 = 443;
+
+/// Where the connectivity board is reached: the host name of `--duco-host` (used to validate the certificate), the
+/// port and the optional address of `--duco-ip` that replaces the name resolution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DucoEndpoint {
+    /// Host name or ip address, without the brackets of an IPv6 address
+    pub name: String,
+    pub port: u16,
+    pub ip: Option<IpAddr>,
+}
+
+impl DucoEndpoint {
+    /// The host is `<host>`, `<host>:<port>`, an IPv6 address or `[<ipv6>]:<port>`, the ip address is
+    /// `<ip>`, `<ip>:<port>` or `[<ipv6>]:<port>`. The port defaults to 443 and can be given in either of them.
+    pub fn new(host: &str, ip_address: Option<&str>) -> ApiResult<DucoEndpoint> {
+        let (name, host_port) =
+            split_port(host).ok_or_else(|| Error::Config(format!("Invalid duco host '{}'", host)))?;
+        if name.is_empty() {
+            return Err(Error::Config(format!("Invalid duco host '{}'", host)));
+        }
+
+        let (ip, ip_port) = match ip_address {
+            Some(ip_address) => {
+                let invalid = || Error::Config(format!("Invalid duco ip address '{}'", ip_address));
+                let (ip, port) = split_port(ip_address).ok_or_else(invalid)?;
+                (Some(ip.parse::<IpAddr>().map_err(|_| invalid())?), port)
+            }
+            None => (None, None),
+        };
+
+        let port = match (host_port, ip_port) {
+            (Some(host_port), Some(ip_port)) if host_port != ip_port => {
+                return Err(Error::Config(format!(
+                    "The port of the duco host ({}) and the duco ip address ({}) differ",
+                    host_port, ip_port
+                )));
+            }
+            (host_port, ip_port) => host_port.or(ip_port).unwrap_or(DUCO_PORT),
+        };
+
+        Ok(DucoEndpoint {
+            name: name.to_string(),
+            port,
+            ip,
+        })
+    }
+
+    /// The host and port as used in the urls of the api, the port is omitted when it is the https port
+    pub fn authority(&self) -> String {
+        let name = if self.name.contains(':') {
+            format!("[{}]", self.name)
+        } else {
+            self.name.clone()
+        };
+
+        match self.port {
+            DUCO_PORT => name,
+            port => format!("{}:{}", name, port),
+        }
+    }
+
+    /// The address of `--duco-ip` with the port
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.ip.map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+/// Splits the optional port off, None when the port or the brackets are invalid
+fn split_port(host: &str) -> Option<(&str, Option<u16>)> {
+    if let Some(bracketed) = host.strip_prefix('[') {
+        let (address, rest) = bracketed.split_once(']')?;
+        address.parse::<Ipv6Addr>().ok()?;
+        return match rest {
+            "" => Some((address, None)),
+            port => Some((address, Some(port.strip_prefix(':')?.parse().ok()?))),
+        };
+    }
+
+    if host.parse::<Ipv6Addr>().is_ok() {
+        return Some((host, None));
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') => Some((name, Some(port.parse().ok()?))),
+        Some(_) => None,
+        None => Some((host, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duco_endpoint() {
+        let endpoint = |host: &str, ip: Option<&str>| DucoEndpoint::new(host, ip).unwrap();
+
+        let plain = endpoint("duco.local", None);
+        assert_eq!((plain.name.as_str(), plain.port, plain.ip), ("duco.local", 443, None));
+        assert_eq!(plain.authority(), "duco.local");
+        assert_eq!(endpoint("duco.local:8443", None).authority(), "duco.local:8443");
+
+        let ipv6 = endpoint("[fd00::1]:8443", None);
+        assert_eq!((ipv6.name.as_str(), ipv6.port), ("fd00::1", 8443));
+        assert_eq!(ipv6.authority(), "[fd00::1]:8443");
+        assert_eq!(endpoint("fd00::1", None).authority(), "[fd00::1]");
+        assert_eq!(endpoint("[fd00::1]", None).authority(), "[fd00::1]");
+
+        let with_ip = endpoint("duco.local", Some("[fd00::1]:8443"));
+        assert_eq!(with_ip.socket_addr(), Some("[fd00::1]:8443".parse().unwrap()));
+        assert_eq!(with_ip.authority(), "duco.local:8443");
+        assert_eq!(
+            endpoint("duco.local", Some("192.168.1.5")).socket_addr(),
+            Some("192.168.1.5:443".parse().unwrap())
+        );
+        assert_eq!(
+            endpoint("duco.local:8443", Some("fd00::1")).socket_addr(),
+            Some("[fd00::1]:8443".parse().unwrap())
+        );
+
+        // without brackets the port is a group of the IPv6 address
+        assert_eq!(endpoint("duco.local", Some("fd00::1:8443")).port, 443);
+
+        for (host, ip) in [
+            ("duco.local", Some("192.168.1.300")),
+            ("duco.local", Some("[fd00::1]8443")),
+            ("duco.local", Some("duco.local")),
+            ("duco.local:8443", Some("192.168.1.5:443")),
+            ("duco.local:port", None),
+            ("[duco.local]:8443", None),
+            (":8443", None),
+        ] {
+            assert!(
+                matches!(DucoEndpoint::new(host, ip), Err(Error::Config(_))),
+                "{} {:?}",
+                host,
+                ip
+            );
+        }
+    }
+}
@@ -1,15 +1,17 @@
 use strum::{Display, EnumString};
 
-#[derive(FromPrimitive, EnumString, Display, Clone, Copy)]
+#[derive(FromPrimitive, EnumString, Display, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 pub enum NodeType {
     Unknown = 0,
+    #[strum(serialize = "UCBAT")]
     RemoteControlRFBAT = 8,
     RemoteControlRFWired = 9,
     HumidityRoomSensor = 10,
     #[strum(serialize = "UCCO2")]
     CO2RoomSensor = 12,
     SensorlessControlValve = 13,
+    #[strum(serialize = "VLVRH")]
     HumidityControlValve = 14,
     #[strum(serialize = "VLV")]
     CO2ControlValve = 16,
@@ -17,6 +19,7 @@ pub enum NodeType {
     DucoBox = 17,
     SwitchSensor = 18,
     ControlUnit = 27,
+    #[strum(serialize = "VLVCO2RH")]
     CO2RHControlValve = 28,
     RemoteControlSunControlRFWired = 29,
     RemoteControlNightventRFWired = 30,
@@ -24,6 +27,86 @@ pub enum NodeType {
     HumidityBoxSensor = 35,
     CO2BoxSensors = 37,
     DucoWeatherStation = 39,
+    /// Logical node defined in the bridge configuration, not known by the duco box
+    #[strum(serialize = "VIRTUAL")]
+    Virtual = 1000,
+}
+
+/// The groups of home assistant entities a node provides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Ventilation state select, fan, permanent modes, flow level target and remaining state time
+    VentilationControl,
+    /// Ventilation state select, when the node accepts the ventilation state action
+    VentilationState,
+    /// Permanent ventilation mode switches, when the node accepts the ventilation state action
+    PermanentModes,
+    /// Ventilation state select and fan of a valve, when the node accepts the ventilation state action
+    Valve,
+    StateTimeRemaining,
+    Identify,
+    /// Identify light, only when the node accepts the identify action
+    IdentifyIfSupported,
+    /// Heat recovery values, when the box reports them
+    HeatRecovery,
+    Co2Sensor,
+    HumiditySensor,
+    Battery,
+    BoxMountedSensors,
+    WeatherStation,
+    /// The status values as diagnostic sensors, when none of the other capabilities provided an entity
+    StatusFallback,
+    /// Every value and action the node reports, for nodes without a dedicated mapping
+    Generic,
+}
+
+use Capability::*;
+
+/// The capabilities per node type, an entry with a SubType overrides the default of its type.
+/// SubType 0 of a CO2 control valve has no built-in sensor, the valve follows the room sensors in its zone.
+const NODE_CAPABILITIES: &[(NodeType, Option<i64>, &[Capability])] = &[
+    (NodeType::DucoBox, None, &[VentilationControl, Identify, HeatRecovery]),
+    (
+        NodeType::CO2ControlValve,
+        None,
+        &[VentilationControl, Identify, Co2Sensor],
+    ),
+    (NodeType::CO2ControlValve, Some(0), &[VentilationControl, Identify]),
+    (NodeType::CO2RoomSensor, None, &[Co2Sensor, Identify]),
+    (NodeType::RemoteControlRFBAT, None, &[VentilationState, Battery]),
+    (NodeType::SensorlessControlValve, None, &[Valve]),
+    (NodeType::HumidityControlValve, None, &[Valve, HumiditySensor]),
+    (
+        NodeType::SwitchSensor,
+        None,
+        &[VentilationState, IdentifyIfSupported, StatusFallback],
+    ),
+    (
+        NodeType::ControlUnit,
+        None,
+        &[VentilationState, IdentifyIfSupported, StatusFallback],
+    ),
+    (NodeType::CO2RHControlValve, None, &[Valve, Co2Sensor, HumiditySensor]),
+    // every valve controls a single zone of a multi-zone installation
+    (NodeType::ExternalMultiZoneValve, None, &[Valve, StateTimeRemaining]),
+    (NodeType::HumidityBoxSensor, None, &[BoxMountedSensors]),
+    (NodeType::CO2BoxSensors, None, &[BoxMountedSensors]),
+    (NodeType::DucoWeatherStation, None, &[WeatherStation]),
+    (NodeType::Virtual, None, &[VentilationState, PermanentModes]),
+];
+
+impl NodeType {
+    /// The capabilities of a node of this type with the given SubType, types without an entry expose everything
+    /// they report
+    pub fn capabilities(self, sub_type: Option<i64>) -> &'static [Capability] {
+        NODE_CAPABILITIES
+            .iter()
+            .filter(|(node_type, entry_sub_type, _)| {
+                *node_type == self && (entry_sub_type.is_none() || *entry_sub_type == sub_type)
+            })
+            .max_by_key(|(_, entry_sub_type, _)| entry_sub_type.is_some())
+            .map_or(&[Generic], |(_, _, capabilities)| *capabilities)
+    }
 }
 
 // "auto" = AutomaticMode;
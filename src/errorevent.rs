@@ -0,0 +1,69 @@
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+/// Kind of failure, so alerts can be filtered without matching on the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCategory {
+    /// The box could not be reached or refused a request
+    Poll,
+    /// The box returned a response the bridge does not understand
+    Parse,
+    /// A command was invalid or the box failed to perform it
+    Command,
+}
+
+/// Published (not retained) on `<base>/bridge/errors` for every failure the bridge logs as an error
+#[derive(Debug, Serialize)]
+pub struct ErrorEvent {
+    pub category: ErrorCategory,
+    pub message: String,
+    /// UTC, RFC 3339
+    pub timestamp: String,
+    /// Details of the failed operation, e.g. the poll cycle or the node of a command
+    pub context: serde_json::Value,
+}
+
+impl ErrorEvent {
+    pub fn new(category: ErrorCategory, err: &anyhow::Error, context: serde_json::Value) -> ErrorEvent {
+        ErrorEvent {
+            category,
+            message: format!("{:#}", err),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            context,
+        }
+    }
+
+    /// Poll failures caused by an unexpected api response are reported as parse errors
+    pub fn poll(err: &anyhow::Error, poll_cycle: u64) -> ErrorEvent {
+        let category = if err.chain().any(|cause| cause.is::<serde_json::Error>()) {
+            ErrorCategory::Parse
+        } else {
+            ErrorCategory::Poll
+        };
+
+        ErrorEvent::new(category, err, serde_json::json!({ "poll_cycle": poll_cycle }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+
+    #[test]
+    fn test_poll_error_category() {
+        let err = anyhow!("connection refused").context("Failed to obtain nodes");
+        let event = ErrorEvent::poll(&err, 3);
+        assert_eq!(event.category, ErrorCategory::Poll);
+        assert_eq!(event.message, "Failed to obtain nodes: connection refused");
+
+        let err = serde_json::from_str::<serde_json::Value>("{")
+            .context("Failed to obtain nodes")
+            .unwrap_err();
+        let event = serde_json::to_value(ErrorEvent::poll(&err, 4)).unwrap();
+        assert_eq!(event["category"], "parse");
+        assert_eq!(event["context"]["poll_cycle"], 4);
+        assert!(event["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
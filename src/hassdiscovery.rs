@@ -1,12 +1,42 @@
 use crate::{
     Result,
-    ducoboxnode::{GENERAL, SENSOR, VENTILATION},
+    ducoapi::ConfigParameter,
+    ducoboxnode::{
+        AUTO_STATE, CO2_SETPOINT, CONFIG, DucoNodeAction, EMPTY_HOUSE_STATE, FAN_SPEED_ACTION, GENERAL, JSON_STATE,
+        LAST_SEEN, MANUAL_STATE, PERMANENT_MODE_ACTION, PERMANENT_MODE_STATE, RH_DELTA, RH_SETPOINT, SENSOR,
+        SET_IDENTIFY, SET_VENTILATION_STATE, VENTILATION,
+    },
 };
 use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
 
-use crate::{ducoboxnode::DucoBoxNode, mqtt::MqttData};
+use crate::{
+    ducoboxdevice::{
+        BYPASS_POSITION, DucoBoxDevice, FILTER_REMAINING_DAYS, MAC_ADDRESS, SERIAL_NUMBER, SUPPLY_TEMPERATURE_TARGET,
+        SUPPLY_TEMPERATURE_TARGET_ZONE, UPTIME, WIFI_NETWORKS, WIFI_SIGNAL,
+    },
+    ducoboxnode::DucoBoxNode,
+    duconodetypes::NodeType,
+    mqtt::MqttData,
+    naming::{self, NamingStrategy},
+    valuetransform::ValueTransform,
+};
+
+pub const HASS_DISCOVERY_TOPIC: &str = "homeassistant";
+/// Home assistant publishes its availability (birth and last will) on this topic
+pub const HASS_STATUS_TOPIC: &str = "homeassistant/status";
 
-const HASS_DISCOVERY_TOPIC: &str = "homeassistant";
+/// Status values of a node that describe its place in the duco network rather than the ventilation
+const NETWORK_DIAGNOSTIC_VALUES: [&str; 5] = ["SubType", "NetworkType", "Parent", "Asso", "CommErrorCtr"];
+
+/// Entities that are disabled by default next to the diagnostic entities, matched on the end of the unique id
+const DISABLED_BY_DEFAULT: [&str; 1] = ["_time_remaining"];
+
+/// Logical home assistant devices the data of the box itself is split into
+pub const VENTILATION_DEVICE: &str = "Ventilation";
+pub const HEAT_RECOVERY_DEVICE: &str = "HeatRecovery";
+/// Topic level of the commands of the box that do not belong to one of the logical devices, node topics never use it
+const BOX_COMMAND_LEVEL: &str = "duco_box";
 
 #[derive(Serialize)]
 pub struct Origin {
@@ -25,6 +55,183 @@ impl Origin {
     }
 }
 
+#[derive(Serialize)]
+pub struct Device {
+    pub identifiers: Vec<String>,
+    pub name: String,
+    pub manufacturer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<String>,
+    /// Network connections of the device, e.g. `("mac", "b1:b1:b1:b1:b1:b1")`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<(String, String)>,
+}
+
+impl Device {
+    /// The bridge itself, groups the entities that are not related to the duco installation
+    pub fn bridge() -> Device {
+        Device {
+            identifiers: vec!["duco2mqtt_bridge".to_string()],
+            name: "duco2mqtt".to_string(),
+            manufacturer: "duco2mqtt".to_string(),
+            model: None,
+            via_device: None,
+            connections: Vec::new(),
+        }
+    }
+}
+
+/// Settings shared by the discovery documents of the box and its nodes
+pub struct DiscoveryContext {
+    pub base_topic: String,
+    /// The statistics of the sample history are published as json attributes of the node sensors
+    pub history_attributes: bool,
+    /// Derives the unique ids and names of the node entities
+    pub naming: Arc<dyn NamingStrategy>,
+    /// Nodes that keep the air quality percentage sensor next to the CO2 concentration sensor
+    pub iaq_sensor_nodes: Vec<u16>,
+    /// The nodes get a diagnostic sensor with the time they were last reported by the box
+    pub last_seen: bool,
+    /// The values that are published scaled, they do not need a value template
+    pub value_transform: ValueTransform,
+    /// Flips whether home assistant enables an entity on a fresh install, by unique id
+    pub entity_defaults: BTreeMap<String, bool>,
+    serial: String,
+    model: Option<String>,
+    mac: Option<String>,
+    heat_recovery: bool,
+    /// The range of the supply temperature target of the heat recovery, from the configuration of the box
+    supply_temperature_target: Option<ConfigParameter>,
+}
+
+impl DiscoveryContext {
+    pub fn new(base_topic: &str, device: Option<&DucoBoxDevice>) -> DiscoveryContext {
+        let value = |key: &str| {
+            device
+                .and_then(|dev| dev.status_value(key))
+                .map(|val| val.to_string())
+                .filter(|val| !val.is_empty())
+        };
+
+        DiscoveryContext {
+            base_topic: base_topic.to_string(),
+            history_attributes: false,
+            naming: naming::default_strategy(),
+            iaq_sensor_nodes: Vec::new(),
+            last_seen: false,
+            value_transform: ValueTransform::default(),
+            entity_defaults: BTreeMap::new(),
+            serial: value(SERIAL_NUMBER).unwrap_or_else(|| "box".to_string()),
+            model: value("General/Board/BoxSubTypeName"),
+            mac: value(MAC_ADDRESS).map(|mac| mac.to_lowercase()),
+            heat_recovery: device.is_some_and(DucoBoxDevice::has_heat_recovery),
+            supply_temperature_target: device
+                .and_then(|dev| dev.config_parameter(SUPPLY_TEMPERATURE_TARGET))
+                .cloned(),
+        }
+    }
+
+    pub fn has_heat_recovery(&self) -> bool {
+        self.heat_recovery
+    }
+
+    /// Template of a value in tenths, None when the value is published scaled
+    fn tenths_template(&self, key: &str) -> Option<String> {
+        (!self.value_transform.is_transformed(key)).then(|| "{{ value | float / 10 }}".to_string())
+    }
+
+    /// One of the logical devices the data of the box itself is split into
+    fn box_device(&self, device: &str) -> Device {
+        let ventilation_id = format!("duco_{}_{}", self.serial, box_device_id(VENTILATION_DEVICE));
+        let (name, id, via_device, connections) = match device {
+            HEAT_RECOVERY_DEVICE => (
+                "Heat Recovery",
+                format!("duco_{}_{}", self.serial, box_device_id(HEAT_RECOVERY_DEVICE)),
+                Some(ventilation_id),
+                Vec::new(),
+            ),
+            // the connectivity board is the network interface of the ventilation device
+            _ => (
+                "Ventilation",
+                ventilation_id,
+                None,
+                self.mac.iter().map(|mac| ("mac".to_string(), mac.clone())).collect(),
+            ),
+        };
+
+        Device {
+            identifiers: vec![id],
+            name: name.to_string(),
+            manufacturer: "Duco".to_string(),
+            model: self.model.clone(),
+            via_device,
+            connections,
+        }
+    }
+
+    /// The device of a node, connected through the box. The entities of the box node are part of the ventilation device.
+    fn node_device(&self, node: &DucoBoxNode) -> Device {
+        if matches!(node.node_type(), NodeType::DucoBox) {
+            return self.box_device(VENTILATION_DEVICE);
+        }
+
+        let name = node
+            .name()
+            .unwrap_or_else(|| format!("Duco {} {}", node.node_type(), node.number()));
+
+        Device {
+            identifiers: vec![format!("duco_{}_node_{}", self.serial, node.number())],
+            name,
+            manufacturer: "Duco".to_string(),
+            model: Some(node.node_type().to_string()),
+            via_device: Some(format!("duco_{}_ventilation", self.serial)),
+            connections: Vec::new(),
+        }
+    }
+
+    /// Unique id of an entity of a node, `id` identifies the entity within the node (e.g. "sensor_co2")
+    fn unique_id(&self, node: &DucoBoxNode, id: &str) -> String {
+        self.naming.unique_id(&self.serial, node.number(), id)
+    }
+
+    /// Unique id of an entity of one of the logical box devices, `id` identifies the entity within the device
+    fn box_unique_id(&self, device: &str, id: &str) -> String {
+        self.naming.box_unique_id(&self.serial, box_device_id(device), id)
+    }
+
+    /// Command topic of the box, `level` is one of the reserved box levels
+    fn box_command_topic(&self, level: &str, command: &str) -> String {
+        format!("{}{}/cmnd/{}", self.base_topic, level, command)
+    }
+
+    /// Name and object id of an entity of a node
+    fn entity_name(&self, node: &DucoBoxNode, topic_name: &str, unique_id: &str) -> (String, String) {
+        self.naming.entity_name(node.name().as_deref(), topic_name, unique_id)
+    }
+
+    /// The discovery document of an entity, `component` is the home assistant platform (e.g. "sensor")
+    fn document<T: DiscoveryEntity>(&self, component: &str, mut entity: T) -> Result<MqttData> {
+        let enabled = self
+            .entity_defaults
+            .get(entity.unique_id())
+            .copied()
+            .unwrap_or_else(|| enabled_by_default(&entity));
+        entity.set_enabled_by_default((!enabled).then_some(false));
+
+        Ok(MqttData {
+            topic: format!("{}/{}/{}/config", HASS_DISCOVERY_TOPIC, component, entity.unique_id()),
+            payload: serde_json::to_string(&entity)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Availability {
+    pub topic: String,
+}
+
 #[derive(Serialize)]
 pub struct Sensor {
     pub origin: Origin,
@@ -32,11 +239,28 @@ pub struct Sensor {
     pub obj_id: String,
     pub unique_id: String,
     pub stat_t: String,
-    pub avty_t: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avty_t: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<Availability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attr_t: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
     pub state_class: Option<String>,
     pub unit_of_measurement: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -45,12 +269,21 @@ pub struct Select {
     pub name: String,
     pub obj_id: String,
     pub unique_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub stat_t: String,
     pub avty_t: String,
     pub cmd_t: String,
     pub options: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -65,113 +298,665 @@ pub struct Light {
     pub payload_on: String,
     pub payload_off: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Switch {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub stat_t: String,
+    pub avty_t: String,
+    pub cmd_t: String,
+    pub payload_on: String,
+    pub payload_off: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Fan {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    pub stat_t: String,
+    pub avty_t: String,
+    pub cmd_t: String,
+    pub payload_on: String,
+    pub payload_off: String,
+    pub state_value_template: String,
+    pub pr_mode_stat_t: String,
+    pub pr_mode_cmd_t: String,
+    pub preset_modes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pct_stat_t: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pct_cmd_t: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Climate {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    pub avty_t: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub curr_temp_t: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_temperature_template: Option<String>,
+    pub temp_stat_t: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_state_template: Option<String>,
+    pub temp_cmd_t: String,
+    pub act_t: String,
+    pub action_template: String,
+    pub modes: Vec<String>,
+    pub min_temp: f64,
+    pub max_temp: f64,
+    pub temp_step: f64,
+    pub temperature_unit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Number {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    pub stat_t: String,
+    pub avty_t: String,
+    pub cmd_t: String,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    pub mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Button {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    pub avty_t: String,
+    pub cmd_t: String,
+    pub payload_press: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct BinarySensor {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    pub stat_t: String,
+    pub avty_t: String,
+    pub payload_on: String,
+    pub payload_off: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Update {
+    pub origin: Origin,
+    pub name: String,
+    pub obj_id: String,
+    pub unique_id: String,
+    pub stat_t: String,
+    pub avty_t: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+}
+
+/// The fields of the discovery documents that decide whether home assistant enables the entity on a fresh install
+trait DiscoveryEntity: Serialize {
+    fn unique_id(&self) -> &str;
+    fn entity_category(&self) -> Option<&str>;
+    fn device(&self) -> Option<&Device>;
+    fn set_enabled_by_default(&mut self, enabled: Option<bool>);
+}
+
+macro_rules! discovery_entities {
+    ($($entity:ty),+) => {
+        $(impl DiscoveryEntity for $entity {
+            fn unique_id(&self) -> &str {
+                &self.unique_id
+            }
+
+            fn entity_category(&self) -> Option<&str> {
+                self.entity_category.as_deref()
+            }
+
+            fn device(&self) -> Option<&Device> {
+                self.device.as_ref()
+            }
+
+            fn set_enabled_by_default(&mut self, enabled: Option<bool>) {
+                self.enabled_by_default = enabled;
+            }
+        })+
+    };
 }
 
-pub fn create_sensor_for_status(node_nr: u16, base_topic: &str, topic_name: &str, status: &str) -> Sensor {
-    let unique_id = format!("duco_node_{}_{}", node_nr, status);
+discovery_entities!(
+    Sensor,
+    Select,
+    Light,
+    Switch,
+    Fan,
+    Climate,
+    Number,
+    Button,
+    BinarySensor,
+    Update
+);
+
+pub fn create_sensor_for_status(node: &DucoBoxNode, ctx: &DiscoveryContext, topic_name: &str, status: &str) -> Sensor {
+    let unique_id = ctx.unique_id(node, status);
+    let stat_t = format!("{}{}", ctx.base_topic, node.status_topic(topic_name));
+    let (name, obj_id) = ctx.entity_name(node, topic_name, &unique_id);
 
     Sensor {
         origin: Origin::duco2mqtt(),
-        name: topic_name.to_string(),
-        obj_id: unique_id.clone(),
+        name,
+        obj_id,
         unique_id,
-        stat_t: format!("{}duco_node_{}/{}", base_topic, node_nr, topic_name),
-        avty_t: format!("{}state", base_topic),
+        json_attr_t: (ctx.history_attributes && !topic_name.starts_with(GENERAL))
+            .then(|| format!("{}/attributes", stat_t)),
+        stat_t,
+        avty_t: Some(format!("{}state", ctx.base_topic)),
+        availability: Vec::new(),
+        availability_mode: None,
+        device: Some(ctx.node_device(node)),
+        value_template: None,
         state_class: None,
         unit_of_measurement: None,
+        device_class: None,
+        entity_category: None,
         icon: None,
+        enabled_by_default: None,
     }
 }
 
 pub fn create_light_for_status(
-    node_nr: u16,
-    base_topic: &str,
+    node: &DucoBoxNode,
+    ctx: &DiscoveryContext,
     topic_name: &str,
     cmd_topic_name: &str,
     status: &str,
 ) -> Light {
-    let unique_id = format!("duco_node_{}_{}", node_nr, status);
+    let unique_id = ctx.unique_id(node, status);
+    let (name, obj_id) = ctx.entity_name(node, topic_name, &unique_id);
 
     Light {
         origin: Origin::duco2mqtt(),
-        name: String::from(topic_name),
-        obj_id: unique_id.clone(),
+        name,
+        obj_id,
         unique_id,
-        stat_t: format!("{}duco_node_{}/{}", base_topic, node_nr, topic_name),
-        avty_t: format!("{}state", base_topic),
-        cmd_t: format!("{}duco_node_{}/cmnd/{}", base_topic, node_nr, cmd_topic_name),
+        stat_t: format!("{}{}", ctx.base_topic, node.status_topic(topic_name)),
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: format!("{}{}", ctx.base_topic, node.command_topic(cmd_topic_name)),
         payload_on: "1".to_string(),
         payload_off: "0".to_string(),
+        entity_category: None,
         icon: None,
+        device: Some(ctx.node_device(node)),
+        enabled_by_default: None,
     }
 }
 
 pub fn create_select_for_status(
-    node_nr: u16,
-    base_topic: &str,
+    node: &DucoBoxNode,
+    ctx: &DiscoveryContext,
     topic_name: &str,
     cmd_topic_name: &str,
     status: &str,
     valid_states: &[String],
 ) -> Select {
-    let unique_id = format!("duco_node_{}_{}", node_nr, status);
+    let unique_id = ctx.unique_id(node, status);
+    let (name, obj_id) = ctx.entity_name(node, topic_name, &unique_id);
 
     Select {
         origin: Origin::duco2mqtt(),
-        name: topic_name.to_string(),
-        obj_id: unique_id.clone(),
+        name,
+        obj_id,
         unique_id,
-        stat_t: format!("{}duco_node_{}/{}", base_topic, node_nr, topic_name),
-        avty_t: format!("{}state", base_topic),
-        cmd_t: format!("{}duco_node_{}/cmnd/{}", base_topic, node_nr, cmd_topic_name),
+        stat_t: format!("{}{}", ctx.base_topic, node.status_topic(topic_name)),
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: format!("{}{}", ctx.base_topic, node.command_topic(cmd_topic_name)),
         options: Vec::from(valid_states),
+        value_template: None,
+        entity_category: None,
         icon: None,
+        device: Some(ctx.node_device(node)),
+        enabled_by_default: None,
     }
 }
 
-pub fn ventilation_state_topic(node: &DucoBoxNode, base_topic: &str, valid_states: &[String]) -> Result<MqttData> {
+pub fn ventilation_state_topic(
+    node: &DucoBoxNode,
+    ctx: &DiscoveryContext,
+    valid_states: &[String],
+) -> Result<MqttData> {
     let mut select = create_select_for_status(
-        node.number(),
-        base_topic,
+        node,
+        ctx,
         &format!("{}/State", VENTILATION),
-        "SetVentilationState",
+        SET_VENTILATION_STATE,
         "ventilation_state",
         valid_states,
     );
     select.icon = Some("mdi:fan".to_string());
 
-    Ok(MqttData {
-        topic: format!("{}/select/{}/config", HASS_DISCOVERY_TOPIC, select.unique_id),
-        payload: serde_json::to_string(&select)?,
-    })
+    ctx.document("select", select)
 }
 
-pub fn filter_days_remaining_topic(base_topic: &str) -> Result<MqttData> {
-    let unique_id = "duco_device_remaining_filter_days".to_string();
+/// Fan entity for the ventilation: the presets are the ventilation states, turning it off selects the empty house
+/// state and the percentage follows the flow level target (setting it selects the matching manual state)
+pub fn fan_topic(node: &DucoBoxNode, ctx: &DiscoveryContext, valid_states: &[String]) -> Result<MqttData> {
+    let unique_id = ctx.unique_id(node, "ventilation_fan");
+    let node_topic = format!("{}{}", ctx.base_topic, node.topic());
+    let state_topic = format!(
+        "{}{}",
+        ctx.base_topic,
+        node.status_topic(&format!("{}/State", VENTILATION))
+    );
+    let manual_speeds = (1..=3).all(|level| valid_states.contains(&format!("{}{}", MANUAL_STATE, level)));
+    let (name, obj_id) = ctx.entity_name(node, "Ventilation", &unique_id);
 
-    let sensor = Sensor {
+    let fan = Fan {
         origin: Origin::duco2mqtt(),
-        name: "Remaining filter days".to_string(),
+        name,
+        obj_id,
+        unique_id,
+        stat_t: state_topic.clone(),
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: format!("{}/cmnd/{}", node_topic, SET_VENTILATION_STATE),
+        payload_on: AUTO_STATE.to_string(),
+        payload_off: EMPTY_HOUSE_STATE.to_string(),
+        state_value_template: format!(
+            "{{{{ '{0}' if value == '{0}' else '{1}' }}}}",
+            EMPTY_HOUSE_STATE, AUTO_STATE
+        ),
+        pr_mode_stat_t: state_topic,
+        pr_mode_cmd_t: format!("{}/cmnd/{}", node_topic, SET_VENTILATION_STATE),
+        preset_modes: Vec::from(valid_states),
+        pct_stat_t: manual_speeds.then(|| {
+            format!(
+                "{}{}",
+                ctx.base_topic,
+                node.status_topic(&format!("{}/FlowLvlTgt", VENTILATION))
+            )
+        }),
+        pct_cmd_t: manual_speeds.then(|| format!("{}/cmnd/{}", node_topic, FAN_SPEED_ACTION)),
+        icon: Some("mdi:fan".to_string()),
+        device: Some(ctx.node_device(node)),
+        entity_category: None,
+        enabled_by_default: None,
+    };
+
+    ctx.document("fan", fan)
+}
+
+pub fn permanent_mode_topics(
+    node: &DucoBoxNode,
+    ctx: &DiscoveryContext,
+    valid_states: &[String],
+) -> Result<Vec<MqttData>> {
+    valid_states
+        .iter()
+        .filter_map(|state| state.strip_prefix(PERMANENT_MODE_STATE))
+        .map(|level| {
+            let unique_id = ctx.unique_id(node, &format!("permanent_mode_{}", level));
+            let (name, obj_id) = ctx.entity_name(node, &format!("Permanent mode {}", level), &unique_id);
+            let switch = Switch {
+                origin: Origin::duco2mqtt(),
+                name,
+                obj_id,
+                unique_id,
+                stat_t: format!(
+                    "{}{}",
+                    ctx.base_topic,
+                    node.status_topic(&format!("{}/State", VENTILATION))
+                ),
+                avty_t: format!("{}state", ctx.base_topic),
+                cmd_t: format!(
+                    "{}{}",
+                    ctx.base_topic,
+                    node.command_topic(&format!("{}{}", PERMANENT_MODE_ACTION, level))
+                ),
+                payload_on: "ON".to_string(),
+                payload_off: "OFF".to_string(),
+                value_template: Some(format!(
+                    "{{{{ 'ON' if value == '{}{}' else 'OFF' }}}}",
+                    PERMANENT_MODE_STATE, level
+                )),
+                entity_category: None,
+                icon: Some("mdi:fan-lock".to_string()),
+                device: Some(ctx.node_device(node)),
+                enabled_by_default: None,
+            };
+
+            ctx.document("switch", switch)
+        })
+        .collect()
+}
+
+/// The part of the unique ids that identifies the logical box device
+fn box_device_id(device: &str) -> &'static str {
+    match device {
+        HEAT_RECOVERY_DEVICE => "heat_recovery",
+        _ => "ventilation",
+    }
+}
+
+pub fn device_availability_topic(base_topic: &str, device: &str) -> String {
+    format!("{}{}/state", base_topic, device)
+}
+
+/// Sensor for a value of the box itself, attached to one of the logical box devices.
+/// The sensor is available when both the bridge and the logical device are online.
+fn box_device_sensor(ctx: &DiscoveryContext, device: &str, key: &str) -> Sensor {
+    let box_device = ctx.box_device(device);
+    let unique_id = ctx.box_unique_id(device, &key.replace('/', "_").to_lowercase());
+
+    Sensor {
+        origin: Origin::duco2mqtt(),
+        name: key.rsplit('/').next().unwrap_or(key).to_string(),
         obj_id: unique_id.clone(),
         unique_id,
-        stat_t: format!("{}HeatRecovery/{}/TimeFilterRemain", base_topic, GENERAL),
-        avty_t: format!("{}state", base_topic),
-        state_class: Some("measurement".to_string()),
-        unit_of_measurement: Some("days".to_string()),
-        icon: Some("mdi:calendar-clock".to_string()),
+        stat_t: format!("{}{}", ctx.base_topic, key),
+        avty_t: None,
+        availability: vec![
+            Availability {
+                topic: format!("{}state", ctx.base_topic),
+            },
+            Availability {
+                topic: device_availability_topic(&ctx.base_topic, device),
+            },
+        ],
+        availability_mode: Some("all".to_string()),
+        device: Some(box_device),
+        json_attr_t: None,
+        value_template: None,
+        state_class: None,
+        unit_of_measurement: None,
+        device_class: None,
+        entity_category: None,
+        icon: None,
+        enabled_by_default: None,
+    }
+}
+
+/// Sensors for the values of the box itself, split in a ventilation and a heat recovery device. Json arrays only get a
+/// sensor when their meaning is known, their payload does not fit in a sensor state.
+pub fn box_device_topics(device: &DucoBoxDevice, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    device
+        .status_keys()
+        .into_iter()
+        .filter(|key| *key == WIFI_NETWORKS || !device.is_json_value(key))
+        .map(|key| {
+            let sensor = if key.starts_with(HEAT_RECOVERY_DEVICE) {
+                let mut sensor = box_device_sensor(ctx, HEAT_RECOVERY_DEVICE, key);
+                if key == FILTER_REMAINING_DAYS {
+                    sensor.name = "Remaining filter days".to_string();
+                    sensor.unique_id = ctx.box_unique_id(HEAT_RECOVERY_DEVICE, "remaining_filter_days");
+                    sensor.obj_id = sensor.unique_id.clone();
+                    sensor.state_class = Some("measurement".to_string());
+                    sensor.unit_of_measurement = Some("days".to_string());
+                    sensor.icon = Some("mdi:calendar-clock".to_string());
+                } else if key == BYPASS_POSITION {
+                    // keeps the unique id of the generic sensor, so the entity keeps its history
+                    sensor.name = "Bypass position".to_string();
+                    sensor.state_class = Some("measurement".to_string());
+                    sensor.unit_of_measurement = Some("%".to_string());
+                    sensor.icon = Some("mdi:valve".to_string());
+                }
+                sensor
+            } else {
+                let mut sensor = box_device_sensor(ctx, VENTILATION_DEVICE, key);
+                sensor.entity_category = Some("diagnostic".to_string());
+                if key == WIFI_NETWORKS {
+                    sensor.name = "Wifi networks".to_string();
+                    sensor.value_template = Some("{{ value_json | count }}".to_string());
+                    sensor.state_class = Some("measurement".to_string());
+                    sensor.icon = Some("mdi:wifi".to_string());
+                } else if key == WIFI_SIGNAL {
+                    sensor.name = "Wifi signal".to_string();
+                    sensor.device_class = Some("signal_strength".to_string());
+                    sensor.state_class = Some("measurement".to_string());
+                    sensor.unit_of_measurement = Some("dBm".to_string());
+                } else if key == UPTIME {
+                    sensor.name = "Uptime".to_string();
+                    sensor.device_class = Some("duration".to_string());
+                    sensor.unit_of_measurement = Some("s".to_string());
+                }
+                sensor
+            };
+
+            ctx.document("sensor", sensor)
+        })
+        .collect()
+}
+
+/// Button to restart the filter timer after replacing the filters
+pub fn filter_reset_topic(ctx: &DiscoveryContext) -> Result<MqttData> {
+    let unique_id = ctx.box_unique_id(HEAT_RECOVERY_DEVICE, "filter_reset");
+    let button = Button {
+        origin: Origin::duco2mqtt(),
+        name: "Reset filter timer".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: ctx.box_command_topic(BOX_COMMAND_LEVEL, "FilterReset"),
+        payload_press: "PRESS".to_string(),
+        entity_category: Some("config".to_string()),
+        icon: Some("mdi:air-filter".to_string()),
+        device: Some(ctx.box_device(HEAT_RECOVERY_DEVICE)),
+        enabled_by_default: None,
     };
 
-    Ok(MqttData {
-        topic: format!("{}/sensor/{}/config", HASS_DISCOVERY_TOPIC, sensor.unique_id),
-        payload: serde_json::to_string(&sensor)?,
-    })
+    ctx.document("button", button)
 }
 
-pub fn flow_level_target_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+/// Numbers for the supply temperature targets of the zones of a two-zone box, in °C while the box uses 0.1°C. The
+/// zones and their ranges are the configuration parameters of the box.
+pub fn supply_temperature_zone_topics(device: &DucoBoxDevice, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    device
+        .supply_temperature_zones()
+        .into_iter()
+        .filter_map(|key| Some((key, device.config_parameter(key)?)))
+        .map(|(key, parameter)| {
+            let command = key.rsplit('/').next().unwrap_or(key);
+            let zone = command.trim_start_matches(SUPPLY_TEMPERATURE_TARGET_ZONE);
+            let unique_id = ctx.box_unique_id(HEAT_RECOVERY_DEVICE, &format!("supply_temperature_target_zone{}", zone));
+            let number = Number {
+                origin: Origin::duco2mqtt(),
+                name: format!("Supply temperature target zone {}", zone),
+                obj_id: unique_id.clone(),
+                unique_id,
+                stat_t: format!("{}{}", ctx.base_topic, key),
+                avty_t: format!("{}state", ctx.base_topic),
+                cmd_t: ctx.box_command_topic(HEAT_RECOVERY_DEVICE, command),
+                min: parameter.min as f64 / 10.0,
+                max: parameter.max as f64 / 10.0,
+                step: parameter.inc.unwrap_or(1) as f64 / 10.0,
+                mode: "box".to_string(),
+                value_template: ctx.tenths_template(key),
+                unit_of_measurement: Some("°C".to_string()),
+                device_class: Some("temperature".to_string()),
+                entity_category: None,
+                icon: Some("mdi:thermometer".to_string()),
+                device: Some(ctx.box_device(HEAT_RECOVERY_DEVICE)),
+                enabled_by_default: None,
+            };
+
+            ctx.document("number", number)
+        })
+        .collect()
+}
+
+/// Button to reboot the box, the command is confirmed with the serial number of the box as payload
+pub fn reboot_topic(ctx: &DiscoveryContext) -> Result<MqttData> {
+    let unique_id = ctx.box_unique_id(VENTILATION_DEVICE, "reboot");
+    let button = Button {
+        origin: Origin::duco2mqtt(),
+        name: "Reboot".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: ctx.box_command_topic(BOX_COMMAND_LEVEL, "Reboot"),
+        payload_press: ctx.serial.clone(),
+        entity_category: Some("diagnostic".to_string()),
+        icon: Some("mdi:restart".to_string()),
+        device: Some(ctx.box_device(VENTILATION_DEVICE)),
+        enabled_by_default: None,
+    };
+
+    ctx.document("button", button)
+}
+
+/// Climate entity of the heat recovery, controlling the supply temperature target within the range the box reports for
+/// it. The current temperature is the temperature of the extracted (inside) air, the action shows when the bypass is
+/// open.
+pub fn heat_recovery_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+    if let Some(parameter) = &ctx.supply_temperature_target {
+        topics.push(heat_recovery_climate_topic(node, ctx, parameter)?);
+    }
+
+    if node.published_value(&format!("{}/TempOda", SENSOR)).is_some() {
+        let mut sensor = create_sensor_for_status(node, ctx, &format!("{}/TempOda", SENSOR), "sensor_temp_oda");
+        sensor.name = "Outside temperature".to_string();
+        sensor.device = Some(ctx.box_device(HEAT_RECOVERY_DEVICE));
+        sensor.value_template = ctx.tenths_template(&format!("{}/TempOda", SENSOR));
+        sensor.state_class = Some("measurement".to_string());
+        sensor.unit_of_measurement = Some("°C".to_string());
+        sensor.device_class = Some("temperature".to_string());
+        topics.push(ctx.document("sensor", sensor)?);
+    }
+
+    Ok(topics)
+}
+
+/// The box uses 0.1°C for the range of the supply temperature target
+fn heat_recovery_climate_topic(
+    node: &DucoBoxNode,
+    ctx: &DiscoveryContext,
+    parameter: &ConfigParameter,
+) -> Result<MqttData> {
+    let inside_temperature = node.published_value(&format!("{}/TempEta", SENSOR)).map(|_| {
+        format!(
+            "{}{}",
+            ctx.base_topic,
+            node.status_topic(&format!("{}/TempEta", SENSOR))
+        )
+    });
+
+    let unique_id = ctx.unique_id(node, "heat_recovery");
+    let climate = Climate {
+        origin: Origin::duco2mqtt(),
+        name: "Heat recovery".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        avty_t: format!("{}state", ctx.base_topic),
+        current_temperature_template: inside_temperature
+            .as_ref()
+            .and_then(|_| ctx.tenths_template(&format!("{}/TempEta", SENSOR))),
+        curr_temp_t: inside_temperature,
+        temp_stat_t: format!("{}{}", ctx.base_topic, SUPPLY_TEMPERATURE_TARGET),
+        temperature_state_template: ctx.tenths_template(SUPPLY_TEMPERATURE_TARGET),
+        temp_cmd_t: ctx.box_command_topic(HEAT_RECOVERY_DEVICE, "TempSupTgt"),
+        act_t: format!("{}{}", ctx.base_topic, BYPASS_POSITION),
+        action_template: "{{ 'cooling' if value | int > 0 else 'idle' }}".to_string(),
+        modes: vec!["auto".to_string()],
+        min_temp: parameter.min as f64 / 10.0,
+        max_temp: parameter.max as f64 / 10.0,
+        temp_step: parameter.inc.unwrap_or(1) as f64 / 10.0,
+        temperature_unit: "C".to_string(),
+        icon: Some("mdi:heat-wave".to_string()),
+        device: Some(ctx.box_device(HEAT_RECOVERY_DEVICE)),
+        entity_category: None,
+        enabled_by_default: None,
+    };
+
+    ctx.document("climate", climate)
+}
+
+pub fn flow_level_target_topic(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<MqttData> {
     let mut sensor = create_sensor_for_status(
-        node.number(),
-        base_topic,
+        node,
+        ctx,
         &format!("{}/FlowLvlTgt", VENTILATION),
         "ventilation_flow_level_target",
     );
@@ -179,33 +964,171 @@ pub fn flow_level_target_topic(node: &DucoBoxNode, base_topic: &str) -> Result<M
     sensor.unit_of_measurement = Some("%".to_string());
     sensor.icon = Some("mdi:fan-clock".to_string());
 
-    Ok(MqttData {
-        topic: format!("{}/sensor/{}/config", HASS_DISCOVERY_TOPIC, sensor.unique_id),
-        payload: serde_json::to_string(&sensor)?,
-    })
+    ctx.document("sensor", sensor)
 }
 
-pub fn co2_sensor_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
-    let mut sensor = create_sensor_for_status(
-        node.number(),
-        base_topic,
-        &format!("{}/IaqCo2", SENSOR),
-        "sensor_iaq_co2",
-    );
+/// The CO2 concentration (ppm) when the node reports it. The air quality percentage (IaqCo2) is only exposed for the
+/// nodes selected in the context or when the node does not report the concentration.
+pub fn co2_sensor_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+
+    let has_concentration = node.published_value(&format!("{}/Co2", SENSOR)).is_some();
+    if has_concentration {
+        let mut sensor = create_sensor_for_status(node, ctx, &format!("{}/Co2", SENSOR), "sensor_co2");
+        sensor.state_class = Some("measurement".to_string());
+        sensor.unit_of_measurement = Some("ppm".to_string());
+        sensor.device_class = Some("carbon_dioxide".to_string());
+        topics.push(ctx.document("sensor", sensor)?);
+    }
+
+    if !has_concentration || ctx.iaq_sensor_nodes.contains(&node.number()) {
+        let mut sensor = create_sensor_for_status(node, ctx, &format!("{}/IaqCo2", SENSOR), "sensor_iaq_co2");
+        sensor.state_class = Some("measurement".to_string());
+        sensor.unit_of_measurement = Some("%".to_string());
+        sensor.icon = Some("mdi:molecule-co2".to_string());
+        topics.push(ctx.document("sensor", sensor)?);
+    }
+
+    Ok(topics)
+}
+
+/// Number entity of a configuration parameter of a node, limited to the range the box reports
+pub fn create_number_for_config(
+    node: &DucoBoxNode,
+    ctx: &DiscoveryContext,
+    name: &str,
+    parameter: &ConfigParameter,
+) -> Number {
+    let topic_name = format!("{}/{}", CONFIG, name);
+    let unique_id = ctx.unique_id(node, &format!("config_{}", name.to_lowercase()));
+    let (entity_name, obj_id) = ctx.entity_name(node, &topic_name, &unique_id);
+
+    Number {
+        origin: Origin::duco2mqtt(),
+        name: entity_name,
+        obj_id,
+        unique_id,
+        stat_t: format!("{}{}", ctx.base_topic, node.status_topic(&topic_name)),
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: format!("{}{}", ctx.base_topic, node.command_topic(&topic_name)),
+        min: parameter.min as f64,
+        max: parameter.max as f64,
+        step: parameter.inc.unwrap_or(1) as f64,
+        mode: "box".to_string(),
+        value_template: None,
+        unit_of_measurement: None,
+        device_class: None,
+        entity_category: Some("config".to_string()),
+        icon: None,
+        device: Some(ctx.node_device(node)),
+        enabled_by_default: None,
+    }
+}
+
+/// The CO2 concentration the ventilation of the node aims for, lower values ventilate more
+pub fn co2_setpoint_topic(node: &DucoBoxNode, ctx: &DiscoveryContext, parameter: &ConfigParameter) -> Result<MqttData> {
+    let mut number = create_number_for_config(node, ctx, CO2_SETPOINT, parameter);
+    number.unit_of_measurement = Some("ppm".to_string());
+    number.device_class = Some("carbon_dioxide".to_string());
+    number.icon = Some("mdi:molecule-co2".to_string());
+
+    ctx.document("number", number)
+}
+
+/// The relative humidity the ventilation of the node aims for and the rise of the humidity that triggers extra
+/// ventilation, for the parameters the node reports
+pub fn humidity_setpoint_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+
+    if let Some(parameter) = node.config_parameter(RH_SETPOINT) {
+        let mut number = create_number_for_config(node, ctx, RH_SETPOINT, parameter);
+        number.unit_of_measurement = Some("%".to_string());
+        number.device_class = Some("humidity".to_string());
+        topics.push(ctx.document("number", number)?);
+    }
+
+    if let Some(parameter) = node.config_parameter(RH_DELTA) {
+        let mut number = create_number_for_config(node, ctx, RH_DELTA, parameter);
+        number.unit_of_measurement = Some("%".to_string());
+        number.icon = Some("mdi:water-percent-alert".to_string());
+        topics.push(ctx.document("number", number)?);
+    }
+
+    Ok(topics)
+}
+
+pub fn humidity_sensor_topic(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<MqttData> {
+    let mut sensor = create_sensor_for_status(node, ctx, &format!("{}/Rh", SENSOR), "sensor_rh");
     sensor.state_class = Some("measurement".to_string());
     sensor.unit_of_measurement = Some("%".to_string());
-    sensor.icon = Some("mdi:molecule-co2".to_string());
+    sensor.device_class = Some("humidity".to_string());
 
-    Ok(MqttData {
-        topic: format!("{}/sensor/{}/config", HASS_DISCOVERY_TOPIC, sensor.unique_id),
-        payload: serde_json::to_string(&sensor)?,
-    })
+    ctx.document("sensor", sensor)
 }
 
-pub fn state_time_remaining_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
+/// Sensors of the sensor nodes mounted in the box (duct sensors), grouped under the ventilation device of the box
+pub fn box_mounted_sensor_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    node.status_keys()
+        .into_iter()
+        .filter(|key| key.starts_with(SENSOR))
+        .map(|key| {
+            let name = key.rsplit('/').next().unwrap_or(key);
+            let mut sensor = create_sensor_for_status(node, ctx, key, &key.replace('/', "_").to_lowercase());
+            sensor.device = Some(ctx.box_device(VENTILATION_DEVICE));
+            sensor.state_class = Some("measurement".to_string());
+
+            match name {
+                "Rh" => {
+                    sensor.unit_of_measurement = Some("%".to_string());
+                    sensor.device_class = Some("humidity".to_string());
+                }
+                "Co2" => {
+                    sensor.unit_of_measurement = Some("ppm".to_string());
+                    sensor.device_class = Some("carbon_dioxide".to_string());
+                }
+                _ if name.starts_with("Iaq") => {
+                    sensor.unit_of_measurement = Some("%".to_string());
+                    sensor.icon = Some("mdi:air-filter".to_string());
+                }
+                _ => {}
+            }
+
+            ctx.document("sensor", sensor)
+        })
+        .collect()
+}
+
+/// Sensors of the weather station, the device class and unit are derived from the name of the sensor value
+pub fn weather_station_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    node.status_keys()
+        .into_iter()
+        .filter(|key| key.starts_with(SENSOR))
+        .map(|key| {
+            let name = key.rsplit('/').next().unwrap_or(key);
+            let mut sensor = create_sensor_for_status(node, ctx, key, &key.replace('/', "_").to_lowercase());
+            sensor.state_class = Some("measurement".to_string());
+
+            let (device_class, unit) = if name.contains("Temp") {
+                (Some("temperature"), Some("°C"))
+            } else if name.contains("Wind") {
+                (Some("wind_speed"), Some("m/s"))
+            } else if name.contains("Sun") || name.contains("Illum") {
+                (Some("illuminance"), Some("lx"))
+            } else {
+                (None, None)
+            };
+            sensor.device_class = device_class.map(str::to_string);
+            sensor.unit_of_measurement = unit.map(str::to_string);
+
+            ctx.document("sensor", sensor)
+        })
+        .collect()
+}
+
+pub fn state_time_remaining_topic(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<MqttData> {
     let mut sensor = create_sensor_for_status(
-        node.number(),
-        base_topic,
+        node,
+        ctx,
         &format!("{}/TimeStateRemain", VENTILATION),
         "ventilation_state_time_remaining",
     );
@@ -213,24 +1136,385 @@ pub fn state_time_remaining_topic(node: &DucoBoxNode, base_topic: &str) -> Resul
     sensor.unit_of_measurement = Some(String::from("seconds"));
     sensor.icon = Some("mdi:timer".to_string());
 
-    Ok(MqttData {
-        topic: format!("{}/sensor/{}/config", HASS_DISCOVERY_TOPIC, sensor.unique_id),
-        payload: serde_json::to_string(&sensor)?,
-    })
+    ctx.document("sensor", sensor)
 }
 
-pub fn identify_topic(node: &DucoBoxNode, base_topic: &str) -> Result<MqttData> {
-    let mut light = create_light_for_status(
-        node.number(),
-        base_topic,
-        &format!("{}/Identify", GENERAL),
-        "SetIdentify",
-        "identify",
-    );
+/// Diagnostic timestamp of the last poll that reported the node, old retained values remain when a node stops
+/// responding
+pub fn last_seen_topic(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<MqttData> {
+    let mut sensor = create_sensor_for_status(node, ctx, LAST_SEEN, LAST_SEEN);
+    sensor.json_attr_t = None;
+    sensor.device_class = Some("timestamp".to_string());
+    sensor.entity_category = Some("diagnostic".to_string());
+    ctx.document("sensor", sensor)
+}
+
+/// Checks if the discovery document describes a diagnostic entity
+pub fn is_diagnostic(document: &MqttData) -> bool {
+    serde_json::from_str::<serde_json::Value>(&document.payload)
+        .is_ok_and(|config| config["entity_category"] == "diagnostic")
+}
+
+/// Diagnostic sensors for the battery level and communication status of battery powered nodes,
+/// only created for the values the node actually reports
+pub fn battery_diagnostic_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+
+    for key in node.status_keys() {
+        let Some(name) = key.rsplit('/').next() else {
+            continue;
+        };
+
+        let mut sensor = create_sensor_for_status(node, ctx, key, &name.to_lowercase());
+        sensor.entity_category = Some("diagnostic".to_string());
+
+        if name.starts_with("Batt") {
+            sensor.state_class = Some("measurement".to_string());
+            sensor.unit_of_measurement = Some("%".to_string());
+            sensor.device_class = Some("battery".to_string());
+        } else if name.starts_with("Comm") {
+            sensor.icon = Some("mdi:access-point-network".to_string());
+        } else {
+            continue;
+        }
+
+        topics.push(ctx.document("sensor", sensor)?);
+    }
+
+    Ok(topics)
+}
+
+/// Diagnostic sensors for all the status values of a node, used for nodes that can not be controlled
+pub fn status_diagnostic_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    node.status_keys()
+        .into_iter()
+        .filter(|key| key.as_str() != "General/Type")
+        .map(|key| {
+            let mut sensor = create_sensor_for_status(node, ctx, key, &key.replace('/', "_").to_lowercase());
+            sensor.entity_category = Some("diagnostic".to_string());
+            ctx.document("sensor", sensor)
+        })
+        .collect()
+}
+
+/// Diagnostic sensors for the network information of a node (e.g. the parent node and the communication errors),
+/// kept off the main device page in home assistant
+pub fn network_diagnostic_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    node.status_keys()
+        .into_iter()
+        .filter(|key| {
+            key.rsplit('/')
+                .next()
+                .is_some_and(|name| NETWORK_DIAGNOSTIC_VALUES.contains(&name))
+        })
+        .map(|key| {
+            let mut sensor = create_sensor_for_status(node, ctx, key, &key.replace('/', "_").to_lowercase());
+            sensor.entity_category = Some("diagnostic".to_string());
+            ctx.document("sensor", sensor)
+        })
+        .collect()
+}
+
+/// Finds the status value that reflects the state of an action (e.g. SetVentilationState -> Ventilation/State)
+fn status_key_for_action<'a>(node: &'a DucoBoxNode, action: &str) -> Option<&'a String> {
+    let name = action.strip_prefix("Set").unwrap_or(action);
+    node.status_keys()
+        .into_iter()
+        .find(|key| key.replace('/', "") == name || key.rsplit('/').next() == Some(name))
+}
+
+/// Entities for node types without a dedicated mapping: a sensor per status value and a select or switch per action
+pub fn generic_topics(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<Vec<MqttData>> {
+    let mut topics = Vec::new();
+
+    for key in node.status_keys() {
+        if key.as_str() == "General/Type" {
+            continue;
+        }
+
+        let mut sensor = create_sensor_for_status(node, ctx, key, &key.replace('/', "_").to_lowercase());
+        if key.starts_with(GENERAL) {
+            sensor.entity_category = Some("diagnostic".to_string());
+        }
+        topics.push(ctx.document("sensor", sensor)?);
+    }
+
+    for action in node.actions() {
+        match action {
+            DucoNodeAction::SetEnum(name, values) if name == SET_VENTILATION_STATE => {
+                topics.push(ventilation_state_topic(node, ctx, values)?);
+            }
+            DucoNodeAction::SetBoolean(name) if name == SET_IDENTIFY => {
+                topics.push(identify_topic(node, ctx)?);
+            }
+            DucoNodeAction::SetEnum(name, values) => {
+                let mut select = create_select_for_status(
+                    node,
+                    ctx,
+                    name,
+                    name,
+                    &format!("action_{}", name.to_lowercase()),
+                    values,
+                );
+                select.stat_t = status_key_for_action(node, name)
+                    .map(|key| format!("{}{}", ctx.base_topic, node.status_topic(key)))
+                    .unwrap_or_default();
+
+                topics.push(ctx.document("select", select)?);
+            }
+            DucoNodeAction::SetBoolean(name) => {
+                let unique_id = ctx.unique_id(node, &format!("action_{}", name.to_lowercase()));
+                let (entity_name, obj_id) = ctx.entity_name(node, name, &unique_id);
+                let switch = Switch {
+                    origin: Origin::duco2mqtt(),
+                    name: entity_name,
+                    obj_id,
+                    unique_id,
+                    stat_t: status_key_for_action(node, name)
+                        .map(|key| format!("{}{}", ctx.base_topic, node.status_topic(key)))
+                        .unwrap_or_default(),
+                    avty_t: format!("{}state", ctx.base_topic),
+                    cmd_t: format!("{}{}", ctx.base_topic, node.command_topic(name)),
+                    payload_on: "1".to_string(),
+                    payload_off: "0".to_string(),
+                    value_template: None,
+                    entity_category: None,
+                    icon: None,
+                    device: Some(ctx.node_device(node)),
+                    enabled_by_default: None,
+                };
+
+                topics.push(ctx.document("switch", switch)?);
+            }
+        }
+    }
+
+    Ok(topics)
+}
+
+pub fn identify_topic(node: &DucoBoxNode, ctx: &DiscoveryContext) -> Result<MqttData> {
+    let mut light = create_light_for_status(node, ctx, &format!("{}/Identify", GENERAL), SET_IDENTIFY, "identify");
     light.icon = Some("mdi:led-on".to_string());
+    light.entity_category = Some("config".to_string());
+
+    ctx.document("light", light)
+}
+
+pub fn update_topic(ctx: &DiscoveryContext) -> Result<MqttData> {
+    let unique_id = "duco2mqtt_update".to_string();
+
+    let update = Update {
+        origin: Origin::duco2mqtt(),
+        name: "duco2mqtt".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}bridge/update", ctx.base_topic),
+        avty_t: format!("{}state", ctx.base_topic),
+        entity_category: Some("diagnostic".to_string()),
+        device: Some(Device::bridge()),
+        enabled_by_default: None,
+    };
+
+    ctx.document("update", update)
+}
+
+pub fn away_mode_topic(ctx: &DiscoveryContext) -> Result<MqttData> {
+    let unique_id = "duco_bridge_away_mode".to_string();
+
+    let switch = Switch {
+        origin: Origin::duco2mqtt(),
+        name: "Away mode".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}bridge/away", ctx.base_topic),
+        avty_t: format!("{}state", ctx.base_topic),
+        cmd_t: format!("{}bridge/cmnd/away", ctx.base_topic),
+        payload_on: "ON".to_string(),
+        payload_off: "OFF".to_string(),
+        value_template: None,
+        entity_category: None,
+        icon: Some("mdi:home-export-outline".to_string()),
+        device: Some(Device::bridge()),
+        enabled_by_default: None,
+    };
+
+    ctx.document("switch", switch)
+}
+
+/// Reports a problem when the discovery documents retained on the broker differ from the ones the bridge generates
+pub fn discovery_drift_topic(ctx: &DiscoveryContext) -> Result<MqttData> {
+    let unique_id = "duco_bridge_discovery_drift".to_string();
+
+    let sensor = BinarySensor {
+        origin: Origin::duco2mqtt(),
+        name: "Discovery drift".to_string(),
+        obj_id: unique_id.clone(),
+        unique_id,
+        stat_t: format!("{}bridge/discovery_drift", ctx.base_topic),
+        avty_t: format!("{}state", ctx.base_topic),
+        payload_on: "ON".to_string(),
+        payload_off: "OFF".to_string(),
+        device_class: Some("problem".to_string()),
+        entity_category: Some("diagnostic".to_string()),
+        icon: None,
+        device: Some(Device::bridge()),
+        enabled_by_default: None,
+    };
+
+    ctx.document("binary_sensor", sensor)
+}
+
+/// Combines the entity discovery documents into a single device discovery document per device
+/// (`homeassistant/device/<device id>/config`), documents without a device are kept as they are
+pub fn device_discovery_documents(documents: Vec<MqttData>) -> Result<Vec<MqttData>> {
+    let mut devices: Vec<(String, serde_json::Value)> = Vec::new();
+    let mut entity_documents = Vec::new();
+
+    for document in documents {
+        let mut config: serde_json::Value = serde_json::from_str(&document.payload)?;
+        let (Some(platform), Some(config_map)) = (document.topic.split('/').nth(1), config.as_object_mut()) else {
+            entity_documents.push(document);
+            continue;
+        };
+
+        let (Some(device), Some(unique_id)) = (
+            config_map.remove("device"),
+            config_map
+                .get("unique_id")
+                .and_then(|id| id.as_str())
+                .map(str::to_string),
+        ) else {
+            entity_documents.push(document);
+            continue;
+        };
+
+        let Some(device_id) = device["identifiers"][0].as_str().map(str::to_string) else {
+            entity_documents.push(document);
+            continue;
+        };
+
+        let origin = config_map.remove("origin");
+        config_map.insert("platform".to_string(), platform.into());
+
+        let index = match devices.iter().position(|(id, _)| *id == device_id) {
+            Some(index) => index,
+            None => {
+                devices.push((
+                    device_id,
+                    serde_json::json!({ "device": device, "origin": origin, "components": {} }),
+                ));
+                devices.len() - 1
+            }
+        };
+        devices[index].1["components"][unique_id] = config;
+    }
+
+    let mut result = devices
+        .into_iter()
+        .map(|(device_id, config)| {
+            Ok(MqttData {
+                topic: format!("{}/device/{}/config", HASS_DISCOVERY_TOPIC, device_id),
+                payload: serde_json::to_string(&config)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    result.extend(entity_documents);
+    Ok(result)
+}
+
+/// Whether home assistant enables the entity on a fresh install: the diagnostic entities (e.g. the communication
+/// counters) and the timers are disabled, the entities of the bridge itself are opt-in and stay enabled
+fn enabled_by_default(entity: &impl DiscoveryEntity) -> bool {
+    if entity
+        .device()
+        .is_some_and(|device| device.identifiers == Device::bridge().identifiers)
+    {
+        return true;
+    }
+
+    entity.entity_category() != Some("diagnostic")
+        && !DISABLED_BY_DEFAULT
+            .iter()
+            .any(|suffix| entity.unique_id().ends_with(suffix))
+}
+
+/// State topics of the entities and the template option that extracts the value from the state payload
+const STATE_TOPIC_TEMPLATES: [(&str, &str); 6] = [
+    ("stat_t", "value_template"),
+    ("pr_mode_stat_t", "preset_mode_value_template"),
+    ("pct_stat_t", "percentage_value_template"),
+    ("curr_temp_t", "current_temperature_template"),
+    ("temp_stat_t", "temperature_state_template"),
+    ("act_t", "action_template"),
+];
+
+/// The node level and status key of a node status topic (`<base><node>/<group>/<key>`), the group is matched
+/// case insensitive for the lowercase topic scheme. Topics of the box (e.g. `HeatRecovery/General/TimeFilterRemain`)
+/// are not node status topics, the node level has to be one of `node_topics`.
+fn parse_node_status_topic<'a>(
+    base_topic: &str,
+    node_topics: &[String],
+    topic: &'a str,
+) -> Option<(&'a str, &'a str, &'a str)> {
+    let (node, key) = topic.strip_prefix(base_topic)?.split_once('/')?;
+    let (group, name) = key.split_once('/')?;
+    if !node_topics.iter().any(|node_topic| node_topic == node)
+        || ![GENERAL, VENTILATION, SENSOR, CONFIG]
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(group))
+        || name.contains('/')
+    {
+        return None;
+    }
+
+    Some((node, group, name))
+}
+
+/// Points the state topics of the node entities at the json state document of the node, the value is extracted with
+/// a template (e.g. `{{ value_json.Ventilation.State }}`) that is prepended to the template of the entity, if any.
+/// `node_topics` are the node levels of the nodes that publish a json state document.
+pub fn json_state_documents(
+    documents: Vec<MqttData>,
+    base_topic: &str,
+    node_topics: &[String],
+) -> Result<Vec<MqttData>> {
+    documents
+        .into_iter()
+        .map(|document| {
+            let mut config: serde_json::Value = serde_json::from_str(&document.payload)?;
+            let platform = document.topic.split('/').nth(1).unwrap_or_default();
+            let Some(config_map) = config.as_object_mut() else {
+                return Ok(document);
+            };
+
+            for (topic_key, template_key) in STATE_TOPIC_TEMPLATES {
+                let template_key = match (topic_key, platform) {
+                    ("stat_t", "light" | "fan") => "state_value_template",
+                    _ => template_key,
+                };
+
+                let Some(topic) = config_map.get(topic_key).and_then(|topic| topic.as_str()) else {
+                    continue;
+                };
+                let Some((node, group, name)) = parse_node_status_topic(base_topic, node_topics, topic) else {
+                    continue;
+                };
+
+                let value = format!("value_json.{}.{}", group, name);
+                let template = match config_map.get(template_key).and_then(|template| template.as_str()) {
+                    Some(template) => format!("{{% set value = {} %}}{}", value, template),
+                    None => format!("{{{{ {} }}}}", value),
+                };
+
+                let state_topic = format!("{}{}/{}", base_topic, node, JSON_STATE);
+                config_map.insert(topic_key.to_string(), state_topic.into());
+                config_map.insert(template_key.to_string(), template.into());
+            }
 
-    Ok(MqttData {
-        topic: format!("{}/light/{}/config", HASS_DISCOVERY_TOPIC, light.unique_id),
-        payload: serde_json::to_string(&light)?,
-    })
+            Ok(MqttData {
+                topic: document.topic,
+                payload: serde_json::to_string(&config)?,
+            })
+        })
+        .collect()
 }
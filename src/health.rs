@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time,
+};
+
+const HEALTH_PATH: &str = "/healthz";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// The state of the bridge that is reported by the health endpoint
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthStatus {
+    pub mqtt_connected: bool,
+    /// Completion time of the last poll of the box
+    pub last_poll: Option<DateTime<Utc>>,
+    pub last_poll_ok: bool,
+    pub last_successful_poll: Option<DateTime<Utc>>,
+}
+
+impl HealthStatus {
+    /// Connected to the broker and the last poll succeeded, a bridge that stopped polling is not healthy once the
+    /// last successful poll is older than the maximum age (zero disables this check)
+    pub fn is_healthy(&self, max_poll_age: Duration, now: DateTime<Utc>) -> bool {
+        let recent = max_poll_age.is_zero()
+            || self
+                .last_successful_poll
+                .is_some_and(|poll| (now - poll).to_std().unwrap_or_default() <= max_poll_age);
+
+        self.mqtt_connected && self.last_poll_ok && recent
+    }
+
+    fn to_json(&self, healthy: bool) -> serde_json::Value {
+        let timestamp = |time: Option<DateTime<Utc>>| time.map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true));
+        serde_json::json!({
+            "healthy": healthy,
+            "mqtt_connected": self.mqtt_connected,
+            "last_poll": timestamp(self.last_poll),
+            "last_poll_ok": self.last_poll_ok,
+            "last_successful_poll": timestamp(self.last_successful_poll),
+        })
+    }
+}
+
+/// Serves the health status on `/healthz`: status 200 when healthy, 503 otherwise, with the status as json body
+pub async fn serve(listener: TcpListener, status: watch::Receiver<HealthStatus>, max_poll_age: Duration) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let status = status.borrow().clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, &status, max_poll_age).await {
+                        tracing::debug!("Failed to answer the health request: {}", err);
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("Failed to accept a health request: {}", err),
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    status: &HealthStatus,
+    max_poll_age: Duration,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 512];
+
+    // only the request line is of interest, the headers are not needed
+    while !request.windows(2).any(|bytes| bytes == b"\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let size = time::timeout(REQUEST_TIMEOUT, stream.read(&mut buffer))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))??;
+        if size == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..size]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let request_line = request.lines().next().unwrap_or_default();
+    stream
+        .write_all(response(request_line, status, max_poll_age, Utc::now()).as_bytes())
+        .await?;
+    stream.shutdown().await
+}
+
+fn response(request_line: &str, status: &HealthStatus, max_poll_age: Duration, now: DateTime<Utc>) -> String {
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let path = path.split('?').next().unwrap_or_default();
+
+    let (code, body) = match (method, path) {
+        ("GET" | "HEAD", HEALTH_PATH) => {
+            let healthy = status.is_healthy(max_poll_age, now);
+            let code = if healthy { "200 OK" } else { "503 Service Unavailable" };
+            (code, status.to_json(healthy).to_string())
+        }
+        (_, HEALTH_PATH) => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let body = if method == "HEAD" { "" } else { body.as_str() };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_response() {
+        let now = Utc::now();
+        let max_age = Duration::from_secs(600);
+        let mut status = HealthStatus::default();
+        assert!(response("GET /healthz HTTP/1.1", &status, max_age, now).starts_with("HTTP/1.1 503 "));
+
+        status.mqtt_connected = true;
+        status.last_poll = Some(now);
+        status.last_poll_ok = true;
+        status.last_successful_poll = Some(now - chrono::Duration::seconds(30));
+        let ok = response("GET /healthz?verbose HTTP/1.1", &status, max_age, now);
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body: serde_json::Value = serde_json::from_str(ok.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["healthy"], true);
+        assert_eq!(body["mqtt_connected"], true);
+        assert!(body["last_poll"].as_str().unwrap().ends_with('Z'));
+
+        // a bridge that stopped polling is reported as unhealthy
+        assert!(!status.is_healthy(max_age, now + chrono::Duration::seconds(600)));
+        assert!(status.is_healthy(Duration::ZERO, now + chrono::Duration::seconds(600)));
+
+        status.last_poll_ok = false;
+        assert!(!status.is_healthy(max_age, now));
+        status.last_poll_ok = true;
+        status.mqtt_connected = false;
+        assert!(!status.is_healthy(max_age, now));
+
+        assert!(response("HEAD /healthz HTTP/1.1", &status, max_age, now).ends_with("\r\n\r\n"));
+        assert!(response("POST /healthz HTTP/1.1", &status, max_age, now).starts_with("HTTP/1.1 405 "));
+        assert!(response("GET / HTTP/1.1", &status, max_age, now).starts_with("HTTP/1.1 404 "));
+        assert!(response("", &status, max_age, now).starts_with("HTTP/1.1 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_health_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = watch::channel(HealthStatus::default());
+        tokio::spawn(serve(listener, receiver, Duration::ZERO));
+
+        let request = || async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        assert!(request().await.starts_with("HTTP/1.1 503 "));
+
+        sender.send_modify(|status| {
+            status.mqtt_connected = true;
+            status.last_poll_ok = true;
+        });
+        assert!(request().await.starts_with("HTTP/1.1 200 "));
+    }
+}
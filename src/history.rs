@@ -0,0 +1,109 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+/// Period the statistics are calculated over
+const STATISTICS_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Statistics {
+    pub min: i64,
+    pub max: i64,
+    pub avg: f64,
+    pub samples: usize,
+}
+
+/// Keeps the most recent samples per topic in memory to provide trend data without an external database
+pub struct SampleHistory {
+    capacity: usize,
+    samples: HashMap<String, VecDeque<(Instant, i64)>>,
+}
+
+impl SampleHistory {
+    /// A capacity of 0 disables the history
+    pub fn new(capacity: usize) -> SampleHistory {
+        SampleHistory {
+            capacity,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(&mut self, topic: &str, value: i64, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let samples = self.samples.entry(topic.to_string()).or_default();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back((now, value));
+    }
+
+    /// Minimum, maximum and average of the retained samples of the last hour
+    pub fn statistics(&self, topic: &str, now: Instant) -> Option<Statistics> {
+        let values: Vec<i64> = self
+            .samples
+            .get(topic)?
+            .iter()
+            .filter(|(time, _)| now.duration_since(*time) <= STATISTICS_WINDOW)
+            .map(|(_, value)| *value)
+            .collect();
+
+        Some(Statistics {
+            min: *values.iter().min()?,
+            max: *values.iter().max()?,
+            avg: values.iter().sum::<i64>() as f64 / values.len() as f64,
+            samples: values.len(),
+        })
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.samples.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_history() {
+        let start = Instant::now();
+        let mut history = SampleHistory::new(3);
+
+        history.record("co2", 10, start);
+        history.record("co2", 20, start + Duration::from_secs(60));
+        history.record("co2", 30, start + Duration::from_secs(120));
+        history.record("co2", 60, start + Duration::from_secs(180));
+
+        // the oldest sample is dropped when the capacity is reached
+        assert_eq!(
+            history.statistics("co2", start + Duration::from_secs(180)),
+            Some(Statistics {
+                min: 20,
+                max: 60,
+                avg: 110.0 / 3.0,
+                samples: 3,
+            })
+        );
+
+        // samples older than an hour are ignored
+        let later = start + Duration::from_secs(60 * 60 + 150);
+        assert_eq!(history.statistics("co2", later).map(|stats| stats.samples), Some(1));
+        assert_eq!(
+            history.statistics("co2", start + Duration::from_secs(3 * 60 * 60)),
+            None
+        );
+        assert_eq!(history.statistics("rh", start), None);
+
+        let mut disabled = SampleHistory::new(0);
+        disabled.record("co2", 10, start);
+        assert_eq!(disabled.statistics("co2", start), None);
+    }
+}
@@ -19,6 +19,10 @@ impl InfoValue {
         }
     }
 
+    pub fn value(&self) -> &StatusValue {
+        &self.value
+    }
+
     pub fn is_modified(&self) -> bool {
         self.modified
     }
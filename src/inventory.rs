@@ -0,0 +1,223 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::Serialize;
+
+use crate::{
+    ducoapi::{DeviceInfo, NodeActions, NodeInfo, StatusValue},
+    ducoboxnode::DucoNodeAction,
+    duconodetypes::NodeType,
+};
+
+/// What the box reports about itself and its nodes, as printed by the discover subcommand. It is built from the raw
+/// api responses, so the nodes the bridge can not handle are listed as well, with the reason.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Inventory {
+    /// The values of the box by status key (e.g. `General/Board/BoxName`)
+    pub device: BTreeMap<String, String>,
+    pub nodes: Vec<NodeInventory>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeInventory {
+    pub number: u16,
+    /// The duco node type (e.g. BOX, UCCO2, VLV), None when the box does not report it
+    pub node_type: Option<String>,
+    pub name: Option<String>,
+    pub actions: Vec<ActionInventory>,
+    /// Why the bridge does not (fully) expose the node, empty when it is supported
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ActionInventory {
+    pub action: String,
+    /// The value type of the action (Enum, Boolean, ...)
+    pub value_type: String,
+    /// The accepted values of an enum action
+    pub values: Vec<String>,
+}
+
+impl Inventory {
+    pub fn new(device: DeviceInfo, nodes: Vec<NodeInfo>, node_actions: Vec<NodeActions>) -> Inventory {
+        let mut node_actions: BTreeMap<u16, NodeActions> = node_actions
+            .into_iter()
+            .map(|actions| (actions.node, actions))
+            .collect();
+
+        Inventory {
+            device: device
+                .general
+                .into_iter()
+                .map(|(key, field)| (key, field.val.to_string()))
+                .collect(),
+            nodes: nodes
+                .into_iter()
+                .map(|node| {
+                    let actions = node_actions.remove(&node.node);
+                    NodeInventory::new(node, actions)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl NodeInventory {
+    fn new(node: NodeInfo, actions: Option<NodeActions>) -> NodeInventory {
+        let mut problems = Vec::new();
+        let general = |key: &str| match node.general.get(key).map(|field| &field.val) {
+            Some(StatusValue::String(val)) if !val.is_empty() => Some(val.clone()),
+            _ => None,
+        };
+
+        let node_type = general("Type");
+        match &node_type {
+            Some(node_type) if NodeType::from_str(node_type).is_err() => {
+                problems.push(format!("Unknown node type: {}", node_type));
+            }
+            Some(_) => {}
+            None => problems.push("The node type is missing".to_string()),
+        }
+
+        let actions = match actions {
+            Some(actions) => actions
+                .actions
+                .into_iter()
+                .map(|action| {
+                    let inventory = ActionInventory {
+                        action: action.action.clone(),
+                        value_type: action.val_type.clone(),
+                        values: action.values.clone().unwrap_or_default(),
+                    };
+                    if let Err(err) = DucoNodeAction::try_from(action) {
+                        problems.push(format!("{}: {}", inventory.action, err));
+                    }
+                    inventory
+                })
+                .collect(),
+            None => {
+                problems.push("The box does not report the actions of the node".to_string());
+                Vec::new()
+            }
+        };
+
+        NodeInventory {
+            number: node.node,
+            name: general("Name"),
+            node_type,
+            actions,
+            problems,
+        }
+    }
+}
+
+/// The values of the box followed by a table of the nodes
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Device")?;
+        let key_width = self.device.keys().map(String::len).max().unwrap_or_default();
+        for (key, value) in &self.device {
+            writeln!(f, "  {:width$}  {}", key, value, width = key_width)?;
+        }
+
+        let rows: Vec<[String; 5]> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let actions = node
+                    .actions
+                    .iter()
+                    .map(|action| {
+                        if action.values.is_empty() {
+                            action.action.clone()
+                        } else {
+                            format!("{} ({})", action.action, action.values.join("|"))
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                [
+                    node.number.to_string(),
+                    node.node_type.clone().unwrap_or_else(|| "-".to_string()),
+                    node.name.clone().unwrap_or_else(|| "-".to_string()),
+                    if actions.is_empty() {
+                        "-".to_string()
+                    } else {
+                        actions.join(", ")
+                    },
+                    node.problems.join("; "),
+                ]
+            })
+            .collect();
+
+        let header = ["Node", "Type", "Name", "Actions", "Problems"];
+        let widths: Vec<usize> = (0..header.len())
+            .map(|col| {
+                rows.iter()
+                    .map(|row| row[col].len())
+                    .fold(header[col].len(), usize::max)
+            })
+            .collect();
+
+        writeln!(f, "\nNodes")?;
+        for row in std::iter::once(header.map(str::to_string)).chain(rows) {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(f, "  {}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ducoapi::{self, StatusField};
+
+    use super::*;
+
+    #[test]
+    fn test_inventory() {
+        let device = ducoapi::parse_device_info(include_bytes!("../test/data/info.json")).unwrap();
+        let mut nodes = ducoapi::parse_node_info(include_bytes!("../test/data/info_nodes.json")).unwrap();
+        let actions = ducoapi::parse_node_actions(include_bytes!("../test/data/node_actions.json")).unwrap();
+
+        let mut unknown = nodes[1].clone();
+        unknown.node = 99;
+        unknown.general.insert("Type".to_string(), StatusField::from("NEWTYPE"));
+        nodes.push(unknown);
+
+        let inventory = Inventory::new(device, nodes, actions);
+        assert_eq!(inventory.device["General/Board/BoxName"], "ENERGY");
+
+        let supported = &inventory.nodes[0];
+        assert_eq!(supported.node_type.as_deref(), Some("BOX"));
+        assert!(supported.problems.is_empty());
+        assert!(
+            supported
+                .actions
+                .iter()
+                .any(|action| action.action == "SetVentilationState" && action.values.contains(&"AUTO".to_string()))
+        );
+
+        let unknown = inventory.nodes.last().unwrap();
+        assert_eq!(unknown.number, 99);
+        assert_eq!(
+            unknown.problems,
+            vec![
+                "Unknown node type: NEWTYPE".to_string(),
+                "The box does not report the actions of the node".to_string()
+            ]
+        );
+
+        let text = inventory.to_string();
+        assert!(text.starts_with("Device\n"));
+        assert!(text.contains("\nNodes\n  Node  Type"));
+        assert!(
+            text.lines()
+                .any(|line| line.starts_with("  99 ") && line.ends_with("of the node"))
+        );
+    }
+}
@@ -2,13 +2,43 @@
 use thiserror::Error;
 
 pub mod bridge;
+mod certpinning;
+mod commandqueue;
+pub mod compat;
+mod discoverydrift;
+mod discoveryv1;
+pub mod doctor;
 mod ducoapi;
+mod ducobackend;
 mod ducoboxdevice;
 mod ducoboxnode;
+mod ducoendpoint;
 mod duconodetypes;
+mod errorevent;
 mod hassdiscovery;
+mod health;
+mod history;
 mod infovalue;
+pub mod inventory;
+mod logsampler;
 pub mod mqtt;
+mod mqttclient;
+mod mqtttls;
+pub mod naming;
+pub mod nodemodel;
+mod overridestack;
+mod persistentstate;
+mod poller;
+mod pollschedule;
+mod recording;
+pub mod redact;
+pub mod secret;
+pub mod simulator;
+pub mod statusfilter;
+pub mod tlsprobe;
+mod updatecheck;
+pub mod valuetransform;
+mod virtualnode;
 
 extern crate num;
 #[macro_use]
@@ -29,13 +59,30 @@ pub enum Error {
     #[error("Invalid address {0}")]
     InvalidAddress(#[from] AddrParseError),
     #[error("MQTT error {0}")]
-    MqttClientError(#[from] rumqttc::v5::ClientError),
+    MqttClientError(#[from] Box<rumqttc::v5::ClientError>),
     #[error("MQTT error {0}")]
-    MqttConnectionError(#[from] rumqttc::v5::ConnectionError),
+    MqttConnectionError(#[from] Box<rumqttc::v5::ConnectionError>),
     #[error("Serialization error {0}")]
     SerializationError(#[from] serde_json::Error),
     #[error("Request error {0}")]
     RequestError(#[from] reqwest::Error),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("{0:#}")]
+    Other(anyhow::Error),
 }
 
+/// Errors of the internal helpers are converted at the public api boundary, typed errors are preserved
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Error {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => Error::Other(err),
+        }
+    }
+}
+
+/// Result of the internal helpers
 type Result<T> = anyhow::Result<T>;
+/// Result of the public api
+pub type ApiResult<T> = std::result::Result<T, Error>;
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+/// Decides which publishes get their payload logged at info level.
+/// Every n-th publish of a topic is sampled, an interval of 0 disables sampling.
+pub struct LogSampler {
+    interval: u32,
+    counters: HashMap<String, u32>,
+}
+
+impl LogSampler {
+    pub fn new(interval: u32) -> Self {
+        Self {
+            interval,
+            counters: HashMap::default(),
+        }
+    }
+
+    pub fn sample(&mut self, topic: &str) -> bool {
+        if self.interval == 0 {
+            return false;
+        }
+
+        let counter = match self.counters.get_mut(topic) {
+            Some(counter) => counter,
+            None => self.counters.entry(topic.to_string()).or_insert(0),
+        };
+
+        let sampled = *counter == 0;
+        *counter = (*counter + 1) % self.interval;
+        sampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_sampling() {
+        let mut sampler = LogSampler::new(3);
+        let samples: Vec<bool> = (0..6).map(|_| sampler.sample("a")).collect();
+        assert_eq!(samples, vec![true, false, false, true, false, false]);
+        assert!(sampler.sample("b"));
+
+        let mut disabled = LogSampler::new(0);
+        assert!(!disabled.sample("a"));
+        assert!(!disabled.sample("a"));
+    }
+}
@@ -1,23 +1,114 @@
-use crate::Result;
-use anyhow::anyhow;
-use std::time::Duration;
-
-use rumqttc::v5::{
-    AsyncClient, Event, EventLoop, MqttOptions,
-    mqttbytes::{
-        QoS,
-        v5::{ConnectReturnCode, LastWill, Packet},
-    },
+use crate::{
+    ApiResult, Error, Result,
+    mqttclient::{self, ConnectOptions, Incoming, MqttClient, MqttEventLoop},
+    mqtttls,
+    secret::Secret,
 };
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rumqttc::{Transport, v5::mqttbytes::QoS};
+use tokio::time::{self, Instant};
+
+pub use crate::mqttclient::MqttProtocol;
 
-#[derive(Clone)]
 pub struct MqttConfig {
+    /// The broker address, or a comma separated list of `host[:port]` addresses of redundant brokers that are tried in
+    /// turn when the connection fails
     pub server: String,
+    /// Port of the brokers that are listed without one
     pub port: u16,
     pub client_id: String,
     pub user: String,
-    pub password: String,
+    pub password: Secret,
     pub base_topic: String,
+    /// Low power mode of the bridge, the connection uses a longer keep alive and unacknowledged publishes.
+    /// The bridge reads the flag from here to lengthen the poll interval, publish the json state of the nodes and skip
+    /// the diagnostic topics.
+    pub low_power: bool,
+    pub protocol: MqttProtocol,
+    pub tls: bool,
+    /// PEM bundle with the CA certificates of the broker, the system certificates are used when not provided
+    pub ca_cert: Option<PathBuf>,
+    /// Name to validate the broker certificate against when it differs from the server address, the SNI is still
+    /// derived from the server address
+    pub tls_server_name: Option<String>,
+    /// PEM encoded client certificate (chain) and private key, for brokers that authenticate the clients by certificate
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// Handle the retained commands the broker replays when subscribing, they are ignored by default so a restart
+    /// does not repeat old commands
+    pub retained_commands: bool,
+}
+
+impl MqttConfig {
+    /// The base topic without surrounding whitespace and slashes, the bridge joins the topic levels with a single '/'
+    pub fn normalized_base_topic(&self) -> ApiResult<String> {
+        let base_topic = self.base_topic.trim().trim_matches('/');
+
+        if base_topic.is_empty() {
+            return Err(Error::Config("The MQTT base topic is empty".to_string()));
+        }
+
+        if base_topic.contains(['+', '#']) {
+            return Err(Error::Config(format!(
+                "The MQTT base topic '{}' contains a wildcard ('+' or '#')",
+                self.base_topic
+            )));
+        }
+
+        if base_topic.split('/').any(str::is_empty) {
+            return Err(Error::Config(format!(
+                "The MQTT base topic '{}' contains an empty topic level",
+                self.base_topic
+            )));
+        }
+
+        Ok(base_topic.to_string())
+    }
+
+    /// The addresses and ports of the brokers in the configured order
+    pub fn brokers(&self) -> ApiResult<Vec<(String, u16)>> {
+        self.server
+            .split(',')
+            .map(str::trim)
+            .map(|broker| {
+                let (host, port) = match broker.strip_prefix('[') {
+                    // ipv6 address, e.g. [::1]:1883
+                    Some(broker) => match broker.split_once(']') {
+                        Some((host, "")) => (host, None),
+                        Some((host, port)) => (host, Some(port.strip_prefix(':').unwrap_or(port))),
+                        None => (broker, None),
+                    },
+                    None => match broker.split_once(':') {
+                        Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                        _ => (broker, None),
+                    },
+                };
+
+                let port = match port {
+                    Some(port) => port
+                        .parse()
+                        .map_err(|_| Error::Config(format!("Invalid port in MQTT broker address '{}'", broker)))?,
+                    None => self.port,
+                };
+
+                if host.is_empty() {
+                    return Err(Error::Config(format!("Invalid MQTT broker address '{}'", self.server)));
+                }
+
+                Ok((host.to_string(), port))
+            })
+            .collect()
+    }
+
+    pub fn normalize(&mut self) -> ApiResult<()> {
+        self.base_topic = self.normalized_base_topic()?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -37,7 +128,7 @@ impl MqttData {
 
 impl PartialOrd for MqttData {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.topic.cmp(&other.topic))
+        Some(self.cmp(other))
     }
 }
 
@@ -48,65 +139,319 @@ impl Ord for MqttData {
 }
 
 pub struct MqttConnection {
-    client: AsyncClient,
-    eventloop: EventLoop,
+    client: MqttClient,
+    eventloop: MqttEventLoop,
+    /// The options of the event loop, changed to connect to the next broker when a connection fails
+    connect_options: ConnectOptions,
+    brokers: Vec<(String, u16)>,
+    broker: usize,
+    /// The broker of the last connection, the retained topics are published again after switching brokers
+    connected_broker: Option<usize>,
+    broker_switched: bool,
     base_topic: String,
-}
-
-fn from_mqtt_string(stream: &bytes::Bytes) -> Result<String> {
-    match String::from_utf8(stream.to_vec()) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(anyhow!("Mqtt string conversion error: {}", e)),
-    }
+    publish_qos: QoS,
+    subscriptions: Vec<String>,
+    retained_commands: bool,
+    connected: bool,
+    /// Failed connection attempts since the connection was lost, determines the reconnect backoff
+    reconnect_attempts: u32,
+    reconnect_at: Option<Instant>,
+    /// Publishes made while disconnected, only the last payload of a topic is kept
+    offline_buffer: VecDeque<MqttData>,
+    /// The last published availability of the bridge, published again after a reconnect to replace the last will
+    online: Option<bool>,
+    /// Publishes that expect an acknowledgement and the acknowledgements received, since the connection was made
+    published: u64,
+    acknowledged: u64,
+    /// Messages received while waiting for acknowledgements, returned by the next polls
+    received: VecDeque<MqttData>,
+    /// Why the last connection attempt failed
+    connect_error: Option<String>,
 }
 
 const OFFLINE_PAYLOAD: &str = "offline";
+const LOW_POWER_KEEP_ALIVE: Duration = Duration::from_secs(900);
 const ONLINE_PAYLOAD: &str = "online";
+const OFFLINE_BUFFER_CAPACITY: usize = 1000;
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with up to 25% jitter, so multiple bridges do not reconnect in lockstep after a broker restart
+fn reconnect_backoff(attempt: u32) -> Duration {
+    let backoff = RECONNECT_MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RECONNECT_MAX_BACKOFF);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos());
+    backoff + backoff.mul_f64(f64::from(nanos % 1000) / 4000.0)
+}
 
 fn state_topic(base_topic: &String) -> String {
     format!("{}/state", base_topic)
 }
 
 impl MqttConnection {
-    pub fn new(cfg: MqttConfig) -> MqttConnection {
-        let mut mqttoptions = MqttOptions::new(cfg.client_id, cfg.server, cfg.port);
-        mqttoptions.set_clean_start(true);
-        mqttoptions.set_keep_alive(Duration::from_secs(180));
-        mqttoptions.set_last_will(LastWill::new(
-            state_topic(&cfg.base_topic),
-            OFFLINE_PAYLOAD,
-            QoS::AtLeastOnce,
-            true,
-            None,
-        ));
+    pub fn new(cfg: MqttConfig) -> Result<MqttConnection> {
+        let brokers = cfg.brokers()?;
+        let transport = if cfg.tls {
+            Transport::tls_with_config(mqtttls::client_config(&cfg)?.into())
+        } else {
+            Transport::tcp()
+        };
 
-        if !cfg.user.is_empty() {
-            mqttoptions.set_credentials(cfg.user, cfg.password);
-        }
+        let options = ConnectOptions {
+            client_id: cfg.client_id,
+            server: brokers[0].0.clone(),
+            port: brokers[0].1,
+            transport,
+            keep_alive: if cfg.low_power {
+                LOW_POWER_KEEP_ALIVE
+            } else {
+                Duration::from_secs(180)
+            },
+            last_will: MqttData::new(state_topic(&cfg.base_topic), OFFLINE_PAYLOAD.to_string()),
+            credentials: (!cfg.user.is_empty()).then_some((cfg.user, cfg.password)),
+        };
 
-        let (client, eventloop) = AsyncClient::new(mqttoptions, 1000);
+        let (client, eventloop) = mqttclient::create(cfg.protocol, &options, 1000);
 
-        log::info!("MQTT connection created");
-        MqttConnection {
+        tracing::info!("MQTT connection created (protocol version {})", cfg.protocol);
+        Ok(MqttConnection {
             client,
             eventloop,
+            connect_options: options,
+            brokers,
+            broker: 0,
+            connected_broker: None,
+            broker_switched: false,
             base_topic: cfg.base_topic,
-        }
+            // without acknowledgements the publishes of a poll are written back to back
+            publish_qos: if cfg.low_power {
+                QoS::AtMostOnce
+            } else {
+                QoS::AtLeastOnce
+            },
+            subscriptions: Vec::new(),
+            retained_commands: cfg.retained_commands,
+            connected: false,
+            reconnect_attempts: 0,
+            reconnect_at: None,
+            offline_buffer: VecDeque::new(),
+            online: None,
+            published: 0,
+            acknowledged: 0,
+            received: VecDeque::new(),
+            connect_error: None,
+        })
     }
 
+    /// Waits for the next message, the event loop reconnects on the next poll after a connection error so the
+    /// reconnect is delayed with a backoff. The deadline is kept across polls, the poll is cancelled by the bridge
+    /// when another event arrives first.
     pub async fn poll(&mut self) -> Result<Option<MqttData>> {
-        if let Ok(msg) = self.eventloop.poll().await {
-            return self.handle_mqtt_message(msg).await;
+        if let Some(data) = self.received.pop_front() {
+            return Ok(Some(data));
         }
 
-        Ok(None)
+        if let Some(reconnect_at) = self.reconnect_at {
+            time::sleep_until(reconnect_at).await;
+            self.reconnect_at = None;
+        }
+
+        match self.eventloop.poll().await {
+            Ok(msg) => self.handle_mqtt_message(msg).await,
+            Err(err) => {
+                self.connection_lost(err);
+                Ok(None)
+            }
+        }
+    }
+
+    fn connection_lost(&mut self, err: impl std::fmt::Display) {
+        self.connect_error = Some(format!("{:#}", err));
+        if self.connected {
+            tracing::warn!("MQTT connection lost: {:#}", err);
+            self.connected = false;
+        }
+
+        // the publishes in flight are sent again after reconnecting
+        self.acknowledged = self.published;
+        self.reconnect_attempts = self.reconnect_attempts.saturating_add(1);
+        if self.brokers.len() > 1 {
+            self.switch_broker();
+        }
+
+        // the next broker is tried immediately, the backoff applies once all brokers failed
+        let broker_count = self.brokers.len() as u32;
+        if !self.reconnect_attempts.is_multiple_of(broker_count) {
+            tracing::info!("MQTT connection failed: {:#}", err);
+            return;
+        }
+
+        let backoff = reconnect_backoff(self.reconnect_attempts / broker_count - 1);
+        tracing::info!("MQTT reconnect in {:.1}s: {:#}", backoff.as_secs_f64(), err);
+        self.reconnect_at = Some(Instant::now() + backoff);
+    }
+
+    fn switch_broker(&mut self) {
+        self.broker = (self.broker + 1) % self.brokers.len();
+        let (server, port) = &self.brokers[self.broker];
+        tracing::info!("Switching to MQTT broker {}:{}", server, port);
+        self.connect_options.server = server.clone();
+        self.connect_options.port = *port;
+        self.eventloop.set_options(&self.connect_options);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// True once after the connection was made to another broker than the previous connection, that broker does not
+    /// have the retained topics of the bridge
+    pub fn take_broker_switched(&mut self) -> bool {
+        std::mem::take(&mut self.broker_switched)
+    }
+
+    /// Drives the connection until the broker acknowledged the publishes made so far, e.g. so home assistant knows
+    /// the entities before their first state arrives. Returns false when the acknowledgements did not arrive in time.
+    pub async fn wait_for_acknowledgements(&mut self, timeout: Duration) -> Result<bool> {
+        let target = self.published;
+        let deadline = Instant::now() + timeout;
+
+        while self.connected && self.acknowledged < target {
+            match time::timeout_at(deadline, self.eventloop.poll()).await {
+                Ok(Ok(msg)) => {
+                    if let Some(data) = self.handle_mqtt_message(msg).await? {
+                        self.received.push_back(data);
+                    }
+                }
+                Ok(Err(err)) => self.connection_lost(err),
+                Err(_) => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Why the last connection attempt failed, e.g. the broker refused the credentials
+    pub fn connect_error(&self) -> Option<&str> {
+        self.connect_error.as_deref()
+    }
+
+    /// Drives the connection until the broker accepted it, the reconnect backoff applies when the broker is not
+    /// reachable. Returns false when the connection was not made in time.
+    pub async fn wait_for_connection(&mut self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+
+        while !self.connected {
+            match time::timeout_at(deadline, self.poll()).await {
+                Ok(Ok(Some(data))) => self.received.push_back(data),
+                Ok(Ok(None)) => {}
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Publishes the offline availability, waits until the pending publishes are acknowledged and disconnects.
+    /// The broker does not publish the last will after a clean disconnect.
+    pub async fn disconnect(&mut self, timeout: Duration) -> Result<bool> {
+        if !self.connected {
+            return Ok(true);
+        }
+
+        self.publish_offline().await?;
+        self.close(timeout).await
+    }
+
+    /// Waits until the pending publishes are acknowledged and disconnects, the availability is left as it is.
+    /// Returns false when the acknowledgements did not arrive in time.
+    pub async fn close(&mut self, timeout: Duration) -> Result<bool> {
+        if !self.connected {
+            return Ok(true);
+        }
+
+        let deadline = Instant::now() + timeout;
+        let acknowledged = self.wait_for_acknowledgements(timeout).await?;
+        if !acknowledged {
+            tracing::warn!("Not all MQTT publishes were acknowledged before disconnecting");
+        }
+
+        self.client.disconnect().await?;
+        while self.connected {
+            match time::timeout_at(deadline, self.eventloop.poll()).await {
+                Ok(Ok(Incoming::Disconnected)) | Ok(Err(_)) | Err(_) => self.connected = false,
+                Ok(Ok(_)) => {}
+            }
+        }
+
+        tracing::info!("Disconnected from the MQTT broker");
+        Ok(acknowledged)
+    }
+
+    async fn send(&mut self, topic: String, qos: QoS, retain: bool, payload: String) -> Result<()> {
+        self.client.publish(topic, qos, retain, payload).await?;
+        if qos != QoS::AtMostOnce {
+            self.published += 1;
+        }
+
+        Ok(())
     }
 
+    /// Publishes a retained value, while disconnected the value is buffered and published after reconnecting
     pub async fn publish(&mut self, data: MqttData) -> Result<()> {
-        Ok(self
-            .client
-            .publish(data.topic, QoS::AtLeastOnce, true, data.payload)
-            .await?)
+        if !self.connected {
+            self.buffer(data);
+            return Ok(());
+        }
+
+        self.send(data.topic, self.publish_qos, true, data.payload).await
+    }
+
+    /// Publishes a message that is not retained (e.g. an error event), it is dropped while disconnected
+    pub async fn publish_event(&mut self, data: MqttData) -> Result<()> {
+        if !self.connected {
+            tracing::debug!(topic = %data.topic, "MQTT not connected, dropping the publish");
+            return Ok(());
+        }
+
+        self.send(data.topic, self.publish_qos, false, data.payload).await
+    }
+
+    fn buffer(&mut self, data: MqttData) {
+        if let Some(buffered) = self
+            .offline_buffer
+            .iter_mut()
+            .find(|buffered| buffered.topic == data.topic)
+        {
+            buffered.payload = data.payload;
+            return;
+        }
+
+        if self.offline_buffer.len() == OFFLINE_BUFFER_CAPACITY
+            && let Some(dropped) = self.offline_buffer.pop_front()
+        {
+            tracing::warn!(topic = %dropped.topic, "MQTT offline buffer full, dropping the publish");
+        }
+        self.offline_buffer.push_back(data);
+    }
+
+    async fn flush_offline_buffer(&mut self) -> Result<()> {
+        if !self.offline_buffer.is_empty() {
+            tracing::info!(
+                "Publishing {} values buffered while disconnected",
+                self.offline_buffer.len()
+            );
+        }
+
+        while let Some(data) = self.offline_buffer.pop_front() {
+            self.send(data.topic, self.publish_qos, true, data.payload).await?;
+        }
+
+        Ok(())
     }
 
     pub async fn publish_multiple(&mut self, data: Vec<MqttData>) -> Result<()> {
@@ -117,52 +462,345 @@ impl MqttConnection {
         Ok(())
     }
 
+    /// Subscribes to a topic outside of the base topic (e.g. the home assistant status) once connected
+    pub fn subscribe_on_connect(&mut self, topic: &str) {
+        self.subscriptions.push(topic.to_string());
+    }
+
+    /// Temporary subscription, e.g. to receive the retained messages of a topic
+    pub async fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.client.subscribe(topic, QoS::AtLeastOnce).await
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        self.client.unsubscribe(topic).await
+    }
+
     async fn subscribe_to_commands(&mut self) -> Result<()> {
-        let cmd_subscription_topic = format!("{}/+/cmnd/+", self.base_topic);
-        self.client.subscribe(cmd_subscription_topic, QoS::ExactlyOnce).await?;
+        // the command can have a sub-resource level (e.g. duco_node_1/cmnd/Config/FlowMax)
+        let cmd_subscription_topic = format!("{}/+/cmnd/#", self.base_topic);
+        self.client.subscribe(&cmd_subscription_topic, QoS::ExactlyOnce).await?;
+        for topic in &self.subscriptions {
+            self.client.subscribe(topic, QoS::AtLeastOnce).await?;
+        }
         Ok(())
     }
 
     pub async fn publish_online(&mut self) -> Result<()> {
-        self.client
-            .publish(state_topic(&self.base_topic), QoS::AtLeastOnce, true, ONLINE_PAYLOAD)
-            .await?;
+        self.online = Some(true);
+        self.publish_state().await
+    }
 
-        Ok(())
+    pub async fn publish_availability(&mut self, topic: String, online: bool) -> Result<()> {
+        let payload = if online { ONLINE_PAYLOAD } else { OFFLINE_PAYLOAD };
+        if !self.connected {
+            self.buffer(MqttData::new(topic, payload.to_string()));
+            return Ok(());
+        }
+
+        self.send(topic, QoS::AtLeastOnce, true, payload.to_string()).await
     }
 
     pub async fn publish_offline(&mut self) -> Result<()> {
-        self.client
-            .publish(state_topic(&self.base_topic), QoS::AtLeastOnce, true, OFFLINE_PAYLOAD)
-            .await?;
-        Ok(())
+        self.online = Some(false);
+        self.publish_state().await
     }
 
-    async fn handle_mqtt_message(&mut self, ev: Event) -> Result<Option<MqttData>> {
-        if let Event::Incoming(event) = ev {
-            match event {
-                Packet::ConnAck(data) => {
-                    if data.code == ConnectReturnCode::Success {
-                        if !data.session_present {
-                            log::info!("Subscribe to mqtt commands");
-                            self.subscribe_to_commands().await?;
-                        } else {
-                            log::debug!("Session still active, no need to resubsribe");
-                        }
-                    } else {
-                        log::error!("MQTT connection refused: {:?}", data.code);
-                    }
+    /// The availability of the bridge is not buffered, the last state is published when the connection is made
+    async fn publish_state(&mut self) -> Result<()> {
+        let Some(online) = self.online.filter(|_| self.connected) else {
+            return Ok(());
+        };
+
+        let payload = if online { ONLINE_PAYLOAD } else { OFFLINE_PAYLOAD };
+        self.send(
+            state_topic(&self.base_topic),
+            QoS::AtLeastOnce,
+            true,
+            payload.to_string(),
+        )
+        .await
+    }
+
+    /// Removes the publishes that are queued for the broker, used to verify the published data in tests
+    #[cfg(test)]
+    pub fn take_queued_publishes(&mut self) -> Vec<MqttData> {
+        let mut publishes = self.eventloop.take_queued_publishes();
+        publishes.extend(self.offline_buffer.drain(..));
+        publishes
+    }
+
+    /// Matches the command subscription (`<base>/+/cmnd/#`)
+    fn is_command_topic(&self, topic: &str) -> bool {
+        topic
+            .strip_prefix(&self.base_topic)
+            .and_then(|topic| topic.strip_prefix('/'))
+            .is_some_and(|topic| topic.split('/').nth(1) == Some("cmnd"))
+    }
+
+    async fn handle_mqtt_message(&mut self, incoming: Incoming) -> Result<Option<MqttData>> {
+        match incoming {
+            Incoming::ConnAck {
+                refused: Some(reason), ..
+            } => {
+                tracing::error!("MQTT connection refused: {}", reason);
+                self.connect_error = Some(format!("Connection refused: {}", reason));
+            }
+            Incoming::ConnAck { session_present, .. } => {
+                self.connected = true;
+                self.connect_error = None;
+                if self
+                    .connected_broker
+                    .replace(self.broker)
+                    .is_some_and(|broker| broker != self.broker)
+                {
+                    tracing::info!(
+                        "Connected to MQTT broker {}:{}",
+                        self.brokers[self.broker].0,
+                        self.brokers[self.broker].1
+                    );
+                    self.broker_switched = true;
                 }
-                Packet::Publish(publ) => {
-                    return Ok(Some(MqttData {
-                        topic: from_mqtt_string(&publ.topic)?,
-                        payload: from_mqtt_string(&publ.payload)?,
-                    }));
+                self.published = 0;
+                self.acknowledged = 0;
+                self.reconnect_attempts = 0;
+                if session_present {
+                    tracing::debug!("Session still active, no need to resubsribe");
+                } else {
+                    tracing::info!("Subscribe to mqtt commands");
+                    self.subscribe_to_commands().await?;
                 }
-                _ => {}
+
+                // the broker published the last will when the connection was lost
+                self.publish_state().await?;
+                self.flush_offline_buffer().await?;
+            }
+            Incoming::Publish { data, retained: true }
+                if !self.retained_commands && self.is_command_topic(&data.topic) =>
+            {
+                tracing::warn!(
+                    "Ignoring retained command {} {}, commands should be published without the retain flag",
+                    data.topic,
+                    data.payload
+                );
             }
+            Incoming::Publish { data, .. } => return Ok(Some(data)),
+            Incoming::Acknowledged => self.acknowledged += 1,
+            Incoming::Disconnected => self.connected = false,
+            Incoming::Other => {}
         }
 
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTED: Incoming = Incoming::ConnAck {
+        session_present: false,
+        refused: None,
+    };
+
+    fn config(base_topic: &str) -> MqttConfig {
+        MqttConfig {
+            server: "localhost".to_string(),
+            port: 1883,
+            client_id: "duco2mqtt".to_string(),
+            user: String::new(),
+            password: Secret::default(),
+            base_topic: base_topic.to_string(),
+            low_power: false,
+            protocol: MqttProtocol::V5,
+            tls: false,
+            ca_cert: None,
+            tls_server_name: None,
+            client_cert: None,
+            client_key: None,
+            retained_commands: false,
+        }
+    }
+
+    #[test]
+    fn test_normalized_base_topic() {
+        for base_topic in ["ventilation", "ventilation/", "/ventilation", " ventilation// "] {
+            assert_eq!(config(base_topic).normalized_base_topic().unwrap(), "ventilation");
+        }
+        assert_eq!(config("home/duco/").normalized_base_topic().unwrap(), "home/duco");
+
+        for base_topic in ["", "/", "home/+/duco", "#", "home//duco"] {
+            assert!(
+                matches!(config(base_topic).normalized_base_topic(), Err(Error::Config(_))),
+                "{}",
+                base_topic
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_versions() {
+        assert_eq!("3.1.1".parse::<MqttProtocol>().unwrap(), MqttProtocol::V311);
+        assert_eq!(MqttProtocol::default().to_string(), "5");
+
+        for protocol in [MqttProtocol::V5, MqttProtocol::V311] {
+            let mut mqtt = MqttConnection::new(MqttConfig {
+                protocol,
+                ..config("ventilation")
+            })
+            .unwrap();
+            mqtt.handle_mqtt_message(CONNECTED).await.unwrap();
+            mqtt.publish(MqttData::new("ventilation/duco_node_1/Ventilation/State", "AUTO"))
+                .await
+                .unwrap();
+            mqtt.publish_online().await.unwrap();
+            assert_eq!(
+                mqtt.take_queued_publishes(),
+                vec![
+                    MqttData::new("ventilation/duco_node_1/Ventilation/State", "AUTO"),
+                    MqttData::new("ventilation/state", "online"),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offline_buffer() {
+        let mut mqtt = MqttConnection::new(config("ventilation")).unwrap();
+        mqtt.publish(MqttData::new("ventilation/duco_node_1/Ventilation/State", "AUTO"))
+            .await
+            .unwrap();
+        mqtt.publish(MqttData::new("ventilation/duco_node_2/Ventilation/State", "AUTO"))
+            .await
+            .unwrap();
+        mqtt.publish(MqttData::new("ventilation/duco_node_1/Ventilation/State", "MAN1"))
+            .await
+            .unwrap();
+        mqtt.publish_online().await.unwrap();
+        assert!(mqtt.eventloop.take_queued_publishes().is_empty());
+
+        // the availability replaces the last will, followed by the last values of the buffered topics
+        mqtt.handle_mqtt_message(CONNECTED).await.unwrap();
+        assert_eq!(
+            mqtt.take_queued_publishes(),
+            vec![
+                MqttData::new("ventilation/state", "online"),
+                MqttData::new("ventilation/duco_node_1/Ventilation/State", "MAN1"),
+                MqttData::new("ventilation/duco_node_2/Ventilation/State", "AUTO"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        for (attempt, expected) in [(0, 1), (1, 2), (3, 8), (6, 60), (40, 60)] {
+            let backoff = reconnect_backoff(attempt);
+            let expected = Duration::from_secs(expected);
+            assert!(
+                backoff >= expected && backoff <= expected.mul_f64(1.25),
+                "{:?}",
+                backoff
+            );
+        }
+    }
+
+    #[test]
+    fn test_brokers() {
+        let brokers = |server: &str| {
+            MqttConfig {
+                server: server.to_string(),
+                ..config("ventilation")
+            }
+            .brokers()
+        };
+
+        assert_eq!(brokers("localhost").unwrap(), vec![("localhost".to_string(), 1883)]);
+        assert_eq!(
+            brokers("broker1, broker2:8883,[::1]:1884,::1").unwrap(),
+            vec![
+                ("broker1".to_string(), 1883),
+                ("broker2".to_string(), 8883),
+                ("::1".to_string(), 1884),
+                ("::1".to_string(), 1883),
+            ]
+        );
+        assert!(matches!(brokers("broker1,"), Err(Error::Config(_))));
+        assert!(matches!(brokers("broker1:mqtt"), Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_broker_failover() {
+        let mut mqtt = MqttConnection::new(MqttConfig {
+            server: "broker1,broker2".to_string(),
+            ..config("ventilation")
+        })
+        .unwrap();
+        mqtt.handle_mqtt_message(CONNECTED).await.unwrap();
+        assert!(!mqtt.take_broker_switched());
+
+        // the second broker is tried immediately, the backoff starts when both failed
+        mqtt.connection_lost("connection refused");
+        assert_eq!(mqtt.connect_options.server, "broker2");
+        assert!(mqtt.reconnect_at.is_none());
+        mqtt.connection_lost("connection refused");
+        assert_eq!(mqtt.connect_options.server, "broker1");
+        assert!(mqtt.reconnect_at.is_some());
+        mqtt.connection_lost("connection refused");
+
+        mqtt.handle_mqtt_message(CONNECTED).await.unwrap();
+        assert_eq!(mqtt.connect_options.server, "broker2");
+        assert!(mqtt.take_broker_switched());
+        assert!(!mqtt.take_broker_switched());
+    }
+
+    #[tokio::test]
+    async fn test_retained_commands() {
+        let command = |retained| Incoming::Publish {
+            data: MqttData::new("ventilation/duco_node_2/cmnd/SetVentilationState", "MAN1"),
+            retained,
+        };
+
+        let mut mqtt = MqttConnection::new(config("ventilation")).unwrap();
+        assert!(mqtt.handle_mqtt_message(command(true)).await.unwrap().is_none());
+        assert!(mqtt.handle_mqtt_message(command(false)).await.unwrap().is_some());
+
+        // retained messages outside of the command topics are still handled
+        let status = Incoming::Publish {
+            data: MqttData::new("homeassistant/status", "online"),
+            retained: true,
+        };
+        assert!(mqtt.handle_mqtt_message(status).await.unwrap().is_some());
+
+        let mut mqtt = MqttConnection::new(MqttConfig {
+            retained_commands: true,
+            ..config("ventilation")
+        })
+        .unwrap();
+        assert!(mqtt.handle_mqtt_message(command(true)).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_acknowledgements() {
+        let mut mqtt = MqttConnection::new(config("ventilation")).unwrap();
+        mqtt.handle_mqtt_message(CONNECTED).await.unwrap();
+        mqtt.publish(MqttData::new("homeassistant/sensor/a/config", "{}"))
+            .await
+            .unwrap();
+        mqtt.publish(MqttData::new("ventilation/duco_node_1/Ventilation/State", "AUTO"))
+            .await
+            .unwrap();
+        assert_eq!(mqtt.published - mqtt.acknowledged, 2);
+
+        let command = Incoming::Publish {
+            data: MqttData::new("ventilation/duco_node_1/cmnd/SetVentilationState", "MAN1"),
+            retained: false,
+        };
+        assert!(mqtt.handle_mqtt_message(command).await.unwrap().is_some());
+        for _ in 0..2 {
+            mqtt.handle_mqtt_message(Incoming::Acknowledged).await.unwrap();
+        }
+
+        // nothing left to wait for, the event loop is not polled
+        assert!(mqtt.wait_for_acknowledgements(Duration::ZERO).await.unwrap());
+    }
+}
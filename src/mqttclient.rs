@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rumqttc::{Transport, v5::mqttbytes::QoS};
+use strum::{Display, EnumString};
+
+use crate::{Result, mqtt::MqttData, secret::Secret};
+
+/// The MQTT protocol version, v3.1.1 is for brokers that reject MQTT v5 connects
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+pub enum MqttProtocol {
+    #[default]
+    #[strum(serialize = "5")]
+    V5,
+    #[strum(serialize = "3.1.1")]
+    V311,
+}
+
+/// The connection options that do not depend on the protocol version
+pub struct ConnectOptions {
+    pub client_id: String,
+    pub server: String,
+    pub port: u16,
+    pub transport: Transport,
+    pub keep_alive: Duration,
+    /// Retained message the broker publishes when the connection is lost
+    pub last_will: MqttData,
+    pub credentials: Option<(String, Secret)>,
+}
+
+/// The packets the bridge handles, independent of the protocol version
+pub enum Incoming {
+    ConnAck {
+        session_present: bool,
+        /// The reason the broker refused the connection
+        refused: Option<String>,
+    },
+    Publish {
+        data: MqttData,
+        /// Retained message of the broker, delivered when subscribing
+        retained: bool,
+    },
+    /// The broker acknowledged a publish (PubAck for QoS 1, PubComp for QoS 2)
+    Acknowledged,
+    /// The disconnect requested by the bridge was sent to the broker
+    Disconnected,
+    Other,
+}
+
+pub enum MqttClient {
+    V5(rumqttc::v5::AsyncClient),
+    V311(rumqttc::AsyncClient),
+}
+
+/// The event loops are boxed, they are large and differ in size
+pub enum MqttEventLoop {
+    V5(Box<rumqttc::v5::EventLoop>),
+    V311(Box<rumqttc::EventLoop>),
+}
+
+/// The client and event loop of the protocol version, the connection is made when the event loop is polled
+pub fn create(protocol: MqttProtocol, options: &ConnectOptions, cap: usize) -> (MqttClient, MqttEventLoop) {
+    match protocol {
+        MqttProtocol::V5 => {
+            let (client, eventloop) = rumqttc::v5::AsyncClient::new(v5_options(options), cap);
+            (MqttClient::V5(client), MqttEventLoop::V5(Box::new(eventloop)))
+        }
+        MqttProtocol::V311 => {
+            let (client, eventloop) = rumqttc::AsyncClient::new(v311_options(options), cap);
+            (MqttClient::V311(client), MqttEventLoop::V311(Box::new(eventloop)))
+        }
+    }
+}
+
+fn v5_options(options: &ConnectOptions) -> rumqttc::v5::MqttOptions {
+    let mut mqttoptions = rumqttc::v5::MqttOptions::new(&options.client_id, &options.server, options.port);
+    mqttoptions.set_clean_start(true);
+    mqttoptions.set_transport(options.transport.clone());
+    mqttoptions.set_keep_alive(options.keep_alive);
+    mqttoptions.set_last_will(rumqttc::v5::mqttbytes::v5::LastWill::new(
+        &options.last_will.topic,
+        options.last_will.payload.clone(),
+        QoS::AtLeastOnce,
+        true,
+        None,
+    ));
+    if let Some((ref user, ref password)) = options.credentials {
+        mqttoptions.set_credentials(user, password.expose());
+    }
+
+    mqttoptions
+}
+
+fn v311_options(options: &ConnectOptions) -> rumqttc::MqttOptions {
+    let mut mqttoptions = rumqttc::MqttOptions::new(&options.client_id, &options.server, options.port);
+    mqttoptions.set_clean_session(true);
+    mqttoptions.set_transport(options.transport.clone());
+    mqttoptions.set_keep_alive(options.keep_alive);
+    mqttoptions.set_last_will(rumqttc::LastWill::new(
+        &options.last_will.topic,
+        options.last_will.payload.clone(),
+        v311_qos(QoS::AtLeastOnce),
+        true,
+    ));
+    if let Some((ref user, ref password)) = options.credentials {
+        mqttoptions.set_credentials(user, password.expose());
+    }
+
+    mqttoptions
+}
+
+fn v311_qos(qos: QoS) -> rumqttc::QoS {
+    match qos {
+        QoS::AtMostOnce => rumqttc::QoS::AtMostOnce,
+        QoS::AtLeastOnce => rumqttc::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => rumqttc::QoS::ExactlyOnce,
+    }
+}
+
+fn from_mqtt_string(stream: &[u8]) -> Result<String> {
+    match String::from_utf8(stream.to_vec()) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(anyhow!("Mqtt string conversion error: {}", e)),
+    }
+}
+
+impl MqttClient {
+    pub async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: String) -> Result<()> {
+        match self {
+            MqttClient::V5(client) => client.publish(topic, qos, retain, payload).await?,
+            MqttClient::V311(client) => client.publish(topic, v311_qos(qos), retain, payload).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<()> {
+        match self {
+            MqttClient::V5(client) => client.subscribe(topic, qos).await?,
+            MqttClient::V311(client) => client.subscribe(topic, v311_qos(qos)).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        match self {
+            MqttClient::V5(client) => client.unsubscribe(topic).await?,
+            MqttClient::V311(client) => client.unsubscribe(topic).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        match self {
+            MqttClient::V5(client) => client.disconnect().await?,
+            MqttClient::V311(client) => client.disconnect().await?,
+        }
+
+        Ok(())
+    }
+}
+
+impl MqttEventLoop {
+    /// Connects with the new options (e.g. another broker) on the next reconnect, the pending requests are kept
+    pub fn set_options(&mut self, options: &ConnectOptions) {
+        match self {
+            MqttEventLoop::V5(eventloop) => eventloop.options = v5_options(options),
+            MqttEventLoop::V311(eventloop) => eventloop.mqtt_options = v311_options(options),
+        }
+    }
+
+    /// Waits for the next event of the connection, reconnects after connection errors
+    pub async fn poll(&mut self) -> Result<Incoming> {
+        match self {
+            MqttEventLoop::V5(eventloop) => {
+                use rumqttc::{
+                    Outgoing,
+                    v5::{
+                        Event,
+                        mqttbytes::v5::{ConnectReturnCode, Packet},
+                    },
+                };
+
+                Ok(match eventloop.poll().await? {
+                    Event::Incoming(Packet::ConnAck(data)) => Incoming::ConnAck {
+                        session_present: data.session_present,
+                        refused: (data.code != ConnectReturnCode::Success).then(|| format!("{:?}", data.code)),
+                    },
+                    Event::Incoming(Packet::Publish(publ)) => Incoming::Publish {
+                        data: MqttData {
+                            topic: from_mqtt_string(&publ.topic)?,
+                            payload: from_mqtt_string(&publ.payload)?,
+                        },
+                        retained: publ.retain,
+                    },
+                    Event::Incoming(Packet::PubAck(_) | Packet::PubComp(_)) => Incoming::Acknowledged,
+                    Event::Outgoing(Outgoing::Disconnect) => Incoming::Disconnected,
+                    _ => Incoming::Other,
+                })
+            }
+            MqttEventLoop::V311(eventloop) => {
+                use rumqttc::{ConnectReturnCode, Event, Outgoing, Packet};
+
+                Ok(match eventloop.poll().await? {
+                    Event::Incoming(Packet::ConnAck(data)) => Incoming::ConnAck {
+                        session_present: data.session_present,
+                        refused: (data.code != ConnectReturnCode::Success).then(|| format!("{:?}", data.code)),
+                    },
+                    Event::Incoming(Packet::Publish(publ)) => Incoming::Publish {
+                        data: MqttData {
+                            topic: publ.topic,
+                            payload: from_mqtt_string(&publ.payload)?,
+                        },
+                        retained: publ.retain,
+                    },
+                    Event::Incoming(Packet::PubAck(_) | Packet::PubComp(_)) => Incoming::Acknowledged,
+                    Event::Outgoing(Outgoing::Disconnect) => Incoming::Disconnected,
+                    _ => Incoming::Other,
+                })
+            }
+        }
+    }
+
+    /// Removes the publishes that are queued for the broker, used to verify the published data in tests
+    #[cfg(test)]
+    pub fn take_queued_publishes(&mut self) -> Vec<MqttData> {
+        match self {
+            MqttEventLoop::V5(eventloop) => {
+                eventloop.clean();
+                eventloop
+                    .pending
+                    .drain(..)
+                    .filter_map(|request| match request {
+                        rumqttc::v5::Request::Publish(publ) => Some(MqttData {
+                            topic: from_mqtt_string(&publ.topic).ok()?,
+                            payload: from_mqtt_string(&publ.payload).ok()?,
+                        }),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            MqttEventLoop::V311(eventloop) => {
+                eventloop.clean();
+                eventloop
+                    .pending
+                    .drain(..)
+                    .filter_map(|request| match request {
+                        rumqttc::Request::Publish(publ) => Some(MqttData {
+                            topic: publ.topic,
+                            payload: from_mqtt_string(&publ.payload).ok()?,
+                        }),
+                        _ => None,
+                    })
+                    .collect()
+            }
+        }
+    }
+}
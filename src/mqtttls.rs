@@ -0,0 +1,174 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, bail, ensure};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+
+use crate::{Result, mqtt::MqttConfig};
+
+/// The rustls configuration of the broker connection: the broker certificate is validated against the system
+/// certificates or the configured CA bundle, the bridge authenticates with the client certificate when configured
+pub fn client_config(cfg: &MqttConfig) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match cfg.ca_cert {
+        Some(ref path) => {
+            let (added, _) = roots.add_parsable_certificates(load_certificates(path)?);
+            ensure!(added > 0, "No valid certificates found in {}", path.display());
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            if native.certs.is_empty()
+                && let Some(err) = native.errors.first()
+            {
+                bail!("Failed to load the system certificates: {}", err);
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+
+    let provider = Arc::new(ring::default_provider());
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+    let builder = ClientConfig::builder_with_provider(provider).with_safe_default_protocol_versions()?;
+    let builder = match cfg.tls_server_name {
+        Some(ref server_name) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(ServerNameVerifier {
+                inner: verifier,
+                server_name: ServerName::try_from(server_name.clone())
+                    .with_context(|| format!("Invalid MQTT TLS server name '{}'", server_name))?,
+            })),
+        None => builder.with_webpki_verifier(verifier),
+    };
+
+    match (&cfg.client_cert, &cfg.client_key) {
+        (Some(cert), Some(key)) => {
+            let certificates = load_certificates(cert)?;
+            ensure!(!certificates.is_empty(), "No certificates found in {}", cert.display());
+            let key = PrivateKeyDer::from_pem_file(key)
+                .with_context(|| format!("Failed to read the private key {}", key.display()))?;
+            Ok(builder
+                .with_client_auth_cert(certificates, key)
+                .context("Invalid MQTT client certificate")?)
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => bail!("The MQTT client certificate and private key have to be provided together"),
+    }
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read certificate {}", path.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate {}", path.display()))
+}
+
+/// Validates the broker certificate against the configured name instead of the server address,
+/// for brokers that are addressed by ip address or by a name that is not in their certificate.
+/// rumqttc takes the server name indication from the server address, so no SNI is sent for an ip address and the
+/// broker has to present the certificate without it
+#[derive(Debug)]
+struct ServerNameVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    server_name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for ServerNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, &self.server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mqtt::MqttProtocol, secret::Secret};
+    use std::path::PathBuf;
+
+    fn tls_config(ca_cert: &str, tls_server_name: Option<&str>) -> MqttConfig {
+        MqttConfig {
+            server: "192.168.1.10".to_string(),
+            port: 8883,
+            client_id: "duco2mqtt".to_string(),
+            user: String::new(),
+            password: Secret::default(),
+            base_topic: "ventilation".to_string(),
+            low_power: false,
+            protocol: MqttProtocol::V5,
+            tls: true,
+            ca_cert: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(ca_cert)),
+            tls_server_name: tls_server_name.map(str::to_string),
+            client_cert: None,
+            client_key: None,
+            retained_commands: false,
+        }
+    }
+
+    #[test]
+    fn test_client_config() {
+        assert!(client_config(&tls_config("test/data/tls/ca.pem", None)).is_ok());
+        assert!(client_config(&tls_config("test/data/tls/ca.pem", Some("broker.local"))).is_ok());
+        assert!(client_config(&tls_config("test/data/tls/ca.pem", Some("not a host name"))).is_err());
+        assert!(client_config(&tls_config("test/data/info.json", None)).is_err());
+        assert!(client_config(&tls_config("test/data/tls/missing.pem", None)).is_err());
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        let data = |file: &str| {
+            Some(
+                PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("test/data/tls")
+                    .join(file),
+            )
+        };
+
+        let mut cfg = tls_config("test/data/tls/ca.pem", None);
+        cfg.client_cert = data("client.pem");
+        cfg.client_key = data("client.key");
+        assert!(client_config(&cfg).unwrap().client_auth_cert_resolver.has_certs());
+
+        // not a private key
+        cfg.client_key = data("ca.pem");
+        assert!(client_config(&cfg).is_err());
+
+        cfg.client_key = None;
+        assert!(client_config(&cfg).is_err());
+    }
+}
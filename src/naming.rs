@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use strum::{Display, EnumString};
+
+use crate::{ApiResult, Error};
+
+/// Derives the names of a node: the levels of its MQTT topics (following the topic scheme, `duco_node_<nr>` by
+/// default) and the unique ids, names and object ids of its home assistant entities.
+pub trait NamingStrategy: Send + Sync {
+    /// Layout of the node topics
+    fn topic_scheme(&self) -> &TopicScheme;
+
+    /// The node level of the topics of a node, see [`TopicScheme::node_topic`]
+    fn node_topic(&self, node: u16, node_name: Option<&str>) -> String {
+        self.topic_scheme().node_topic(node, node_name)
+    }
+
+    /// The topic levels of a status key (`<group>/<key>`)
+    fn status_key(&self, key: &str) -> String {
+        self.topic_scheme().status_key(key)
+    }
+
+    /// Unique id of an entity of a node, `id` identifies the entity within the node (e.g. "sensor_co2")
+    fn unique_id(&self, _serial: &str, node: u16, id: &str) -> String {
+        format!("duco_node_{}_{}", node, id)
+    }
+
+    /// Unique id of an entity of the box itself, `device` is the logical box device (e.g. "heat_recovery").
+    /// The id always contains the serial of the box, like the identifiers of the box devices.
+    fn box_unique_id(&self, serial: &str, device: &str, id: &str) -> String {
+        format!("duco_{}_{}_{}", serial, device, id)
+    }
+
+    /// Name and object id of an entity of a node, `topic_name` is the status topic or action of the entity
+    fn entity_name(&self, node_name: Option<&str>, topic_name: &str, unique_id: &str) -> (String, String);
+}
+
+/// The naming strategies that can be selected in the configuration
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum EntityNaming {
+    /// Entities of named nodes get a readable label (e.g. "CO2") and an entity id after the node (e.g. "bathroom_co2")
+    #[default]
+    Name,
+    /// Entities are called after the status topic (e.g. "Sensor/IaqCo2")
+    Number,
+    /// Named entities with unique ids prefixed with the serial of the box, for multiple boxes in one installation
+    Serial,
+}
+
+impl EntityNaming {
+    pub fn strategy(&self, topic_scheme: TopicScheme) -> Arc<dyn NamingStrategy> {
+        match self {
+            EntityNaming::Name => Arc::new(NodeNameNaming(topic_scheme)),
+            EntityNaming::Number => Arc::new(NodeNumberNaming(topic_scheme)),
+            EntityNaming::Serial => Arc::new(SerialNaming(topic_scheme)),
+        }
+    }
+}
+
+/// The naming of a node without configuration: the default topic scheme and entity naming
+pub fn default_strategy() -> Arc<dyn NamingStrategy> {
+    EntityNaming::default().strategy(TopicScheme::default())
+}
+
+pub struct NodeNumberNaming(TopicScheme);
+
+impl NamingStrategy for NodeNumberNaming {
+    fn topic_scheme(&self) -> &TopicScheme {
+        &self.0
+    }
+
+    fn entity_name(&self, _node_name: Option<&str>, topic_name: &str, unique_id: &str) -> (String, String) {
+        (topic_name.to_string(), unique_id.to_string())
+    }
+}
+
+/// Named nodes give the entity label with a "<node name> <label>" object id, other nodes are named after the status
+/// topic. Home assistant prefixes the entity name with the device name, which is the node name.
+pub struct NodeNameNaming(TopicScheme);
+
+impl NamingStrategy for NodeNameNaming {
+    fn topic_scheme(&self) -> &TopicScheme {
+        &self.0
+    }
+
+    fn entity_name(&self, node_name: Option<&str>, topic_name: &str, unique_id: &str) -> (String, String) {
+        node_entity_name(node_name, topic_name, unique_id)
+    }
+}
+
+pub struct SerialNaming(TopicScheme);
+
+impl NamingStrategy for SerialNaming {
+    fn topic_scheme(&self) -> &TopicScheme {
+        &self.0
+    }
+
+    fn unique_id(&self, serial: &str, node: u16, id: &str) -> String {
+        format!("duco_{}_node_{}_{}", serial, node, id)
+    }
+
+    fn entity_name(&self, node_name: Option<&str>, topic_name: &str, unique_id: &str) -> (String, String) {
+        node_entity_name(node_name, topic_name, unique_id)
+    }
+}
+
+fn node_entity_name(node_name: Option<&str>, topic_name: &str, unique_id: &str) -> (String, String) {
+    match node_name {
+        Some(node_name) => {
+            let label = entity_label(topic_name);
+            let obj_id = object_id(&format!("{} {}", node_name, label));
+            (label, obj_id)
+        }
+        None => (topic_name.to_string(), unique_id.to_string()),
+    }
+}
+
+/// Readable label of a status topic: "Sensor/Co2" becomes "CO2", unknown topics are split on their capitals
+fn entity_label(topic_name: &str) -> String {
+    match topic_name {
+        "Sensor/Co2" => "CO2".to_string(),
+        "Sensor/IaqCo2" => "CO2 air quality".to_string(),
+        "Sensor/IaqRh" => "Humidity air quality".to_string(),
+        "Sensor/Rh" => "Humidity".to_string(),
+        "Config/Co2SetPoint" => "CO2 setpoint".to_string(),
+        "Config/RhSetPoint" => "Humidity setpoint".to_string(),
+        "Config/RhDelta" => "Humidity delta trigger".to_string(),
+        "Ventilation/State" => "Ventilation state".to_string(),
+        "Ventilation/FlowLvlTgt" => "Flow level target".to_string(),
+        "Ventilation/TimeStateRemain" => "State time remaining".to_string(),
+        "last_seen" => "Last seen".to_string(),
+        _ => {
+            let last = topic_name.rsplit('/').next().unwrap_or(topic_name);
+            let mut label = String::new();
+            for (i, ch) in last.chars().enumerate() {
+                if i > 0 && ch.is_uppercase() {
+                    label.push(' ');
+                    label.extend(ch.to_lowercase());
+                } else {
+                    label.push(ch);
+                }
+            }
+            label
+        }
+    }
+}
+
+/// Lowercase object id of an entity name: "Bathroom CO2" becomes "bathroom_co2"
+fn object_id(name: &str) -> String {
+    name.split(|ch: char| !ch.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+pub const DEFAULT_TOPIC_TEMPLATE: &str = "{base}/duco_node_{nr}/{group}/{key}";
+const DEFAULT_NODE_LEVEL: &str = "duco_node_{nr}";
+const NODE_NUMBER: &str = "{nr}";
+const NODE_NAME: &str = "{node_name}";
+/// The first topic levels of the box and the bridge, a node topic level can not be one of them
+const RESERVED_TOPIC_LEVELS: [&str; 5] = ["General", "HeatRecovery", "NetworkDuco", "duco_box", "bridge"];
+
+/// Layout of the node topics, configured as a template of the form `{base}/<node level>/{group}/{key}`.
+/// The node level is a single topic level with the node number (`{nr}`) and/or the node name (`{node_name}`),
+/// it is also used for the command topics (`<node level>/cmnd/<action>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicScheme {
+    node_level: String,
+    /// Lowercase the node names and the status keys (e.g. `ventilation/state`)
+    lowercase: bool,
+}
+
+impl Default for TopicScheme {
+    fn default() -> TopicScheme {
+        TopicScheme {
+            node_level: DEFAULT_NODE_LEVEL.to_string(),
+            lowercase: false,
+        }
+    }
+}
+
+impl TopicScheme {
+    pub fn new(template: &str, lowercase: bool) -> ApiResult<TopicScheme> {
+        let invalid = |reason: &str| Error::Config(format!("Invalid topic template '{}': {}", template, reason));
+
+        let node_level = template
+            .strip_prefix("{base}/")
+            .ok_or_else(|| invalid("it has to start with {base}/"))?
+            .strip_suffix("/{group}/{key}")
+            .ok_or_else(|| invalid("it has to end with /{group}/{key}"))?;
+
+        if node_level.contains('/') {
+            return Err(invalid("the node has to be a single topic level"));
+        }
+
+        if !node_level.contains(NODE_NUMBER) && !node_level.contains(NODE_NAME) {
+            return Err(invalid("the node level needs {nr} or {node_name}"));
+        }
+
+        if node_level.contains(['+', '#']) {
+            return Err(invalid("the node level contains a wildcard ('+' or '#')"));
+        }
+
+        Ok(TopicScheme {
+            node_level: node_level.to_string(),
+            lowercase,
+        })
+    }
+
+    /// The node level of the topics of a node. When the template uses the name, nodes without a name or with a name
+    /// that gives a level of the box or the bridge (e.g. `HeatRecovery`) keep the default `duco_node_<nr>` level.
+    pub fn node_topic(&self, nr: u16, name: Option<&str>) -> String {
+        let level = match name.map(|name| self.name_level(name)) {
+            Some(name) if !name.is_empty() => self.node_level.replace(NODE_NAME, &name),
+            _ if self.node_level.contains(NODE_NAME) => DEFAULT_NODE_LEVEL.to_string(),
+            _ => self.node_level.clone(),
+        };
+
+        let level = level.replace(NODE_NUMBER, &nr.to_string());
+        if RESERVED_TOPIC_LEVELS
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(&level))
+        {
+            return DEFAULT_NODE_LEVEL.replace(NODE_NUMBER, &nr.to_string());
+        }
+
+        level
+    }
+
+    /// The topic levels of a status key (`<group>/<key>`)
+    pub fn status_key(&self, key: &str) -> String {
+        if self.lowercase {
+            key.to_lowercase()
+        } else {
+            key.to_string()
+        }
+    }
+
+    fn name_level(&self, name: &str) -> String {
+        self.status_key(&topic_name(name))
+    }
+}
+
+/// A node name as topic level, the characters that are not allowed or awkward in a topic are replaced
+/// ("Living room" becomes "Living_room")
+pub fn topic_name(name: &str) -> String {
+    name.split(|ch: char| !ch.is_alphanumeric() && ch != '-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_naming_strategies() {
+        let name = EntityNaming::Name.strategy(TopicScheme::default());
+        assert_eq!(name.unique_id("PS01", 2, "sensor_co2"), "duco_node_2_sensor_co2");
+        assert_eq!(
+            name.entity_name(Some("Bathroom"), "Sensor/Co2", "duco_node_2_sensor_co2"),
+            ("CO2".to_string(), "bathroom_co2".to_string())
+        );
+        assert_eq!(
+            name.entity_name(None, "Sensor/Co2", "duco_node_2_sensor_co2"),
+            ("Sensor/Co2".to_string(), "duco_node_2_sensor_co2".to_string())
+        );
+
+        let number = EntityNaming::Number.strategy(TopicScheme::default());
+        assert_eq!(
+            number.entity_name(Some("Bathroom"), "Ventilation/FlowLvlTgt", "duco_node_2_flow"),
+            ("Ventilation/FlowLvlTgt".to_string(), "duco_node_2_flow".to_string())
+        );
+
+        let serial = EntityNaming::Serial.strategy(TopicScheme::default());
+        assert_eq!(serial.unique_id("PS01", 2, "sensor_co2"), "duco_PS01_node_2_sensor_co2");
+        assert_eq!(
+            serial.entity_name(Some("Living room"), "Ventilation/State", "duco_PS01_node_2_state"),
+            (
+                "Ventilation state".to_string(),
+                "living_room_ventilation_state".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_topic_scheme() {
+        let scheme = TopicScheme::default();
+        assert_eq!(TopicScheme::new(DEFAULT_TOPIC_TEMPLATE, false).unwrap(), scheme);
+        assert_eq!(scheme.node_topic(2, Some("Bathroom")), "duco_node_2");
+        assert_eq!(scheme.status_key("Ventilation/State"), "Ventilation/State");
+
+        let scheme = TopicScheme::new("{base}/{node_name}/{group}/{key}", true).unwrap();
+        assert_eq!(scheme.node_topic(2, Some("Living room/1st")), "living_room_1st");
+        assert_eq!(scheme.node_topic(67, None), "duco_node_67");
+        assert_eq!(scheme.node_topic(67, Some(" ")), "duco_node_67");
+        assert_eq!(scheme.status_key("Ventilation/State"), "ventilation/state");
+
+        // the node names can not take the place of the topics of the box
+        assert_eq!(scheme.node_topic(3, Some("Heat recovery")), "heat_recovery");
+        assert_eq!(scheme.node_topic(3, Some("HeatRecovery")), "duco_node_3");
+        assert_eq!(scheme.node_topic(4, Some("Bridge")), "duco_node_4");
+
+        let scheme = TopicScheme::new("{base}/{node_name}-{nr}/{group}/{key}", false).unwrap();
+        assert_eq!(scheme.node_topic(2, Some("Boven")), "Boven-2");
+        assert_eq!(scheme.node_topic(4, Some("bridge")), "bridge-4");
+
+        for template in [
+            "duco_node_{nr}/{group}/{key}",
+            "{base}/duco_node_{nr}/{key}",
+            "{base}/duco/node_{nr}/{group}/{key}",
+            "{base}/duco_node/{group}/{key}",
+            "{base}/+{nr}/{group}/{key}",
+        ] {
+            assert!(
+                matches!(TopicScheme::new(template, false), Err(Error::Config(_))),
+                "{}",
+                template
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use serde::Serialize;
+use strum::{Display, EnumString};
+
+/// The ventilation states of the duco nodes, as reported in Ventilation/State and accepted by SetVentilationState
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize)]
+#[serde(into = "String")]
+pub enum VentilationState {
+    #[strum(serialize = "AUTO")]
+    Auto,
+    /// Automatic ventilation with a temporary boost for the duration of the timer
+    #[strum(serialize = "AUT1")]
+    Aut1,
+    #[strum(serialize = "AUT2")]
+    Aut2,
+    #[strum(serialize = "AUT3")]
+    Aut3,
+    /// Manual level for the default duration of the box
+    #[strum(serialize = "MAN1")]
+    Man1,
+    #[strum(serialize = "MAN2")]
+    Man2,
+    #[strum(serialize = "MAN3")]
+    Man3,
+    #[strum(serialize = "EMPT")]
+    EmptyHouse,
+    /// Permanent manual level, until another state is set
+    #[strum(serialize = "CNT1")]
+    Cnt1,
+    #[strum(serialize = "CNT2")]
+    Cnt2,
+    #[strum(serialize = "CNT3")]
+    Cnt3,
+    /// Manual level for two or three times the default duration
+    #[strum(serialize = "MAN1x2")]
+    Man1x2,
+    #[strum(serialize = "MAN2x2")]
+    Man2x2,
+    #[strum(serialize = "MAN3x2")]
+    Man3x2,
+    #[strum(serialize = "MAN1x3")]
+    Man1x3,
+    #[strum(serialize = "MAN2x3")]
+    Man2x3,
+    #[strum(serialize = "MAN3x3")]
+    Man3x3,
+}
+
+impl From<VentilationState> for String {
+    fn from(state: VentilationState) -> String {
+        state.to_string()
+    }
+}
+
+/// Snapshot of the values of a node, for library consumers that do not want to parse the status topics.
+/// Values the node does not report (e.g. the ventilation state of a sensor) are None.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeModel {
+    pub number: u16,
+    /// The duco node type (e.g. BOX, UCCO2, VLV)
+    pub node_type: String,
+    pub name: Option<String>,
+    pub ventilation_state: Option<VentilationState>,
+    /// Seconds until a timed ventilation state ends
+    pub time_state_remaining: Option<u32>,
+    /// Target flow level in percent
+    pub flow_level_target: Option<u8>,
+    pub co2_ppm: Option<u32>,
+    /// Air quality based on CO2 in percent, 100 is good air quality
+    pub iaq_co2: Option<u8>,
+}
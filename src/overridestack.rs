@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverrideLayer {
+    pub name: String,
+    pub state: String,
+}
+
+/// Temporary ventilation state overrides of a node layered on top of the state the node had before the first
+/// override was applied. The topmost layer determines the effective state, removing layers unwinds the stack.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverrideStack {
+    base: String,
+    layers: Vec<OverrideLayer>,
+}
+
+impl OverrideStack {
+    pub fn new(base: String) -> OverrideStack {
+        OverrideStack {
+            base,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn layers(&self) -> &[OverrideLayer] {
+        &self.layers
+    }
+
+    pub fn effective(&self) -> &str {
+        self.layers
+            .last()
+            .map_or(self.base.as_str(), |layer| layer.state.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.layers.iter().any(|layer| layer.name == name)
+    }
+
+    /// Adds a layer on top of the stack, an existing layer with the same name keeps its position
+    pub fn push(&mut self, name: &str, state: &str) {
+        match self.layers.iter_mut().find(|layer| layer.name == name) {
+            Some(layer) => layer.state = state.to_string(),
+            None => self.layers.push(OverrideLayer {
+                name: name.to_string(),
+                state: state.to_string(),
+            }),
+        }
+    }
+
+    /// Removes the layer with the given name, returns false if there was no such layer
+    pub fn remove(&mut self, name: &str) -> bool {
+        let layer_count = self.layers.len();
+        self.layers.retain(|layer| layer.name != name);
+        layer_count != self.layers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_override_stack() {
+        let mut stack = OverrideStack::new("AUTO".to_string());
+        assert_eq!(stack.effective(), "AUTO");
+
+        stack.push("away", "EMPT");
+        stack.push("boost", "MAN3");
+        assert_eq!(stack.effective(), "MAN3");
+
+        // updating an existing layer does not move it to the top
+        stack.push("away", "CNT1");
+        assert_eq!(stack.effective(), "MAN3");
+
+        assert!(stack.remove("boost"));
+        assert_eq!(stack.effective(), "CNT1");
+        assert!(!stack.remove("boost"));
+
+        stack.push("quiet", "MAN1");
+        assert!(stack.remove("away"));
+        assert_eq!(stack.effective(), "MAN1");
+        assert!(stack.remove("quiet"));
+        assert!(stack.is_empty());
+        assert_eq!(stack.effective(), "AUTO");
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, overridestack::OverrideStack};
+
+/// Bridge state that has to survive a restart
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PersistentState {
+    /// Active temporary state overrides per node
+    #[serde(default)]
+    pub overrides: BTreeMap<u16, OverrideStack>,
+    /// Home assistant discovery topics published by the bridge, so the entities can be removed when they disappear
+    #[serde(default)]
+    pub discovery_topics: BTreeSet<String>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl PersistentState {
+    /// Loads the state from the given file, a missing file results in the default state
+    pub fn load(path: Option<PathBuf>) -> Result<PersistentState> {
+        let mut state = match path {
+            Some(ref path) if path.exists() => PersistentState::read(path)?,
+            _ => PersistentState::default(),
+        };
+
+        state.path = path;
+        Ok(state)
+    }
+
+    fn read(path: &Path) -> Result<PersistentState> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read state file {}", path.display()))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(ref path) = self.path {
+            std::fs::write(path, serde_json::to_string_pretty(self)?)
+                .with_context(|| format!("Failed to write state file {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let path = std::env::temp_dir().join(format!("duco2mqtt-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut state = PersistentState::load(Some(path.clone())).unwrap();
+        assert!(state.overrides.is_empty());
+
+        let mut stack = OverrideStack::new("MAN2".to_string());
+        stack.push("away", "EMPT");
+        state.overrides.insert(2, stack.clone());
+        state.save().unwrap();
+
+        let state = PersistentState::load(Some(path.clone())).unwrap();
+        assert_eq!(state.overrides, BTreeMap::from([(2, stack)]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
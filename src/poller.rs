@@ -0,0 +1,311 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use tokio::{task::JoinHandle, time};
+use tracing::Instrument;
+
+use crate::{
+    Result,
+    ducoapi::{BoxActions, BoxConfig, DeviceInfo, NodeActions, NodeConfig, NodeInfo, Nodes},
+    ducobackend::DucoBackend,
+};
+
+/// The requests of a poll cycle besides the device info and the nodes
+#[derive(Debug, Default, Clone)]
+pub struct PollRequest {
+    /// The nodes that are discovered, None when the nodes have to be discovered
+    pub known_nodes: Option<Vec<u16>>,
+    /// Requests the configuration parameters of the nodes
+    pub node_config: bool,
+    /// Requests the actions of the box itself
+    pub box_actions: bool,
+    /// Requests the configuration parameters of the box itself
+    pub box_config: bool,
+}
+
+/// The responses of the box for one poll cycle
+pub struct PollResponse {
+    pub device_info: DeviceInfo,
+    pub nodes: Vec<NodeInfo>,
+    /// The nodes and their values did not change since the previous poll
+    pub nodes_unchanged: bool,
+    /// Only requested when the box reports nodes that are not known
+    pub node_actions: Option<Vec<NodeActions>>,
+    /// Only when requested, a box that does not provide them does not fail the poll
+    pub node_config: Option<Result<Vec<NodeConfig>>>,
+    /// Only when requested, a box that does not provide them does not fail the poll
+    pub box_actions: Option<Result<BoxActions>>,
+    /// Only when requested, a box that does not provide it does not fail the poll
+    pub box_config: Option<Result<BoxConfig>>,
+    pub started: time::Instant,
+}
+
+/// Requests the values of the box for a poll cycle, the node actions are requested when the nodes have to be
+/// discovered (no known nodes) or nodes joined the duco network. The independent requests are sent concurrently.
+pub async fn fetch(backend: &dyn DucoBackend, request: &PollRequest) -> Result<PollResponse> {
+    let started = time::Instant::now();
+
+    let (values, node_config, box_actions, box_config) = tokio::join!(
+        fetch_values(backend, request.known_nodes.as_deref()),
+        fetch_optional(request.node_config, backend.get_node_config()),
+        fetch_optional(request.box_actions, backend.get_box_actions()),
+        fetch_optional(request.box_config, backend.get_box_config())
+    );
+    let (device_info, nodes, node_actions) = values?;
+
+    Ok(PollResponse {
+        device_info,
+        nodes: nodes.nodes,
+        nodes_unchanged: nodes.unchanged,
+        node_actions,
+        node_config,
+        box_actions,
+        box_config,
+        started,
+    })
+}
+
+async fn fetch_values(
+    backend: &dyn DucoBackend,
+    known_nodes: Option<&[u16]>,
+) -> Result<(DeviceInfo, Nodes, Option<Vec<NodeActions>>)> {
+    match known_nodes {
+        Some(known) => {
+            let (device_info, nodes) = tokio::try_join!(backend.get_device_info(), backend.poll_nodes())?;
+            let node_actions = if has_new_nodes(known, &nodes.nodes) {
+                Some(backend.get_node_actions().await?)
+            } else {
+                None
+            };
+            Ok((device_info, nodes, node_actions))
+        }
+        None => {
+            let (device_info, nodes, node_actions) = tokio::try_join!(
+                backend.get_device_info(),
+                backend.poll_nodes(),
+                backend.get_node_actions()
+            )?;
+            Ok((device_info, nodes, Some(node_actions)))
+        }
+    }
+}
+
+async fn fetch_optional<T>(requested: bool, request: impl Future<Output = Result<T>>) -> Option<Result<T>> {
+    if requested { Some(request.await) } else { None }
+}
+
+/// The box reports nodes that are not known, nodes that are missing do not have to be discovered
+pub fn has_new_nodes(known: &[u16], node_infos: &[NodeInfo]) -> bool {
+    node_infos.iter().any(|info| !known.contains(&info.node))
+}
+
+/// Performs the api requests of the polls in a background task, so a slow or unreachable box does not block the
+/// MQTT connection and the commands. The bridge applies the responses once the task completes.
+pub struct Poller {
+    backend: Arc<dyn DucoBackend>,
+    running: Option<JoinHandle<(u64, Result<PollResponse>)>>,
+}
+
+impl Poller {
+    pub fn new(backend: Arc<dyn DucoBackend>) -> Poller {
+        Poller { backend, running: None }
+    }
+
+    /// A poll is in progress, only one poll runs at a time
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Starts the poll cycle, the api requests are traced in a span with the poll cycle
+    pub fn start(&mut self, cycle: u64, request: PollRequest) {
+        let backend = self.backend.clone();
+        self.running = Some(tokio::spawn(async move {
+            let response = fetch(backend.as_ref(), &request)
+                .instrument(tracing::info_span!("poll", cycle))
+                .await;
+            (cycle, response)
+        }));
+    }
+
+    /// Completes with the responses of the running poll, never completes when no poll is running
+    pub async fn completed(&mut self) -> (u64, Result<PollResponse>) {
+        let Some(running) = self.running.as_mut() else {
+            return std::future::pending().await;
+        };
+
+        let completed = running.await;
+        self.running = None;
+        completed.unwrap_or_else(|err| (0, Err(anyhow!("The poll task failed: {}", err))))
+    }
+}
+
+/// Requests the values of the commanded nodes in a background task during the burst after the commands, every node
+/// is requested on its own instead of polling the whole network
+pub struct NodeRefresh {
+    backend: Arc<dyn DucoBackend>,
+    running: Option<JoinHandle<Result<Vec<NodeInfo>>>>,
+}
+
+impl NodeRefresh {
+    pub fn new(backend: Arc<dyn DucoBackend>) -> NodeRefresh {
+        NodeRefresh { backend, running: None }
+    }
+
+    /// Requests the nodes, nothing is requested while the previous request is running
+    pub fn start(&mut self, nodes: Vec<u16>) {
+        if self.running.is_some() {
+            return;
+        }
+
+        let backend = self.backend.clone();
+        self.running = Some(tokio::spawn(
+            async move {
+                let mut node_infos = Vec::with_capacity(nodes.len());
+                for node in nodes {
+                    node_infos.push(backend.get_node(node).await?);
+                }
+                Ok(node_infos)
+            }
+            .instrument(tracing::info_span!("refresh")),
+        ));
+    }
+
+    /// Completes with the values of the requested nodes, never completes when no request is running
+    pub async fn completed(&mut self) -> Result<Vec<NodeInfo>> {
+        let Some(running) = self.running.as_mut() else {
+            return std::future::pending().await;
+        };
+
+        let completed = running.await;
+        self.running = None;
+        completed.unwrap_or_else(|err| Err(anyhow!("The node refresh task failed: {}", err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ducobackend::mock::MockBackend;
+
+    use super::*;
+
+    fn known(nodes: Vec<u16>) -> PollRequest {
+        PollRequest {
+            known_nodes: Some(nodes),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poller() {
+        let mut poller = Poller::new(Arc::new(MockBackend::default()));
+        assert!(!poller.is_running());
+
+        poller.start(1, PollRequest::default());
+        assert!(poller.is_running());
+        let (cycle, response) = poller.completed().await;
+        assert_eq!(cycle, 1);
+        assert!(!poller.is_running());
+
+        // the actions are only requested again when nodes joined the network
+        let response = response.unwrap();
+        let nodes: Vec<u16> = response.nodes.iter().map(|info| info.node).collect();
+        assert_eq!(response.node_actions.unwrap().len(), nodes.len());
+
+        poller.start(2, known(nodes.iter().rev().copied().collect()));
+        let (_, response) = poller.completed().await;
+        assert!(response.unwrap().node_actions.is_none());
+
+        poller.start(3, known(nodes[1..].to_vec()));
+        let (_, response) = poller.completed().await;
+        assert!(response.unwrap().node_actions.is_some());
+
+        let mut known_nodes = nodes.clone();
+        known_nodes.push(99);
+        poller.start(4, known(known_nodes));
+        let (_, response) = poller.completed().await;
+        assert!(response.unwrap().node_actions.is_none());
+
+        // the configuration and the box actions are only requested on demand
+        poller.start(
+            5,
+            PollRequest {
+                node_config: true,
+                box_actions: true,
+                box_config: true,
+                ..known(nodes.clone())
+            },
+        );
+        let (_, response) = poller.completed().await;
+        let response = response.unwrap();
+        assert!(response.node_config.is_some_and(|config| config.is_ok()));
+        assert!(response.box_actions.is_some_and(|actions| actions.is_ok()));
+        assert!(response.box_config.is_some_and(|config| config.is_ok()));
+    }
+
+    const REQUEST_DURATION: time::Duration = time::Duration::from_millis(200);
+
+    /// Every data request takes a while
+    struct SlowBackend(MockBackend);
+
+    #[async_trait::async_trait]
+    impl DucoBackend for SlowBackend {
+        async fn get_device_info(&self) -> Result<DeviceInfo> {
+            time::sleep(REQUEST_DURATION).await;
+            self.0.get_device_info().await
+        }
+
+        async fn get_nodes(&self) -> Result<Vec<NodeInfo>> {
+            time::sleep(REQUEST_DURATION).await;
+            self.0.get_nodes().await
+        }
+
+        async fn get_node_actions(&self) -> Result<Vec<NodeActions>> {
+            time::sleep(REQUEST_DURATION).await;
+            self.0.get_node_actions().await
+        }
+
+        async fn get_box_actions(&self) -> Result<crate::ducoapi::BoxActions> {
+            self.0.get_box_actions().await
+        }
+
+        async fn get_box_config(&self) -> Result<BoxConfig> {
+            self.0.get_box_config().await
+        }
+
+        async fn perform_action(&self, node: u16, action: crate::ducoapi::NodeAction) -> Result<()> {
+            self.0.perform_action(node, action).await
+        }
+
+        async fn perform_box_action(&self, action: crate::ducoapi::NodeAction) -> Result<()> {
+            self.0.perform_box_action(action).await
+        }
+
+        async fn set_config(&self, key: &str, val: i64) -> Result<()> {
+            self.0.set_config(key, val).await
+        }
+
+        async fn get_node_config(&self) -> Result<Vec<crate::ducoapi::NodeConfig>> {
+            self.0.get_node_config().await
+        }
+
+        async fn set_node_config(&self, node: u16, key: &str, val: i64) -> Result<()> {
+            self.0.set_node_config(node, key, val).await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_are_concurrent() {
+        let backend = SlowBackend(MockBackend::default());
+
+        let response = fetch(&backend, &PollRequest::default()).await.unwrap();
+        assert!(response.started.elapsed() < REQUEST_DURATION * 2);
+        let nodes: Vec<u16> = response.nodes.iter().map(|info| info.node).collect();
+
+        let response = fetch(&backend, &known(nodes.clone())).await.unwrap();
+        assert!(response.started.elapsed() < REQUEST_DURATION * 2);
+
+        // the nodes determine whether the actions are needed
+        let response = fetch(&backend, &known(nodes[1..].to_vec())).await.unwrap();
+        assert!(response.started.elapsed() >= REQUEST_DURATION * 2);
+    }
+}
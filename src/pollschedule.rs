@@ -0,0 +1,142 @@
+use chrono::{DateTime, Local};
+use cron::Schedule;
+use tokio::time;
+
+/// Determines when the duco box is polled: at a fixed interval or at the times of one or more cron expressions
+pub enum PollSchedule {
+    Interval(time::Interval),
+    Cron { schedules: Vec<Schedule>, first: bool },
+}
+
+impl PollSchedule {
+    pub fn new(poll_interval: time::Duration, schedules: Vec<Schedule>) -> PollSchedule {
+        if schedules.is_empty() {
+            PollSchedule::Interval(time::interval(poll_interval))
+        } else {
+            PollSchedule::Cron { schedules, first: true }
+        }
+    }
+
+    /// The first upcoming time of all the cron expressions
+    fn next_poll_after(schedules: &[Schedule], now: &DateTime<Local>) -> Option<DateTime<Local>> {
+        schedules.iter().filter_map(|schedule| schedule.after(now).next()).min()
+    }
+
+    /// Completes when the next poll is due, the first poll is always immediate
+    pub async fn tick(&mut self) {
+        match self {
+            PollSchedule::Interval(interval) => {
+                interval.tick().await;
+            }
+            PollSchedule::Cron { schedules, first } => {
+                if std::mem::take(first) {
+                    return;
+                }
+
+                let now = Local::now();
+                match PollSchedule::next_poll_after(schedules, &now) {
+                    Some(next) => time::sleep((next - now).to_std().unwrap_or_default()).await,
+                    None => std::future::pending().await,
+                }
+            }
+        }
+    }
+}
+
+/// Polls at a short interval for a while after a command, the box applies some changes only after several seconds
+pub struct BurstPoll {
+    interval: time::Duration,
+    window: time::Duration,
+    until: Option<time::Instant>,
+    next: time::Instant,
+}
+
+impl BurstPoll {
+    /// A zero interval or window disables the burst
+    pub fn new(interval: time::Duration, window: time::Duration) -> BurstPoll {
+        BurstPoll {
+            interval,
+            window,
+            until: None,
+            next: time::Instant::now(),
+        }
+    }
+
+    /// Starts the burst or extends the running burst
+    pub fn start(&mut self) {
+        if self.interval.is_zero() || self.window.is_zero() {
+            return;
+        }
+
+        let now = time::Instant::now();
+        self.until = Some(now + self.window);
+        self.next = now + self.interval;
+    }
+
+    /// When the next poll of the burst is due, None when no burst is running
+    pub fn deadline(&self) -> Option<time::Instant> {
+        self.until.map(|_| self.next)
+    }
+
+    /// Schedules the next poll of the burst, the burst ends when the next poll falls outside the window
+    pub fn polled(&mut self) {
+        self.next = time::Instant::now() + self.interval;
+        if self.until.is_some_and(|until| self.next > until) {
+            self.until = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_poll() {
+        let mut burst = BurstPoll::new(time::Duration::from_secs(2), time::Duration::from_secs(30));
+        assert_eq!(burst.deadline(), None);
+
+        let start = time::Instant::now();
+        burst.start();
+        let deadline = burst.deadline().unwrap();
+        assert!(deadline >= start + time::Duration::from_secs(2));
+        burst.polled();
+        assert!(burst.deadline().unwrap() >= deadline);
+
+        // the first poll is always performed, the next one falls outside the window
+        let mut short = BurstPoll::new(time::Duration::from_secs(2), time::Duration::from_secs(1));
+        short.start();
+        assert!(short.deadline().is_some());
+        short.polled();
+        assert_eq!(short.deadline(), None);
+
+        let mut disabled = BurstPoll::new(time::Duration::from_secs(2), time::Duration::ZERO);
+        disabled.start();
+        assert_eq!(disabled.deadline(), None);
+    }
+
+    #[test]
+    fn test_next_poll() {
+        // every 15 seconds during the day, every 5 minutes at night
+        let schedules = vec![
+            Schedule::from_str("*/15 * 7-22 * * *").unwrap(),
+            Schedule::from_str("0 */5 0-6,23 * * *").unwrap(),
+        ];
+
+        let day = Local.with_ymd_and_hms(2024, 5, 27, 12, 0, 7).unwrap();
+        assert_eq!(
+            PollSchedule::next_poll_after(&schedules, &day),
+            Some(Local.with_ymd_and_hms(2024, 5, 27, 12, 0, 15).unwrap())
+        );
+
+        let night = Local.with_ymd_and_hms(2024, 5, 27, 23, 1, 0).unwrap();
+        assert_eq!(
+            PollSchedule::next_poll_after(&schedules, &night),
+            Some(Local.with_ymd_and_hms(2024, 5, 27, 23, 5, 0).unwrap())
+        );
+    }
+}
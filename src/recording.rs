@@ -0,0 +1,163 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, anyhow};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// The index of a recording, one json document per line for every response
+const INDEX_FILE: &str = "index.jsonl";
+
+/// A response in the index of the recording, the body is stored unmodified in the file next to the index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RecordedResponse {
+    sequence: u64,
+    /// Time the response was received (RFC 3339)
+    time: String,
+    method: String,
+    path: String,
+    status: u16,
+    file: String,
+}
+
+/// Saves the raw api responses of the box, to reproduce parsing problems with firmware versions that are not
+/// available for testing
+pub struct Recorder {
+    dir: PathBuf,
+    index: Mutex<(u64, File)>,
+}
+
+impl Recorder {
+    /// Records to the directory, the responses are appended to an existing recording
+    pub fn new(dir: &Path) -> Result<Recorder> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create recording directory {}", dir.display()))?;
+        let index_path = dir.join(INDEX_FILE);
+        let sequence = match std::fs::read_to_string(&index_path) {
+            Ok(index) => index.lines().filter(|line| !line.trim().is_empty()).count() as u64,
+            Err(_) => 0,
+        };
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)
+            .with_context(|| format!("Failed to open {}", index_path.display()))?;
+
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            index: Mutex::new((sequence, index)),
+        })
+    }
+
+    /// Failures are logged, the recording never interrupts the bridge
+    pub fn record(&self, method: &str, path: &str, status: u16, body: &[u8]) {
+        if let Err(err) = self.write(method, path, status, body) {
+            tracing::warn!(path, "Failed to record the api response: {:#}", err);
+        }
+    }
+
+    fn write(&self, method: &str, path: &str, status: u16, body: &[u8]) -> Result<()> {
+        let mut index = self.index.lock().expect("Poisoned lock");
+        let sequence = index.0 + 1;
+        let name = path.trim_matches('/').replace('/', "_");
+        let response = RecordedResponse {
+            sequence,
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            file: format!("{:06}_{}_{}.json", sequence, method.to_lowercase(), name),
+        };
+
+        std::fs::write(self.dir.join(&response.file), body)?;
+        writeln!(index.1, "{}", serde_json::to_string(&response)?)?;
+        index.0 = sequence;
+        Ok(())
+    }
+}
+
+/// The recorded responses per api path, in the order they were received
+pub struct Recording {
+    dir: PathBuf,
+    responses: Mutex<BTreeMap<String, VecDeque<RecordedResponse>>>,
+}
+
+impl Recording {
+    pub fn load(dir: &Path) -> Result<Recording> {
+        let index_path = dir.join(INDEX_FILE);
+        let index = std::fs::read_to_string(&index_path)
+            .with_context(|| format!("Failed to read recording index {}", index_path.display()))?;
+
+        let mut responses: BTreeMap<String, VecDeque<RecordedResponse>> = BTreeMap::new();
+        for (nr, line) in index.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let response: RecordedResponse = serde_json::from_str(line)
+                .with_context(|| format!("Invalid line {} in {}", nr + 1, index_path.display()))?;
+            if response.method == "GET" {
+                responses.entry(response.path.clone()).or_default().push_back(response);
+            }
+        }
+
+        Ok(Recording {
+            dir: dir.to_path_buf(),
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// The body of the next recorded response of the path, an error once all the responses were replayed
+    pub fn next(&self, path: &str) -> Result<Vec<u8>> {
+        let response = self
+            .responses
+            .lock()
+            .expect("Poisoned lock")
+            .get_mut(path)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| anyhow!("No more recorded responses for {}", path))?;
+
+        tracing::debug!(
+            path,
+            sequence = response.sequence,
+            time = response.time,
+            "Replaying api response"
+        );
+        let file = self.dir.join(&response.file);
+        std::fs::read(&file).with_context(|| format!("Failed to read recorded response {}", file.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("duco2mqtt-recording-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let recorder = Recorder::new(&dir).unwrap();
+        recorder.record("GET", "/info/nodes", 200, b"first");
+        recorder.record("POST", "/action/nodes/2", 200, b"");
+        recorder.record("GET", "/info/nodes", 200, b"{not json");
+        assert!(dir.join("000002_post_action_nodes_2.json").exists());
+
+        // a new recorder continues the recording
+        Recorder::new(&dir).unwrap().record("GET", "/info", 200, b"info");
+
+        let recording = Recording::load(&dir).unwrap();
+        assert_eq!(recording.next("/info/nodes").unwrap(), b"first");
+        assert_eq!(recording.next("/info/nodes").unwrap(), b"{not json");
+        assert!(recording.next("/info/nodes").is_err());
+        assert_eq!(recording.next("/info").unwrap(), b"info");
+        assert!(dir.join("000004_get_info.json").exists());
+        assert!(recording.next("/action/nodes/2").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Recording::load(&dir).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::borrow::Cow;
+
+use strum::{Display, EnumString};
+
+/// Status keys whose values identify the installation (names, serials, network addresses)
+const SENSITIVE_KEYS: &[&str] = &[
+    "Name",
+    "SerialBoardBox",
+    "SerialBoardComm",
+    "SerialDucoBox",
+    "SerialDucoComm",
+    "Ip",
+    "Mac",
+    "HostName",
+    "DefaultGateway",
+    "Dns",
+    "DucoClientIp",
+];
+
+const REDACTED: &str = "<redacted>";
+
+/// Controls how identifying values are written to the logs, the MQTT payloads are never modified
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum LogRedaction {
+    #[default]
+    Off,
+    Hash,
+    Redact,
+}
+
+impl LogRedaction {
+    /// Returns the payload as it should appear in the logs for the given topic
+    pub fn payload<'a>(&self, topic: &str, payload: &'a str) -> Cow<'a, str> {
+        match self {
+            LogRedaction::Off => Cow::Borrowed(payload),
+            _ if !is_sensitive_topic(topic) => Cow::Borrowed(payload),
+            LogRedaction::Hash => Cow::Owned(hash_value(payload)),
+            LogRedaction::Redact => Cow::Borrowed(REDACTED),
+        }
+    }
+}
+
+fn is_sensitive_topic(topic: &str) -> bool {
+    topic
+        .rsplit('/')
+        .next()
+        .is_some_and(|key| SENSITIVE_KEYS.contains(&key))
+}
+
+/// The first 4 bytes of the SHA-256 of the value, the same value gets the same hash in the logs of every run and
+/// version so the log lines of a value can be correlated
+fn hash_value(val: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, val.as_bytes());
+    let hex: String = digest.as_ref()[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("#{}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_sensitive_values() {
+        let topic = "ventilation/General/Board/SerialBoardBox";
+        assert_eq!(LogRedaction::Off.payload(topic, "PS0000000000"), "PS0000000000");
+        assert_eq!(LogRedaction::Redact.payload(topic, "PS0000000000"), REDACTED);
+
+        let hashed = LogRedaction::Hash.payload(topic, "PS0000000000");
+        assert_eq!(hashed, "#0535c674");
+        assert_ne!(hashed, LogRedaction::Hash.payload(topic, "PS0000000001"));
+
+        assert_eq!(
+            LogRedaction::Redact.payload("ventilation/duco_node_1/General/Name", "Bathroom"),
+            REDACTED
+        );
+        assert_eq!(
+            LogRedaction::Redact.payload("ventilation/duco_node_1/Ventilation/State", "AUTO"),
+            "AUTO"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::{convert::Infallible, fmt, io, path::Path, str::FromStr};
+
+use zeroize::Zeroizing;
+
+/// A credential (e.g. the MQTT password) that is wiped from memory when it is dropped and never shows up in the
+/// debug output, so it can not end up in the logs
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    /// The actual value, only to be passed to the library that needs the credential
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the credential from a file (e.g. a docker or kubernetes secret), the trailing line break is removed
+    pub fn from_file(path: &Path) -> io::Result<Secret> {
+        let mut value = Zeroizing::new(std::fs::read_to_string(path)?);
+        let len = value.trim_end_matches(['\r', '\n']).len();
+        value.truncate(len);
+        Ok(Secret(value))
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret(Zeroizing::new(value))
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Secret, Infallible> {
+        Ok(Secret::from(value.to_string()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_not_logged() {
+        let secret = Secret::from_str("hunter2").unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret(<redacted>))");
+        assert!(Secret::default().is_empty());
+    }
+
+    #[test]
+    fn test_secret_from_file() {
+        let path = std::env::temp_dir().join(format!("duco2mqtt-secret-{}", std::process::id()));
+        std::fs::write(&path, "hunter2 \r\n").unwrap();
+        let secret = Secret::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(secret.unwrap().expose(), "hunter2 ");
+
+        assert!(Secret::from_file(&path).is_err());
+    }
+}
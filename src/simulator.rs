@@ -0,0 +1,472 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, anyhow, bail};
+use rustls::{
+    ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{Result, ducoapi};
+
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// The documents the simulated box serves, node actions change the values of the nodes
+#[derive(Debug, Clone)]
+struct SimulatorState {
+    info: Value,
+    nodes: Value,
+    actions: Value,
+    box_actions: Value,
+    box_config: Value,
+    node_config: Value,
+}
+
+/// Serves the api of a duco connectivity board from recorded responses (`info.json`, `info_nodes.json`,
+/// `node_actions.json` and the optional `box_actions.json`, `box_config.json` and `node_config.json`), over TLS with a self-signed certificate like the board does
+pub struct Simulator {
+    state: Arc<Mutex<SimulatorState>>,
+    acceptor: TlsAcceptor,
+    api_key: Option<Arc<str>>,
+}
+
+impl Simulator {
+    pub fn new(data_dir: &Path, certificate: &Path, key: &Path) -> crate::ApiResult<Simulator> {
+        let read = |name: &str| -> Result<Value> {
+            let path = data_dir.join(name);
+            let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            serde_json::from_slice(&data).with_context(|| format!("Invalid json in {}", path.display()))
+        };
+
+        let read_optional = |name: &str, default: Value| -> Result<Value> {
+            if data_dir.join(name).exists() {
+                read(name)
+            } else {
+                Ok(default)
+            }
+        };
+
+        let state = SimulatorState {
+            info: read("info.json")?,
+            nodes: read("info_nodes.json")?,
+            actions: read("node_actions.json")?,
+            box_actions: read_optional("box_actions.json", serde_json::json!({ "Actions": [] }))?,
+            box_config: read_optional("box_config.json", serde_json::json!({}))?,
+            node_config: read_optional("node_config.json", serde_json::json!({ "Nodes": [] }))?,
+        };
+        // the bridge has to be able to parse what is served
+        ducoapi::parse_device_info(state.info.to_string().as_bytes())?;
+        ducoapi::parse_node_info(state.nodes.to_string().as_bytes())?;
+        ducoapi::parse_node_actions(state.actions.to_string().as_bytes())?;
+        ducoapi::parse_box_actions(state.box_actions.to_string().as_bytes())?;
+        ducoapi::parse_box_config(state.box_config.to_string().as_bytes())?;
+        ducoapi::parse_node_config(state.node_config.to_string().as_bytes())?;
+
+        Ok(Simulator {
+            state: Arc::new(Mutex::new(state)),
+            acceptor: TlsAcceptor::from(Arc::new(server_config(certificate, key)?)),
+            api_key: None,
+        })
+    }
+
+    /// Requires the api key of newer firmware, requests without the key are answered with 401
+    pub fn with_api_key(mut self, api_key: &str) -> Simulator {
+        self.api_key = Some(Arc::from(api_key));
+        self
+    }
+
+    /// Answers the api requests until the task is dropped
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let acceptor = self.acceptor.clone();
+                    let state = self.state.clone();
+                    let api_key = self.api_key.clone();
+                    tokio::spawn(async move {
+                        let result = match acceptor.accept(stream).await {
+                            Ok(stream) => handle_connection(stream, &state, api_key.as_deref()).await,
+                            Err(err) => Err(anyhow!(err).context("TLS handshake failed")),
+                        };
+                        if let Err(err) = result {
+                            tracing::debug!(%peer, "Failed to answer the api request: {:#}", err);
+                        }
+                    });
+                }
+                Err(err) => tracing::warn!("Failed to accept an api request: {}", err),
+            }
+        }
+    }
+}
+
+fn server_config(certificate: &Path, key: &Path) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(certificate)
+        .with_context(|| format!("Failed to read certificate {}", certificate.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key).with_context(|| format!("Failed to read key {}", key.display()))?;
+
+    Ok(ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// Reads a single request, the connection is closed after the response
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    state: &Mutex<SimulatorState>,
+    api_key: Option<&str>,
+) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    let header = |headers: &str, header: &str| {
+        headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(header))
+            .map(|(_, value)| value.trim().to_string())
+    };
+
+    let (header_size, content_length) = loop {
+        if let Some(end) = request.windows(4).position(|bytes| bytes == b"\r\n\r\n") {
+            let headers = String::from_utf8_lossy(&request[..end]);
+            let content_length = header(&headers, "content-length")
+                .map_or(Ok(0), |value| value.parse::<usize>())
+                .context("Invalid content length")?;
+            break (end + 4, content_length);
+        }
+        read_more(&mut stream, &mut buffer, &mut request).await?;
+    };
+
+    while request.len() < header_size + content_length {
+        read_more(&mut stream, &mut buffer, &mut request).await?;
+    }
+
+    let request_line = String::from_utf8_lossy(&request[..header_size]);
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let body = &request[header_size..header_size + content_length];
+
+    let (mut code, mut body) = match api_key {
+        Some(api_key) if header(&request_line, "api-key").as_deref() != Some(api_key) => {
+            ("401 Unauthorized", String::new())
+        }
+        _ => {
+            let mut state = state.lock().expect("Poisoned lock");
+            respond(&mut state, method, path, body)
+        }
+    };
+
+    // the documents get an entity tag, so the conditional requests of the bridge can be tested
+    let mut etag_header = String::new();
+    if method == "GET" && code == "200 OK" {
+        let digest = ::ring::digest::digest(&::ring::digest::SHA256, body.as_bytes());
+        let etag = format!("\"{}\"", hex(&digest.as_ref()[..8]));
+        if header(&request_line, "if-none-match").as_deref() == Some(etag.as_str()) {
+            code = "304 Not Modified";
+            body.clear();
+        }
+        etag_header = format!("ETag: {}\r\n", etag);
+    }
+    tracing::info!(method, path, status = code, "Api request");
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+                code,
+                body.len(),
+                etag_header,
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn read_more<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut [u8], request: &mut Vec<u8>) -> Result<()> {
+    let size = time::timeout(REQUEST_TIMEOUT, stream.read(buffer))
+        .await
+        .context("Request timed out")??;
+    if size == 0 {
+        bail!("Connection closed before the request was complete");
+    }
+    if request.len() + size > MAX_REQUEST_SIZE {
+        bail!("Request too large");
+    }
+    request.extend_from_slice(&buffer[..size]);
+    Ok(())
+}
+
+/// The status line and the body of the response
+fn respond(state: &mut SimulatorState, method: &str, path: &str, body: &[u8]) -> (&'static str, String) {
+    let path = path.split('?').next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/info") => ("200 OK", state.info.to_string()),
+        ("GET", "/info/nodes") => ("200 OK", state.nodes.to_string()),
+        ("GET", "/action/nodes") => ("200 OK", state.actions.to_string()),
+        ("GET", "/action") => ("200 OK", state.box_actions.to_string()),
+        ("GET", "/config") => ("200 OK", state.box_config.to_string()),
+        ("GET", "/config/nodes") => ("200 OK", state.node_config.to_string()),
+        ("GET", path) if path.starts_with("/info/nodes/") => {
+            match path["/info/nodes/".len()..]
+                .parse::<u64>()
+                .ok()
+                .and_then(|node| find_node(&state.nodes, node))
+            {
+                Some(node) => ("200 OK", node.to_string()),
+                None => ("404 Not Found", String::new()),
+            }
+        }
+        // box actions and configuration changes are accepted, they do not change the served values
+        ("POST", "/action") | ("PATCH", "/config") => ("200 OK", r#"{"Code":"SUCCESS"}"#.to_string()),
+        ("PATCH", path) if path.starts_with("/config/nodes/") => {
+            let result = path["/config/nodes/".len()..]
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid node number"))
+                .and_then(|node| change_node_config(state, node, body));
+            match result {
+                Ok(()) => ("200 OK", r#"{"Code":"SUCCESS"}"#.to_string()),
+                Err(err) => (
+                    "400 Bad Request",
+                    serde_json::json!({ "Code": "FAILED", "Result": format!("{:#}", err) }).to_string(),
+                ),
+            }
+        }
+        ("POST", path) if path.starts_with("/action/nodes/") => {
+            let result = path["/action/nodes/".len()..]
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid node number"))
+                .and_then(|node| perform_action(state, node, body));
+            match result {
+                Ok(()) => ("200 OK", r#"{"Code":"SUCCESS"}"#.to_string()),
+                Err(err) => (
+                    "400 Bad Request",
+                    serde_json::json!({ "Code": "FAILED", "Result": format!("{:#}", err) }).to_string(),
+                ),
+            }
+        }
+        _ => ("404 Not Found", String::new()),
+    }
+}
+
+/// Validates the action against the actions of the node and applies it: `Set<Group><Key>` changes the value of
+/// `<Key>` in `<Group>` (SetVentilationState changes Ventilation/State), `Set<Key>` the value of `<Key>` in any group
+/// (SetIdentify changes General/Identify). Boolean values are stored as 0 or 1 like the box reports them.
+fn perform_action(state: &mut SimulatorState, node: u64, body: &[u8]) -> Result<()> {
+    let request: Value = serde_json::from_slice(body).context("Invalid json body")?;
+    let (action, val) = match (request["Action"].as_str(), &request["Val"]) {
+        (Some(action), val) if !val.is_null() => (action, val),
+        _ => bail!("Expected an Action and a Val"),
+    };
+
+    let description = find_node(&state.actions, node)
+        .and_then(|actions| actions["Actions"].as_array())
+        .and_then(|actions| actions.iter().find(|description| description["Action"] == action))
+        .ok_or_else(|| anyhow!("Node {} does not support action {}", node, action))?;
+
+    let val = match (description["ValType"].as_str(), val) {
+        (Some("Enum"), Value::String(val)) => {
+            let accepted = description["Enum"]
+                .as_array()
+                .is_some_and(|values| values.contains(&Value::from(val.as_str())));
+            if !accepted {
+                bail!("Invalid value {} for action {}", val, action);
+            }
+            Value::from(val.as_str())
+        }
+        (Some("Boolean"), Value::Bool(val)) => Value::from(u8::from(*val)),
+        (val_type, val) => bail!(
+            "Invalid value {} for action {} of type {}",
+            val,
+            action,
+            val_type.unwrap_or("?")
+        ),
+    };
+
+    let setting = action.strip_prefix("Set").unwrap_or(action);
+    let groups = find_node_mut(&mut state.nodes, node)
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("Unknown node {}", node))?;
+    let field = groups
+        .iter_mut()
+        .filter_map(|(group, keys)| Some((group.as_str(), keys.as_object_mut()?)))
+        .flat_map(|(group, keys)| keys.iter_mut().map(move |(key, field)| (group, key.as_str(), field)))
+        .find(|(group, key, _)| setting == format!("{}{}", group, key) || setting == *key)
+        .map(|(_, _, field)| field)
+        .ok_or_else(|| anyhow!("Node {} has no value for action {}", node, action))?;
+
+    field["Val"] = val;
+    Ok(())
+}
+
+/// Changes the configuration parameters in the body ({"Co2SetPoint":{"Val":900}}) within the range of the parameter
+fn change_node_config(state: &mut SimulatorState, node: u64, body: &[u8]) -> Result<()> {
+    let request: Value = serde_json::from_slice(body).context("Invalid json body")?;
+    let changes = request.as_object().ok_or_else(|| anyhow!("Expected a json object"))?;
+    let parameters = find_node_mut(&mut state.node_config, node)
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("Unknown node {}", node))?;
+
+    for (name, change) in changes {
+        let parameter = parameters
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("Node {} has no configuration parameter {}", node, name))?;
+        let val = change["Val"]
+            .as_i64()
+            .ok_or_else(|| anyhow!("Expected a numeric Val for {}", name))?;
+        let (min, max) = (parameter["Min"].as_i64(), parameter["Max"].as_i64());
+        if min.is_some_and(|min| val < min) || max.is_some_and(|max| val > max) {
+            bail!("Value {} out of range for {}", val, name);
+        }
+        parameter["Val"] = Value::from(val);
+    }
+
+    Ok(())
+}
+
+fn find_node(document: &Value, node: u64) -> Option<&Value> {
+    document["Nodes"].as_array()?.iter().find(|entry| entry["Node"] == node)
+}
+
+fn find_node_mut(document: &mut Value, node: u64) -> Option<&mut Value> {
+    document["Nodes"]
+        .as_array_mut()?
+        .iter_mut()
+        .find(|entry| entry["Node"] == node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> SimulatorState {
+        let read = |data: &[u8]| serde_json::from_slice(data).unwrap();
+        SimulatorState {
+            info: read(include_bytes!("../test/data/info.json")),
+            nodes: read(include_bytes!("../test/data/info_nodes.json")),
+            actions: read(include_bytes!("../test/data/node_actions.json")),
+            box_actions: read(include_bytes!("../test/data/box_actions.json")),
+            box_config: read(include_bytes!("../test/data/box_config.json")),
+            node_config: read(include_bytes!("../test/data/node_config.json")),
+        }
+    }
+
+    #[test]
+    fn test_node_actions() {
+        let mut state = test_state();
+        let post = |state: &mut SimulatorState, path: &str, body: &str| respond(state, "POST", path, body.as_bytes()).0;
+
+        assert_eq!(
+            post(
+                &mut state,
+                "/action/nodes/1",
+                r#"{"Action":"SetVentilationState","Val":"MAN2"}"#
+            ),
+            "200 OK"
+        );
+        assert_eq!(
+            post(&mut state, "/action/nodes/1", r#"{"Action":"SetIdentify","Val":true}"#),
+            "200 OK"
+        );
+        let node = find_node(&state.nodes, 1).unwrap();
+        assert_eq!(node["Ventilation"]["State"]["Val"], "MAN2");
+        assert_eq!(node["General"]["Identify"]["Val"], 1);
+
+        for body in [
+            r#"{"Action":"SetVentilationState","Val":"BOGUS"}"#,
+            r#"{"Action":"SetVentilationState","Val":true}"#,
+            r#"{"Action":"Bogus","Val":"MAN2"}"#,
+            r#"{"Action":"SetVentilationState"}"#,
+            "MAN2",
+        ] {
+            assert_eq!(post(&mut state, "/action/nodes/1", body), "400 Bad Request", "{}", body);
+        }
+        assert_eq!(
+            post(&mut state, "/action/nodes/99", r#"{"Action":"SetIdentify","Val":true}"#),
+            "400 Bad Request"
+        );
+
+        let patch =
+            |state: &mut SimulatorState, body: &str| respond(state, "PATCH", "/config/nodes/2", body.as_bytes()).0;
+        assert_eq!(patch(&mut state, r#"{"Co2SetPoint":{"Val":900}}"#), "200 OK");
+        assert_eq!(find_node(&state.node_config, 2).unwrap()["Co2SetPoint"]["Val"], 900);
+        for body in [r#"{"Co2SetPoint":{"Val":100}}"#, r#"{"RhSetPoint":{"Val":50}}"#, "{}x"] {
+            assert_eq!(patch(&mut state, body), "400 Bad Request", "{}", body);
+        }
+
+        assert_eq!(respond(&mut state, "GET", "/info?module=General", b"").0, "200 OK");
+        assert_eq!(respond(&mut state, "GET", "/bogus", b"").0, "404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_simulator() {
+        let simulator = Simulator::new(
+            Path::new("test/data"),
+            Path::new("test/data/tls/duco.pem"),
+            Path::new("test/data/tls/duco.key"),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(simulator.serve(listener));
+
+        // served over TLS with the pinned certificate of the simulator
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(
+                crate::certpinning::PinnedCertVerifier::from_pem_file(Path::new("test/data/tls/duco.pem"))
+                    .unwrap()
+                    .client_config()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        let api = ducoapi::DucoApi::new(client, addr, None);
+        let cache = ducoapi::NodesCache::default();
+        let nodes = api.get_nodes(Some(&cache)).await.unwrap();
+        assert_eq!(nodes.nodes[1].node, 2);
+        assert!(!nodes.unchanged);
+        // answered with 304, the nodes of the previous response are returned
+        let unchanged = api.get_nodes(Some(&cache)).await.unwrap();
+        assert!(unchanged.unchanged);
+        assert_eq!(unchanged.nodes.len(), nodes.nodes.len());
+        api.perform_action(
+            2,
+            ducoapi::NodeAction::Enum(ducoapi::NodeEnumAction {
+                action: "SetVentilationState".to_string(),
+                val: "MAN3".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+
+        let nodes = api.get_nodes(Some(&cache)).await.unwrap();
+        assert!(!nodes.unchanged);
+        assert_eq!(nodes.nodes[1].ventilation["State"].val.to_string(), "MAN3");
+        let nodes = api.get_nodes(None).await.unwrap().nodes;
+        assert_eq!(nodes[1].ventilation["State"].val.to_string(), "MAN3");
+        assert!(api.get_device_info().await.is_ok());
+        assert!(api.get_node_actions().await.is_ok());
+        assert_eq!(api.get_box_actions().await.unwrap().actions.len(), 4);
+    }
+}
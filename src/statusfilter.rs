@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+/// A rule of the status filter: `General/Asso` or `+General/Asso` publishes the key, `-General/Asso` does not.
+/// The pattern is a status key, all keys of a group (`General/*`) or all keys (`*`), matched case insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusRule {
+    pattern: String,
+    allow: bool,
+}
+
+impl StatusRule {
+    fn matches(&self, key: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => key
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+            None => key.eq_ignore_ascii_case(&self.pattern),
+        }
+    }
+}
+
+impl FromStr for StatusRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<StatusRule, String> {
+        let rule = rule.trim();
+        let (pattern, allow) = match rule.strip_prefix('-') {
+            Some(pattern) => (pattern, false),
+            None => (rule.strip_prefix('+').unwrap_or(rule), true),
+        };
+
+        let wildcard_ok = match pattern.find('*') {
+            None => true,
+            Some(pos) => pos == pattern.len() - 1 && (pattern == "*" || pattern.ends_with("/*")),
+        };
+        if pattern.is_empty() || !wildcard_ok {
+            return Err(format!(
+                "expected [+|-]<group>/<key>, [+|-]<group>/* or [+|-]*, got '{}'",
+                rule
+            ));
+        }
+
+        Ok(StatusRule {
+            pattern: pattern.to_string(),
+            allow,
+        })
+    }
+}
+
+/// Selects the status keys of the nodes that are published, the last matching rule decides and keys without a
+/// matching rule are published (e.g. `-General/*,+General/Name` only publishes the name of the General group)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusFilter {
+    rules: Vec<StatusRule>,
+}
+
+impl StatusFilter {
+    pub fn new(rules: Vec<StatusRule>) -> StatusFilter {
+        StatusFilter { rules }
+    }
+
+    pub fn is_allowed(&self, key: &str) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(key))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_filter() {
+        let filter = |rules: &[&str]| StatusFilter::new(rules.iter().map(|rule| rule.parse().unwrap()).collect());
+
+        let all = StatusFilter::default();
+        assert!(all.is_allowed("General/Asso"));
+
+        let filter = filter(&["-General/*", "+general/name", "-Sensor/IaqCo2"]);
+        assert!(!filter.is_allowed("General/Asso"));
+        assert!(filter.is_allowed("General/Name"));
+        assert!(filter.is_allowed("Ventilation/State"));
+        assert!(!filter.is_allowed("Sensor/IaqCo2"));
+        assert!(filter.is_allowed("Sensor/Co2"));
+
+        let filter = StatusFilter::new(vec!["-*".parse().unwrap(), "Ventilation/State".parse().unwrap()]);
+        assert!(!filter.is_allowed("Ventilation/Mode"));
+        assert!(filter.is_allowed("Ventilation/State"));
+
+        for invalid in ["", "-", "*/Asso", "General*", "Gen*/Asso"] {
+            assert!(invalid.parse::<StatusRule>().is_err(), "{}", invalid);
+        }
+    }
+}
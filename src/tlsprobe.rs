@@ -0,0 +1,286 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, anyhow};
+use base64::Engine;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use serde::Serialize;
+use tokio::{net::TcpStream, time};
+use tokio_rustls::TlsConnector;
+use x509_parser::extensions::GeneralName;
+
+use crate::{ApiResult, Result, certpinning::PinnedCertVerifier, ducoendpoint::DucoEndpoint};
+
+pub(crate) const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// The certificates the connectivity board presents, the server certificate first
+pub struct CertificateChain {
+    certificates: Vec<CertificateDer<'static>>,
+}
+
+/// The details of a certificate to compare with the certificate shown in the web interface of the board
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    /// The current system time is within the validity period
+    pub valid_now: bool,
+    pub names: Vec<String>,
+    /// SHA-256 of the certificate as colon separated hex bytes
+    pub sha256_fingerprint: String,
+}
+
+impl CertificateChain {
+    /// The chain as a PEM file that can be passed to `--certificate`
+    pub fn to_pem(&self) -> String {
+        self.certificates
+            .iter()
+            .map(|cert| {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(cert.as_ref());
+                let lines: Vec<&str> = encoded
+                    .as_bytes()
+                    .chunks(64)
+                    .map(|line| std::str::from_utf8(line).unwrap_or_default())
+                    .collect();
+                format!(
+                    "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                    lines.join("\n")
+                )
+            })
+            .collect()
+    }
+
+    pub fn info(&self) -> ApiResult<Vec<CertificateInfo>> {
+        Ok(self
+            .certificates
+            .iter()
+            .map(certificate_info)
+            .collect::<Result<Vec<_>>>()?)
+    }
+}
+
+pub(crate) fn certificate_info(certificate: &CertificateDer) -> Result<CertificateInfo> {
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(certificate.as_ref()).context("Failed to parse the certificate")?;
+    let validity = cert.validity();
+
+    Ok(CertificateInfo {
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        not_before: validity.not_before.to_string(),
+        not_after: validity.not_after.to_string(),
+        valid_now: validity.is_valid(),
+        names: cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|names| names.value.general_names.iter().filter_map(general_name).collect())
+            .unwrap_or_default(),
+        sha256_fingerprint: ::ring::digest::digest(&::ring::digest::SHA256, certificate.as_ref())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":"),
+    })
+}
+
+fn general_name(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) => Some(name.to_string()),
+        GeneralName::IPAddress(bytes) => match <[u8; 4]>::try_from(*bytes) {
+            Ok(ip) => Some(IpAddr::from(ip).to_string()),
+            Err(_) => <[u8; 16]>::try_from(*bytes).ok().map(|ip| IpAddr::from(ip).to_string()),
+        },
+        _ => None,
+    }
+}
+
+/// Downloads the certificates the board presents, without validating them
+pub async fn fetch_certificate_chain(host: &str, ip_address: Option<&str>) -> ApiResult<CertificateChain> {
+    let endpoint = DucoEndpoint::new(host, ip_address)?;
+    let host = endpoint.name.as_str();
+    let addr = match endpoint.socket_addr() {
+        Some(addr) => addr,
+        None => time::timeout(CONNECT_TIMEOUT, tokio::net::lookup_host((host, endpoint.port)))
+            .await
+            .map_err(|_| anyhow!("Resolving {} timed out", host))?
+            .with_context(|| format!("Failed to resolve {}", host))?
+            .next()
+            .ok_or_else(|| anyhow!("{} has no addresses", host))?,
+    };
+
+    let verifier = Arc::new(RecordingVerifier::new(None, false)?);
+    handshake(host, addr, verifier.clone()).await?;
+    let certificates = std::mem::take(&mut *verifier.presented.lock().expect("Poisoned lock"));
+    if certificates.is_empty() {
+        return Err(anyhow!("{} did not present a certificate", addr).into());
+    }
+
+    Ok(CertificateChain { certificates })
+}
+
+/// The negotiated protocol version and cipher suite
+pub(crate) async fn handshake(host: &str, addr: SocketAddr, verifier: Arc<RecordingVerifier>) -> Result<String> {
+    let config = ClientConfig::builder_with_provider(verifier.provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string()).with_context(|| format!("Invalid host name {}", host))?;
+
+    let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow!("Connecting to {} timed out", addr))?
+        .with_context(|| format!("Failed to connect to {}", addr))?;
+    let stream = time::timeout(
+        CONNECT_TIMEOUT,
+        TlsConnector::from(Arc::new(config)).connect(server_name, stream),
+    )
+    .await
+    .map_err(|_| anyhow!("The TLS handshake with {} timed out", addr))?
+    .context("The TLS handshake failed")?;
+
+    let (_, connection) = stream.get_ref();
+    let version = connection
+        .protocol_version()
+        .map_or("?".to_string(), |version| format!("{:?}", version));
+    let suite = connection
+        .negotiated_cipher_suite()
+        .map_or("?".to_string(), |suite| format!("{:?}", suite.suite()));
+    Ok(format!("{} {}", version, suite))
+}
+
+/// Keeps the certificates the board presents, the verification is left to the verifier the bridge uses (none when no
+/// certificate is configured)
+#[derive(Debug)]
+pub(crate) struct RecordingVerifier {
+    verifier: Option<Arc<dyn ServerCertVerifier>>,
+    provider: Arc<CryptoProvider>,
+    /// The server certificate followed by the intermediates
+    pub(crate) presented: Mutex<Vec<CertificateDer<'static>>>,
+}
+
+impl RecordingVerifier {
+    pub(crate) fn new(certificate: Option<&Path>, cert_pinning: bool) -> Result<RecordingVerifier> {
+        let provider = Arc::new(ring::default_provider());
+        let verifier: Option<Arc<dyn ServerCertVerifier>> = match certificate {
+            Some(cert) if cert_pinning => Some(Arc::new(PinnedCertVerifier::from_pem_file(cert)?)),
+            Some(cert) => {
+                let mut roots = RootCertStore::empty();
+                for root in CertificateDer::pem_file_iter(cert)
+                    .with_context(|| format!("Failed to read certificate {}", cert.display()))?
+                {
+                    roots.add(root?)?;
+                }
+                Some(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?)
+            }
+            None => None,
+        };
+
+        Ok(RecordingVerifier {
+            verifier,
+            provider,
+            presented: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        *self.presented.lock().expect("Poisoned lock") = std::iter::once(end_entity)
+            .chain(intermediates)
+            .map(|cert| cert.clone().into_owned())
+            .collect();
+
+        match &self.verifier {
+            Some(verifier) => verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch_certificate_chain() {
+        let simulator = crate::simulator::Simulator::new(
+            Path::new("test/data"),
+            Path::new("test/data/tls/duco.pem"),
+            Path::new("test/data/tls/duco.key"),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(simulator.serve(listener));
+
+        let chain = fetch_certificate_chain(&format!("duco_sim.local:{}", port), Some("127.0.0.1"))
+            .await
+            .unwrap();
+        // the written file is identical to the certificate of the board
+        let expected: Vec<_> = CertificateDer::pem_file_iter("test/data/tls/duco.pem")
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        let written: Vec<_> = CertificateDer::pem_slice_iter(chain.to_pem().as_bytes())
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(written, expected);
+
+        let info = chain.info().unwrap();
+        assert_eq!(info[0].subject, "CN=duco_sim.local");
+        assert_eq!(info[0].names, vec!["duco_sim.local", "localhost", "127.0.0.1"]);
+        assert_eq!(info[0].sha256_fingerprint.len(), 32 * 3 - 1);
+
+        assert!(matches!(
+            fetch_certificate_chain("duco.local", Some("192.168.1")).await,
+            Err(crate::Error::Config(_))
+        ));
+    }
+}
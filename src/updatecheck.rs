@@ -0,0 +1,79 @@
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/dirkvdb/duco2mqtt/releases/latest";
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+/// State payload of the home assistant update entity
+#[derive(Debug, Serialize)]
+pub struct UpdateState {
+    pub installed_version: String,
+    pub latest_version: String,
+    pub release_url: String,
+}
+
+impl UpdateState {
+    pub fn update_available(&self) -> bool {
+        is_newer_version(&self.latest_version, &self.installed_version)
+    }
+}
+
+pub async fn check_latest_release() -> Result<UpdateState> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+
+    let response = client
+        .get(RELEASES_URL)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()
+        .await
+        .context("Failed to obtain latest release")?
+        .error_for_status()?;
+    let release: Release = serde_json::from_slice(&response.bytes().await?)?;
+
+    Ok(UpdateState {
+        installed_version: CURRENT_VERSION.to_string(),
+        latest_version: release.tag_name.trim_start_matches('v').to_string(),
+        release_url: release.html_url,
+    })
+}
+
+fn parse_version(version: &str) -> Result<Vec<u64>> {
+    version
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .take(3)
+        .map(|part| part.parse().map_err(|_| anyhow!("Invalid version '{}'", version)))
+        .collect()
+}
+
+fn is_newer_version(candidate: &str, current: &str) -> bool {
+    match (parse_version(candidate), parse_version(current)) {
+        (Ok(candidate), Ok(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_compare() {
+        assert!(is_newer_version("1.0.1", "1.0.0"));
+        assert!(is_newer_version("v1.1.0", "1.0.9"));
+        assert!(is_newer_version("2.0.0", "1.10.0"));
+        assert!(!is_newer_version("1.0.0", "1.0.0"));
+        assert!(!is_newer_version("0.9.0", "1.0.0"));
+        assert!(!is_newer_version("nightly", "1.0.0"));
+    }
+}
@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use crate::ducoapi::StatusValue;
+
+/// How the raw value of a status key is published
+#[derive(Debug, Clone, PartialEq)]
+enum Transform {
+    /// Multiplied with the factor, e.g. 0.1 for the values in tenths (215 becomes 21.5)
+    Scale(f64),
+    /// Seconds since the unix epoch as an ISO 8601 timestamp in UTC (e.g. TimeStateEnd)
+    Timestamp,
+}
+
+/// A transformation rule: `<key>=<factor>` scales the value, `<key>=timestamp` decodes an epoch timestamp.
+/// The key is a status key (e.g. `Sensor/TempOda` or `HeatRecovery/Bypass/TempSupTgt`), matched case insensitive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformRule {
+    key: String,
+    transform: Transform,
+}
+
+impl FromStr for TransformRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<TransformRule, String> {
+        let invalid = || format!("expected <key>=<factor> or <key>=timestamp, got '{}'", rule);
+        let (key, transform) = rule.trim().split_once('=').ok_or_else(invalid)?;
+        if key.is_empty() {
+            return Err(invalid());
+        }
+
+        let transform = match transform {
+            "timestamp" => Transform::Timestamp,
+            factor => match factor.parse::<f64>() {
+                Ok(factor) if factor.is_finite() && factor != 0.0 => Transform::Scale(factor),
+                _ => return Err(invalid()),
+            },
+        };
+
+        Ok(TransformRule {
+            key: key.to_string(),
+            transform,
+        })
+    }
+}
+
+/// Converts the raw status values of the box (e.g. temperatures in tenths) before they are published.
+/// Values without a rule and values that are not a number are published unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValueTransform {
+    rules: Vec<TransformRule>,
+}
+
+impl ValueTransform {
+    pub fn new(rules: Vec<TransformRule>) -> ValueTransform {
+        ValueTransform { rules }
+    }
+
+    fn transform(&self, key: &str) -> Option<&Transform> {
+        self.rules
+            .iter()
+            .find(|rule| rule.key.eq_ignore_ascii_case(key))
+            .map(|rule| &rule.transform)
+    }
+
+    /// The key is published in another unit than the box reports it
+    pub fn is_transformed(&self, key: &str) -> bool {
+        self.transform(key).is_some()
+    }
+
+    /// The payload of the status topic
+    pub fn payload(&self, key: &str, value: &StatusValue) -> String {
+        match self.json_value(key, value) {
+            serde_json::Value::String(val) => val,
+            val => val.to_string(),
+        }
+    }
+
+    /// The value in the json state document of the node
+    pub fn json_value(&self, key: &str, value: &StatusValue) -> serde_json::Value {
+        let val = match value {
+            StatusValue::Number(val) => *val,
+            StatusValue::String(val) => return serde_json::Value::from(val.as_str()),
+        };
+
+        match self.transform(key) {
+            None => serde_json::Value::from(val),
+            Some(Transform::Scale(factor)) => {
+                // round off the floating point noise, 215 * 0.1 is 21.500000000000004
+                let decimals = (-factor.abs().log10()).ceil().clamp(0.0, 6.0) as i32;
+                let scale = 10f64.powi(decimals);
+                serde_json::Value::from((val as f64 * factor * scale).round() / scale)
+            }
+            Some(Transform::Timestamp) => match chrono::DateTime::from_timestamp(val, 0) {
+                // zero means there is no timestamp (e.g. no timer is running)
+                Some(timestamp) if val > 0 => serde_json::Value::from(timestamp.to_rfc3339()),
+                _ => serde_json::Value::from(val),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_transform() {
+        let transform = ValueTransform::new(vec![
+            "Sensor/TempOda=0.1".parse().unwrap(),
+            "ventilation/timestateend=timestamp".parse().unwrap(),
+            "Sensor/Rh=2".parse().unwrap(),
+        ]);
+
+        assert_eq!(transform.payload("Sensor/TempOda", &StatusValue::Number(215)), "21.5");
+        assert_eq!(transform.payload("Sensor/TempOda", &StatusValue::Number(-3)), "-0.3");
+        assert_eq!(transform.payload("Sensor/Rh", &StatusValue::Number(21)), "42.0");
+        assert_eq!(
+            transform.payload("Ventilation/TimeStateEnd", &StatusValue::Number(1735689600)),
+            "2025-01-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            transform.payload("Ventilation/TimeStateEnd", &StatusValue::Number(0)),
+            "0"
+        );
+        assert_eq!(transform.payload("Sensor/Co2", &StatusValue::Number(215)), "215");
+        assert_eq!(
+            transform.payload("Sensor/TempOda", &StatusValue::String("UNKNOWN".to_string())),
+            "UNKNOWN"
+        );
+        assert_eq!(
+            transform.json_value("Sensor/TempOda", &StatusValue::Number(215)),
+            serde_json::json!(21.5)
+        );
+        assert!(transform.is_transformed("sensor/tempoda"));
+        assert!(!transform.is_transformed("Sensor/Co2"));
+
+        for invalid in ["Sensor/TempOda", "=0.1", "Sensor/TempOda=0", "Sensor/TempOda=tenths"] {
+            assert!(invalid.parse::<TransformRule>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, ensure};
+use serde::Deserialize;
+
+use crate::{
+    Result,
+    ducoapi::{NodeActionDescription, NodeActions, NodeInfo, StatusField, StatusValue},
+    ducoboxnode::{DucoBoxNode, DucoNodeAction},
+    duconodetypes::NodeType,
+};
+
+const MIXED_STATE: &str = "MIXED";
+
+/// A logical node that is not known by the duco box, commands are forwarded to all of its member nodes
+#[derive(Debug, Clone, Deserialize)]
+pub struct VirtualNodeConfig {
+    #[serde(rename = "Node")]
+    pub number: u16,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Members")]
+    pub members: Vec<u16>,
+}
+
+pub fn load_virtual_nodes(path: &Path) -> Result<Vec<VirtualNodeConfig>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read virtual nodes {}", path.display()))?;
+    let nodes: Vec<VirtualNodeConfig> = serde_json::from_slice(&data)?;
+
+    for node in &nodes {
+        ensure!(!node.members.is_empty(), "Virtual node {} has no members", node.number);
+    }
+
+    Ok(nodes)
+}
+
+impl VirtualNodeConfig {
+    fn members<'a>(&self, nodes: &'a [DucoBoxNode]) -> Result<Vec<&'a DucoBoxNode>> {
+        self.members
+            .iter()
+            .map(|nr| {
+                nodes
+                    .iter()
+                    .find(|node| node.number() == *nr)
+                    .with_context(|| format!("Member {} of virtual node {} not found", nr, self.number))
+            })
+            .collect()
+    }
+
+    /// Creates the node that represents this virtual node, it supports the actions that all members support
+    pub fn create_node(&self, nodes: &[DucoBoxNode]) -> Result<DucoBoxNode> {
+        ensure!(
+            nodes.iter().all(|node| node.number() != self.number),
+            "Virtual node number {} is already in use",
+            self.number
+        );
+
+        let members = self.members(nodes)?;
+        let mut node = DucoBoxNode::create_for_node_type(NodeType::Virtual, self.number);
+        node.set_actions(NodeActions {
+            node: self.number,
+            actions: common_actions(&members),
+        })?;
+        node.update_status(self.node_info(&members))?;
+        Ok(node)
+    }
+
+    pub fn update_node(&self, nodes: &mut [DucoBoxNode]) -> Result<()> {
+        let node_info = self.node_info(&self.members(nodes)?);
+        nodes
+            .iter_mut()
+            .find(|node| node.number() == self.number)
+            .with_context(|| format!("Virtual node {} not found", self.number))?
+            .update_status(node_info)
+    }
+
+    fn node_info(&self, members: &[&DucoBoxNode]) -> NodeInfo {
+        let states: Vec<&StatusValue> = members
+            .iter()
+            .filter_map(|node| node.status_value("Ventilation/State"))
+            .collect();
+
+        let state = match states.first() {
+            Some(first) if states.len() == members.len() && states.iter().all(|state| state == first) => {
+                (*first).clone()
+            }
+            _ => StatusValue::String(MIXED_STATE.to_string()),
+        };
+
+        let member_list = self.members.iter().map(u16::to_string).collect::<Vec<_>>().join(",");
+
+        NodeInfo {
+            node: self.number,
+            general: HashMap::from([
+                (
+                    "Type".to_string(),
+                    StatusField::from(NodeType::Virtual.to_string().as_str()),
+                ),
+                ("Name".to_string(), StatusField::from(self.name.as_str())),
+                ("Members".to_string(), StatusField::from(member_list.as_str())),
+            ]),
+            ventilation: HashMap::from([("State".to_string(), StatusField { val: state })]),
+            sensor: None,
+        }
+    }
+}
+
+fn common_actions(members: &[&DucoBoxNode]) -> Vec<NodeActionDescription> {
+    let Some((first, others)) = members.split_first() else {
+        return Vec::new();
+    };
+
+    first
+        .actions()
+        .iter()
+        .filter_map(|action| match action {
+            DucoNodeAction::SetBoolean(name) => others
+                .iter()
+                .all(|node| {
+                    node.actions()
+                        .iter()
+                        .any(|other| matches!(other, DucoNodeAction::SetBoolean(other_name) if other_name == name))
+                })
+                .then(|| NodeActionDescription {
+                    action: name.clone(),
+                    val_type: "Boolean".to_string(),
+                    values: None,
+                }),
+            DucoNodeAction::SetEnum(name, values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .filter(|val| {
+                        others
+                            .iter()
+                            .all(|node| node.valid_action_values(name).is_ok_and(|other| other.contains(val)))
+                    })
+                    .cloned()
+                    .collect();
+
+                (!values.is_empty()).then(|| NodeActionDescription {
+                    action: name.clone(),
+                    val_type: "Enum".to_string(),
+                    values: Some(values),
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ducoapi;
+
+    fn test_nodes() -> Vec<DucoBoxNode> {
+        let nodes = ducoapi::parse_node_info(include_bytes!("../test/data/info_nodes.json")).unwrap();
+        let actions = ducoapi::parse_node_actions(include_bytes!("../test/data/node_actions.json")).unwrap();
+
+        nodes
+            .into_iter()
+            .zip(actions)
+            .map(|(info, actions)| {
+                let mut node = DucoBoxNode::try_from(info).unwrap();
+                node.set_actions(actions).unwrap();
+                node
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_virtual_node() {
+        let nodes = test_nodes();
+        let config = VirtualNodeConfig {
+            number: 1001,
+            name: "Upstairs".to_string(),
+            members: vec![67, 68],
+        };
+
+        let mut node = config.create_node(&nodes).unwrap();
+        assert_eq!(node.number(), 1001);
+        assert!(
+            node.valid_action_values("SetVentilationState")
+                .unwrap()
+                .contains(&"MAN2".to_string())
+        );
+        assert_eq!(
+            node.status_value("General/Members"),
+            Some(&StatusValue::String("67,68".to_string()))
+        );
+        assert!(node.status_value("Ventilation/State").is_some());
+        assert!(!node.topics_that_need_updating().is_empty());
+
+        let invalid = VirtualNodeConfig {
+            number: 1,
+            name: "Duplicate".to_string(),
+            members: vec![67],
+        };
+        assert!(invalid.create_node(&nodes).is_err());
+
+        let missing_member = VirtualNodeConfig {
+            number: 1002,
+            name: "Missing".to_string(),
+            members: vec![99],
+        };
+        assert!(missing_member.create_node(&nodes).is_err());
+    }
+}